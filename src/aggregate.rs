@@ -0,0 +1,228 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use log::trace;
+use crate::ast::{AggregateFunc, Expr};
+use crate::eval;
+use crate::lexer::Position;
+use crate::record;
+use crate::schema::{Schema, Value};
+
+/// 集約関数1つ分の途中の値
+#[derive(Clone, Debug)]
+struct Accumulator {
+    func: AggregateFunc,
+    /// 加えたNULLでない値の数。`count(*)`では行の数
+    count: i64,
+    /// sumとavgでは合計、minとmaxではそれまでの最小か最大。まだ値がなければNULL
+    value: Value,
+}
+
+impl Accumulator {
+    fn new(func: AggregateFunc) -> Self {
+        Accumulator { func, count: 0, value: Value::Null }
+    }
+
+    /// 値を1つ加える。NULLは数えない
+    fn add(&mut self, value: Value, position: Position) -> Result<(), String> {
+        if value == Value::Null {
+            return Ok(());
+        }
+        self.count += 1;
+        let current = std::mem::replace(&mut self.value, Value::Null);
+        self.value = match (self.func, current) {
+            (AggregateFunc::Count, _) => Value::Null,
+            (AggregateFunc::Sum, Value::Null) => sum(Value::Integer(0), value, position)?,
+            (AggregateFunc::Avg, Value::Null) => sum(Value::Real(0.0), value, position)?,
+            (AggregateFunc::Sum, current) | (AggregateFunc::Avg, current) => sum(current, value, position)?,
+            (AggregateFunc::Min, Value::Null) | (AggregateFunc::Max, Value::Null) => value,
+            (AggregateFunc::Min, current) if eval::compare(&value, &current) == Some(Ordering::Less) => value,
+            (AggregateFunc::Max, current) if eval::compare(&value, &current) == Some(Ordering::Greater) => value,
+            (AggregateFunc::Min, current) | (AggregateFunc::Max, current) => current,
+        };
+        Ok(())
+    }
+
+    /// 集約関数の値。countのほかは、値が1つもなければNULLになる
+    fn result(&self) -> Value {
+        match self.func {
+            AggregateFunc::Count => Value::Integer(self.count),
+            AggregateFunc::Avg if self.count > 0 => match eval::real(&self.value) {
+                Some(sum) => Value::Real(sum / self.count as f64),
+                None => Value::Null,
+            },
+            AggregateFunc::Avg => Value::Null,
+            AggregateFunc::Sum | AggregateFunc::Min | AggregateFunc::Max => self.value.clone(),
+        }
+    }
+}
+
+/// 合計に値を足す。INTEGERどうしならINTEGERのまま、それ以外はREALで足す
+fn sum(total: Value, value: Value, position: Position) -> Result<Value, String> {
+    if let (Some(l), Some(r)) = (eval::integer(&total), eval::integer(&value)) {
+        return l.checked_add(r).map(Value::Integer).ok_or_else(|| eval::error(position, "integer overflow"));
+    }
+    match (eval::real(&total), eval::real(&value)) {
+        (Some(l), Some(r)) => Ok(Value::Real(l + r)),
+        _ => Err(eval::error(position, format!("cannot sum {}", value.type_name()))),
+    }
+}
+
+/// まとめた行の1つ分。rowはグループの最初の行で、集約関数の外のカラムはこの値になる
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Group<'a> {
+    pub(crate) row: Vec<Value>,
+    /// 集約関数と、このグループで計算した値
+    pub(crate) results: Vec<(&'a Expr, Value)>,
+}
+
+impl Group<'_> {
+    /// 式の中の集約関数を、このグループで計算した値のリテラルに置き換える
+    pub(crate) fn resolve(&self, expr: &Expr) -> Expr {
+        expr.replace(&mut |e| {
+            let (_, value) = self.results.iter().find(|(aggregate, _)| *aggregate == e)?;
+            Some(Expr::Literal { value: value.clone(), position: e.position() })
+        })
+    }
+}
+
+/// 行をgroup byの値ごとにまとめ、グループごとに集約関数を計算する。
+/// グループはgroup byの値をrecordにしたものをkeyにしたハッシュ表で探し、最初に現れた順に並べる
+pub(crate) struct Grouping<'a> {
+    schema: &'a Schema,
+    group_by: &'a [Expr],
+    aggregates: Vec<&'a Expr>,
+    groups: Vec<(Vec<Value>, Vec<Accumulator>)>,
+    index: HashMap<Vec<u8>, usize>,
+}
+
+impl<'a> Grouping<'a> {
+    /// exprsはselectやhavingの式で、その中の集約関数を計算する
+    pub(crate) fn new(schema: &'a Schema, group_by: &'a [Expr], exprs: impl IntoIterator<Item = &'a Expr>) -> Self {
+        let aggregates = exprs.into_iter().flat_map(|expr| expr.aggregates()).collect();
+        Grouping { schema, group_by, aggregates, groups: vec![], index: HashMap::new() }
+    }
+
+    fn accumulators(&self) -> Vec<Accumulator> {
+        self.aggregates.iter().map(|aggregate| match aggregate {
+            Expr::Aggregate { func, .. } => Accumulator::new(*func),
+            e => unreachable!("accumulators: not an aggregate: {:?}", e),
+        }).collect()
+    }
+
+    /// 行を1つ加える
+    pub(crate) fn add(&mut self, row: Vec<Value>) -> Result<(), String> {
+        let keys = self.group_by.iter()
+            .map(|expr| eval::eval(expr, self.schema, &row))
+            .collect::<Result<Vec<Value>, String>>()?;
+        // `count(*)`はNULLでない値を数えるのと同じにする
+        let args = self.aggregates.iter().map(|aggregate| match aggregate {
+            Expr::Aggregate { arg: Some(arg), position, .. } => Ok((eval::eval(arg, self.schema, &row)?, *position)),
+            _ => Ok((Value::Boolean(true), aggregate.position())),
+        }).collect::<Result<Vec<(Value, Position)>, String>>()?;
+        let mut key = vec![];
+        record::encode(&keys, &mut key);
+        let index = match self.index.get(&key) {
+            Some(index) => *index,
+            None => {
+                let accumulators = self.accumulators();
+                self.groups.push((row, accumulators));
+                self.index.insert(key, self.groups.len() - 1);
+                self.groups.len() - 1
+            }
+        };
+        for (accumulator, (value, position)) in self.groups[index].1.iter_mut().zip(args) {
+            accumulator.add(value, position)?;
+        }
+        Ok(())
+    }
+
+    /// グループごとの結果を返す。group byがなければ、行がなくても1つのグループになる
+    pub(crate) fn finish(mut self) -> Vec<Group<'a>> {
+        if self.groups.is_empty() && self.group_by.is_empty() {
+            let accumulators = self.accumulators();
+            self.groups.push((vec![Value::Null; self.schema.columns.len()], accumulators));
+        }
+        trace!("grouping: {} groups", self.groups.len());
+        let aggregates = self.aggregates;
+        self.groups.into_iter().map(|(row, accumulators)| Group {
+            row,
+            results: aggregates.iter().zip(accumulators).map(|(aggregate, acc)| (*aggregate, acc.result())).collect(),
+        }).collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ast::{SelectItem, Statement};
+    use crate::parser;
+
+    fn schema() -> Schema {
+        Schema::parse("create table items (id integer primary key, name text, price integer, discount real)").unwrap()
+    }
+
+    fn row(id: i64, name: &str, price: i64, discount: Option<f64>) -> Vec<Value> {
+        vec![Value::Integer(id), Value::Text(name.to_string()), Value::Integer(price), discount.map_or(Value::Null, Value::Real)]
+    }
+
+    /// selectの式とgroup byを読み、行をまとめた結果を、式ごとの値にして返す
+    fn group(sql: &str, rows: Vec<Vec<Value>>) -> Result<Vec<Vec<Value>>, String> {
+        let schema = schema();
+        let (columns, group_by) = match parser::parse(sql).unwrap() {
+            Some(Statement::Select { columns, group_by, .. }) => (columns, group_by),
+            s => panic!("unexpected statement: {:?}", s),
+        };
+        let exprs: Vec<&Expr> = columns.iter().map(|item| match item {
+            SelectItem::Expr { expr, .. } => expr,
+            SelectItem::Wildcard => unreachable!(),
+        }).collect();
+        let mut grouping = Grouping::new(&schema, &group_by, exprs.iter().copied());
+        for row in rows {
+            grouping.add(row)?;
+        }
+        let groups = grouping.finish();
+        Ok(groups.iter().map(|group| {
+            exprs.iter().map(|expr| eval::eval(&group.resolve(expr), &schema, &group.row).unwrap()).collect()
+        }).collect())
+    }
+
+    #[test]
+    fn test_group_by() {
+        let rows = vec![
+            row(1, "pen", 100, None),
+            row(2, "book", 300, Some(0.5)),
+            row(3, "pen", 150, Some(0.1)),
+            row(4, "pen", 200, Some(0.3)),
+        ];
+        let result = group(
+            "select name, count(*), count(discount), sum(price), avg(price), min(discount), max(discount) * 10 group by name",
+            rows,
+        );
+        assert_eq!(result, Ok(vec![
+            vec![Value::Text("pen".to_string()), Value::Integer(3), Value::Integer(2), Value::Integer(450),
+                 Value::Real(150.0), Value::Real(0.1), Value::Real(3.0)],
+            vec![Value::Text("book".to_string()), Value::Integer(1), Value::Integer(1), Value::Integer(300),
+                 Value::Real(300.0), Value::Real(0.5), Value::Real(5.0)],
+        ]));
+    }
+
+    #[test]
+    fn test_without_rows() {
+        // group byがなければ行がなくても1行になり、countのほかはNULL
+        assert_eq!(group("select count(*), count(name), sum(price), avg(price), min(name)", vec![]), Ok(vec![
+            vec![Value::Integer(0), Value::Integer(0), Value::Null, Value::Null, Value::Null],
+        ]));
+        assert_eq!(group("select count(*) group by name", vec![]), Ok(vec![]));
+    }
+
+    #[test]
+    fn test_sum() {
+        let rows = vec![row(1, "a", 1, Some(0.5)), row(2, "b", 2, None), row(3, "c", 3, Some(0.25))];
+        assert_eq!(group("select sum(price), sum(discount), sum(price + discount), avg(discount)", rows), Ok(vec![
+            vec![Value::Integer(6), Value::Real(0.75), Value::Real(4.75), Value::Real(0.375)],
+        ]));
+        let rows = vec![row(1, "a", i64::MAX, None), row(2, "b", 1, None)];
+        assert_eq!(group("select sum(price)", rows.clone()), Err("line 1, column 8: integer overflow".to_string()));
+        assert_eq!(group("select sum(name)", rows), Err("line 1, column 8: cannot sum TEXT".to_string()));
+    }
+}
@@ -0,0 +1,389 @@
+use std::fmt;
+use crate::lexer::Position;
+use crate::schema::{ColumnType, Value};
+
+/// parserが作る文。tableの名前を省略したときはNoneで、usersを指す
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Statement {
+    /// `insert [into table] value ...`
+    Insert {
+        table: Option<String>,
+        values: Vec<Expr>,
+    },
+    /// `select [item, ...] [from table] [where expr] [group by expr, ...] [having expr]
+    /// [order by column [asc|desc]] [limit n]`
+    Select {
+        /// 空なら全てのカラム
+        columns: Vec<SelectItem>,
+        table: Option<String>,
+        where_clause: Option<Expr>,
+        group_by: Vec<Expr>,
+        having: Option<Expr>,
+        order_by: Option<OrderBy>,
+        limit: Option<usize>,
+    },
+    /// `update [table] set column = expr, ... [where expr]`
+    Update {
+        table: Option<String>,
+        assignments: Vec<Assignment>,
+        where_clause: Option<Expr>,
+    },
+    /// `delete [from table] [where expr]`
+    Delete {
+        table: Option<String>,
+        where_clause: Option<Expr>,
+    },
+    /// `create table name (column TYPE[(n)] [primary key], ...)`
+    CreateTable {
+        name: String,
+        columns: Vec<ColumnDefinition>,
+        position: Position,
+    },
+    Begin,
+    Commit,
+    /// `rollback [transaction] [to [savepoint] name]`
+    Rollback {
+        savepoint: Option<String>,
+    },
+    Savepoint {
+        name: String,
+    },
+    Release {
+        name: String,
+    },
+    Vacuum,
+}
+
+/// selectで出力するもの
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum SelectItem {
+    /// `*`。tableの全てのカラム
+    Wildcard,
+    /// `expr [[as] alias]`
+    Expr {
+        expr: Expr,
+        alias: Option<String>,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct ColumnDefinition {
+    pub(crate) name: String,
+    pub(crate) col_type: ColumnType,
+    /// `TEXT(n)`のn。省略するとNone
+    pub(crate) length: Option<usize>,
+    pub(crate) primary_key: bool,
+    pub(crate) position: Position,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Assignment {
+    pub(crate) column: String,
+    pub(crate) value: Expr,
+    pub(crate) position: Position,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct OrderBy {
+    pub(crate) column: String,
+    pub(crate) descending: bool,
+    pub(crate) position: Position,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum UnaryOp {
+    Neg,
+    Not,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum BinaryOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    And,
+    Or,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    Concat,
+}
+
+impl fmt::Display for BinaryOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            BinaryOp::Eq => "=",
+            BinaryOp::Ne => "<>",
+            BinaryOp::Lt => "<",
+            BinaryOp::Le => "<=",
+            BinaryOp::Gt => ">",
+            BinaryOp::Ge => ">=",
+            BinaryOp::And => "and",
+            BinaryOp::Or => "or",
+            BinaryOp::Add => "+",
+            BinaryOp::Sub => "-",
+            BinaryOp::Mul => "*",
+            BinaryOp::Div => "/",
+            BinaryOp::Rem => "%",
+            BinaryOp::Concat => "||",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// 集約関数
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum AggregateFunc {
+    Count,
+    Sum,
+    Avg,
+    Min,
+    Max,
+}
+
+impl AggregateFunc {
+    pub(crate) fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "count" => Some(AggregateFunc::Count),
+            "sum" => Some(AggregateFunc::Sum),
+            "avg" => Some(AggregateFunc::Avg),
+            "min" => Some(AggregateFunc::Min),
+            "max" => Some(AggregateFunc::Max),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for AggregateFunc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            AggregateFunc::Count => "count",
+            AggregateFunc::Sum => "sum",
+            AggregateFunc::Avg => "avg",
+            AggregateFunc::Min => "min",
+            AggregateFunc::Max => "max",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// 式。positionは式が始まる位置
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Expr {
+    Literal {
+        value: Value,
+        position: Position,
+    },
+    Column {
+        name: String,
+        position: Position,
+    },
+    Unary {
+        op: UnaryOp,
+        operand: Box<Expr>,
+        position: Position,
+    },
+    Binary {
+        op: BinaryOp,
+        left: Box<Expr>,
+        right: Box<Expr>,
+        position: Position,
+    },
+    /// `operand [not] between low and high`
+    Between {
+        operand: Box<Expr>,
+        low: Box<Expr>,
+        high: Box<Expr>,
+        negated: bool,
+        position: Position,
+    },
+    /// `operand is [not] null`
+    IsNull {
+        operand: Box<Expr>,
+        negated: bool,
+        position: Position,
+    },
+    /// `operand [not] in (expr, ...)`
+    InList {
+        operand: Box<Expr>,
+        list: Vec<Expr>,
+        negated: bool,
+        position: Position,
+    },
+    /// `operand [not] like pattern`。`%`は任意の文字列、`_`は任意の1文字に当たる
+    Like {
+        operand: Box<Expr>,
+        pattern: Box<Expr>,
+        negated: bool,
+        position: Position,
+    },
+    /// `name(arg, ...)`。名前は小文字
+    Function {
+        name: String,
+        args: Vec<Expr>,
+        position: Position,
+    },
+    /// `func(arg)`。argがNoneなら`count(*)`
+    Aggregate {
+        func: AggregateFunc,
+        arg: Option<Box<Expr>>,
+        position: Position,
+    },
+}
+
+impl Expr {
+    pub(crate) fn position(&self) -> Position {
+        match self {
+            Expr::Literal { position, .. }
+            | Expr::Column { position, .. }
+            | Expr::Unary { position, .. }
+            | Expr::Binary { position, .. }
+            | Expr::Between { position, .. }
+            | Expr::IsNull { position, .. }
+            | Expr::InList { position, .. }
+            | Expr::Like { position, .. }
+            | Expr::Function { position, .. }
+            | Expr::Aggregate { position, .. } => *position,
+        }
+    }
+
+    /// 自身と中の式を全て、外側から順に渡す
+    pub(crate) fn walk<'a>(&'a self, f: &mut impl FnMut(&'a Expr)) {
+        f(self);
+        match self {
+            Expr::Literal { .. } | Expr::Column { .. } => {}
+            Expr::Unary { operand, .. } | Expr::IsNull { operand, .. } => operand.walk(f),
+            Expr::Binary { left, right, .. } => {
+                left.walk(f);
+                right.walk(f);
+            }
+            Expr::Between { operand, low, high, .. } => {
+                operand.walk(f);
+                low.walk(f);
+                high.walk(f);
+            }
+            Expr::InList { operand, list, .. } => {
+                operand.walk(f);
+                list.iter().for_each(|e| e.walk(f));
+            }
+            Expr::Like { operand, pattern, .. } => {
+                operand.walk(f);
+                pattern.walk(f);
+            }
+            Expr::Function { args, .. } => args.iter().for_each(|e| e.walk(f)),
+            Expr::Aggregate { arg, .. } => arg.iter().for_each(|e| e.walk(f)),
+        }
+    }
+
+    /// 中の集約関数を外側から順に返す
+    pub(crate) fn aggregates(&self) -> Vec<&Expr> {
+        let mut aggregates = vec![];
+        self.walk(&mut |expr| {
+            if let Expr::Aggregate { .. } = expr {
+                aggregates.push(expr);
+            }
+        });
+        aggregates
+    }
+
+    /// fがSomeを返した式をその値に置き換えた式を作る。置き換えた式の中は辿らない
+    pub(crate) fn replace(&self, f: &mut impl FnMut(&Expr) -> Option<Expr>) -> Expr {
+        if let Some(expr) = f(self) {
+            return expr;
+        }
+        let mut replace = |expr: &Expr| Box::new(expr.replace(f));
+        match self {
+            Expr::Literal { .. } | Expr::Column { .. } => self.clone(),
+            Expr::Unary { op, operand, position } => Expr::Unary { op: *op, operand: replace(operand), position: *position },
+            Expr::Binary { op, left, right, position } => {
+                Expr::Binary { op: *op, left: replace(left), right: replace(right), position: *position }
+            }
+            Expr::Between { operand, low, high, negated, position } => Expr::Between {
+                operand: replace(operand),
+                low: replace(low),
+                high: replace(high),
+                negated: *negated,
+                position: *position,
+            },
+            Expr::IsNull { operand, negated, position } => {
+                Expr::IsNull { operand: replace(operand), negated: *negated, position: *position }
+            }
+            Expr::InList { operand, list, negated, position } => Expr::InList {
+                operand: replace(operand),
+                list: list.iter().map(|e| *replace(e)).collect(),
+                negated: *negated,
+                position: *position,
+            },
+            Expr::Like { operand, pattern, negated, position } => {
+                Expr::Like { operand: replace(operand), pattern: replace(pattern), negated: *negated, position: *position }
+            }
+            Expr::Function { name, args, position } => {
+                Expr::Function { name: name.clone(), args: args.iter().map(|e| *replace(e)).collect(), position: *position }
+            }
+            Expr::Aggregate { func, arg, position } => {
+                Expr::Aggregate { func: *func, arg: arg.as_deref().map(replace), position: *position }
+            }
+        }
+    }
+
+    /// リテラルならその値を返す
+    pub(crate) fn literal(&self) -> Option<&Value> {
+        match self {
+            Expr::Literal { value, .. } => Some(value),
+            _ => None,
+        }
+    }
+}
+
+/// SQLの形で表示する。selectの結果のカラムの名前に使う。
+/// 演算の中の演算は括弧で囲むので、元の文と括弧の付け方が違うことがある
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let not = |negated: &bool| if *negated { "not " } else { "" };
+        match self {
+            Expr::Literal { value: Value::Text(v), .. } => write!(f, "'{}'", v.replace('\'', "''")),
+            Expr::Literal { value, .. } => write!(f, "{}", value),
+            Expr::Column { name, .. } => write!(f, "{}", name),
+            Expr::Unary { op: UnaryOp::Neg, operand, .. } => write!(f, "-{}", Operand(operand)),
+            Expr::Unary { op: UnaryOp::Not, operand, .. } => write!(f, "not {}", Operand(operand)),
+            Expr::Binary { op, left, right, .. } => write!(f, "{} {} {}", Operand(left), op, Operand(right)),
+            Expr::Between { operand, low, high, negated, .. } => {
+                write!(f, "{} {}between {} and {}", Operand(operand), not(negated), Operand(low), Operand(high))
+            }
+            Expr::IsNull { operand, negated, .. } => write!(f, "{} is {}null", Operand(operand), not(negated)),
+            Expr::InList { operand, list, negated, .. } => {
+                let list: Vec<String> = list.iter().map(|e| e.to_string()).collect();
+                write!(f, "{} {}in ({})", Operand(operand), not(negated), list.join(", "))
+            }
+            Expr::Like { operand, pattern, negated, .. } => {
+                write!(f, "{} {}like {}", Operand(operand), not(negated), Operand(pattern))
+            }
+            Expr::Function { name, args, .. } => {
+                let args: Vec<String> = args.iter().map(|e| e.to_string()).collect();
+                write!(f, "{}({})", name, args.join(", "))
+            }
+            Expr::Aggregate { func, arg: Some(arg), .. } => write!(f, "{}({})", func, arg),
+            Expr::Aggregate { func, arg: None, .. } => write!(f, "{}(*)", func),
+        }
+    }
+}
+
+/// 演算の中の式。演算なら括弧で囲んで表示する
+struct Operand<'a>(&'a Expr);
+
+impl fmt::Display for Operand<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Expr::Literal { .. } | Expr::Column { .. } | Expr::Function { .. } | Expr::Aggregate { .. } => write!(f, "{}", self.0),
+            Expr::Unary { op: UnaryOp::Neg, operand, .. } if matches!(**operand, Expr::Literal { .. } | Expr::Column { .. }) => {
+                write!(f, "{}", self.0)
+            }
+            _ => write!(f, "({})", self.0),
+        }
+    }
+}
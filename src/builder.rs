@@ -0,0 +1,248 @@
+use log::trace;
+use crate::table::Pager;
+use crate::tree::{BTreeNode, BTreeLeafNode, BTreeInternalNode, KC, KV};
+
+/// ソート済みのkeyと行から、B-treeを葉から順に一度で組み立てる。
+/// 挿入を繰り返して分割するのと違い、leafを詰めて書けるのでvacuumや一括読み込みで使う。
+///
+/// 行の大きさから先に各段のノードの数と中身の数を決め、page_numも先に割り当てておくので、
+/// leafを書いた後で親ポインタを直すために読み直す必要がない
+pub(crate) struct TreeBuilder<'a> {
+    pager: &'a mut Pager,
+    // 各段のノードのpage_num。levels[0]がleafで、最後の段がroot
+    levels: Vec<Vec<usize>>,
+    // 各段のノードの子の数。leafならcellの数
+    sizes: Vec<Vec<usize>>,
+    // 組み立て中のleafの位置と中身
+    leaf_index: usize,
+    key_values: Vec<KV>,
+    // 書き終えたleafの最大のkey
+    max_keys: Vec<u32>,
+    last_key: Option<u32>,
+}
+
+/// 一括読み込みでleafをどこまで埋めるか(%)。後から挿入するなら小さくして分割を減らす
+pub(crate) const DEFAULT_FILL_FACTOR: usize = 100;
+
+/// m個を、1つあたりmin以上cap以下になるようにできるだけ均等に分ける
+fn split_evenly(m: usize, cap: usize, min: usize) -> Vec<usize> {
+    if m == 0 {
+        return vec![];
+    }
+    let mut k = m.div_ceil(cap);
+    if k > 1 && m / k < min {
+        k = std::cmp::max(m / min, 1);
+    }
+    (0..k).map(|i| m / k + usize::from(i < m % k)).collect()
+}
+
+/// 大きさsizesのcellを順にleafに詰め、leafごとのcellの数を返す。
+/// 各leafはcapバイトまで詰めるが、minバイトに届くまでは1ページに収まる限り詰める。
+/// 最後のleafがminに届かなければ、1つ前のleafと合わせて分け直す
+fn pack_leaves(sizes: &[usize], cap: usize, min: usize) -> Vec<usize> {
+    let mut counts = vec![];
+    let (mut count, mut used) = (0, 0);
+    for size in sizes {
+        let over = if used < min { BTreeLeafNode::NODE_SPACE_FOR_CELLS } else { cap };
+        if count > 0 && used + size > over {
+            counts.push(count);
+            count = 0;
+            used = 0;
+        }
+        count += 1;
+        used += size;
+    }
+    if count > 0 {
+        counts.push(count);
+    }
+    if used < min && counts.len() > 1 {
+        let last = counts.pop().unwrap();
+        let prev = counts.pop().unwrap();
+        let start = sizes.len() - last - prev;
+        let merged = &sizes[start..];
+        if merged.iter().sum::<usize>() <= BTreeLeafNode::NODE_SPACE_FOR_CELLS {
+            counts.push(last + prev);
+        } else {
+            let left = BTreeLeafNode::split_point(merged);
+            counts.push(left);
+            counts.push(merged.len() - left);
+        }
+    }
+    counts
+}
+
+/// max個のfill_factor(%)。min以上max以下に収める
+fn fill(max: usize, fill_factor: usize, min: usize) -> usize {
+    (max * fill_factor).div_ceil(100).clamp(min, max)
+}
+
+impl<'a> TreeBuilder<'a> {
+    /// record_sizesの大きさの行を順に入れる木を組み立てる準備をする。first_page_numは空のrootのleafで、そのままrootとして使う。
+    /// 各ノードはfill_factor(%)まで埋めるが、下限を下回るほど減らすことはない
+    pub(crate) fn new(pager: &'a mut Pager, first_page_num: usize, record_sizes: &[usize], fill_factor: usize) -> Self {
+        let num_rows = record_sizes.len();
+        let leaf_cap = fill(BTreeLeafNode::NODE_SPACE_FOR_CELLS, fill_factor, BTreeLeafNode::NODE_MIN_SIZE);
+        let internal_max_cells = pager.internal_max_cells();
+        let internal_min = internal_max_cells / 2 + 1;
+        let internal_cap = fill(internal_max_cells + 1, fill_factor, internal_min);
+        let cell_sizes: Vec<usize> = record_sizes.iter().map(|len| BTreeLeafNode::cell_size(*len)).collect();
+        let mut sizes = vec![pack_leaves(&cell_sizes, leaf_cap, BTreeLeafNode::NODE_MIN_SIZE)];
+        if sizes[0].is_empty() {
+            sizes[0].push(0);
+        }
+        while sizes.last().unwrap().len() > 1 {
+            let children = sizes.last().unwrap().len();
+            sizes.push(split_evenly(children, internal_cap, internal_min));
+        }
+        // leafから順に割り当てるので、leafはkeyの順に並んだpage_numになる。
+        // rootのpage_numはcatalogに載っているので動かさない
+        let height = sizes.len();
+        let mut levels = vec![];
+        for (depth, level) in sizes.iter().enumerate() {
+            let pages: Vec<usize> = (0..level.len())
+                .map(|_| if depth + 1 == height { first_page_num } else { pager.new_page_num() })
+                .collect();
+            levels.push(pages);
+        }
+        trace!("TreeBuilder::new: num_rows: {}, nodes per level: {:?}", num_rows, levels.iter().map(|l| l.len()).collect::<Vec<_>>());
+        TreeBuilder {
+            pager,
+            levels,
+            sizes,
+            leaf_index: 0,
+            key_values: vec![],
+            max_keys: vec![],
+            last_key: None,
+        }
+    }
+
+    /// depth段目のindex番目のノードの親のpage_num。rootなら0
+    fn parent_of(&self, depth: usize, index: usize) -> u32 {
+        let parents = match self.sizes.get(depth + 1) {
+            Some(v) => v,
+            None => return 0,
+        };
+        let mut first = 0;
+        for (i, size) in parents.iter().enumerate() {
+            first += size;
+            if index < first {
+                return self.levels[depth + 1][i] as u32;
+            }
+        }
+        unreachable!("TreeBuilder::parent_of: node {} at depth {} has no parent", index, depth)
+    }
+
+    fn is_root(&self, depth: usize) -> u8 {
+        u8::from(depth + 1 == self.levels.len())
+    }
+
+    /// 次の行を追加する。keyは昇順でなければならない
+    pub(crate) fn push(&mut self, key: u32, value: Vec<u8>) -> Result<(), String> {
+        if self.last_key.is_some_and(|last| last >= key) {
+            return Err(format!("keys must be sorted and unique: {} after {:?}", key, self.last_key));
+        }
+        if self.leaf_index >= self.sizes[0].len() {
+            return Err("more rows than planned".to_string());
+        }
+        self.last_key = Some(key);
+        let kv = self.pager.new_cell(key, value);
+        self.key_values.push(kv);
+        if self.key_values.len() == self.sizes[0][self.leaf_index] {
+            self.write_leaf();
+        }
+        Ok(())
+    }
+
+    fn write_leaf(&mut self) {
+        let index = self.leaf_index;
+        let page_num = self.levels[0][index];
+        let key_values = std::mem::take(&mut self.key_values);
+        let leaf = BTreeLeafNode {
+            is_root: self.is_root(0),
+            parent: self.parent_of(0, index),
+            num_cells: key_values.len() as u32,
+            next_leaf: self.levels[0].get(index + 1).map_or(0, |page_num| *page_num as u32),
+            key_values,
+            ..BTreeLeafNode::default()
+        };
+        trace!("TreeBuilder::write_leaf: page_num: {}, num_cells: {}", page_num, leaf.num_cells);
+        self.max_keys.push(leaf.max_key());
+        self.pager.put_page(page_num, BTreeNode::Leaf(leaf));
+        self.leaf_index += 1;
+    }
+
+    /// leafの上の段を組み立てる。rootのpage_numを返す
+    pub(crate) fn finish(mut self) -> Result<usize, String> {
+        if self.sizes[0] == [0] {
+            self.write_leaf();
+        }
+        if self.leaf_index != self.sizes[0].len() {
+            return Err(format!("fewer rows than planned: {} of {} leaves", self.leaf_index, self.sizes[0].len()));
+        }
+        let mut max_keys = std::mem::take(&mut self.max_keys);
+        for depth in 1..self.levels.len() {
+            let mut next_max_keys = vec![];
+            let mut first = 0;
+            for index in 0..self.levels[depth].len() {
+                let size = self.sizes[depth][index];
+                let children = &self.levels[depth - 1][first..first + size];
+                let keys = &max_keys[first..first + size];
+                let key_children: Vec<KC> = children[..size - 1].iter().zip(keys)
+                    .map(|(child, key)| KC { child: *child as u32, key: *key })
+                    .collect();
+                let mut node = BTreeInternalNode::new(self.is_root(depth), self.parent_of(depth, index));
+                node.num_keys = key_children.len() as u32;
+                node.key_children = key_children;
+                node.right_child = children[size - 1] as u32;
+                next_max_keys.push(keys[size - 1]);
+                trace!("TreeBuilder::finish: depth: {}, page_num: {}, num_keys: {}", depth, self.levels[depth][index], node.num_keys);
+                self.pager.put_page(self.levels[depth][index], BTreeNode::Internal(node));
+                first += size;
+            }
+            max_keys = next_max_keys;
+        }
+        Ok(self.levels.last().unwrap()[0])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_split_evenly() {
+        assert_eq!(split_evenly(0, 13, 6), Vec::<usize>::new());
+        assert_eq!(split_evenly(5, 13, 6), vec![5]);
+        assert_eq!(split_evenly(26, 13, 6), vec![13, 13]);
+        assert_eq!(split_evenly(27, 13, 6), vec![9, 9, 9]);
+        assert_eq!(split_evenly(14, 8, 6), vec![7, 7]);
+        assert_eq!(split_evenly(13, 7, 6), vec![7, 6]);
+        assert_eq!(split_evenly(9, 4, 2), vec![3, 3, 3]);
+        // capで分けると下限を下回る場合は、個数を減らす
+        assert_eq!(split_evenly(8, 7, 6), vec![8]);
+    }
+
+    #[test]
+    fn test_pack_leaves() {
+        let space = BTreeLeafNode::NODE_SPACE_FOR_CELLS;
+        let min = BTreeLeafNode::NODE_MIN_SIZE;
+        assert_eq!(pack_leaves(&[], space, min), Vec::<usize>::new());
+        assert_eq!(pack_leaves(&[100; 10], space, min), vec![10]);
+        let n = space / 100;
+        assert_eq!(pack_leaves(&vec![100; n * 2], space, min), vec![n, n]);
+        // 最後のleafが小さすぎる場合は、1つ前と分け直す
+        let counts = pack_leaves(&vec![100; n + 1], space, min);
+        assert_eq!(counts.len(), 2);
+        assert!(counts.iter().all(|c| c * 100 >= min && c * 100 <= space), "{:?}", counts);
+        // fill_factorで減らしたcapでも、下限までは詰める
+        assert_eq!(pack_leaves(&[100; 10], 100, 250), vec![3, 3, 4]);
+    }
+
+    #[test]
+    fn test_fill() {
+        assert_eq!(fill(13, 100, 6), 13);
+        assert_eq!(fill(13, 70, 6), 10);
+        assert_eq!(fill(13, 10, 6), 6);
+        assert_eq!(fill(4, 50, 2), 2);
+    }
+}
@@ -0,0 +1,182 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use log::trace;
+use crate::PAGE_SIZE;
+
+/// キャッシュの容量。ページ数かバイト数で指定する
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum CacheSize {
+    Pages(usize),
+    Bytes(usize),
+}
+
+impl CacheSize {
+    /// ページ数に換算する。最低でも1ページは持つ
+    pub(crate) fn pages(&self) -> usize {
+        let pages = match self {
+            CacheSize::Pages(n) => *n,
+            CacheSize::Bytes(n) => n / PAGE_SIZE,
+        };
+        std::cmp::max(pages, 1)
+    }
+}
+
+struct CacheEntry<T> {
+    value: T,
+    last_used: u64,
+}
+
+/// page_numをkeyにしたLRUキャッシュ。
+/// 追い出したページの書き戻しは呼び出し側(Pager)の責任
+pub(crate) struct PageCache<T> {
+    capacity: usize,
+    entries: HashMap<usize, CacheEntry<T>>,
+    // 最後に使った時刻 -> page_num。先頭が一番古い
+    lru: BTreeMap<u64, usize>,
+    clock: u64,
+    // 追い出さないページ。分割の途中で一時的に溢れたノードなど
+    pinned: HashSet<usize>,
+}
+
+impl<T> PageCache<T> {
+    pub(crate) fn new(size: CacheSize) -> Self {
+        PageCache {
+            capacity: size.pages(),
+            entries: HashMap::new(),
+            lru: BTreeMap::new(),
+            clock: 0,
+            pinned: HashSet::new(),
+        }
+    }
+
+    pub(crate) fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub(crate) fn set_capacity(&mut self, size: CacheSize) {
+        trace!("PageCache::set_capacity: {:?}", size);
+        self.capacity = size.pages();
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// 新しいページを入れる前に追い出しが必要か
+    pub(crate) fn is_full(&self) -> bool {
+        self.entries.len() >= self.capacity
+    }
+
+    pub(crate) fn contains(&self, page_num: usize) -> bool {
+        self.entries.contains_key(&page_num)
+    }
+
+    pub(crate) fn get(&mut self, page_num: usize) -> Option<&T> {
+        self.touch(page_num);
+        self.entries.get(&page_num).map(|entry| &entry.value)
+    }
+
+    /// LRUの順番を変えずに参照する
+    pub(crate) fn peek(&self, page_num: usize) -> Option<&T> {
+        self.entries.get(&page_num).map(|entry| &entry.value)
+    }
+
+    pub(crate) fn get_mut(&mut self, page_num: usize) -> Option<&mut T> {
+        self.touch(page_num);
+        self.entries.get_mut(&page_num).map(|entry| &mut entry.value)
+    }
+
+    /// ページを入れる。容量の確認はしないので、必要なら先にpop_lruで追い出しておく
+    pub(crate) fn insert(&mut self, page_num: usize, value: T) {
+        self.remove(page_num);
+        self.clock += 1;
+        self.lru.insert(self.clock, page_num);
+        self.entries.insert(page_num, CacheEntry { value, last_used: self.clock });
+    }
+
+    pub(crate) fn remove(&mut self, page_num: usize) -> Option<T> {
+        let entry = self.entries.remove(&page_num)?;
+        self.lru.remove(&entry.last_used);
+        Some(entry.value)
+    }
+
+    /// unpinするまでpop_lruで取り出さないようにする。全てpinされていれば容量を超えて持つ
+    pub(crate) fn pin(&mut self, page_num: usize) {
+        trace!("PageCache::pin: page {}", page_num);
+        self.pinned.insert(page_num);
+    }
+
+    pub(crate) fn unpin(&mut self, page_num: usize) {
+        trace!("PageCache::unpin: page {}", page_num);
+        self.pinned.remove(&page_num);
+    }
+
+    /// pinされていないページのうち、一番長く使われていないページを取り出す
+    pub(crate) fn pop_lru(&mut self) -> Option<(usize, T)> {
+        let (&clock, &page_num) = self.lru.iter().find(|(_, page_num)| !self.pinned.contains(page_num))?;
+        self.lru.remove(&clock);
+        trace!("PageCache::pop_lru: evict page {}", page_num);
+        let entry = self.entries.remove(&page_num).expect("PageCache::pop_lru: lru and entries are inconsistent");
+        Some((page_num, entry.value))
+    }
+
+    /// キャッシュにあるpage_numを昇順で返す
+    pub(crate) fn page_nums(&self) -> Vec<usize> {
+        let mut page_nums: Vec<usize> = self.entries.keys().copied().collect();
+        page_nums.sort_unstable();
+        page_nums
+    }
+
+    fn touch(&mut self, page_num: usize) {
+        if let Some(entry) = self.entries.get_mut(&page_num) {
+            self.lru.remove(&entry.last_used);
+            self.clock += 1;
+            entry.last_used = self.clock;
+            self.lru.insert(self.clock, page_num);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_cache_size_pages() {
+        assert_eq!(CacheSize::Pages(10).pages(), 10);
+        assert_eq!(CacheSize::Bytes(PAGE_SIZE * 3 + 1).pages(), 3);
+        assert_eq!(CacheSize::Bytes(0).pages(), 1);
+    }
+
+    #[test]
+    fn test_pop_lru_returns_least_recently_used() {
+        let mut cache = PageCache::new(CacheSize::Pages(3));
+        cache.insert(0, "a");
+        cache.insert(1, "b");
+        cache.insert(2, "c");
+        assert!(cache.is_full());
+        let _ = cache.get(0);
+        assert_eq!(cache.pop_lru(), Some((1, "b")));
+        let _ = cache.get_mut(2);
+        assert_eq!(cache.pop_lru(), Some((0, "a")));
+        assert_eq!(cache.pop_lru(), Some((2, "c")));
+        assert_eq!(cache.pop_lru(), None);
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn test_pop_lru_skips_pinned_pages() {
+        let mut cache = PageCache::new(CacheSize::Pages(2));
+        cache.insert(0, "a");
+        cache.insert(1, "b");
+        cache.pin(0);
+        assert_eq!(cache.pop_lru(), Some((1, "b")));
+        assert_eq!(cache.pop_lru(), None);
+        assert_eq!(cache.len(), 1);
+        cache.unpin(0);
+        assert_eq!(cache.pop_lru(), Some((0, "a")));
+    }
+}
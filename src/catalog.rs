@@ -0,0 +1,97 @@
+use crate::schema::{ColumnDef, ColumnType, Schema, Value};
+
+/// 何も指定しなかったときに使うtable。新しいデータベースには最初からある
+pub(crate) const DEFAULT_TABLE: &str = "users";
+
+/// catalogの1行。ファイルの中のtableごとに、名前とrootのページとCREATE TABLE文を持つ。
+/// catalog自体もB-treeで、rootはCATALOG_PAGE_NUMに固定されている
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct CatalogEntry {
+    pub(crate) id: u32,
+    // 今は"table"だけ
+    pub(crate) entry_type: String,
+    pub(crate) name: String,
+    pub(crate) root_page_num: usize,
+    pub(crate) sql: String,
+}
+
+impl CatalogEntry {
+    pub(crate) const TABLE: &'static str = "table";
+    pub(crate) const MAX_NAME_SIZE: usize = 32;
+    pub(crate) const MAX_SQL_SIZE: usize = 900;
+
+    /// catalogの行の形式。長さの上限いっぱいでもleafのcellに収まる
+    fn schema() -> Schema {
+        Schema {
+            name: "lbsd_catalog".to_string(),
+            columns: vec![
+                ColumnDef::new("id", ColumnType::Integer),
+                ColumnDef::with_length("type", ColumnType::Text, 8),
+                ColumnDef::with_length("name", ColumnType::Text, Self::MAX_NAME_SIZE),
+                ColumnDef::new("root_page", ColumnType::Integer),
+                ColumnDef::with_length("sql", ColumnType::Text, Self::MAX_SQL_SIZE),
+            ],
+        }
+    }
+
+    /// schemaのtableをcatalogに書けるか。名前やCREATE TABLE文が長すぎると入らない
+    pub(crate) fn fits(schema: &Schema) -> bool {
+        schema.name.len() <= Self::MAX_NAME_SIZE && schema.to_sql().len() <= Self::MAX_SQL_SIZE
+    }
+
+    pub(crate) fn encode(&self, buf: &mut Vec<u8>) -> Result<(), String> {
+        let values = vec![
+            Value::Integer(self.id as i64),
+            Value::Text(self.entry_type.clone()),
+            Value::Text(self.name.clone()),
+            Value::Integer(self.root_page_num as i64),
+            Value::Text(self.sql.clone()),
+        ];
+        Self::schema().encode(values, buf).map_err(|e| format!("cannot write catalog entry {}: {}", self.name, e))
+    }
+
+    pub(crate) fn decode(row: &[u8]) -> Result<Self, String> {
+        match Self::schema().decode(row).as_slice() {
+            [Value::Integer(id), Value::Text(entry_type), Value::Text(name), Value::Integer(root_page_num), Value::Text(sql)] => {
+                Ok(CatalogEntry {
+                    id: *id as u32,
+                    entry_type: entry_type.clone(),
+                    name: name.clone(),
+                    root_page_num: *root_page_num as usize,
+                    sql: sql.clone(),
+                })
+            }
+            values => Err(format!("corrupt catalog entry: {:?}", values)),
+        }
+    }
+
+    /// tableならCREATE TABLE文を読み直す
+    pub(crate) fn table_schema(&self) -> Option<Schema> {
+        if self.entry_type != Self::TABLE {
+            return None;
+        }
+        Schema::parse(&self.sql).ok()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tree::BTreeLeafNode;
+
+    #[test]
+    fn test_encode_and_decode() {
+        assert!(CatalogEntry::schema().max_record_size() <= BTreeLeafNode::MAX_RECORD_SIZE);
+        let entry = CatalogEntry {
+            id: 2,
+            entry_type: CatalogEntry::TABLE.to_string(),
+            name: "users".to_string(),
+            root_page_num: 5,
+            sql: Schema::users().to_sql(),
+        };
+        let mut buf = vec![];
+        entry.encode(&mut buf).unwrap();
+        assert_eq!(CatalogEntry::decode(&buf), Ok(entry.clone()));
+        assert_eq!(entry.table_schema(), Some(Schema::users()));
+    }
+}
@@ -0,0 +1,446 @@
+use std::cmp::Ordering;
+use crate::ast::{BinaryOp, Expr, UnaryOp};
+use crate::lexer::{ParseError, Position};
+use crate::schema::{Schema, Value};
+
+/// 使える関数の名前と、引数の数の最小と最大
+const FUNCTIONS: [(&str, usize, usize); 6] = [
+    ("length", 1, 1),
+    ("upper", 1, 1),
+    ("lower", 1, 1),
+    ("substr", 2, 3),
+    ("coalesce", 2, usize::MAX),
+    ("abs", 1, 1),
+];
+
+/// 式のカラムがschemaにあり、関数の名前と引数の数が正しいかを調べる。集約関数の中に集約関数は書けない
+pub(crate) fn check(expr: &Expr, schema: &Schema) -> Result<(), ParseError> {
+    let mut result = Ok(());
+    expr.walk(&mut |expr| {
+        if result.is_err() {
+            return;
+        }
+        match expr {
+            Expr::Column { name, position } if schema.column_index(name).is_none() => {
+                result = Err(ParseError::new(format!("no such column in table {}: {}", schema.name, name), *position));
+            }
+            Expr::Function { name, args, position } => match FUNCTIONS.iter().find(|(f, _, _)| f == name) {
+                None => result = Err(ParseError::new(format!("no such function: {}", name), *position)),
+                Some((_, min, max)) if args.len() < *min || args.len() > *max => {
+                    let message = format!("wrong number of arguments to function {}: {}", name, args.len());
+                    result = Err(ParseError::new(message, *position));
+                }
+                Some(_) => {}
+            },
+            Expr::Aggregate { func, arg: Some(arg), .. } => {
+                if let Some(inner) = arg.aggregates().first() {
+                    result = Err(ParseError::new(format!("aggregate function calls cannot be nested in {}", func), inner.position()));
+                }
+            }
+            _ => {}
+        }
+    });
+    result
+}
+
+/// 行の値に対して式を計算する。
+///
+/// NULLを含む比較や演算の結果はNULLになり、`and`, `or`, `not`は真偽の分からない値を
+/// SQLと同じ3値論理で扱う。INTEGERとREALとBOOLEANは数値として比べられる
+pub(crate) fn eval(expr: &Expr, schema: &Schema, row: &[Value]) -> Result<Value, String> {
+    let value = match expr {
+        Expr::Literal { value, .. } => value.clone(),
+        Expr::Column { name, position } => match schema.column_index(name) {
+            Some(index) => row[index].clone(),
+            None => return Err(error(*position, format!("no such column: {}", name))),
+        },
+        Expr::Unary { op: UnaryOp::Not, operand, position } => {
+            from_truth(truth(&eval(operand, schema, row)?, *position)?.map(|v| !v))
+        }
+        Expr::Unary { op: UnaryOp::Neg, operand, position } => match eval(operand, schema, row)? {
+            Value::Null => Value::Null,
+            Value::Real(v) => Value::Real(-v),
+            value => match integer(&value) {
+                Some(v) => Value::Integer(v.checked_neg().ok_or_else(|| error(*position, "integer overflow"))?),
+                None => return Err(error(*position, format!("cannot negate {}", value.type_name()))),
+            },
+        },
+        Expr::Binary { op: BinaryOp::And, left, right, .. } => {
+            let left_truth = truth(&eval(left, schema, row)?, left.position())?;
+            if left_truth == Some(false) {
+                return Ok(Value::Boolean(false));
+            }
+            let right_truth = truth(&eval(right, schema, row)?, right.position())?;
+            from_truth(and(left_truth, right_truth))
+        }
+        Expr::Binary { op: BinaryOp::Or, left, right, .. } => {
+            let left_truth = truth(&eval(left, schema, row)?, left.position())?;
+            if left_truth == Some(true) {
+                return Ok(Value::Boolean(true));
+            }
+            let right_truth = truth(&eval(right, schema, row)?, right.position())?;
+            from_truth(and(left_truth.map(|v| !v), right_truth.map(|v| !v)).map(|v| !v))
+        }
+        Expr::Binary { op, left, right, position } => {
+            let left = eval(left, schema, row)?;
+            let right = eval(right, schema, row)?;
+            binary(*op, &left, &right, *position)?
+        }
+        Expr::Between { operand, low, high, negated, .. } => {
+            let value = eval(operand, schema, row)?;
+            let above = compare(&value, &eval(low, schema, row)?).map(|o| o != Ordering::Less);
+            let below = compare(&value, &eval(high, schema, row)?).map(|o| o != Ordering::Greater);
+            from_truth(and(above, below).map(|v| v != *negated))
+        }
+        Expr::IsNull { operand, negated, .. } => Value::Boolean((eval(operand, schema, row)? == Value::Null) != *negated),
+        Expr::InList { operand, list, negated, .. } => {
+            let value = eval(operand, schema, row)?;
+            // 一致するものがなくてNULLがあれば、含まれるかどうかは分からない
+            let mut found = Some(false);
+            for item in list {
+                match compare(&value, &eval(item, schema, row)?) {
+                    Some(Ordering::Equal) => {
+                        found = Some(true);
+                        break;
+                    }
+                    Some(_) => {}
+                    None => found = None,
+                }
+            }
+            from_truth(found.map(|v| v != *negated))
+        }
+        Expr::Like { operand, pattern, negated, .. } => {
+            let value = eval(operand, schema, row)?;
+            let pattern = eval(pattern, schema, row)?;
+            if value == Value::Null || pattern == Value::Null {
+                Value::Null
+            } else {
+                Value::Boolean(like(&text(&pattern), &text(&value)) != *negated)
+            }
+        }
+        Expr::Function { name, args, position } => {
+            let args = args.iter().map(|arg| eval(arg, schema, row)).collect::<Result<Vec<Value>, String>>()?;
+            function(name, args, *position)?
+        }
+        // 集約関数は行ごとには計算できない。グループの値に置き換えてから計算する
+        Expr::Aggregate { func, position, .. } => {
+            return Err(error(*position, format!("misuse of aggregate function {}", func)));
+        }
+    };
+    Ok(value)
+}
+
+/// 関数を呼ぶ。coalesce以外はNULLを渡すとNULLを返す
+fn function(name: &str, args: Vec<Value>, position: Position) -> Result<Value, String> {
+    if name == "coalesce" {
+        return Ok(args.into_iter().find(|v| *v != Value::Null).unwrap_or(Value::Null));
+    }
+    if args.contains(&Value::Null) {
+        return Ok(Value::Null);
+    }
+    let value = match (name, args.as_slice()) {
+        ("length", [Value::Blob(v)]) => Value::Integer(v.len() as i64),
+        ("length", [v]) => Value::Integer(text(v).chars().count() as i64),
+        ("upper", [v]) => Value::Text(text(v).to_uppercase()),
+        ("lower", [v]) => Value::Text(text(v).to_lowercase()),
+        ("substr", [v, start, rest @ ..]) => {
+            let integer_arg = |arg: &Value| integer(arg).ok_or_else(|| {
+                error(position, format!("substr expects INTEGER position and length, but got {}", arg.type_name()))
+            });
+            let start = integer_arg(start)?;
+            let len = match rest.first() {
+                Some(len) => Some(integer_arg(len)?),
+                None => None,
+            };
+            match v {
+                Value::Blob(v) => {
+                    let (from, to) = substr_range(v.len(), start, len);
+                    Value::Blob(v[from..to].to_vec())
+                }
+                v => {
+                    let chars: Vec<char> = text(v).chars().collect();
+                    let (from, to) = substr_range(chars.len(), start, len);
+                    Value::Text(chars[from..to].iter().collect())
+                }
+            }
+        }
+        ("abs", [Value::Real(v)]) => Value::Real(v.abs()),
+        ("abs", [v]) => match integer(v) {
+            Some(v) => Value::Integer(v.checked_abs().ok_or_else(|| error(position, "integer overflow"))?),
+            None => return Err(error(position, format!("abs expects a number, but got {}", v.type_name()))),
+        },
+        _ => return Err(error(position, format!("no such function: {}", name))),
+    };
+    Ok(value)
+}
+
+/// substrで取り出す範囲。startは1から数え、負なら末尾から数える。lenが負なら手前を取る
+fn substr_range(total: usize, start: i64, len: Option<i64>) -> (usize, usize) {
+    let total = total as i64;
+    let mut len = len.unwrap_or(total);
+    let mut from = match start {
+        s if s > 0 => s - 1,
+        s if s < 0 => total + s,
+        // 0は先頭の1つ前を指すので、1文字少なくなる
+        _ => {
+            if len > 0 {
+                len -= 1;
+            }
+            0
+        }
+    };
+    if len < 0 {
+        from += len;
+        len = -len;
+    }
+    if from < 0 {
+        len += from;
+        from = 0;
+    }
+    let from = from.min(total);
+    let to = from.saturating_add(len.max(0)).min(total);
+    (from as usize, to as usize)
+}
+
+/// where句の条件を満たすか。NULLは満たさない
+pub(crate) fn is_true(expr: &Expr, schema: &Schema, row: &[Value]) -> Result<bool, String> {
+    Ok(truth(&eval(expr, schema, row)?, expr.position())? == Some(true))
+}
+
+/// 値を比べる。NULLとは比べられないのでNoneを返す。
+/// 型が違うときは数値、TEXT、BLOBの順に並べる
+pub(crate) fn compare(left: &Value, right: &Value) -> Option<Ordering> {
+    let rank = |v: &Value| match v {
+        Value::Null => 0,
+        Value::Integer(_) | Value::Real(_) | Value::Boolean(_) => 1,
+        Value::Text(_) => 2,
+        Value::Blob(_) => 3,
+    };
+    match (left, right) {
+        (Value::Null, _) | (_, Value::Null) => None,
+        (Value::Text(l), Value::Text(r)) => Some(l.cmp(r)),
+        (Value::Blob(l), Value::Blob(r)) => Some(l.cmp(r)),
+        _ if rank(left) != rank(right) => Some(rank(left).cmp(&rank(right))),
+        _ => match (integer(left), integer(right)) {
+            (Some(l), Some(r)) => Some(l.cmp(&r)),
+            _ => real(left)?.partial_cmp(&real(right)?),
+        },
+    }
+}
+
+pub(crate) fn error(position: Position, message: impl Into<String>) -> String {
+    format!("{}: {}", position, message.into())
+}
+
+/// BOOLEANはINTEGERの0と1として計算する
+pub(crate) fn integer(value: &Value) -> Option<i64> {
+    match value {
+        Value::Integer(v) => Some(*v),
+        Value::Boolean(v) => Some(*v as i64),
+        _ => None,
+    }
+}
+
+pub(crate) fn real(value: &Value) -> Option<f64> {
+    match value {
+        Value::Real(v) => Some(*v),
+        _ => integer(value).map(|v| v as f64),
+    }
+}
+
+/// `||`やlikeで文字列として扱うときの値
+fn text(value: &Value) -> String {
+    match value {
+        Value::Text(v) => v.clone(),
+        Value::Blob(v) => String::from_utf8_lossy(v).into_owned(),
+        _ => value.to_string(),
+    }
+}
+
+/// 条件としての真偽。NULLは分からないのでNone
+fn truth(value: &Value, position: Position) -> Result<Option<bool>, String> {
+    match value {
+        Value::Null => Ok(None),
+        Value::Boolean(v) => Ok(Some(*v)),
+        Value::Integer(v) => Ok(Some(*v != 0)),
+        Value::Real(v) => Ok(Some(*v != 0.0)),
+        Value::Text(_) | Value::Blob(_) => Err(error(position, format!("{} is not a condition", value.type_name()))),
+    }
+}
+
+fn from_truth(truth: Option<bool>) -> Value {
+    truth.map_or(Value::Null, Value::Boolean)
+}
+
+/// 3値論理のand。片方がfalseならもう片方が分からなくてもfalse
+fn and(left: Option<bool>, right: Option<bool>) -> Option<bool> {
+    match (left, right) {
+        (Some(false), _) | (_, Some(false)) => Some(false),
+        (Some(true), Some(true)) => Some(true),
+        _ => None,
+    }
+}
+
+/// and, or以外の2項演算。0で割るとNULLになる
+fn binary(op: BinaryOp, left: &Value, right: &Value, position: Position) -> Result<Value, String> {
+    if *left == Value::Null || *right == Value::Null {
+        return Ok(Value::Null);
+    }
+    let ordering = compare(left, right);
+    let value = match op {
+        BinaryOp::Eq => Value::Boolean(ordering == Some(Ordering::Equal)),
+        BinaryOp::Ne => Value::Boolean(ordering != Some(Ordering::Equal)),
+        BinaryOp::Lt => Value::Boolean(ordering == Some(Ordering::Less)),
+        BinaryOp::Le => Value::Boolean(ordering.is_some_and(|o| o != Ordering::Greater)),
+        BinaryOp::Gt => Value::Boolean(ordering == Some(Ordering::Greater)),
+        BinaryOp::Ge => Value::Boolean(ordering.is_some_and(|o| o != Ordering::Less)),
+        BinaryOp::Concat => Value::Text(text(left) + &text(right)),
+        BinaryOp::Add | BinaryOp::Sub | BinaryOp::Mul | BinaryOp::Div | BinaryOp::Rem => {
+            if let (Some(l), Some(r)) = (integer(left), integer(right)) {
+                let v = match op {
+                    BinaryOp::Add => l.checked_add(r),
+                    BinaryOp::Sub => l.checked_sub(r),
+                    BinaryOp::Mul => l.checked_mul(r),
+                    _ if r == 0 => return Ok(Value::Null),
+                    BinaryOp::Div => l.checked_div(r),
+                    _ => l.checked_rem(r),
+                };
+                return v.map(Value::Integer).ok_or_else(|| error(position, "integer overflow"));
+            }
+            let (l, r) = match (real(left), real(right)) {
+                (Some(l), Some(r)) => (l, r),
+                _ => return Err(error(position, format!("cannot apply {} to {} and {}", op, left.type_name(), right.type_name()))),
+            };
+            match op {
+                BinaryOp::Add => Value::Real(l + r),
+                BinaryOp::Sub => Value::Real(l - r),
+                BinaryOp::Mul => Value::Real(l * r),
+                _ if r == 0.0 => Value::Null,
+                BinaryOp::Div => Value::Real(l / r),
+                _ => Value::Real(l % r),
+            }
+        }
+        BinaryOp::And | BinaryOp::Or => unreachable!("binary: {} is evaluated lazily", op),
+    };
+    Ok(value)
+}
+
+/// likeの照合。英字の大文字と小文字は区別しない
+fn like(pattern: &str, s: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().map(|c| c.to_ascii_lowercase()).collect();
+    let s: Vec<char> = s.chars().map(|c| c.to_ascii_lowercase()).collect();
+    let (mut p, mut i) = (0, 0);
+    // 最後に見た`%`の位置と、そこで読み飛ばし始めた位置。合わなければ1文字ずつ延ばして戻る
+    let mut backtrack = None;
+    while i < s.len() {
+        if p < pattern.len() && (pattern[p] == '_' || pattern[p] == s[i]) {
+            p += 1;
+            i += 1;
+        } else if p < pattern.len() && pattern[p] == '%' {
+            backtrack = Some((p, i));
+            p += 1;
+        } else if let Some((star, start)) = backtrack {
+            backtrack = Some((star, start + 1));
+            p = star + 1;
+            i = start + 1;
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '%')
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parser;
+    use crate::ast::Statement;
+
+    fn eval_where(sql: &str, row: &[Value]) -> Result<Value, String> {
+        let schema = Schema::parse("create table t (id integer primary key, n integer, x real, s text, f boolean)").unwrap();
+        match parser::parse(&format!("select where {}", sql)).unwrap() {
+            Some(Statement::Select { where_clause: Some(expr), .. }) => eval(&expr, &schema, row),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_eval() {
+        let row = [Value::Integer(1), Value::Integer(10), Value::Real(2.5), Value::Text("Alice".to_string()), Value::Null];
+        let check = |sql: &str, expected: Value| assert_eq!(eval_where(sql, &row), Ok(expected), "{}", sql);
+        check("n = 10 and x > 2", Value::Boolean(true));
+        check("n + x * 2", Value::Real(15.0));
+        check("n / 3 = 3 and n % 3 = 1", Value::Boolean(true));
+        check("n / 0", Value::Null);
+        check("-n <> 10", Value::Boolean(true));
+        check("s || '!'", Value::Text("Alice!".to_string()));
+        check("s > 'Al' and s < 'B' and s > 100", Value::Boolean(true));
+        check("id between 0 and 1 and x not between 3 and 4", Value::Boolean(true));
+        check("n in (1, 2, 10) and s not in ('Bob')", Value::Boolean(true));
+        check("s like 'a%' and s like '_LI_e' and s not like '%z%'", Value::Boolean(true));
+        check("s like '%c_' and not s like 'al'", Value::Boolean(true));
+        // NULLとの比較は分からない。orは片方が真なら真
+        check("f = 1", Value::Null);
+        check("f is null and n is not null", Value::Boolean(true));
+        check("n in (1, f)", Value::Null);
+        check("f = 1 or n = 10", Value::Boolean(true));
+        check("f = 1 and n = 0", Value::Boolean(false));
+        check("not f", Value::Null);
+        check("f like '%'", Value::Null);
+
+        assert!(eval_where("s + 1", &row).is_err());
+        assert_eq!(eval_where("n > 1 and s", &row), Err("line 1, column 24: TEXT is not a condition".to_string()));
+        assert_eq!(eval_where("9223372036854775807 + n", &row), Err("line 1, column 14: integer overflow".to_string()));
+    }
+
+    #[test]
+    fn test_functions() {
+        let row = [Value::Integer(1), Value::Integer(-10), Value::Real(-2.5), Value::Text("Ünïcode".to_string()), Value::Null];
+        let check = |sql: &str, expected: Value| assert_eq!(eval_where(sql, &row), Ok(expected), "{}", sql);
+        let text = |s: &str| Value::Text(s.to_string());
+        check("length(s) + length(x'0001') + length(n)", Value::Integer(7 + 2 + 3));
+        check("upper(s) || lower('ABC')", text("ÜNÏCODEabc"));
+        check("substr(s, 2, 3)", text("nïc"));
+        check("substr(s, -4)", text("code"));
+        check("substr(s, 0, 2)", text("Ü"));
+        check("substr(s, 3, -2)", text("Ün"));
+        check("substr(s, 10)", text(""));
+        check("substr(x'010203', 2)", Value::Blob(vec![2, 3]));
+        check("coalesce(f, null, n, 1)", Value::Integer(-10));
+        check("coalesce(f, null)", Value::Null);
+        check("abs(n) + abs(x)", Value::Real(12.5));
+        check("abs(f)", Value::Null);
+        check("length(f)", Value::Null);
+
+        assert!(eval_where("abs(s)", &row).is_err());
+        assert!(eval_where("substr(s, 'a')", &row).is_err());
+        assert!(eval_where("abs(-9223372036854775807 - 1)", &row).is_err());
+    }
+
+    #[test]
+    fn test_check() {
+        let schema = Schema::users();
+        let check_where = |sql: &str| match parser::parse(&format!("select where {}", sql)).unwrap() {
+            Some(Statement::Select { where_clause: Some(expr), .. }) => check(&expr, &schema),
+            _ => unreachable!(),
+        };
+        let at = |column: usize| Position { line: 1, column };
+        assert_eq!(check_where("upper(username) = 'A' and length(email) > 3"), Ok(()));
+        assert_eq!(check_where("id = 1 or name = 'a'"), Err(ParseError::new("no such column in table users: name", at(24))));
+        assert_eq!(check_where("now() > 1"), Err(ParseError::new("no such function: now", at(14))));
+        assert_eq!(check_where("substr(username)"), Err(ParseError::new("wrong number of arguments to function substr: 1", at(14))));
+        assert_eq!(check_where("coalesce(username)").unwrap_err().position, at(14));
+        assert_eq!(check_where("count(*) > 1 and min(length(email)) > 3"), Ok(()));
+        assert_eq!(check_where("sum(max(id)) > 1"), Err(ParseError::new("aggregate function calls cannot be nested in sum", at(18))));
+    }
+
+    #[test]
+    fn test_like() {
+        assert!(like("%", ""));
+        assert!(like("a%c", "abbbc"));
+        assert!(like("%b%b%", "abcb"));
+        assert!(!like("a_c", "ac"));
+        assert!(!like("%ab", "aba"));
+        assert!(like("ÄB_", "Äbc"));
+    }
+}
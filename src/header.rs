@@ -0,0 +1,97 @@
+use std::convert::TryInto;
+use byteorder::{LittleEndian, WriteBytesExt};
+use crate::PAGE_SIZE;
+
+/// page 0に置くデータベースファイルのheader。
+/// ページ数を起動時に数えなくて済むように、Pagerが常に最新の値を書いておく
+///
+/// 形式: MAGIC(16) + version(u32) + page_size(u32) + num_pages(u32) + free_list_head(u32)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Header {
+    pub(crate) version: u32,
+    pub(crate) page_size: u32,
+    pub(crate) num_pages: u32,
+    /// 空きページのリストの先頭。なければ0(page 0はheaderなので空きページにはならない)
+    pub(crate) free_list_head: u32,
+}
+
+pub(crate) const HEADER_PAGE_NUM: usize = 0;
+/// catalogのB-treeのroot。rootは分割しても動かないので、headerに書かなくてよい
+pub(crate) const CATALOG_PAGE_NUM: usize = 1;
+
+impl Header {
+    pub(crate) const MAGIC: &'static [u8; 16] = b"lbsd format 1\0\0\0";
+    pub(crate) const VERSION: u32 = 3;
+    pub(crate) const SIZE: usize = 16 + 4 * 4;
+
+    /// 新しいデータベースのheader。page 1が空のcatalogになる
+    pub(crate) fn new() -> Self {
+        Header {
+            version: Self::VERSION,
+            page_size: PAGE_SIZE as u32,
+            num_pages: CATALOG_PAGE_NUM as u32 + 1,
+            free_list_head: 0,
+        }
+    }
+
+    pub(crate) fn serialize(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(Self::MAGIC);
+        let _ = buf.write_u32::<LittleEndian>(self.version);
+        let _ = buf.write_u32::<LittleEndian>(self.page_size);
+        let _ = buf.write_u32::<LittleEndian>(self.num_pages);
+        let _ = buf.write_u32::<LittleEndian>(self.free_list_head);
+        buf.resize(PAGE_SIZE, 0);
+    }
+
+    /// page 0を読む。別の形式のファイルや、このビルドとページサイズが違うファイルはエラーにする
+    pub(crate) fn deserialize(buf: &[u8]) -> Result<Self, String> {
+        if buf.len() < Self::SIZE || &buf[..16] != Self::MAGIC {
+            return Err("file is not a database".to_string());
+        }
+        let field = |i: usize| u32::from_le_bytes(buf[16 + i * 4..16 + (i + 1) * 4].try_into().unwrap());
+        let header = Header {
+            version: field(0),
+            page_size: field(1),
+            num_pages: field(2),
+            free_list_head: field(3),
+        };
+        if header.version != Self::VERSION {
+            return Err(format!("unsupported file format version: {}", header.version));
+        }
+        if header.page_size as usize != PAGE_SIZE {
+            return Err(format!("page size mismatch: file has {}, expected {}", header.page_size, PAGE_SIZE));
+        }
+        if header.num_pages as usize <= CATALOG_PAGE_NUM {
+            return Err(format!("corrupt header: {} pages", header.num_pages));
+        }
+        if header.free_list_head >= header.num_pages {
+            return Err(format!("corrupt header: free list head {} of {} pages", header.free_list_head, header.num_pages));
+        }
+        Ok(header)
+    }
+}
+
+impl Default for Header {
+    fn default() -> Self {
+        Header::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_serialize_and_deserialize() {
+        let header = Header { num_pages: 9, free_list_head: 3, ..Header::new() };
+        let mut buf = vec![];
+        header.serialize(&mut buf);
+        assert_eq!(buf.len(), PAGE_SIZE);
+        assert_eq!(Header::deserialize(&buf), Ok(header));
+
+        assert!(Header::deserialize(&[0u8; PAGE_SIZE]).is_err());
+        let mut other = vec![];
+        Header { page_size: 1024, ..Header::new() }.serialize(&mut other);
+        assert!(Header::deserialize(&other).is_err());
+    }
+}
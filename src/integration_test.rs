@@ -22,11 +22,11 @@ select
     let mut r = BufReader::new(&mut buf);
     let mut w: Vec<u8> = vec![];
     let filename = "tmp/test_integration.db";
-    let _ = fs::remove_file(&filename);
+    let _ = fs::remove_file(filename);
     _main(filename, &mut r, &mut w);
     let s = std::str::from_utf8(&w).unwrap();
     let mut row = vec![];
-    cols_to_row(&mut row, 1, "foo", "bar");
+    let _ = cols_to_row(&mut row, 1, "foo", "bar");
     assert_eq!(s, format!("db > Executed\ndb > {:?}\nExecuted\ndb > ", display_row(&row)));
 }
 
@@ -43,7 +43,7 @@ fn test_keeps_data_after_closing_connection() {
     let mut r = BufReader::new(&mut buf);
     let mut w: Vec<u8> = vec![];
     let filename = "tmp/test_keeps_data_after_closing_connection.db";
-    let _ = fs::remove_file(&filename);
+    let _ = fs::remove_file(filename);
     _main(filename, &mut r, &mut w);
     let s = std::str::from_utf8(&w).unwrap();
 
@@ -60,7 +60,7 @@ fn test_keeps_data_after_closing_connection() {
     _main(filename, &mut r, &mut w);
     let s = std::str::from_utf8(&w).unwrap();
     let mut row = vec![];
-    cols_to_row(&mut row, 1, "foo", "bar");
+    let _ = cols_to_row(&mut row, 1, "foo", "bar");
     let expected = format!(
         r#"db > {:?}
 Executed
@@ -69,3 +69,755 @@ db > "#,
     );
     assert_eq!(s, expected);
 }
+
+#[test]
+fn test_delete() {
+    init();
+    let mut buf: Vec<u8> = vec![];
+    let _ = buf.write_fmt(format_args!(
+        r#"insert 1 "foo" "bar"
+insert 2 "hoge" "fuga"
+insert 3 "piyo" "piyo"
+delete where id = 2
+select
+.exit
+"#
+    ));
+    let mut buf: &[u8] = buf.as_ref();
+    let mut r = BufReader::new(&mut buf);
+    let mut w: Vec<u8> = vec![];
+    let filename = "tmp/test_delete.db";
+    let _ = fs::remove_file(filename);
+    _main(filename, &mut r, &mut w);
+    let s = std::str::from_utf8(&w).unwrap();
+    let mut row1 = vec![];
+    let _ = cols_to_row(&mut row1, 1, "foo", "bar");
+    let mut row3 = vec![];
+    let _ = cols_to_row(&mut row3, 3, "piyo", "piyo");
+    assert_eq!(s, format!(
+        "db > Executed\ndb > Executed\ndb > Executed\ndb > Executed\ndb > {:?}\n{:?}\nExecuted\ndb > ",
+        display_row(&row1),
+        display_row(&row3)
+    ));
+}
+
+#[test]
+fn test_update() {
+    init();
+    let mut buf: Vec<u8> = vec![];
+    let _ = buf.write_fmt(format_args!(
+        r#"insert 1 "foo" "bar"
+insert 2 "hoge" "fuga"
+update set email = "foo@example.com" where id = 1
+update set username = "nobody" where id = 3
+select
+.exit
+"#
+    ));
+    let mut buf: &[u8] = buf.as_ref();
+    let mut r = BufReader::new(&mut buf);
+    let mut w: Vec<u8> = vec![];
+    let filename = "tmp/test_update.db";
+    let _ = fs::remove_file(filename);
+    _main(filename, &mut r, &mut w);
+    let s = std::str::from_utf8(&w).unwrap();
+    let mut row1 = vec![];
+    let _ = cols_to_row(&mut row1, 1, "foo", "foo@example.com");
+    let mut row2 = vec![];
+    let _ = cols_to_row(&mut row2, 2, "hoge", "fuga");
+    assert_eq!(s, format!(
+        "db > Executed\ndb > Executed\ndb > 1 rows updated\nExecuted\ndb > 0 rows updated\nExecuted\ndb > {:?}\n{:?}\nExecuted\ndb > ",
+        display_row(&row1),
+        display_row(&row2)
+    ));
+}
+
+#[test]
+fn test_update_with_expressions() {
+    init();
+    let mut buf: &[u8] = br#"create table t (id integer primary key, name text, n integer)
+insert into t 1 "pen" 10
+insert into t 2 "ink" 20
+insert into t 3 "cap" 1
+update t set name = upper(name), n = n + id where n > 5
+update t set n = name
+select from t
+.exit
+"#;
+    let mut r = BufReader::new(&mut buf);
+    let mut w: Vec<u8> = vec![];
+    let filename = "tmp/test_update_with_expressions.db";
+    let _ = fs::remove_file(filename);
+    _main(filename, &mut r, &mut w);
+    let s = std::str::from_utf8(&w).unwrap();
+    // 右辺は書き換える前の行で計算し、計算に失敗した文はどの行も書き換えない
+    assert_eq!(s, concat!(
+        "db > Executed\n",
+        "db > Executed\n",
+        "db > Executed\n",
+        "db > Executed\n",
+        "db > 2 rows updated\nExecuted\n",
+        "db > Evaluation error at line 1, column 18: failed to convert columns to row. Column(n) has a value of wrong type\n",
+        "db > \"Row<id:1, name:PEN, n:11>\"\n\"Row<id:2, name:INK, n:22>\"\n\"Row<id:3, name:cap, n:1>\"\nExecuted\n",
+        "db > ",
+    ));
+}
+
+#[test]
+fn test_select_order_by_desc() {
+    init();
+    let mut buf: Vec<u8> = vec![];
+    let _ = buf.write_fmt(format_args!(
+        r#"insert 1 "foo" "bar"
+insert 2 "hoge" "fuga"
+insert 3 "piyo" "piyo"
+select order by id desc limit 2
+.exit
+"#
+    ));
+    let mut buf: &[u8] = buf.as_ref();
+    let mut r = BufReader::new(&mut buf);
+    let mut w: Vec<u8> = vec![];
+    let filename = "tmp/test_select_order_by_desc.db";
+    let _ = fs::remove_file(filename);
+    _main(filename, &mut r, &mut w);
+    let s = std::str::from_utf8(&w).unwrap();
+    let mut row3 = vec![];
+    let _ = cols_to_row(&mut row3, 3, "piyo", "piyo");
+    let mut row2 = vec![];
+    let _ = cols_to_row(&mut row2, 2, "hoge", "fuga");
+    assert_eq!(s, format!(
+        "db > Executed\ndb > Executed\ndb > Executed\ndb > {:?}\n{:?}\nExecuted\ndb > ",
+        display_row(&row3),
+        display_row(&row2)
+    ));
+}
+
+#[test]
+fn test_select_order_by_desc_on_other_key_column() {
+    init();
+    let mut buf: &[u8] = br#"create table t (k integer primary key, v text)
+insert into t 10 "a"
+insert into t 11 "b"
+insert into t 12 "c"
+insert into t 13 "d"
+select * from t order by k desc
+select k, v from t where k between 10 and 12 order by k desc limit 2
+select k, v from t where k between 10 and 12 order by id desc
+.exit
+"#;
+    let mut r = BufReader::new(&mut buf);
+    let mut w: Vec<u8> = vec![];
+    let filename = "tmp/test_select_order_by_desc_on_other_key_column.db";
+    let _ = fs::remove_file(filename);
+    _main(filename, &mut r, &mut w);
+    let s = std::str::from_utf8(&w).unwrap();
+    assert_eq!(s, concat!(
+        "db > Executed\n",
+        "db > Executed\n",
+        "db > Executed\n",
+        "db > Executed\n",
+        "db > Executed\n",
+        "db > \"Row<k:13, v:d>\"\n\"Row<k:12, v:c>\"\n\"Row<k:11, v:b>\"\n\"Row<k:10, v:a>\"\nExecuted\n",
+        "db > \"Row<k:12, v:c>\"\n\"Row<k:11, v:b>\"\nExecuted\n",
+        "db > Syntax error at line 1, column 55: only k can be used in order by\n",
+        "db > ",
+    ));
+}
+
+#[test]
+fn test_cache_size() {
+    init();
+    let mut buf: Vec<u8> = vec![];
+    let _ = buf.write_fmt(format_args!(
+        r#".cache_size 10
+.cache_size
+.cache_size -64
+.cache_size
+.cache_size foo
+.exit
+"#
+    ));
+    let mut buf: &[u8] = buf.as_ref();
+    let mut r = BufReader::new(&mut buf);
+    let mut w: Vec<u8> = vec![];
+    let filename = "tmp/test_cache_size.db";
+    let _ = fs::remove_file(filename);
+    _main(filename, &mut r, &mut w);
+    let s = std::str::from_utf8(&w).unwrap();
+    assert_eq!(s, "db > db > 10\ndb > db > 16\ndb > Invalid argument '.cache_size foo'\ndb > ");
+}
+
+#[test]
+fn test_synchronous() {
+    init();
+    let mut buf: Vec<u8> = vec![];
+    let _ = buf.write_fmt(format_args!(
+        r#".synchronous
+.synchronous normal
+.synchronous
+.synchronous always
+.exit
+"#
+    ));
+    let mut buf: &[u8] = buf.as_ref();
+    let mut r = BufReader::new(&mut buf);
+    let mut w: Vec<u8> = vec![];
+    let filename = "tmp/test_synchronous.db";
+    let _ = fs::remove_file(filename);
+    _main(filename, &mut r, &mut w);
+    let s = std::str::from_utf8(&w).unwrap();
+    assert_eq!(s, "db > full\ndb > db > normal\ndb > Invalid argument '.synchronous always'\ndb > ");
+}
+
+#[test]
+fn test_journal_mode_wal() {
+    init();
+    let mut buf: Vec<u8> = vec![];
+    let _ = buf.write_fmt(format_args!(
+        r#".journal_mode
+.journal_mode wal
+.journal_mode
+insert 1 "foo" "bar"
+.checkpoint
+.journal_mode truncate
+.journal_mode delete
+select
+.exit
+"#
+    ));
+    let mut buf: &[u8] = buf.as_ref();
+    let mut r = BufReader::new(&mut buf);
+    let mut w: Vec<u8> = vec![];
+    let filename = "tmp/test_journal_mode_wal.db";
+    let _ = fs::remove_file(filename);
+    let _ = fs::remove_file("tmp/test_journal_mode_wal.db-wal");
+    _main(filename, &mut r, &mut w);
+    let s = std::str::from_utf8(&w).unwrap();
+    let mut row = vec![];
+    let _ = cols_to_row(&mut row, 1, "foo", "bar");
+    assert_eq!(s, format!(
+        "db > delete\ndb > db > wal\ndb > Executed\ndb > 1 pages checkpointed\ndb > Invalid argument '.journal_mode truncate'\ndb > db > {:?}\nExecuted\ndb > ",
+        display_row(&row)
+    ));
+}
+
+#[test]
+fn test_transaction() {
+    init();
+    let mut buf: Vec<u8> = vec![];
+    let _ = buf.write_fmt(format_args!(
+        r#"insert 1 "foo" "bar"
+begin
+insert 2 "hoge" "fuga"
+begin
+rollback
+select
+begin transaction
+insert 3 "piyo" "piyo"
+commit
+commit
+select
+.exit
+"#
+    ));
+    let mut buf: &[u8] = buf.as_ref();
+    let mut r = BufReader::new(&mut buf);
+    let mut w: Vec<u8> = vec![];
+    let filename = "tmp/test_transaction.db";
+    let _ = fs::remove_file(filename);
+    _main(filename, &mut r, &mut w);
+    let s = std::str::from_utf8(&w).unwrap();
+    let mut row1 = vec![];
+    let _ = cols_to_row(&mut row1, 1, "foo", "bar");
+    let mut row3 = vec![];
+    let _ = cols_to_row(&mut row3, 3, "piyo", "piyo");
+    assert_eq!(s, format!(
+        "db > Executed\ndb > Executed\ndb > Executed\ndb > cannot start a transaction within a transaction\ndb > Executed\ndb > {:?}\nExecuted\ndb > Executed\ndb > Executed\ndb > Executed\ndb > no transaction is active\ndb > {:?}\n{:?}\nExecuted\ndb > ",
+        display_row(&row1),
+        display_row(&row1),
+        display_row(&row3)
+    ));
+}
+
+#[test]
+fn test_savepoint() {
+    init();
+    let mut buf: Vec<u8> = vec![];
+    let _ = buf.write_fmt(format_args!(
+        r#"begin
+insert 1 "foo" "bar"
+savepoint step1
+insert 2 "hoge" "fuga"
+rollback to step1
+release step2
+insert 3 "piyo" "piyo"
+release step1
+commit
+select
+.exit
+"#
+    ));
+    let mut buf: &[u8] = buf.as_ref();
+    let mut r = BufReader::new(&mut buf);
+    let mut w: Vec<u8> = vec![];
+    let filename = "tmp/test_savepoint.db";
+    let _ = fs::remove_file(filename);
+    _main(filename, &mut r, &mut w);
+    let s = std::str::from_utf8(&w).unwrap();
+    let mut row1 = vec![];
+    let _ = cols_to_row(&mut row1, 1, "foo", "bar");
+    let mut row3 = vec![];
+    let _ = cols_to_row(&mut row3, 3, "piyo", "piyo");
+    assert_eq!(s, format!(
+        "db > Executed\ndb > Executed\ndb > Executed\ndb > Executed\ndb > Executed\ndb > no such savepoint\ndb > Executed\ndb > Executed\ndb > Executed\ndb > {:?}\n{:?}\nExecuted\ndb > ",
+        display_row(&row1),
+        display_row(&row3)
+    ));
+}
+
+#[test]
+fn test_vacuum() {
+    init();
+    let mut buf: Vec<u8> = vec![];
+    let _ = buf.write_fmt(format_args!(
+        r#"vacuum
+insert 1 "foo" "bar"
+insert 2 "hoge" "fuga"
+begin
+vacuum
+commit
+vacuum
+select
+.exit
+"#
+    ));
+    let mut buf: &[u8] = buf.as_ref();
+    let mut r = BufReader::new(&mut buf);
+    let mut w: Vec<u8> = vec![];
+    let filename = "tmp/test_vacuum.db";
+    let _ = fs::remove_file(filename);
+    _main(filename, &mut r, &mut w);
+    let s = std::str::from_utf8(&w).unwrap();
+    let mut row1 = vec![];
+    let _ = cols_to_row(&mut row1, 1, "foo", "bar");
+    let mut row2 = vec![];
+    let _ = cols_to_row(&mut row2, 2, "hoge", "fuga");
+    assert_eq!(s, format!(
+        "db > 0 bytes reclaimed\nExecuted\ndb > Executed\ndb > Executed\ndb > Executed\ndb > vacuum failed\ndb > Executed\ndb > 0 bytes reclaimed\nExecuted\ndb > {:?}\n{:?}\nExecuted\ndb > ",
+        display_row(&row1),
+        display_row(&row2)
+    ));
+}
+
+#[test]
+fn test_import() {
+    init();
+    let csv = "tmp/test_import.csv";
+    let mut content = String::new();
+    for id in (1..=100).rev() {
+        content.push_str(&format!("{},user{},user{}@example.com\n", id, id, id));
+    }
+    fs::write(csv, content).unwrap();
+    let mut buf: Vec<u8> = vec![];
+    let _ = buf.write_fmt(format_args!(
+        r#".import {csv} 70
+.import {csv}
+.import tmp/no_such_file.csv
+select where id = 50
+.exit
+"#
+    ));
+    let mut buf: &[u8] = buf.as_ref();
+    let mut r = BufReader::new(&mut buf);
+    let mut w: Vec<u8> = vec![];
+    let filename = "tmp/test_import.db";
+    let _ = fs::remove_file(filename);
+    _main(filename, &mut r, &mut w);
+    let s = std::str::from_utf8(&w).unwrap();
+    let mut row = vec![];
+    let _ = cols_to_row(&mut row, 50, "user50", "user50@example.com");
+    assert_eq!(s, format!(
+        "db > 100 rows imported\ndb > Invalid argument '.import {csv}'\ndb > Invalid argument '.import tmp/no_such_file.csv'\ndb > {:?}\nExecuted\ndb > ",
+        display_row(&row)
+    ));
+}
+
+#[test]
+fn test_create_table() {
+    init();
+    let mut buf: Vec<u8> = vec![];
+    let _ = buf.write_fmt(format_args!(
+        r#"begin
+create table items (id integer primary key, name text(16), price real, data blob(8), sold boolean)
+rollback
+.schema
+create table items (id integer primary key, name text(16), price real, data blob(8), sold boolean)
+insert into items 2 "pen" 1.5 x'cafe' false
+insert into items 1 "notebook" 3 "raw" true
+insert into items 3 "eraser" "cheap" x'' false
+insert into items 3 "eraser" 0.5
+create table other (id integer)
+create table items (id integer)
+.exit
+"#
+    ));
+    let mut buf: &[u8] = buf.as_ref();
+    let mut r = BufReader::new(&mut buf);
+    let mut w: Vec<u8> = vec![];
+    let filename = "tmp/test_create_table.db";
+    let _ = fs::remove_file(filename);
+    _main(filename, &mut r, &mut w);
+    let s = std::str::from_utf8(&w).unwrap();
+    assert_eq!(s, concat!(
+        "db > Executed\n",
+        "db > Executed\n",
+        "db > Executed\n",
+        "db > CREATE TABLE users (id INTEGER PRIMARY KEY, username TEXT, email TEXT)\n",
+        "db > Executed\n",
+        "db > Executed\n",
+        "db > Executed\n",
+        "db > Record invalid 'insert into items 3 \"eraser\" \"cheap\" x'' false'\n",
+        "db > Record invalid 'insert into items 3 \"eraser\" 0.5'\n",
+        "db > Executed\n",
+        "db > table already exists\n",
+        "db > ",
+    ));
+
+    // schemaはファイルに残る
+    let mut buf: &[u8] = b".schema\n.tables\nselect from items\n.exit\n";
+    let mut r = BufReader::new(&mut buf);
+    let mut w: Vec<u8> = vec![];
+    _main(filename, &mut r, &mut w);
+    let s = std::str::from_utf8(&w).unwrap();
+    assert_eq!(s, concat!(
+        "db > CREATE TABLE users (id INTEGER PRIMARY KEY, username TEXT, email TEXT)\n",
+        "CREATE TABLE items (id INTEGER PRIMARY KEY, name TEXT(16), price REAL, data BLOB(8), sold BOOLEAN)\n",
+        "CREATE TABLE other (id INTEGER PRIMARY KEY)\n",
+        "db > users items other\n",
+        "db > \"Row<id:1, name:notebook, price:3, data:x'726177', sold:true>\"\n",
+        "\"Row<id:2, name:pen, price:1.5, data:x'cafe', sold:false>\"\n",
+        "Executed\n",
+        "db > ",
+    ));
+}
+
+#[test]
+fn test_multiple_tables() {
+    init();
+    let mut buf: Vec<u8> = vec![];
+    let _ = buf.write_fmt(format_args!(
+        r#"create table items (id integer primary key, name text(16), price integer)
+insert 1 "user1" "person1@example.com"
+insert into items 1 "pen" 100
+insert into items 2 "notebook" 300
+update items set price = 150 where id = 1
+delete from items where id = 2
+select
+select from items
+select from nothing
+insert into nothing 1
+vacuum
+.exit
+"#
+    ));
+    let mut buf: &[u8] = buf.as_ref();
+    let mut r = BufReader::new(&mut buf);
+    let mut w: Vec<u8> = vec![];
+    let filename = "tmp/test_multiple_tables.db";
+    let _ = fs::remove_file(filename);
+    _main(filename, &mut r, &mut w);
+    let s = std::str::from_utf8(&w).unwrap();
+    let mut row = vec![];
+    let _ = cols_to_row(&mut row, 1, "user1", "person1@example.com");
+    let expected = format!(concat!(
+        "db > Executed\n",
+        "db > Executed\n",
+        "db > Executed\n",
+        "db > Executed\n",
+        "db > 1 rows updated\nExecuted\n",
+        "db > Executed\n",
+        "db > {:?}\nExecuted\n",
+        "db > \"Row<id:1, name:pen, price:150>\"\nExecuted\n",
+        "db > No such table at 'select from nothing'\n",
+        "db > No such table at 'insert into nothing 1'\n",
+    ), display_row(&row));
+    assert!(s.starts_with(&expected), "{}", s);
+
+    // 開き直しても、それぞれのtableの行が読める
+    let mut buf: &[u8] = b"select from items\nselect from users\n.exit\n";
+    let mut r = BufReader::new(&mut buf);
+    let mut w: Vec<u8> = vec![];
+    _main(filename, &mut r, &mut w);
+    let s = std::str::from_utf8(&w).unwrap();
+    assert_eq!(s, format!(
+        "db > \"Row<id:1, name:pen, price:150>\"\nExecuted\ndb > {:?}\nExecuted\ndb > ",
+        display_row(&row)
+    ));
+}
+
+#[test]
+fn test_large_text() {
+    init();
+    let long = "a".repeat(10000);
+    let longer = "b".repeat(30000);
+    let mut buf: Vec<u8> = vec![];
+    let _ = buf.write_fmt(format_args!(
+        r#"create table docs (id integer primary key, body text(50000))
+insert into docs 1 "{}"
+insert into docs 2 "short"
+update docs set body = "{}" where id = 2
+delete from docs where id = 1
+select from docs
+.exit
+"#,
+        long, longer
+    ));
+    let mut buf: &[u8] = buf.as_ref();
+    let mut r = BufReader::new(&mut buf);
+    let mut w: Vec<u8> = vec![];
+    let filename = "tmp/test_large_text.db";
+    let _ = fs::remove_file(filename);
+    _main(filename, &mut r, &mut w);
+    let s = std::str::from_utf8(&w).unwrap();
+    let selected = format!("db > \"Row<id:2, body:{}>\"\nExecuted\n", longer);
+    let expected = format!(
+        "db > Executed\ndb > Executed\ndb > Executed\ndb > 1 rows updated\nExecuted\ndb > Executed\n{}db > ",
+        selected
+    );
+    assert_eq!(s, expected);
+
+    // 開き直しても長い行が読める
+    let mut buf: &[u8] = b"select from docs\n.exit\n";
+    let mut r = BufReader::new(&mut buf);
+    let mut w: Vec<u8> = vec![];
+    _main(filename, &mut r, &mut w);
+    assert_eq!(std::str::from_utf8(&w).unwrap(), format!("{}db > ", selected));
+}
+
+#[test]
+fn test_text_without_length_holds_large_values() {
+    init();
+    // 長さを指定しないTEXTは、何ページにもなる値を入れられる
+    let body: String = (0..20000).map(|i| (b'a' + (i % 26) as u8) as char).collect();
+    let mut buf: Vec<u8> = vec![];
+    let _ = buf.write_fmt(format_args!(
+        r#"create table docs (id integer primary key, body text)
+insert into docs 1 "{}"
+.exit
+"#,
+        body
+    ));
+    let mut buf: &[u8] = buf.as_ref();
+    let mut r = BufReader::new(&mut buf);
+    let mut w: Vec<u8> = vec![];
+    let filename = "tmp/test_text_without_length_holds_large_values.db";
+    let _ = fs::remove_file(filename);
+    _main(filename, &mut r, &mut w);
+    assert_eq!(std::str::from_utf8(&w).unwrap(), "db > Executed\ndb > Executed\ndb > ");
+
+    // 開き直しても長さの上限は変わらず、値も全て読める
+    let mut buf: &[u8] = b".schema\nselect from docs\n.exit\n";
+    let mut r = BufReader::new(&mut buf);
+    let mut w: Vec<u8> = vec![];
+    _main(filename, &mut r, &mut w);
+    let s = std::str::from_utf8(&w).unwrap();
+    let expected = format!(
+        concat!(
+            "db > CREATE TABLE users (id INTEGER PRIMARY KEY, username TEXT, email TEXT)\n",
+            "CREATE TABLE docs (id INTEGER PRIMARY KEY, body TEXT)\n",
+            "db > \"Row<id:1, body:{}>\"\nExecuted\n",
+            "db > ",
+        ),
+        body
+    );
+    assert_eq!(s, expected);
+}
+
+#[test]
+fn test_users_holds_long_username_and_email() {
+    init();
+    // 最初からあるusersにも、1ページに収まらない値を入れられる
+    let username = "u".repeat(40);
+    let email = "e".repeat(5000);
+    let mut buf: Vec<u8> = vec![];
+    let _ = buf.write_fmt(format_args!("insert 1 \"{}\" \"a\"\ninsert 2 \"b\" \"{}\"\n.exit\n", username, email));
+    let mut buf: &[u8] = buf.as_ref();
+    let mut r = BufReader::new(&mut buf);
+    let mut w: Vec<u8> = vec![];
+    let filename = "tmp/test_users_holds_long_username_and_email.db";
+    let _ = fs::remove_file(filename);
+    _main(filename, &mut r, &mut w);
+    assert_eq!(std::str::from_utf8(&w).unwrap(), "db > Executed\ndb > Executed\ndb > ");
+
+    let mut buf: &[u8] = b"select\n.exit\n";
+    let mut r = BufReader::new(&mut buf);
+    let mut w: Vec<u8> = vec![];
+    _main(filename, &mut r, &mut w);
+    let s = std::str::from_utf8(&w).unwrap();
+    let expected = format!(
+        "db > \"Row<id:1, username:{}, email:a>\"\n\"Row<id:2, username:b, email:{}>\"\nExecuted\ndb > ",
+        username, email
+    );
+    assert_eq!(s, expected);
+}
+
+#[test]
+fn test_syntax_errors() {
+    init();
+    let mut buf: Vec<u8> = vec![];
+    let _ = buf.write_fmt(format_args!(
+        r#"insert 1 'it''s' "say ""hi"""
+insert 2 "foo" bar
+insert 3 "foo
+select where id = 1 order by username
+update set id = 5
+select where id = 1; -- comment
+.exit
+"#
+    ));
+    let mut buf: &[u8] = buf.as_ref();
+    let mut r = BufReader::new(&mut buf);
+    let mut w: Vec<u8> = vec![];
+    let filename = "tmp/test_syntax_errors.db";
+    let _ = fs::remove_file(filename);
+    _main(filename, &mut r, &mut w);
+    let s = std::str::from_utf8(&w).unwrap();
+    let expected = concat!(
+        "db > Executed\n",
+        "db > Syntax error at line 1, column 16: expected a literal value, found 'bar'\n",
+        "db > Syntax error at line 1, column 10: unterminated string literal\n",
+        "db > Syntax error at line 1, column 30: only id can be used in order by\n",
+        "db > Syntax error at line 1, column 12: primary key id cannot be updated\n",
+        "db > \"Row<id:1, username:it's, email:say \\\"hi\\\">\"\nExecuted\n",
+        "db > ",
+    );
+    assert_eq!(s, expected);
+}
+
+#[test]
+fn test_where_clause() {
+    init();
+    let mut buf: Vec<u8> = vec![];
+    let _ = buf.write_fmt(format_args!(
+        r#"create table items (id integer primary key, name text(16), price integer, note text)
+insert into items 1 "pen" 100 null
+insert into items 2 "notebook" 300 "a5"
+insert into items 3 "eraser" 50 null
+insert into items 4 "pencil" 80 "hb"
+select from items where price >= 80 and note is null
+select from items where name like 'PEN%' order by id desc
+select from items where id in (2, 3, 9) or price < 60
+select from items where not (price between 60 and 200) limit 1
+update items set note = "sale" where price < 100 and note is null
+delete from items where note = 'sale' or name = 'notebook'
+select from items
+select from items where name + 1 > 0
+.exit
+"#
+    ));
+    let mut buf: &[u8] = buf.as_ref();
+    let mut r = BufReader::new(&mut buf);
+    let mut w: Vec<u8> = vec![];
+    let filename = "tmp/test_where_clause.db";
+    let _ = fs::remove_file(filename);
+    _main(filename, &mut r, &mut w);
+    let s = std::str::from_utf8(&w).unwrap();
+    let row = |id: u32| match id {
+        1 => "\"Row<id:1, name:pen, price:100, note:NULL>\"\n",
+        2 => "\"Row<id:2, name:notebook, price:300, note:a5>\"\n",
+        3 => "\"Row<id:3, name:eraser, price:50, note:NULL>\"\n",
+        _ => "\"Row<id:4, name:pencil, price:80, note:hb>\"\n",
+    };
+    let expected = [
+        "db > Executed\n".repeat(5),
+        format!("db > {}Executed\n", row(1)),
+        format!("db > {}{}Executed\n", row(4), row(1)),
+        format!("db > {}{}Executed\n", row(2), row(3)),
+        format!("db > {}Executed\n", row(2)),
+        "db > 1 rows updated\nExecuted\n".to_string(),
+        "db > Executed\n".to_string(),
+        format!("db > {}{}Executed\n", row(1), row(4)),
+        "db > Evaluation error at line 1, column 25: cannot apply + to TEXT and INTEGER\n".to_string(),
+        "db > ".to_string(),
+    ].concat();
+    assert_eq!(s, expected);
+}
+
+#[test]
+fn test_select_list() {
+    init();
+    let mut buf: Vec<u8> = vec![];
+    let _ = buf.write_fmt(format_args!(
+        r#"create table items (id integer primary key, name text(16), price integer, discount real)
+insert into items 1 "pen" 100 null
+insert into items 2 "notebook" 300 0.5
+select name, price * 2 as doubled from items
+select id, upper(substr(name, 1, 3)) || '!' label, price - coalesce(discount, 0) * price from items where id = 2
+select *, abs(-price) from items order by id desc limit 1
+select name from items where price / 0 is null and length(name) > 3
+select nothing from items
+.exit
+"#
+    ));
+    let mut buf: &[u8] = buf.as_ref();
+    let mut r = BufReader::new(&mut buf);
+    let mut w: Vec<u8> = vec![];
+    let filename = "tmp/test_select_list.db";
+    let _ = fs::remove_file(filename);
+    _main(filename, &mut r, &mut w);
+    let s = std::str::from_utf8(&w).unwrap();
+    let expected = concat!(
+        "db > Executed\n",
+        "db > Executed\n",
+        "db > Executed\n",
+        "db > \"Row<name:pen, doubled:200>\"\n\"Row<name:notebook, doubled:600>\"\nExecuted\n",
+        "db > \"Row<id:2, label:NOT!, price - (coalesce(discount, 0) * price):150>\"\nExecuted\n",
+        "db > \"Row<id:2, name:notebook, price:300, discount:0.5, abs(-price):300>\"\nExecuted\n",
+        "db > \"Row<name:notebook>\"\nExecuted\n",
+        "db > Syntax error at line 1, column 8: no such column in table items: nothing\n",
+        "db > ",
+    );
+    assert_eq!(s, expected);
+}
+
+#[test]
+fn test_aggregates() {
+    init();
+    let mut buf: Vec<u8> = vec![];
+    let _ = buf.write_fmt(format_args!(
+        r#"create table items (id integer primary key, category text(16), price integer, discount real)
+insert into items 1 "pen" 100 null
+insert into items 2 "notebook" 300 0.5
+insert into items 3 "pen" 150 0.1
+insert into items 4 "pen" 200 0.2
+select count(*), count(discount), sum(price), avg(price) from items
+select category, count(*) as n, min(price), max(discount) from items group by category
+select category, sum(price) total from items where id > 1 group by category having sum(price) > 300
+select min(id), max(id) from items
+select count(*) from items where count(*) > 1
+.exit
+"#
+    ));
+    let mut buf: &[u8] = buf.as_ref();
+    let mut r = BufReader::new(&mut buf);
+    let mut w: Vec<u8> = vec![];
+    let filename = "tmp/test_aggregates.db";
+    let _ = fs::remove_file(filename);
+    _main(filename, &mut r, &mut w);
+    let s = std::str::from_utf8(&w).unwrap();
+    let expected = concat!(
+        "db > Executed\n",
+        "db > Executed\n",
+        "db > Executed\n",
+        "db > Executed\n",
+        "db > Executed\n",
+        "db > \"Row<count(*):4, count(discount):3, sum(price):750, avg(price):187.5>\"\nExecuted\n",
+        "db > \"Row<category:pen, n:3, min(price):100, max(discount):0.2>\"\n\"Row<category:notebook, n:1, min(price):300, max(discount):0.5>\"\nExecuted\n",
+        "db > \"Row<category:pen, total:350>\"\nExecuted\n",
+        "db > \"Row<min(id):1, max(id):4>\"\nExecuted\n",
+        "db > Syntax error at line 1, column 34: aggregate functions are not allowed in where\n",
+        "db > ",
+    );
+    assert_eq!(s, expected);
+}
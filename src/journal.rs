@@ -0,0 +1,222 @@
+use std::collections::HashSet;
+use std::convert::TryInto;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use log::trace;
+use crate::PAGE_SIZE;
+use crate::table::sync_dir;
+
+/// ロールバックジャーナル。
+/// データファイルのページを上書きする前に元のページをここに書いておき、
+/// commitが終わったら消す。開いたときに残っていれば(hot journal)書き戻して元に戻す。
+///
+/// ファイルの形式:
+/// - header: MAGIC(8) + 元のデータファイルの長さ(u64)
+/// - record: page_num(u32) + 元のページ(PAGE_SIZE) + checksum(u32)
+pub(crate) struct Journal {
+    path: PathBuf,
+    file: File,
+    original_length: u64,
+    // このトランザクションで既に書いたページ。最初の状態だけを残す
+    journaled: HashSet<usize>,
+    // 書いたrecordをまだfsyncしていない
+    unsynced: bool,
+    // 作ったジャーナルのディレクトリのエントリをまだfsyncしていない
+    dir_unsynced: bool,
+}
+
+impl Journal {
+    const MAGIC: &'static [u8; 8] = b"LBSDJNL\0";
+    const HEADER_SIZE: usize = 8 + 8;
+    const RECORD_SIZE: usize = 4 + PAGE_SIZE + 4;
+
+    pub(crate) fn path_for(db_path: &Path) -> PathBuf {
+        let mut path = db_path.as_os_str().to_owned();
+        path.push("-journal");
+        PathBuf::from(path)
+    }
+
+    /// 新しいジャーナルを作り、headerを書く
+    pub(crate) fn create(db_path: &Path, original_length: u64) -> Result<Self, String> {
+        let path = Self::path_for(db_path);
+        trace!("Journal::create: {}, original_length: {}", path.display(), original_length);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .map_err(|e| e.to_string())?;
+        let mut header = Vec::with_capacity(Self::HEADER_SIZE);
+        header.extend_from_slice(Self::MAGIC);
+        let _ = header.write_u64::<LittleEndian>(original_length);
+        file.write_all(&header).map_err(|e| e.to_string())?;
+        Ok(Journal {
+            path,
+            file,
+            original_length,
+            journaled: HashSet::new(),
+            unsynced: true,
+            dir_unsynced: true,
+        })
+    }
+
+    pub(crate) fn original_length(&self) -> u64 {
+        self.original_length
+    }
+
+    /// page_numの元の内容を残す必要があるか。
+    /// トランザクション中に既に残したページや、元のファイルになかったページは不要
+    pub(crate) fn needs(&self, page_num: usize) -> bool {
+        ((page_num * PAGE_SIZE) as u64) < self.original_length && !self.journaled.contains(&page_num)
+    }
+
+    pub(crate) fn append(&mut self, page_num: usize, image: &[u8]) -> Result<(), String> {
+        trace!("Journal::append: page_num: {}", page_num);
+        let mut record = Vec::with_capacity(Self::RECORD_SIZE);
+        let _ = record.write_u32::<LittleEndian>(page_num as u32);
+        record.extend_from_slice(image);
+        record.resize(4 + PAGE_SIZE, 0);
+        let _ = record.write_u32::<LittleEndian>(checksum(&record));
+        self.file.seek(SeekFrom::End(0)).map_err(|e| e.to_string())?;
+        self.file.write_all(&record).map_err(|e| e.to_string())?;
+        self.journaled.insert(page_num);
+        self.unsynced = true;
+        Ok(())
+    }
+
+    pub(crate) fn is_synced(&self) -> bool {
+        !self.unsynced
+    }
+
+    /// fullなら、最初の1回はディレクトリもfsyncする。
+    /// ファイルの作成が永続化されていないと、落ちた後にジャーナルが見つからず書き戻せない
+    pub(crate) fn sync(&mut self, full: bool) -> Result<(), String> {
+        trace!("Journal::sync: full: {}", full);
+        self.file.sync_all().map_err(|e| e.to_string())?;
+        self.unsynced = false;
+        if full && self.dir_unsynced {
+            sync_dir(&self.path)?;
+            self.dir_unsynced = false;
+        }
+        Ok(())
+    }
+
+    /// commitが終わったのでジャーナルを消す
+    pub(crate) fn delete(self) -> Result<(), String> {
+        trace!("Journal::delete: {}", self.path.display());
+        drop(self.file);
+        fs::remove_file(&self.path).map_err(|e| e.to_string())
+    }
+
+    /// hot journalがあればデータファイルに書き戻し、元の長さに切り詰めてから消す。
+    /// 書き戻した場合はtrueを返す
+    pub(crate) fn rollback(db_path: &Path, sync: bool) -> Result<bool, String> {
+        let path = Self::path_for(db_path);
+        let mut journal = match File::open(&path) {
+            Ok(v) => v,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e.to_string()),
+        };
+        let mut buf = vec![];
+        journal.read_to_end(&mut buf).map_err(|e| e.to_string())?;
+        drop(journal);
+        if buf.len() < Self::HEADER_SIZE || &buf[..8] != Self::MAGIC {
+            // headerを書き終える前に落ちたので、データファイルにはまだ何も書いていない
+            trace!("Journal::rollback: journal header is incomplete. just remove it");
+            fs::remove_file(&path).map_err(|e| e.to_string())?;
+            return Ok(false);
+        }
+        let original_length = (&buf[8..16]).read_u64::<LittleEndian>().map_err(|e| e.to_string())?;
+        trace!("Journal::rollback: hot journal found. original_length: {}", original_length);
+
+        let mut db = match OpenOptions::new().write(true).open(db_path) {
+            Ok(v) => v,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                // データファイルが消されているので戻す先がない
+                trace!("Journal::rollback: database file not found. just remove the journal");
+                fs::remove_file(&path).map_err(|e| e.to_string())?;
+                return Ok(false);
+            }
+            Err(e) => return Err(e.to_string()),
+        };
+        for record in buf[Self::HEADER_SIZE..].chunks(Self::RECORD_SIZE) {
+            if record.len() < Self::RECORD_SIZE {
+                trace!("Journal::rollback: ignore torn record");
+                break;
+            }
+            let (body, sum) = record.split_at(4 + PAGE_SIZE);
+            if checksum(body) != u32::from_le_bytes(sum.try_into().unwrap()) {
+                // fsyncの前に落ちたrecord。対応するページはまだ上書きしていない
+                trace!("Journal::rollback: checksum mismatch. ignore the rest");
+                break;
+            }
+            let page_num = u32::from_le_bytes(body[..4].try_into().unwrap()) as u64;
+            trace!("Journal::rollback: restore page {}", page_num);
+            db.seek(SeekFrom::Start(page_num * PAGE_SIZE as u64)).map_err(|e| e.to_string())?;
+            db.write_all(&body[4..]).map_err(|e| e.to_string())?;
+        }
+        db.set_len(original_length).map_err(|e| e.to_string())?;
+        if sync {
+            db.sync_all().map_err(|e| e.to_string())?;
+        }
+        fs::remove_file(&path).map_err(|e| e.to_string())?;
+        Ok(true)
+    }
+}
+
+/// FNV-1aでrecordが途中で切れていないかを確かめる
+pub(crate) fn checksum(data: &[u8]) -> u32 {
+    let mut hash: u32 = 0x811c9dc5;
+    for b in data {
+        hash ^= *b as u32;
+        hash = hash.wrapping_mul(0x01000193);
+    }
+    hash
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_rollback_ignores_torn_record() {
+        let db_path = Path::new("tmp/test_journal_torn_record.db");
+        let _ = fs::remove_file(Journal::path_for(db_path));
+        fs::write(db_path, [vec![1u8; PAGE_SIZE], vec![2u8; PAGE_SIZE]].concat()).unwrap();
+
+        let mut journal = Journal::create(db_path, (PAGE_SIZE * 2) as u64).unwrap();
+        assert!(journal.needs(0));
+        journal.append(0, &[1u8; PAGE_SIZE]).unwrap();
+        assert!(!journal.needs(0));
+        assert!(!journal.needs(2));
+        journal.append(1, &[2u8; PAGE_SIZE]).unwrap();
+        journal.sync(true).unwrap();
+        assert!(!journal.dir_unsynced);
+        drop(journal);
+        // 2つ目のrecordは書きかけのまま落ちたことにする
+        let journal_path = Journal::path_for(db_path);
+        let len = fs::metadata(&journal_path).unwrap().len();
+        OpenOptions::new().write(true).open(&journal_path).unwrap().set_len(len - 10).unwrap();
+        // 1ページ目だけ上書きし、増えたページも書いていた
+        fs::write(db_path, [vec![9u8; PAGE_SIZE], vec![2u8; PAGE_SIZE], vec![9u8; PAGE_SIZE]].concat()).unwrap();
+
+        assert_eq!(Journal::rollback(db_path, false), Ok(true));
+        assert_eq!(fs::read(db_path).unwrap(), [vec![1u8; PAGE_SIZE], vec![2u8; PAGE_SIZE]].concat());
+        assert!(!journal_path.exists());
+        assert_eq!(Journal::rollback(db_path, false), Ok(false));
+    }
+
+    #[test]
+    fn test_rollback_removes_journal_without_header() {
+        let db_path = Path::new("tmp/test_journal_without_header.db");
+        fs::write(db_path, vec![1u8; PAGE_SIZE]).unwrap();
+        let journal_path = Journal::path_for(db_path);
+        fs::write(&journal_path, b"LBSD").unwrap();
+        assert_eq!(Journal::rollback(db_path, false), Ok(false));
+        assert!(!journal_path.exists());
+        assert_eq!(fs::read(db_path).unwrap(), vec![1u8; PAGE_SIZE]);
+    }
+}
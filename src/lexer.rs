@@ -0,0 +1,307 @@
+use std::fmt;
+use std::iter::Peekable;
+use std::str::CharIndices;
+
+/// 入力の中の位置。lineもcolumnも1から数える
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Position {
+    pub(crate) line: usize,
+    pub(crate) column: usize,
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}, column {}", self.line, self.column)
+    }
+}
+
+/// 字句解析と構文解析のエラー。問題のあるtokenの位置を持つ
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct ParseError {
+    pub(crate) message: String,
+    pub(crate) position: Position,
+}
+
+impl ParseError {
+    pub(crate) fn new(message: impl Into<String>, position: Position) -> Self {
+        ParseError { message: message.into(), position }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.position, self.message)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum TokenKind {
+    /// 名前。大文字と小文字を区別しないので小文字にしておく。キーワードもここに入る
+    Identifier(String),
+    Integer(i64),
+    Real(f64),
+    /// `'...'`か`"..."`。同じ引用符を2つ重ねると引用符そのものになる
+    String(String),
+    /// `x'0a1b'`
+    Blob(Vec<u8>),
+    /// `=`, `<>`, `||`, `(`, `,` などの記号
+    Operator(&'static str),
+    Eof,
+}
+
+impl fmt::Display for TokenKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TokenKind::Identifier(v) => write!(f, "'{}'", v),
+            TokenKind::Integer(v) => write!(f, "{}", v),
+            TokenKind::Real(v) => write!(f, "{}", v),
+            TokenKind::String(v) => write!(f, "string '{}'", v),
+            TokenKind::Blob(_) => write!(f, "blob literal"),
+            TokenKind::Operator(v) => write!(f, "'{}'", v),
+            TokenKind::Eof => write!(f, "end of input"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Token {
+    pub(crate) kind: TokenKind,
+    pub(crate) position: Position,
+}
+
+/// 長いものから順に試す
+const OPERATORS: [&str; 20] = [
+    "<=", ">=", "<>", "!=", "==", "||",
+    "=", "<", ">", "+", "-", "*", "/", "%", "(", ")", ",", ";", ".", "?",
+];
+
+/// 入力をtokenに分ける。最後は必ずEofになる
+pub(crate) fn tokenize(input: &str) -> Result<Vec<Token>, ParseError> {
+    let mut lexer = Lexer { input, chars: input.char_indices().peekable(), line: 1, column: 1 };
+    let mut tokens = vec![];
+    loop {
+        let token = lexer.next_token()?;
+        let eof = token.kind == TokenKind::Eof;
+        tokens.push(token);
+        if eof {
+            return Ok(tokens);
+        }
+    }
+}
+
+struct Lexer<'a> {
+    input: &'a str,
+    chars: Peekable<CharIndices<'a>>,
+    line: usize,
+    column: usize,
+}
+
+impl<'a> Lexer<'a> {
+    fn position(&self) -> Position {
+        Position { line: self.line, column: self.column }
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        self.chars.peek().map(|(_, c)| *c)
+    }
+
+    /// 今の位置からの残りの入力
+    fn rest(&mut self) -> &'a str {
+        match self.chars.peek() {
+            Some((i, _)) => &self.input[*i..],
+            None => "",
+        }
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let (_, c) = self.chars.next()?;
+        if c == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+        Some(c)
+    }
+
+    /// 空白とコメントを読み飛ばす
+    fn skip_trivia(&mut self) -> Result<(), ParseError> {
+        loop {
+            let rest = self.rest();
+            if rest.starts_with("--") {
+                while self.peek().is_some_and(|c| c != '\n') {
+                    self.bump();
+                }
+            } else if rest.starts_with("/*") {
+                let start = self.position();
+                self.bump();
+                self.bump();
+                loop {
+                    if self.rest().starts_with("*/") {
+                        self.bump();
+                        self.bump();
+                        break;
+                    }
+                    if self.bump().is_none() {
+                        return Err(ParseError::new("unterminated comment", start));
+                    }
+                }
+            } else if self.peek().is_some_and(char::is_whitespace) {
+                self.bump();
+            } else {
+                return Ok(());
+            }
+        }
+    }
+
+    fn next_token(&mut self) -> Result<Token, ParseError> {
+        self.skip_trivia()?;
+        let position = self.position();
+        let c = match self.peek() {
+            Some(c) => c,
+            None => return Ok(Token { kind: TokenKind::Eof, position }),
+        };
+        let kind = if (c == 'x' || c == 'X') && self.rest()[1..].starts_with('\'') {
+            self.bump();
+            self.blob(position)?
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let mut name = String::new();
+            while let Some(c) = self.peek().filter(|c| c.is_ascii_alphanumeric() || *c == '_') {
+                name.push(c.to_ascii_lowercase());
+                self.bump();
+            }
+            TokenKind::Identifier(name)
+        } else if c.is_ascii_digit() || (c == '.' && self.rest()[1..].starts_with(|c: char| c.is_ascii_digit())) {
+            self.number(position)?
+        } else if c == '\'' || c == '"' {
+            TokenKind::String(self.quoted(position)?)
+        } else {
+            let rest = self.rest();
+            let op = OPERATORS.iter().find(|op| rest.starts_with(**op))
+                .ok_or_else(|| ParseError::new(format!("unexpected character '{}'", c), position))?;
+            for _ in 0..op.len() {
+                self.bump();
+            }
+            TokenKind::Operator(op)
+        };
+        Ok(Token { kind, position })
+    }
+
+    /// 引用符で囲まれた文字列を読む。引用符を2つ重ねると1つの引用符になる
+    fn quoted(&mut self, start: Position) -> Result<String, ParseError> {
+        let quote = self.bump().expect("quoted: quote must be there");
+        let mut s = String::new();
+        loop {
+            match self.bump() {
+                Some(c) if c == quote => {
+                    if self.peek() == Some(quote) {
+                        self.bump();
+                        s.push(quote);
+                    } else {
+                        return Ok(s);
+                    }
+                }
+                Some(c) => s.push(c),
+                None => return Err(ParseError::new("unterminated string literal", start)),
+            }
+        }
+    }
+
+    fn blob(&mut self, start: Position) -> Result<TokenKind, ParseError> {
+        let hex = self.quoted(start)?;
+        if !hex.len().is_multiple_of(2) || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(ParseError::new(format!("invalid blob literal: x'{}'", hex), start));
+        }
+        let blob = (0..hex.len()).step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).expect("blob: hex digits are already checked"))
+            .collect();
+        Ok(TokenKind::Blob(blob))
+    }
+
+    /// `12`, `1.5`, `.5`, `1e3`を読む。符号は演算子として別のtokenになる
+    fn number(&mut self, start: Position) -> Result<TokenKind, ParseError> {
+        let mut s = String::new();
+        let mut real = false;
+        while let Some(c) = self.peek() {
+            if c.is_ascii_digit() {
+                s.push(c);
+            } else if c == '.' && !real {
+                real = true;
+                s.push(c);
+            } else if (c == 'e' || c == 'E') && !s.contains(['e', 'E']) {
+                real = true;
+                s.push(c);
+                self.bump();
+                if let Some(sign) = self.peek().filter(|c| *c == '+' || *c == '-') {
+                    s.push(sign);
+                    self.bump();
+                }
+                continue;
+            } else {
+                break;
+            }
+            self.bump();
+        }
+        if self.peek().is_some_and(|c| c.is_ascii_alphabetic() || c == '_') {
+            return Err(ParseError::new(format!("invalid number: {}{}", s, self.peek().unwrap()), start));
+        }
+        let kind = if real {
+            s.parse::<f64>().map(TokenKind::Real).map_err(|e| e.to_string())
+        } else {
+            s.parse::<i64>().map(TokenKind::Integer).map_err(|e| e.to_string())
+        };
+        kind.map_err(|e| ParseError::new(format!("invalid number {}: {}", s, e), start))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn kinds(input: &str) -> Vec<TokenKind> {
+        tokenize(input).unwrap().into_iter().map(|t| t.kind).collect()
+    }
+
+    #[test]
+    fn test_tokenize() {
+        let ident = |s: &str| TokenKind::Identifier(s.to_string());
+        assert_eq!(kinds("SELECT from Users where id>=3"), vec![
+            ident("select"), ident("from"), ident("users"), ident("where"), ident("id"),
+            TokenKind::Operator(">="), TokenKind::Integer(3), TokenKind::Eof,
+        ]);
+        assert_eq!(kinds(r#"'it''s' "say ""hi""" x'00fF' 1.5 .5 2e3 -7"#), vec![
+            TokenKind::String("it's".to_string()),
+            TokenKind::String(r#"say "hi""#.to_string()),
+            TokenKind::Blob(vec![0, 255]),
+            TokenKind::Real(1.5),
+            TokenKind::Real(0.5),
+            TokenKind::Real(2000.0),
+            TokenKind::Operator("-"),
+            TokenKind::Integer(7),
+            TokenKind::Eof,
+        ]);
+        assert_eq!(kinds("a||b <> c -- comment\n/* block\n comment */ d"), vec![
+            ident("a"), TokenKind::Operator("||"), ident("b"), TokenKind::Operator("<>"), ident("c"), ident("d"), TokenKind::Eof,
+        ]);
+    }
+
+    #[test]
+    fn test_positions() {
+        let tokens = tokenize("select\n  from  t").unwrap();
+        let positions: Vec<(usize, usize)> = tokens.iter().map(|t| (t.position.line, t.position.column)).collect();
+        assert_eq!(positions, vec![(1, 1), (2, 3), (2, 9), (2, 10)]);
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = |input: &str| tokenize(input).unwrap_err();
+        assert_eq!(error("insert 'abc"), ParseError::new("unterminated string literal", Position { line: 1, column: 8 }));
+        assert_eq!(error("a\n /* b").position, Position { line: 2, column: 2 });
+        assert_eq!(error("x'0g'").position, Position { line: 1, column: 1 });
+        assert_eq!(error("1 12abc").position, Position { line: 1, column: 3 });
+        assert_eq!(error("99999999999999999999").position, Position { line: 1, column: 1 });
+        assert_eq!(error("a # b"), ParseError::new("unexpected character '#'", Position { line: 1, column: 3 }));
+    }
+}
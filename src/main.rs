@@ -4,21 +4,35 @@ extern crate byteorder;
 extern crate env_logger;
 extern crate log;
 
-use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
-use std::convert::TryInto;
-use std::fmt;
-use std::io::{self, BufRead, Write, Read};
+use std::io;
 use std::path::Path;
 use std::process::exit;
 
 use log::trace;
 use crate::tree::{BTreeNode, BTreeLeafNode, BTreeInternalNode};
-use crate::table::{Table, Cursor};
-use std::error::Error;
-use std::fmt::Formatter;
+use crate::table::{Table, Cursor, Synchronous, JournalMode};
+use crate::cache::CacheSize;
+use crate::builder::DEFAULT_FILL_FACTOR;
+use crate::schema::{Schema, Value, RowConversionError};
+use crate::catalog::{CatalogEntry, DEFAULT_TABLE};
+use crate::lexer::ParseError;
+use crate::aggregate::{Group, Grouping};
 
 pub mod tree;
 pub mod table;
+pub mod cache;
+pub mod header;
+pub mod journal;
+pub mod wal;
+pub mod builder;
+pub mod schema;
+pub mod catalog;
+pub mod record;
+pub mod lexer;
+pub mod ast;
+pub mod parser;
+pub mod eval;
+pub mod aggregate;
 
 #[cfg(test)]
 mod integration_test;
@@ -47,11 +61,13 @@ enum MetaCommandResult {
     Exit,
     TableNotGiven,
     UnrecognizedCommand,
+    InvalidArgument,
 }
 
 struct MetaCommandArgs<'a> {
     input: &'a str,
     table: Option<&'a mut Table>,
+    output: &'a mut dyn io::Write,
 }
 
 fn do_meta_command(args: MetaCommandArgs) -> Result<(), MetaCommandResult> {
@@ -63,10 +79,223 @@ fn do_meta_command(args: MetaCommandArgs) -> Result<(), MetaCommandResult> {
         ".constants" => {
             show_constants()
         }
+        input if input.starts_with(".synchronous") => {
+            synchronous(args.table, &input[".synchronous".len()..], args.output)
+        }
+        input if input.starts_with(".cache_size") => {
+            cache_size(args.table, &input[".cache_size".len()..], args.output)
+        }
+        input if input.starts_with(".journal_mode") => {
+            journal_mode(args.table, &input[".journal_mode".len()..], args.output)
+        }
+        ".checkpoint" => {
+            checkpoint(args.table, args.output)
+        }
+        ".schema" => {
+            show_schema(args.table, args.output)
+        }
+        ".tables" => {
+            show_tables(args.table, args.output)
+        }
+        input if input.starts_with(".import") => {
+            import(args.table, &input[".import".len()..], args.output)
+        }
         _ => Err(MetaCommandResult::UnrecognizedCommand),
     }
 }
 
+/// `.synchronous` で現在のレベルを表示し、`.synchronous off|normal|full` で変更する
+fn synchronous(table: Option<&mut Table>, arg: &str, w: &mut dyn io::Write) -> Result<(), MetaCommandResult> {
+    let table = match table {
+        Some(v) => v,
+        None => return Err(MetaCommandResult::TableNotGiven),
+    };
+    let arg = arg.trim();
+    if arg.is_empty() {
+        let _ = writeln!(w, "{}", table.pager.synchronous());
+        return Ok(());
+    }
+    match arg.parse::<Synchronous>() {
+        Ok(level) => {
+            table.pager.set_synchronous(level);
+            Ok(())
+        }
+        Err(e) => {
+            log::error!("{}", e);
+            Err(MetaCommandResult::InvalidArgument)
+        }
+    }
+}
+
+/// `.journal_mode` で現在のモードを表示し、`.journal_mode delete|wal` で切り替える
+fn journal_mode(table: Option<&mut Table>, arg: &str, w: &mut dyn io::Write) -> Result<(), MetaCommandResult> {
+    let table = match table {
+        Some(v) => v,
+        None => return Err(MetaCommandResult::TableNotGiven),
+    };
+    let arg = arg.trim();
+    if arg.is_empty() {
+        let _ = writeln!(w, "{}", table.pager.journal_mode());
+        return Ok(());
+    }
+    let mode = match arg.parse::<JournalMode>() {
+        Ok(v) => v,
+        Err(e) => {
+            log::error!("{}", e);
+            return Err(MetaCommandResult::InvalidArgument);
+        }
+    };
+    match table.pager.set_journal_mode(mode) {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            log::error!("failed to change journal mode: {}", e);
+            Err(MetaCommandResult::InvalidArgument)
+        }
+    }
+}
+
+/// `.checkpoint` でWALのframeをデータファイルに書き戻し、書き戻したページ数を表示する
+fn checkpoint(table: Option<&mut Table>, w: &mut dyn io::Write) -> Result<(), MetaCommandResult> {
+    let table = match table {
+        Some(v) => v,
+        None => return Err(MetaCommandResult::TableNotGiven),
+    };
+    match table.pager.checkpoint() {
+        Ok(pages) => {
+            let _ = writeln!(w, "{} pages checkpointed", pages);
+            Ok(())
+        }
+        Err(e) => {
+            log::error!("failed to checkpoint: {}", e);
+            Err(MetaCommandResult::InvalidArgument)
+        }
+    }
+}
+
+/// `.schema` で全てのtableのCREATE TABLE文を作った順に表示する
+fn show_schema(table: Option<&mut Table>, w: &mut dyn io::Write) -> Result<(), MetaCommandResult> {
+    let table = match table {
+        Some(v) => v,
+        None => return Err(MetaCommandResult::TableNotGiven),
+    };
+    for entry in &table.catalog {
+        let _ = writeln!(w, "{}", entry.sql);
+    }
+    Ok(())
+}
+
+/// `.tables` でtableの名前を表示する
+fn show_tables(table: Option<&mut Table>, w: &mut dyn io::Write) -> Result<(), MetaCommandResult> {
+    let table = match table {
+        Some(v) => v,
+        None => return Err(MetaCommandResult::TableNotGiven),
+    };
+    let names: Vec<&str> = table.catalog.iter().map(|entry| entry.name.as_str()).collect();
+    let _ = writeln!(w, "{}", names.join(" "));
+    Ok(())
+}
+
+/// `.import FILE [TABLE] [FILL_FACTOR]` でカラムの値を`,`で区切った行が並んだファイルを空のtableに一括で読み込む。
+/// 最後のカラムには`,`を含めてよい。TABLEを省略するとusers、FILL_FACTORはleafを埋める割合(%)
+fn import(table: Option<&mut Table>, arg: &str, w: &mut dyn io::Write) -> Result<(), MetaCommandResult> {
+    let table = match table {
+        Some(v) => v,
+        None => return Err(MetaCommandResult::TableNotGiven),
+    };
+    let parse_fill_factor = |s: &str| s.parse::<usize>().map_err(|e| {
+        log::error!("invalid fill factor: {}", e);
+        MetaCommandResult::InvalidArgument
+    });
+    let args: Vec<&str> = arg.split_whitespace().collect();
+    let (path, name, fill_factor) = match args[..] {
+        [path] => (path, DEFAULT_TABLE, DEFAULT_FILL_FACTOR),
+        // 2つ目が数値ならFILL_FACTOR
+        [path, arg] if arg.parse::<usize>().is_ok() => (path, DEFAULT_TABLE, parse_fill_factor(arg)?),
+        [path, name] => (path, name, DEFAULT_FILL_FACTOR),
+        [path, name, fill_factor] => (path, name, parse_fill_factor(fill_factor)?),
+        _ => return Err(MetaCommandResult::InvalidArgument),
+    };
+    if let Err(e) = table.select_table(&name.to_lowercase()) {
+        log::error!("failed to import: {}", e);
+        return Err(MetaCommandResult::InvalidArgument);
+    }
+    let content = match std::fs::read_to_string(path) {
+        Ok(v) => v,
+        Err(e) => {
+            log::error!("failed to read {}: {}", path, e);
+            return Err(MetaCommandResult::InvalidArgument);
+        }
+    };
+    let mut rows = vec![];
+    for (i, line) in content.lines().enumerate().filter(|(_, line)| !line.trim().is_empty()) {
+        let fields: Vec<&str> = line.splitn(table.schema.columns.len(), ',').collect();
+        if fields.len() != table.schema.columns.len() {
+            log::error!("line {}: expected {} columns: {}", i + 1, table.schema.columns.len(), line);
+            return Err(MetaCommandResult::InvalidArgument);
+        }
+        let values = table.schema.columns.iter().zip(fields)
+            .map(|(column, field)| Value::from_text(column.col_type, field))
+            .collect::<Result<Vec<Value>, String>>();
+        let values = match values {
+            Ok(v) => v,
+            Err(e) => {
+                log::error!("line {}: invalid value: {}", i + 1, e);
+                return Err(MetaCommandResult::InvalidArgument);
+            }
+        };
+        let mut row = Vec::new();
+        if let Err(e) = table.schema.encode(values, &mut row) {
+            log::error!("line {}: invalid row: {}", i + 1, e);
+            return Err(MetaCommandResult::InvalidArgument);
+        }
+        let id = table.schema.key(&row);
+        rows.push((id, row));
+    }
+    let result = table.bulk_load(rows, fill_factor).and_then(|n| {
+        // トランザクション中はCOMMITするまで書き出さない
+        if !table.in_transaction() {
+            table.commit()?;
+        }
+        Ok(n)
+    });
+    match result {
+        Ok(n) => {
+            let _ = writeln!(w, "{} rows imported", n);
+            Ok(())
+        }
+        Err(e) => {
+            log::error!("failed to import: {}", e);
+            Err(MetaCommandResult::InvalidArgument)
+        }
+    }
+}
+
+/// `.cache_size` で現在の容量(ページ数)を表示し、`.cache_size N` で変更する。
+/// SQLiteのPRAGMA cache_sizeと同じく、負の値はKiB単位とみなす
+fn cache_size(table: Option<&mut Table>, arg: &str, w: &mut dyn io::Write) -> Result<(), MetaCommandResult> {
+    let table = match table {
+        Some(v) => v,
+        None => return Err(MetaCommandResult::TableNotGiven),
+    };
+    let arg = arg.trim();
+    if arg.is_empty() {
+        let _ = writeln!(w, "{}", table.pager.cache_capacity());
+        return Ok(());
+    }
+    let size = match arg.parse::<i64>() {
+        Ok(n) if n > 0 => CacheSize::Pages(n as usize),
+        Ok(n) if n < 0 => CacheSize::Bytes(n.unsigned_abs() as usize * 1024),
+        _ => return Err(MetaCommandResult::InvalidArgument),
+    };
+    match table.pager.set_cache_size(size) {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            log::error!("failed to resize cache: {}", e);
+            Err(MetaCommandResult::InvalidArgument)
+        }
+    }
+}
+
 fn show_btree(table: Option<&mut Table>) -> Result<(), MetaCommandResult> {
     if let Some(table) = table {
         println!("Tree:");
@@ -93,6 +322,14 @@ fn show_btree_node(table: &mut Table, page_num: usize, indent: &str) -> Result<(
                 println!("{}internal (size {})", indent, node.num_keys);
                 Some((node.key_children.clone(), node.right_child))
             }
+            BTreeNode::Free(node) => {
+                println!("{}free (next {})", indent, node.next_free);
+                None
+            }
+            BTreeNode::Overflow(node) => {
+                println!("{}overflow (size {}, next {})", indent, node.data.len(), node.next);
+                None
+            }
         }
     } else {
         return Ok(());
@@ -111,14 +348,14 @@ fn show_btree_node(table: &mut Table, page_num: usize, indent: &str) -> Result<(
 
 fn show_constants() -> Result<(), MetaCommandResult> {
     println!("Constants:");
-    println!("ROW_SIZE: {}", ROW_SIZE);
     println!("NODE_HEADER_SIZE: {}", BTreeLeafNode::NODE_HEADER_SIZE);
-    println!("NODE_CELL_SIZE: {}", BTreeLeafNode::NODE_CELL_SIZE);
     println!("NODE_SPACE_FOR_CELLS: {}", BTreeLeafNode::NODE_SPACE_FOR_CELLS);
-    println!("NODE_MAX_CELLS: {}", BTreeLeafNode::NODE_MAX_CELLS);
+    println!("NODE_MIN_SIZE: {}", BTreeLeafNode::NODE_MIN_SIZE);
+    println!("MAX_RECORD_SIZE: {}", BTreeLeafNode::MAX_RECORD_SIZE);
+    println!("OVERFLOW_LOCAL_SIZE: {}", BTreeLeafNode::OVERFLOW_LOCAL_SIZE);
     println!("INTERNAL_CELL_SIZE: {}", BTreeInternalNode::INTERNAL_CELL_SIZE);
     println!("INTERNAL_SPACE_FOR_CELLS: {}", BTreeInternalNode::INTERNAL_SPACE_FOR_CELLS);
-    println!("INTERNAL_MAX_CELLS: {}", BTreeInternalNode::INTERNAL_MAX_CELLS);
+    println!("INTERNAL_PAGE_MAX_CELLS: {}", BTreeInternalNode::INTERNAL_PAGE_MAX_CELLS);
     Ok(())
 }
 
@@ -126,6 +363,28 @@ fn show_constants() -> Result<(), MetaCommandResult> {
 struct Statement {
     st_type: StatementType,
     row_to_insert: Option<Vec<u8>>,
+    // insertする値。bindでschemaに合わせてrow_to_insertにする
+    values: Vec<Value>,
+    key_range: Option<KeyRange>,
+    // where句。bindでkeyの条件をkey_rangeにし、実行時には範囲の中の行をこれで選ぶ
+    where_clause: Option<ast::Expr>,
+    // selectで出力するもの。空なら全てのカラム
+    columns: Vec<ast::SelectItem>,
+    // selectで行をまとめる式と、まとめたグループを選ぶ条件
+    group_by: Vec<ast::Expr>,
+    having: Option<ast::Expr>,
+    // updateでカラムに入れる式。リテラルはbindでカラムの型に合わせた値にする
+    assignments: Vec<ast::Assignment>,
+    // order byのカラム。bindでtableのkeyか調べる
+    order_by: Option<ast::OrderBy>,
+    descending: bool,
+    limit: Option<usize>,
+    // SAVEPOINT, RELEASE, ROLLBACK TOの対象
+    savepoint: Option<String>,
+    // CREATE TABLEで作るtable
+    schema: Option<Schema>,
+    // insert, select, update, deleteの対象。省略するとusers
+    table_name: Option<String>,
 }
 
 impl Statement {
@@ -133,22 +392,186 @@ impl Statement {
         Statement {
             st_type,
             row_to_insert: None,
+            values: vec![],
+            key_range: None,
+            where_clause: None,
+            columns: vec![],
+            group_by: vec![],
+            having: None,
+            assignments: vec![],
+            order_by: None,
+            descending: false,
+            limit: None,
+            savepoint: None,
+            schema: None,
+            table_name: None,
         }
     }
+
+    /// tableの行を読み書きする文なら対象のtableの名前を返す
+    fn table_name(&self) -> Option<&str> {
+        match self.st_type {
+            StatementType::Insert | StatementType::Select | StatementType::Delete | StatementType::Update => {
+                Some(self.table_name.as_deref().unwrap_or(DEFAULT_TABLE))
+            }
+            _ => None,
+        }
+    }
+
+    /// 集約関数かgroup by、havingがあり、行をまとめて出力するselectか
+    fn is_aggregate(&self) -> bool {
+        let has_aggregate = |item: &ast::SelectItem| match item {
+            ast::SelectItem::Expr { expr, .. } => !expr.aggregates().is_empty(),
+            ast::SelectItem::Wildcard => false,
+        };
+        !self.group_by.is_empty() || self.having.is_some() || self.columns.iter().any(has_aggregate)
+    }
+
+    /// 集約関数を書けるselectとhavingの式
+    fn aggregate_exprs(&self) -> Vec<&ast::Expr> {
+        self.columns.iter()
+            .filter_map(|item| match item {
+                ast::SelectItem::Expr { expr, .. } => Some(expr),
+                ast::SelectItem::Wildcard => None,
+            })
+            .chain(self.having.as_ref())
+            .collect()
+    }
+
+    /// tableのschemaに合わせて値を検査する。insertする値は行にしておく
+    fn bind(&mut self, schema: &Schema) -> Result<(), PrepareError> {
+        match self.st_type {
+            StatementType::Insert if self.row_to_insert.is_none() => {
+                let mut row = Vec::new();
+                schema.encode(std::mem::take(&mut self.values), &mut row)?;
+                self.row_to_insert = Some(row);
+            }
+            StatementType::Update => {
+                for assignment in self.assignments.iter_mut() {
+                    // 先頭のカラムはkeyなので書き換えられない
+                    let index = match schema.column_index(&assignment.column) {
+                        Some(0) => {
+                            let message = format!("primary key {} cannot be updated", assignment.column);
+                            return Err(ParseError::new(message, assignment.position).into());
+                        }
+                        Some(index) => index,
+                        None => {
+                            let message = format!("no such column in table {}: {}", schema.name, assignment.column);
+                            return Err(ParseError::new(message, assignment.position).into());
+                        }
+                    };
+                    eval::check(&assignment.value, schema)?;
+                    reject_aggregates(&assignment.value, "update")?;
+                    // リテラルはここで型を合わせておき、式は行ごとに計算してから合わせる
+                    if let Some(value) = assignment.value.literal() {
+                        let value = schema.coerce(index, value.clone())?;
+                        assignment.value = ast::Expr::Literal { value, position: assignment.value.position() };
+                    }
+                }
+            }
+            _ => {}
+        }
+        // keyの順にしか並べられない
+        if let Some(order_by) = &self.order_by {
+            let key = &schema.columns[0].name;
+            if &order_by.column != key {
+                return Err(ParseError::new(format!("only {} can be used in order by", key), order_by.position).into());
+            }
+        }
+        for item in &self.columns {
+            if let ast::SelectItem::Expr { expr, .. } = item {
+                eval::check(expr, schema)?;
+            }
+        }
+        for expr in &self.group_by {
+            eval::check(expr, schema)?;
+            reject_aggregates(expr, "group by")?;
+        }
+        if let Some(having) = &self.having {
+            eval::check(having, schema)?;
+        }
+        if let Some(where_clause) = &self.where_clause {
+            eval::check(where_clause, schema)?;
+            reject_aggregates(where_clause, "where")?;
+            let key_range = key_range(where_clause, &schema.columns[0].name);
+            trace!("bind: key_range: {:?}", key_range);
+            self.key_range = Some(key_range);
+        }
+        Ok(())
+    }
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 enum StatementType {
     Insert,
     Select,
+    Delete,
+    Update,
+    Begin,
+    Commit,
+    Rollback,
+    Savepoint,
+    Release,
+    Vacuum,
+    CreateTable,
 }
 
+/// where句のkeyの条件から分かるkeyの範囲(両端を含む)。startがendより大きければ空
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct KeyRange {
+    start: u32,
+    end: u32,
+}
+
+impl KeyRange {
+    fn all() -> Self {
+        KeyRange { start: u32::MIN, end: u32::MAX }
+    }
+
+    fn empty() -> Self {
+        KeyRange { start: 1, end: 0 }
+    }
+
+    /// 範囲をkeyに取りうる値に切り詰める
+    fn from_bounds(start: i64, end: i64) -> Self {
+        if start > end || end < 0 || start > u32::MAX as i64 {
+            return KeyRange::empty();
+        }
+        KeyRange { start: start.max(0) as u32, end: end.min(u32::MAX as i64) as u32 }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.start > self.end
+    }
+
+    fn intersect(&self, other: &KeyRange) -> KeyRange {
+        KeyRange::from_bounds(self.start.max(other.start) as i64, self.end.min(other.end) as i64)
+    }
+
+    /// 両方を含む範囲。間も含む
+    fn union(&self, other: &KeyRange) -> KeyRange {
+        match (self.is_empty(), other.is_empty()) {
+            (true, _) => *other,
+            (_, true) => *self,
+            _ => KeyRange { start: self.start.min(other.start), end: self.end.max(other.end) },
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
 enum PrepareError {
     UnrecognizedStatement,
     InvalidRecord,
-    // ここではなさそう
-    SyntaxError,
+    // 文を読めなかった。どこが悪いかを持つ
+    SyntaxError(ParseError),
+    // 存在しないtableを指定した
+    NoSuchTable,
+}
+
+impl From<ParseError> for PrepareError {
+    fn from(e: ParseError) -> Self {
+        PrepareError::SyntaxError(e)
+    }
 }
 
 impl From<RowConversionError> for PrepareError {
@@ -209,119 +632,145 @@ impl From<RowConversionError> for PrepareError {
 //     }
 // }
 
-const COLUMN_USERNAME_SIZE: usize = 32;
-const COLUMN_EMAIL_SIZE: usize = 255;
-
-const ID_SIZE: usize = std::mem::size_of::<u32>();
-const USERNAME_SIZE: usize = std::mem::size_of::<[u8; COLUMN_USERNAME_SIZE]>();
-const EMAIL_SIZE: usize = std::mem::size_of::<[u8; COLUMN_EMAIL_SIZE]>();
-const ID_OFFSET: usize = 0;
-const USERNAME_OFFSET: usize = ID_OFFSET + ID_SIZE;
-const EMAIL_OFFSET: usize = USERNAME_SIZE + USERNAME_SIZE;
-const ROW_SIZE: usize = ID_SIZE + USERNAME_SIZE + EMAIL_SIZE;
 const PAGE_SIZE: usize = 4096;
-const TABLE_MAX_PAGES: usize = 100;
-const ROWS_PER_PAGE: usize = PAGE_SIZE / ROW_SIZE;
-const TABLE_MAX_ROWS: usize = ROWS_PER_PAGE * TABLE_MAX_PAGES;
 
 fn prepare_statement(input: &InputBuffer) -> Result<Statement, PrepareError> {
-    let lower = input.buffer.to_lowercase();
-    if lower.starts_with("insert") {
-        let mut statement = Statement::new(StatementType::Insert);
-        let bytes = input.buffer.clone().into_bytes();
-        let buf: &[u8] = bytes.as_ref();
-        let mut buf = io::BufReader::new(buf);
-        let _ = buf.read_until(b' ', &mut Vec::new());
-        let mut id = vec![];
-        let _ = buf.read_until(b' ', &mut id);
-        let id_str = match std::str::from_utf8(id.trim()) {
-            Ok(v) => v,
-            Err(e) => {
-                log::error!(
-                    "id u8 to str conversion failed. input:{:?}, error:{}",
-                    id.trim(),
-                    e
-                );
-                return Err(PrepareError::SyntaxError);
-            }
-        };
-        let id: u32 = match id_str.parse::<u32>() {
-            Ok(v) => v,
-            Err(e) => {
-                log::error!(
-                    "id str -> u32 conversion failed. input:{}, error:{}",
-                    id_str,
-                    e
-                );
-                return Err(PrepareError::SyntaxError);
-            }
-        };
-        log::trace!("id: {}", id);
-        let _ = buf.read_until(b'"', &mut vec![]);
-        let mut username = vec![];
-        match buf.read_until(b'"', &mut username) {
-            Ok(v) => {
-                log::trace!("read {} bytes for username", v);
-            }
-            Err(e) => {
-                log::error!("read username failed: {}", e);
-                return Err(PrepareError::SyntaxError);
-            }
-        };
-        let _ = username.pop(); // 末尾の"を取り除く
-        #[allow(unused)]
-            let username_str = match std::str::from_utf8(&username) {
-            Ok(v) => v,
-            Err(e) => {
-                log::error!(
-                    "username u8 -> str conversion failed. input: {:?}, error: {}",
-                    username,
-                    e
-                );
-                return Err(PrepareError::SyntaxError);
-            }
-        };
-        log::trace!("username: {}", username_str);
-        let _ = buf.read_until(b'"', &mut vec![]);
-        let mut email = vec![];
-        match buf.read_until(b'"', &mut email) {
-            Ok(v) => {
-                log::trace!("read {} bytes for email", v);
+    let ast = match parser::parse(&input.buffer)? {
+        Some(v) => v,
+        None => return Err(PrepareError::UnrecognizedStatement),
+    };
+    let statement = match ast {
+        ast::Statement::Insert { table, values } => {
+            let mut statement = Statement::new(StatementType::Insert);
+            statement.table_name = table;
+            statement.values = values.iter().map(constant).collect::<Result<Vec<Value>, ParseError>>()?;
+            log::trace!("insert values: {:?}", statement.values);
+            statement
+        }
+        ast::Statement::Select { columns, table, where_clause, group_by, having, order_by, limit } => {
+            let mut statement = Statement::new(StatementType::Select);
+            statement.table_name = table;
+            statement.columns = columns;
+            statement.where_clause = where_clause;
+            statement.group_by = group_by;
+            statement.having = having;
+            if let Some(order_by) = &order_by {
+                statement.descending = order_by.descending;
             }
-            Err(e) => {
-                log::error!("read email failed: {}", e);
-                return Err(PrepareError::SyntaxError);
+            statement.order_by = order_by;
+            statement.limit = limit;
+            statement
+        }
+        ast::Statement::Update { table, assignments, where_clause } => {
+            let mut statement = Statement::new(StatementType::Update);
+            statement.table_name = table;
+            for assignment in &assignments {
+                log::trace!("update: {} = {:?}", assignment.column, assignment.value);
             }
-        };
-        let _ = email.pop(); // 末尾の"を取り除く
-        #[allow(unused)]
-            let email_str = match std::str::from_utf8(&email) {
-            Ok(v) => v,
-            Err(e) => {
-                log::error!(
-                    "email u8 -> str conversion failed. input: {:?}, error: {}",
-                    email,
-                    e
-                );
-                return Err(PrepareError::SyntaxError);
+            statement.assignments = assignments;
+            statement.key_range = Some(KeyRange::all());
+            statement.where_clause = where_clause;
+            statement
+        }
+        ast::Statement::Delete { table, where_clause } => {
+            let mut statement = Statement::new(StatementType::Delete);
+            statement.table_name = table;
+            statement.key_range = Some(KeyRange::all());
+            statement.where_clause = where_clause;
+            statement
+        }
+        ast::Statement::CreateTable { name, columns, position } => {
+            let mut statement = Statement::new(StatementType::CreateTable);
+            let schema = Schema::from_definition(&name, &columns, position)?;
+            // catalogの行に入らない名前や定義は作れない
+            if !CatalogEntry::fits(&schema) {
+                log::error!("table definition is too long: {}", schema.to_sql());
+                return Err(PrepareError::InvalidRecord);
             }
-        };
-        log::trace!("email: {}", email_str);
-        let mut u = [0u8; 32];
-        u[0..(username.len())].copy_from_slice(&username);
-        let mut e = [0u8; 255];
-        e[0..(email.len())].copy_from_slice(&email);
-        let mut row = Vec::with_capacity(ROW_SIZE);
-        // ここでこのエラーを出すのはおかしい気がするが
-        cols_to_row(&mut row, id, username_str, email_str)?;
-        statement.row_to_insert = Some(row);
-        return Ok(statement);
+            statement.schema = Some(schema);
+            statement
+        }
+        ast::Statement::Begin => Statement::new(StatementType::Begin),
+        ast::Statement::Commit => Statement::new(StatementType::Commit),
+        ast::Statement::Rollback { savepoint } => {
+            let mut statement = Statement::new(StatementType::Rollback);
+            statement.savepoint = savepoint;
+            statement
+        }
+        ast::Statement::Savepoint { name } => {
+            let mut statement = Statement::new(StatementType::Savepoint);
+            statement.savepoint = Some(name);
+            statement
+        }
+        ast::Statement::Release { name } => {
+            let mut statement = Statement::new(StatementType::Release);
+            statement.savepoint = Some(name);
+            statement
+        }
+        ast::Statement::Vacuum => Statement::new(StatementType::Vacuum),
+    };
+    Ok(statement)
+}
+
+/// where句やgroup byには集約関数を書けない
+fn reject_aggregates(expr: &ast::Expr, clause: &str) -> Result<(), ParseError> {
+    match expr.aggregates().first() {
+        Some(aggregate) => Err(ParseError::new(format!("aggregate functions are not allowed in {}", clause), aggregate.position())),
+        None => Ok(()),
     }
-    if lower.starts_with("select") {
-        let statement = Statement::new(StatementType::Select);
-        return Ok(statement);
+}
+
+/// 式がリテラルならその値を返す。insertの値にはリテラルしか書けない
+fn constant(expr: &ast::Expr) -> Result<Value, ParseError> {
+    expr.literal().cloned().ok_or_else(|| ParseError::new("expected a literal value", expr.position()))
+}
+
+/// where句を満たす行のkeyが必ず入る範囲を求める。
+/// keyとリテラルの整数の比較、between、inから範囲を作り、andは重なり、orは両方を含む範囲にする。
+/// それ以外の条件では絞れないので全体を返す
+fn key_range(expr: &ast::Expr, key: &str) -> KeyRange {
+    use ast::{BinaryOp, Expr};
+    let is_key = |expr: &Expr| matches!(expr, Expr::Column { name, .. } if name == key);
+    let integer = |expr: &Expr| match expr.literal() {
+        Some(Value::Integer(v)) => Some(*v),
+        _ => None,
+    };
+    match expr {
+        Expr::Binary { op: BinaryOp::And, left, right, .. } => key_range(left, key).intersect(&key_range(right, key)),
+        Expr::Binary { op: BinaryOp::Or, left, right, .. } => key_range(left, key).union(&key_range(right, key)),
+        Expr::Binary { op, left, right, .. } => {
+            // `3 < id`は`id > 3`と同じ
+            let (op, v) = match (is_key(left), integer(right), is_key(right), integer(left)) {
+                (true, Some(v), _, _) => (*op, v),
+                (_, _, true, Some(v)) => match op {
+                    BinaryOp::Lt => (BinaryOp::Gt, v),
+                    BinaryOp::Le => (BinaryOp::Ge, v),
+                    BinaryOp::Gt => (BinaryOp::Lt, v),
+                    BinaryOp::Ge => (BinaryOp::Le, v),
+                    op => (*op, v),
+                },
+                _ => return KeyRange::all(),
+            };
+            match op {
+                BinaryOp::Eq => KeyRange::from_bounds(v, v),
+                BinaryOp::Lt => KeyRange::from_bounds(i64::MIN, v.saturating_sub(1)),
+                BinaryOp::Le => KeyRange::from_bounds(i64::MIN, v),
+                BinaryOp::Gt => KeyRange::from_bounds(v.saturating_add(1), i64::MAX),
+                BinaryOp::Ge => KeyRange::from_bounds(v, i64::MAX),
+                _ => KeyRange::all(),
+            }
+        }
+        Expr::Between { operand, low, high, negated: false, .. } if is_key(operand) => match (integer(low), integer(high)) {
+            (Some(low), Some(high)) => KeyRange::from_bounds(low, high),
+            _ => KeyRange::all(),
+        },
+        Expr::InList { operand, list, negated: false, .. } if is_key(operand) => {
+            list.iter().try_fold(KeyRange::empty(), |range, item| {
+                integer(item).map(|v| range.union(&KeyRange::from_bounds(v, v)))
+            }).unwrap_or_else(KeyRange::all)
+        }
+        _ => KeyRange::all(),
     }
-    Err(PrepareError::UnrecognizedStatement)
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -331,153 +780,461 @@ enum ExecuteResult {
     PageNotFound,
     // RootNodeIsInternal,
     DuplicateKey,
+    CommitFailure,
+    RollbackFailure,
+    // BEGINの中でBEGINした
+    TransactionActive,
+    // BEGINせずにCOMMITやROLLBACKした
+    NoTransaction,
+    // RELEASEやROLLBACK TOに存在しないsavepointを指定した
+    NoSuchSavepoint,
+    // VACUUMに失敗した。元のファイルはそのまま残る
+    VacuumFailure,
+    // CREATE TABLEしようとしたが、既に同じ名前のtableがある
+    TableExists,
+    // where句を計算できなかった。どこで何が起きたかを持つ
+    EvaluationError(String),
 }
 
 fn execute_insert(statement: &Statement, table: &mut Table) -> Result<(), ExecuteResult> {
     trace!("execute_insert");
-    let node = match table.pager.get_page(table.root_page_num) {
-        Some(page) => page,
-        None => { return Err(ExecuteResult::PageNotFound); }
+    let row_to_insert = match &statement.row_to_insert {
+        Some(s) => s,
+        None => {
+            return Err(ExecuteResult::InvalidStatement);
+        }
     };
-    match node {
-        BTreeNode::Leaf(page) => {
-            let num_cells = page.num_cells;
-            trace!("execute_insert: num_cells: {}", num_cells);
-            let row_to_insert = match &statement.row_to_insert {
-                Some(s) => s,
-                None => {
-                    return Err(ExecuteResult::InvalidStatement);
-                }
-            };
-            let is_max = page.is_max();
-            let key_to_insert = get_id_from_row(row_to_insert).unwrap();
-            trace!("execute_insert: key_to_insert: {}", key_to_insert);
-            let mut cursor = Cursor::find_insert_position(table, table.root_page_num, key_to_insert);
-            trace!("execute_insert: cursor.cell_num: {}", cursor.cell_num);
-            let cell_num = cursor.cell_num;
+    if table.pager.get_page(table.root_page_num).is_none() {
+        return Err(ExecuteResult::PageNotFound);
+    }
+    let key_to_insert = table.schema.key(row_to_insert);
+    trace!("execute_insert: key_to_insert: {}", key_to_insert);
+    if !table.insert_row(table.root_page_num, key_to_insert, row_to_insert.clone()) {
+        return Err(ExecuteResult::DuplicateKey);
+    }
+    log::trace!("row inserted");
+    Ok(())
+}
 
-            if is_max {
-                log::debug!("table is full");
-                if let Some(root) = cursor.split_and_insert(key_to_insert, row_to_insert.clone()) {
-                    cursor.table.root_page_num = root;
-                }
-                return Ok(());
-            }
-            match cursor.get_page_mut() {
-                Some(BTreeNode::Leaf(page)) => {
-                    if cell_num < num_cells.try_into().unwrap() {
-                        let key_at_index = page.key_values[cell_num].key;
-                        if key_at_index == key_to_insert {
-                            return Err(ExecuteResult::DuplicateKey);
-                        }
-                    }
-                    log::trace!("row inserted");
-                    page.insert_at(cell_num, get_id_from_row(row_to_insert).unwrap(), row_to_insert.clone());
-                }
-                Some(BTreeNode::Internal(_)) => {}
-                None => {
-                    log::error!("cannot get mutable reference to page!");
-                    return Err(ExecuteResult::PageMutFailure);
-                }
-            };
+fn execute_delete(statement: &Statement, table: &mut Table) -> Result<(), ExecuteResult> {
+    trace!("execute_delete");
+    let key_range = match statement.key_range {
+        Some(v) => v,
+        None => {
+            return Err(ExecuteResult::InvalidStatement);
         }
-        BTreeNode::Internal(page) => {
-            let num_keys = page.num_keys;
-            trace!("execute_insert: num_keys: {}", num_keys);
-            let row_to_insert = match &statement.row_to_insert {
-                Some(s) => s,
-                None => {
-                    return Err(ExecuteResult::InvalidStatement);
-                }
-            };
-            let key_to_insert = get_id_from_row(row_to_insert).unwrap();
-            trace!("execute_insert: key_to_insert: {}", key_to_insert);
-            let mut cursor = Cursor::find_insert_position(table, table.root_page_num, key_to_insert);
-            trace!("execute_insert: cursor.cell_num: {}", cursor.cell_num);
-            let page = match cursor.get_page() {
-                Some(BTreeNode::Leaf(node)) => { node }
-                Some(_) => { unreachable!("find_insert_position must return leaf node page num") }
-                None => { unreachable!("page must be present.") }
-            };
-            let is_max = page.is_max();
-            if is_max {
-                log::debug!("table is full");
-                if let Some(root) = cursor.split_and_insert(key_to_insert, row_to_insert.clone()) {
-                    cursor.table.root_page_num = root;
-                }
-                return Ok(());
+    };
+    if table.pager.get_page(table.root_page_num).is_none() {
+        return Err(ExecuteResult::PageNotFound);
+    }
+    let mut keys = vec![];
+    table.collect_keys(table.root_page_num, key_range.start, key_range.end, &mut keys);
+    let keys = filter_keys(table, keys, statement.where_clause.as_ref())?;
+    trace!("execute_delete: {} keys to delete", keys.len());
+    for key in keys {
+        let mut cursor = Cursor::find_insert_position(table, table.root_page_num, key);
+        cursor.delete();
+    }
+    Ok(())
+}
+
+fn execute_update(statement: &Statement, table: &mut Table, w: &mut impl io::Write) -> Result<(), ExecuteResult> {
+    trace!("execute_update");
+    let key_range = match statement.key_range {
+        Some(v) => v,
+        None => {
+            return Err(ExecuteResult::InvalidStatement);
+        }
+    };
+    if table.pager.get_page(table.root_page_num).is_none() {
+        return Err(ExecuteResult::PageNotFound);
+    }
+    let mut keys = vec![];
+    table.collect_keys(table.root_page_num, key_range.start, key_range.end, &mut keys);
+    let keys = filter_keys(table, keys, statement.where_clause.as_ref())?;
+    let schema = table.schema.clone();
+    // 右辺は書き換える前の行で計算する。全ての行を計算してから書き換えるので、途中で失敗しても行は変わらない
+    let mut rows = vec![];
+    for key in keys {
+        let mut cursor = Cursor::find_insert_position(table, table.root_page_num, key);
+        let old_values = match cursor.get_row() {
+            Some(v) => schema.decode(&v),
+            None => {
+                log::error!("cannot get row to update!");
+                return Err(ExecuteResult::PageMutFailure);
             }
-            let num_cells = page.num_cells;
-            let cell_num = cursor.cell_num;
-            match cursor.get_page_mut() {
-                Some(BTreeNode::Leaf(page)) => {
-                    if cell_num < num_cells.try_into().unwrap() {
-                        let key_at_index = page.key_values[cell_num].key;
-                        if key_at_index == key_to_insert {
-                            return Err(ExecuteResult::DuplicateKey);
-                        }
-                    }
-                    log::trace!("row inserted");
-                    page.insert_at(cell_num, get_id_from_row(row_to_insert).unwrap(), row_to_insert.clone());
-                }
-                Some(BTreeNode::Internal(_)) => {}
-                None => {
-                    log::error!("cannot get mutable reference to page!");
-                    return Err(ExecuteResult::PageMutFailure);
+        };
+        let mut values = old_values.clone();
+        for assignment in &statement.assignments {
+            let index = match schema.column_index(&assignment.column) {
+                Some(index) if index > 0 => index,
+                _ => {
+                    log::error!("invalid assignment to update: {:?}", assignment);
+                    return Err(ExecuteResult::InvalidStatement);
                 }
             };
+            let value = eval::eval(&assignment.value, &schema, &old_values).map_err(ExecuteResult::EvaluationError)?;
+            values[index] = schema.coerce(index, value)
+                .map_err(|e| ExecuteResult::EvaluationError(eval::error(assignment.value.position(), e.to_string())))?;
+        }
+        let mut row = vec![];
+        if let Err(e) = schema.encode(values, &mut row) {
+            log::error!("failed to update row: {}", e);
+            return Err(ExecuteResult::InvalidStatement);
         }
+        rows.push((key, row));
+    }
+    let updated = rows.len();
+    for (key, row) in rows {
+        // 行の長さが変わるとleafに収まらないことがあるので、書き換えはTableに任せる
+        table.replace_row(table.root_page_num, key, row);
     }
+    trace!("execute_update: {} rows updated", updated);
+    let _ = writeln!(w, "{} rows updated", updated);
     Ok(())
 }
 
-fn execute_select(_statement: &Statement, table: &mut Table, w: &mut impl io::Write) -> Result<Vec<u8>, ExecuteResult> {
-    trace!("execute_select");
-    let mut cursor = Cursor::table_start(table);
-    select_all(&mut cursor, w);
-    Ok(vec![])
-}
-
-fn select_all(cursor: &mut Cursor, w: &mut impl io::Write) {
-    trace!("select_all");
-    while !cursor.end_of_table {
-        match cursor.get_page() {
-            Some(BTreeNode::Leaf(_)) => {
-                trace!("select_all: node is leaf");
-                if let Some(row) = cursor.get_row() {
-                    let _ = writeln!(w, "{:?}", display_row(row));
-                }
-                cursor.advance();
+/// where句を満たす行のkeyだけを残す。
+/// 書き換える前に全ての行を調べるので、where句の計算に失敗しても行は変わらない
+fn filter_keys(table: &mut Table, keys: Vec<u32>, where_clause: Option<&ast::Expr>) -> Result<Vec<u32>, ExecuteResult> {
+    let where_clause = match where_clause {
+        Some(v) => v,
+        None => return Ok(keys),
+    };
+    let schema = table.schema.clone();
+    let mut filtered = vec![];
+    for key in keys {
+        let mut cursor = Cursor::find_insert_position(table, table.root_page_num, key);
+        let row = cursor.get_row().ok_or(ExecuteResult::PageNotFound)?;
+        if matches_where(&schema, &row, Some(where_clause))? {
+            filtered.push(key);
+        }
+    }
+    trace!("filter_keys: {} keys matched", filtered.len());
+    Ok(filtered)
+}
+
+/// 行がwhere句を満たすか。where句がなければ全ての行が満たす
+fn matches_where(schema: &Schema, row: &[u8], where_clause: Option<&ast::Expr>) -> Result<bool, ExecuteResult> {
+    match where_clause {
+        Some(expr) => eval::is_true(expr, schema, &schema.decode(row)).map_err(ExecuteResult::EvaluationError),
+        None => Ok(true),
+    }
+}
+
+/// selectの結果。カラムの名前と、行ごとの型の付いた値
+#[derive(Clone, Debug, Default, PartialEq)]
+struct ResultSet {
+    columns: Vec<String>,
+    rows: Vec<Vec<Value>>,
+}
+
+impl ResultSet {
+    /// `Row<id:1, username:foo, email:bar>` の形式で表示する
+    fn display(&self, row: &[Value]) -> String {
+        let cols: Vec<String> = self.columns.iter().zip(row)
+            .map(|(column, value)| format!("{}:{}", column, value))
+            .collect();
+        format!("Row<{}>", cols.join(", "))
+    }
+}
+
+/// selectの結果のカラムの名前。別名がなければ式をそのまま名前にする
+fn result_columns(schema: &Schema, items: &[ast::SelectItem]) -> Vec<String> {
+    if items.is_empty() {
+        return schema.columns.iter().map(|c| c.name.clone()).collect();
+    }
+    let mut columns = vec![];
+    for item in items {
+        match item {
+            ast::SelectItem::Wildcard => columns.extend(schema.columns.iter().map(|c| c.name.clone())),
+            ast::SelectItem::Expr { alias: Some(alias), .. } => columns.push(alias.clone()),
+            ast::SelectItem::Expr { expr, alias: None } => columns.push(expr.to_string()),
+        }
+    }
+    columns
+}
+
+/// 行の値から、selectで出力する値を求める
+fn project(schema: &Schema, items: &[ast::SelectItem], values: Vec<Value>) -> Result<Vec<Value>, ExecuteResult> {
+    if items.is_empty() {
+        return Ok(values);
+    }
+    let mut projected = vec![];
+    for item in items {
+        match item {
+            ast::SelectItem::Wildcard => projected.extend(values.iter().cloned()),
+            ast::SelectItem::Expr { expr, .. } => {
+                projected.push(eval::eval(expr, schema, &values).map_err(ExecuteResult::EvaluationError)?);
             }
-            Some(BTreeNode::Internal(page)) => {
-                trace!("select_all: node is internal");
-                let kc = page.key_children.first().expect("select_all: no children");
-                trace!("select_all: key = {}", kc.key);
-                cursor.page_num = kc.child as usize;
-                cursor.cell_num = 0;
-                trace!("select_all: page_num = {}", cursor.page_num);
-                select_all(cursor, w);
+        }
+    }
+    Ok(projected)
+}
+
+fn execute_select(statement: &Statement, table: &mut Table) -> Result<ResultSet, ExecuteResult> {
+    trace!("execute_select");
+    if statement.is_aggregate() {
+        return execute_aggregate(statement, table);
+    }
+    let schema = table.schema.clone();
+    let mut result = ResultSet { columns: result_columns(&schema, &statement.columns), rows: vec![] };
+    let limit = statement.limit.unwrap_or(usize::MAX);
+    scan(statement, table, limit, &mut |values| {
+        result.rows.push(project(&schema, &statement.columns, values)?);
+        Ok(true)
+    })?;
+    trace!("execute_select: {} rows", result.rows.len());
+    Ok(result)
+}
+
+/// 集約関数やgroup byのあるselectを実行する。
+/// 行をGroupingでまとめ、having句を満たすグループごとに1行を出力する
+fn execute_aggregate(statement: &Statement, table: &mut Table) -> Result<ResultSet, ExecuteResult> {
+    trace!("execute_aggregate");
+    let schema = table.schema.clone();
+    let mut result = ResultSet { columns: result_columns(&schema, &statement.columns), rows: vec![] };
+    let exprs = statement.aggregate_exprs();
+    let groups = match key_extremes(statement, table, &exprs) {
+        Some(group) => vec![group],
+        None => {
+            let mut grouping = Grouping::new(&schema, &statement.group_by, exprs.iter().copied());
+            // limitはまとめた後のグループに対して数える
+            scan(statement, table, usize::MAX, &mut |values| {
+                grouping.add(values).map_err(ExecuteResult::EvaluationError)?;
+                Ok(true)
+            })?;
+            grouping.finish()
+        }
+    };
+    let limit = statement.limit.unwrap_or(usize::MAX);
+    for group in groups {
+        if result.rows.len() >= limit {
+            break;
+        }
+        if let Some(having) = &statement.having {
+            if !eval::is_true(&group.resolve(having), &schema, &group.row).map_err(ExecuteResult::EvaluationError)? {
+                continue;
             }
-            None => {
-                cursor.advance();
+        }
+        let items: Vec<ast::SelectItem> = statement.columns.iter().map(|item| match item {
+            ast::SelectItem::Expr { expr, alias } => ast::SelectItem::Expr { expr: group.resolve(expr), alias: alias.clone() },
+            ast::SelectItem::Wildcard => ast::SelectItem::Wildcard,
+        }).collect();
+        result.rows.push(project(&schema, &items, group.row)?);
+    }
+    trace!("execute_aggregate: {} rows", result.rows.len());
+    Ok(result)
+}
+
+/// `select min(id), max(id)`のように集約関数がkeyのminとmaxだけなら、
+/// 全ての行を辿らずに左端と右端のleafの行から求める。当てはまらなければNone
+fn key_extremes<'a>(statement: &Statement, table: &mut Table, exprs: &[&'a ast::Expr]) -> Option<Group<'a>> {
+    use ast::{AggregateFunc, Expr};
+    if statement.where_clause.is_some() || !statement.group_by.is_empty() || statement.having.is_some()
+        || statement.columns.iter().any(|item| matches!(item, ast::SelectItem::Wildcard)) {
+        return None;
+    }
+    let key = &table.schema.columns[0].name;
+    let mut aggregates = vec![];
+    for expr in exprs {
+        // 集約関数の外でカラムを使っていれば、行を読まなければならない
+        let mut outside = false;
+        let null = |e: &Expr| Expr::Literal { value: Value::Null, position: e.position() };
+        expr.replace(&mut |e| matches!(e, Expr::Aggregate { .. }).then(|| null(e)))
+            .walk(&mut |e| outside |= matches!(e, Expr::Column { .. }));
+        if outside {
+            return None;
+        }
+        for aggregate in expr.aggregates() {
+            let func = match aggregate {
+                Expr::Aggregate { func: func @ (AggregateFunc::Min | AggregateFunc::Max), arg: Some(arg), .. }
+                    if matches!(&**arg, Expr::Column { name, .. } if name == key) => *func,
+                _ => return None,
+            };
+            aggregates.push((aggregate, func));
+        }
+    }
+    let schema = table.schema.clone();
+    let results = aggregates.into_iter().map(|(aggregate, func)| {
+        let mut cursor = match func {
+            AggregateFunc::Min => Cursor::table_start(table),
+            _ => Cursor::table_end(table),
+        };
+        let value = match cursor.get_row() {
+            Some(row) if !cursor.end_of_table => Value::Integer(schema.key(&row) as i64),
+            _ => Value::Null,
+        };
+        trace!("key_extremes: {} = {}", aggregate, value);
+        (aggregate, value)
+    }).collect();
+    Some(Group { row: vec![Value::Null; schema.columns.len()], results })
+}
+
+/// key_rangeの中でwhere句を満たす行をorder byの向きに辿り、値にしてemitに渡す。
+/// emitがtrueを返した行がlimitに達したら止める
+fn scan<F>(statement: &Statement, table: &mut Table, limit: usize, emit: &mut F) -> Result<(), ExecuteResult>
+    where
+        F: FnMut(Vec<Value>) -> Result<bool, ExecuteResult>,
+{
+    let schema = table.schema.clone();
+    let key_range = statement.key_range.unwrap_or_else(KeyRange::all);
+    if key_range.is_empty() {
+        return Ok(());
+    }
+    let mut filter = |row: &[u8]| -> Result<bool, ExecuteResult> {
+        let values = schema.decode(row);
+        if let Some(expr) = &statement.where_clause {
+            if !eval::is_true(expr, &schema, &values).map_err(ExecuteResult::EvaluationError)? {
+                return Ok(false);
             }
+        }
+        emit(values)
+    };
+    if statement.descending {
+        let mut cursor = match statement.key_range {
+            Some(key_range) => Cursor::table_seek_back(table, key_range.end),
+            None => Cursor::table_end(table),
+        };
+        select_range_desc(&mut cursor, &schema, key_range.start, limit, &mut filter)
+    } else {
+        let mut cursor = match statement.key_range {
+            Some(key_range) => Cursor::table_seek(table, key_range.start),
+            None => Cursor::table_start(table),
         };
+        select_range(&mut cursor, &schema, key_range.end, limit, &mut filter)
+    }
+}
+
+/// cursorの位置からkeyがendを超えるまでleafを辿り、emitが受け取った行がlimitに達したら止める
+fn select_range<F>(cursor: &mut Cursor, schema: &Schema, end: u32, limit: usize, emit: &mut F) -> Result<(), ExecuteResult>
+    where
+        F: FnMut(&[u8]) -> Result<bool, ExecuteResult>,
+{
+    trace!("select_range: end: {}, limit: {}", end, limit);
+    let mut count = 0;
+    while !cursor.end_of_table && count < limit {
+        if let Some(row) = cursor.get_row() {
+            let key = schema.key(&row);
+            if key > end {
+                break;
+            }
+            if emit(&row)? {
+                count += 1;
+            }
+        }
+        cursor.advance();
+    }
+    Ok(())
+}
+
+/// cursorの位置からkeyがstartを下回るまで逆向きに辿る
+fn select_range_desc<F>(cursor: &mut Cursor, schema: &Schema, start: u32, limit: usize, emit: &mut F) -> Result<(), ExecuteResult>
+    where
+        F: FnMut(&[u8]) -> Result<bool, ExecuteResult>,
+{
+    trace!("select_range_desc: start: {}, limit: {}", start, limit);
+    let mut count = 0;
+    while !cursor.end_of_table && count < limit {
+        if let Some(row) = cursor.get_row() {
+            let key = schema.key(&row);
+            if key < start {
+                break;
+            }
+            if emit(&row)? {
+                count += 1;
+            }
+        }
+        cursor.retreat();
     }
+    Ok(())
 }
 
-// テストのため一時的にVec<Row>を返すようにしておく
-fn execute_statement(statement: &Statement, table: &mut Table, w: &mut impl io::Write) -> Result<Vec<u8>, ExecuteResult> {
+/// 文を実行する。selectなら結果の行を返し、それ以外は空の結果を返す
+fn execute_statement(statement: &Statement, table: &mut Table, w: &mut impl io::Write) -> Result<ResultSet, ExecuteResult> {
+    if let Some(name) = statement.table_name() {
+        table.select_table(name).map_err(|e| {
+            log::error!("{}", e);
+            ExecuteResult::InvalidStatement
+        })?;
+    }
     match statement.st_type {
         StatementType::Insert => {
-            // テストのためselectでRowsを返したいので一時的に合わせておく
-            match execute_insert(statement, table) {
-                Ok(_) => Ok(vec![]),
-                Err(e) => Err(e),
-            }
+            execute_insert(statement, table).map(|_| ResultSet::default())
         }
         StatementType::Select => {
-            execute_select(statement, table, w)
+            execute_select(statement, table)
+        }
+        StatementType::Delete => {
+            execute_delete(statement, table).map(|_| ResultSet::default())
+        }
+        StatementType::Update => {
+            execute_update(statement, table, w).map(|_| ResultSet::default())
+        }
+        StatementType::Begin
+        | StatementType::Commit
+        | StatementType::Rollback
+        | StatementType::Savepoint
+        | StatementType::Release => {
+            execute_transaction(statement, table).map(|_| ResultSet::default())
+        }
+        StatementType::Vacuum => {
+            execute_vacuum(table, w).map(|_| ResultSet::default())
+        }
+        StatementType::CreateTable => {
+            execute_create_table(statement, table).map(|_| ResultSet::default())
+        }
+    }
+}
+
+fn execute_create_table(statement: &Statement, table: &mut Table) -> Result<(), ExecuteResult> {
+    trace!("execute_create_table");
+    let schema = match &statement.schema {
+        Some(v) => v.clone(),
+        None => return Err(ExecuteResult::InvalidStatement),
+    };
+    if table.table_schema(&schema.name).is_some() {
+        return Err(ExecuteResult::TableExists);
+    }
+    table.create_table(schema).map(|_| ()).map_err(|e| {
+        log::error!("failed to create table: {}", e);
+        ExecuteResult::InvalidStatement
+    })
+}
+
+fn execute_vacuum(table: &mut Table, w: &mut impl io::Write) -> Result<(), ExecuteResult> {
+    trace!("execute_vacuum");
+    let reclaimed = table.vacuum().map_err(|e| {
+        log::error!("failed to vacuum: {}", e);
+        ExecuteResult::VacuumFailure
+    })?;
+    let _ = writeln!(w, "{} bytes reclaimed", reclaimed);
+    Ok(())
+}
+
+fn execute_transaction(statement: &Statement, table: &mut Table) -> Result<(), ExecuteResult> {
+    trace!("execute_transaction: {:?}, savepoint: {:?}", statement.st_type, statement.savepoint);
+    let commit_failure = |e: String| {
+        log::error!("failed to commit: {}", e);
+        ExecuteResult::CommitFailure
+    };
+    let rollback_failure = |e: String| {
+        log::error!("failed to rollback: {}", e);
+        ExecuteResult::RollbackFailure
+    };
+    match (statement.st_type, statement.savepoint.as_deref()) {
+        (StatementType::Begin, _) if table.in_transaction() => Err(ExecuteResult::TransactionActive),
+        (StatementType::Begin, _) => table.begin().map_err(commit_failure),
+        (StatementType::Savepoint, Some(name)) => table.savepoint(name).map_err(commit_failure),
+        (StatementType::Release, Some(name)) | (StatementType::Rollback, Some(name)) if !table.has_savepoint(name) => {
+            Err(ExecuteResult::NoSuchSavepoint)
         }
+        (StatementType::Release, Some(name)) => table.release(name).map(|_| ()).map_err(commit_failure),
+        (StatementType::Rollback, Some(name)) => table.rollback_to(name).map_err(rollback_failure),
+        (_, _) if !table.in_transaction() => Err(ExecuteResult::NoTransaction),
+        (StatementType::Commit, None) => table.end_transaction().map_err(commit_failure),
+        (StatementType::Rollback, None) => table.rollback().map_err(rollback_failure),
+        _ => Err(ExecuteResult::InvalidStatement),
     }
 }
 
@@ -515,6 +1272,7 @@ fn _main<P: AsRef<Path>>(filename: P, r: &mut impl io::BufRead, w: &mut impl io:
                     let args = MetaCommandArgs {
                         input: &input_buffer.buffer,
                         table: Some(&mut table),
+                        output: w,
                     };
                     match do_meta_command(args) {
                         Ok(_) => {}
@@ -530,21 +1288,38 @@ fn _main<P: AsRef<Path>>(filename: P, r: &mut impl io::BufRead, w: &mut impl io:
                         Err(MetaCommandResult::UnrecognizedCommand) => {
                             let _ = writeln!(w, "Unrecognized command '{}'", &input_buffer.buffer);
                         }
+                        Err(MetaCommandResult::InvalidArgument) => {
+                            let _ = writeln!(w, "Invalid argument '{}'", &input_buffer.buffer);
+                        }
                         Err(MetaCommandResult::TableNotGiven) => {
                             let _ = writeln!(w, "called meta command '{}' that requires table, but table not given", &input_buffer.buffer);
                         }
                     }
                     continue;
                 }
-                let statement = prepare_statement(&input_buffer);
+                let statement = prepare_statement(&input_buffer).and_then(|mut statement| {
+                    if let Some(name) = statement.table_name() {
+                        let schema = table.table_schema(name).ok_or(PrepareError::NoSuchTable)?;
+                        statement.bind(&schema)?;
+                    }
+                    Ok(statement)
+                });
                 match statement {
                     Ok(statement) => {
-                        match execute_statement(&statement, &mut table, w) {
-                            Ok(rows) => {
-                                if !rows.is_empty() {
-                                    for row in rows {
-                                        let _ = writeln!(w, "{:?}", row);
-                                    }
+                        match execute_statement(&statement, &mut table, w).and_then(|result| {
+                            // トランザクション中はCOMMITするまで書き出さない
+                            if table.in_transaction() {
+                                return Ok(result);
+                            }
+                            // ディスクに書き出すまでExecutedを返さない
+                            table.commit().map(|_| result).map_err(|e| {
+                                log::error!("failed to commit: {}", e);
+                                ExecuteResult::CommitFailure
+                            })
+                        }) {
+                            Ok(result) => {
+                                for row in &result.rows {
+                                    let _ = writeln!(w, "{:?}", result.display(row));
                                 }
                                 let _ = writeln!(w, "Executed");
                             }
@@ -564,6 +1339,32 @@ fn _main<P: AsRef<Path>>(filename: P, r: &mut impl io::BufRead, w: &mut impl io:
                                 let _ = writeln!(w, "duplicate key error");
                                 break;
                             }
+                            Err(ExecuteResult::CommitFailure) => {
+                                let _ = writeln!(w, "commit failed");
+                                break;
+                            }
+                            Err(ExecuteResult::RollbackFailure) => {
+                                let _ = writeln!(w, "rollback failed");
+                                break;
+                            }
+                            Err(ExecuteResult::TransactionActive) => {
+                                let _ = writeln!(w, "cannot start a transaction within a transaction");
+                            }
+                            Err(ExecuteResult::NoTransaction) => {
+                                let _ = writeln!(w, "no transaction is active");
+                            }
+                            Err(ExecuteResult::NoSuchSavepoint) => {
+                                let _ = writeln!(w, "no such savepoint");
+                            }
+                            Err(ExecuteResult::VacuumFailure) => {
+                                let _ = writeln!(w, "vacuum failed");
+                            }
+                            Err(ExecuteResult::TableExists) => {
+                                let _ = writeln!(w, "table already exists");
+                            }
+                            Err(ExecuteResult::EvaluationError(e)) => {
+                                let _ = writeln!(w, "Evaluation error at {}", e);
+                            }
                         };
                     }
                     Err(PrepareError::UnrecognizedStatement) => {
@@ -574,13 +1375,16 @@ fn _main<P: AsRef<Path>>(filename: P, r: &mut impl io::BufRead, w: &mut impl io:
                         );
                         continue;
                     }
-                    Err(PrepareError::SyntaxError) => {
-                        let _ = writeln!(w, "Syntax error at '{}'", &input_buffer.buffer);
+                    Err(PrepareError::SyntaxError(e)) => {
+                        let _ = writeln!(w, "Syntax error at line {}, column {}: {}", e.position.line, e.position.column, e.message);
                         continue;
                     }
                     Err(PrepareError::InvalidRecord) => {
                         let _ = writeln!(w, "Record invalid '{}'", &input_buffer.buffer);
                     }
+                    Err(PrepareError::NoSuchTable) => {
+                        let _ = writeln!(w, "No such table at '{}'", &input_buffer.buffer);
+                    }
                 }
             }
             Err(e) => {
@@ -623,79 +1427,27 @@ impl SliceExt for [u8] {
     }
 }
 
-#[derive(Debug)]
-enum RowConversionError {
-    TooLargeLength { col_name: String },
-    IoError(io::Error),
-}
-
-impl fmt::Display for RowConversionError {
-    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        match self {
-            RowConversionError::TooLargeLength { col_name } => {
-                write!(f, "failed to convert columns to row. Column({}) is too long", col_name)
-            }
-            RowConversionError::IoError(e) => {
-                write!(f, "failed to convert columns to row. io error: {}", e)
-            }
-        }
-    }
-}
-
-impl From<io::Error> for RowConversionError {
-    fn from(e: io::Error) -> Self {
-        RowConversionError::IoError(e)
-    }
-}
-
-impl Error for RowConversionError {}
-
+/// 既定のusers tableの行を表示する
 fn display_row(row: &[u8]) -> String {
-    let mut row_buf = row;
-    let id = row_buf.read_u32::<LittleEndian>().unwrap();
-    let mut username_buf = vec![0u8; USERNAME_SIZE];
-    let _ = row_buf.read(&mut username_buf);
-    let mut username_buf = username_buf.split_mut(|b| b == &b'\0');
-    let mut username_buf = username_buf.next().unwrap();
-    let username = std::str::from_utf8(&username_buf[..]).unwrap();
-
-    let mut email_buf = vec![0u8; EMAIL_SIZE];
-    let _ = row_buf.read(&mut email_buf);
-    let mut email_buf = email_buf.split_mut(|b| b == &b'\0');
-    let mut email_buf = email_buf.next().unwrap();
-    let email = std::str::from_utf8(&email_buf[..]).unwrap();
-    format!("Row<id:{}, username:{}, email:{}>", id, username, email)
+    Schema::users().display(row)
 }
 
 #[test]
 fn test_display_row() {
-    let mut row = vec![0u8; ROW_SIZE];
+    let mut row = Vec::new();
     let _ = cols_to_row(&mut row, 27, "hoge", "fuga");
     let row_str = display_row(&row);
     assert_eq!(row_str, "Row<id:27, username:hoge, email:fuga>".to_string());
 }
 
+/// 既定のusers tableの行を作る
 fn cols_to_row<S: AsRef<str>, T: AsRef<str>>(buf: &mut Vec<u8>, id: u32, username: S, email: T) -> Result<(), RowConversionError> {
-    if buf.len() < ROW_SIZE {
-        buf.extend(vec![0; ROW_SIZE-buf.len()])
-    }
-    let username: &str = username.as_ref();
-    if username.len() > 32 {
-        return Err(RowConversionError::TooLargeLength { col_name: "username".to_string() });
-    }
-    let email: &str = email.as_ref();
-    if email.len() > 255 {
-        return Err(RowConversionError::TooLargeLength { col_name: "email".to_string() });
-    }
-
-    (&mut buf[0..4]).write_u32::<LittleEndian>(id)?;
-    (&mut buf[4..]).write_all((format!("{:\0<32}", username)).as_ref())?;
-    (&mut buf[36..]).write_all((format!("{:\0<255}", email)).as_ref())?;
-    Ok(())
-}
-
-fn get_id_from_row(row: &[u8]) -> Result<u32, io::Error> {
-    (&row[..]).read_u32::<LittleEndian>()
+    let values = vec![
+        Value::Integer(id as i64),
+        Value::Text(username.as_ref().to_string()),
+        Value::Text(email.as_ref().to_string()),
+    ];
+    Schema::users().encode(values, buf)
 }
 
 fn default_row(buf: &mut Vec<u8>) -> Result<(), RowConversionError> {
@@ -706,16 +1458,25 @@ fn default_row(buf: &mut Vec<u8>) -> Result<(), RowConversionError> {
 mod test {
     use super::*;
     use crate::table::Pager;
-    use crate::tree::KV;
+    use crate::tree::{BTreeOverflowNode, KV};
+    use crate::lexer::Position;
 
     fn init() {
         let _ = env_logger::builder().is_test(true).try_init();
     }
 
+    /// usersのtableに対する文として読む。keyの範囲はbindで決まる
+    fn prepare_users(buffer: &str) -> Result<Statement, PrepareError> {
+        let mut statement = prepare_statement(&InputBuffer { buffer: buffer.to_string() })?;
+        statement.bind(&Schema::users())?;
+        Ok(statement)
+    }
+
     #[test]
     fn test_unrecognized_meta_command() {
         init();
-        let args = MetaCommandArgs { input: ".foo", table: None };
+        let mut w = vec![];
+        let args = MetaCommandArgs { input: ".foo", table: None, output: &mut w };
         let res = do_meta_command(args);
         let err = res.err().unwrap();
         assert_eq!(err, MetaCommandResult::UnrecognizedCommand);
@@ -731,11 +1492,43 @@ mod test {
         assert_eq!(stmt.st_type, StatementType::Insert);
     }
 
+    #[test]
+    fn test_prepare_statement_insert_values() {
+        init();
+        let input = InputBuffer {
+            buffer: r#"insert 1 'a "b"' "c" x'00Ff' TRUE -2.5 7"#.to_string(),
+        };
+        let stmt = prepare_statement(&input).unwrap();
+        assert_eq!(stmt.values, vec![
+            Value::Integer(1),
+            Value::Text(r#"a "b""#.to_string()),
+            Value::Text("c".to_string()),
+            Value::Blob(vec![0, 255]),
+            Value::Boolean(true),
+            Value::Real(-2.5),
+            Value::Integer(7),
+        ]);
+
+        let input = InputBuffer { buffer: "insert 1 foo".to_string() };
+        assert_eq!(
+            prepare_statement(&input).err(),
+            Some(PrepareError::SyntaxError(ParseError::new("expected a literal value, found 'foo'", Position { line: 1, column: 10 })))
+        );
+        let input = InputBuffer { buffer: "insert 1 x'0'".to_string() };
+        assert!(matches!(prepare_statement(&input), Err(PrepareError::SyntaxError(_))));
+        // 閉じていない文字列は末尾まで読まずにエラーにする
+        let input = InputBuffer { buffer: r#"insert 1 "foo" "bar"#.to_string() };
+        assert_eq!(
+            prepare_statement(&input).err(),
+            Some(PrepareError::SyntaxError(ParseError::new("unterminated string literal", Position { line: 1, column: 16 })))
+        );
+    }
+
     #[test]
     fn test_prepare_statement_large_insert() {
         init();
         let input = InputBuffer {
-            buffer: r#"INSERT 1 "foo" "bar"#.to_string(),
+            buffer: r#"INSERT 1 "foo" "bar""#.to_string(),
         };
         let stmt = prepare_statement(&input).unwrap();
         assert_eq!(stmt.st_type, StatementType::Insert);
@@ -761,6 +1554,62 @@ mod test {
         assert_eq!(stmt.st_type, StatementType::Select);
     }
 
+    #[test]
+    fn test_prepare_statement_select_where() {
+        init();
+        let stmt = prepare_users("select where id=3").unwrap();
+        assert_eq!(stmt.st_type, StatementType::Select);
+        assert_eq!(stmt.key_range, Some(KeyRange { start: 3, end: 3 }));
+
+        let stmt = prepare_users("SELECT WHERE id BETWEEN 1 AND 5").unwrap();
+        assert_eq!(stmt.key_range, Some(KeyRange { start: 1, end: 5 }));
+        assert!(prepare_statement(&InputBuffer { buffer: "select where id = 3".to_string() }).unwrap().key_range.is_none());
+
+        // keyの条件からkeyの範囲を作り、それ以外の条件は行ごとに調べる
+        let key_range = |buffer: &str| prepare_users(buffer).unwrap().key_range;
+        assert_eq!(key_range("select where id > 10 and 20 >= id"), Some(KeyRange { start: 11, end: 20 }));
+        assert_eq!(key_range("select where id < 5 or id in (7, 9)"), Some(KeyRange { start: 0, end: 9 }));
+        assert_eq!(key_range("select where id >= 3 and username = 'a'"), Some(KeyRange { start: 3, end: u32::MAX }));
+        assert_eq!(key_range("select where id < 0"), Some(KeyRange::empty()));
+        assert_eq!(key_range("select where id = 1 and id = 2"), Some(KeyRange::empty()));
+        assert_eq!(key_range("select where id = 3 or username = 'a'"), Some(KeyRange::all()));
+        assert_eq!(key_range("select where not id = 3"), Some(KeyRange::all()));
+        assert_eq!(key_range("select where id <> 3"), Some(KeyRange::all()));
+        assert_eq!(key_range("select where id = 4294967296"), Some(KeyRange::empty()));
+
+        assert_eq!(
+            prepare_users("select where id = 1 and nickname is null").err(),
+            Some(PrepareError::SyntaxError(ParseError::new("no such column in table users: nickname", Position { line: 1, column: 25 })))
+        );
+    }
+
+    #[test]
+    fn test_prepare_statement_select_order_by() {
+        init();
+        let stmt = prepare_users("select where id between 1 and 5 order by id desc limit 2").unwrap();
+        assert_eq!(stmt.key_range, Some(KeyRange { start: 1, end: 5 }));
+        assert!(stmt.descending);
+        assert_eq!(stmt.limit, Some(2));
+
+        let input = InputBuffer {
+            buffer: "SELECT ORDER BY id ASC".to_string(),
+        };
+        let stmt = prepare_statement(&input).unwrap();
+        assert!(!stmt.descending);
+        assert_eq!(stmt.limit, None);
+
+        let input = InputBuffer {
+            buffer: "select limit 2 order by id desc".to_string(),
+        };
+        assert!(matches!(prepare_statement(&input), Err(PrepareError::SyntaxError(e)) if e.position.column == 16));
+        // order byのカラムはbindでtableのkeyと比べる
+        assert!(prepare_statement(&InputBuffer { buffer: "select order by username".to_string() }).is_ok());
+        assert_eq!(
+            prepare_users("select order by username").err(),
+            Some(PrepareError::SyntaxError(ParseError::new("only id can be used in order by", Position { line: 1, column: 17 })))
+        );
+    }
+
     #[test]
     fn test_prepare_statement_unknown() {
         init();
@@ -781,93 +1630,1032 @@ mod test {
         let email = "totem3@totem3.com";
         let mut buffer = vec![];
         cols_to_row(&mut buffer, id, username, email).unwrap();
-        let mut expected = vec![];
-        let _ = expected.write_u32::<byteorder::LittleEndian>(id);
-        expected.extend_from_slice(&[
-            116, 111, 116, 101, 109, 51, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-            0, 0, 0, 0, 0, 0, 0,
-        ]);
-        expected.extend_from_slice(&[
-            116, 111, 116, 101, 109, 51, 64, 116, 111, 116, 101, 109, 51, 46, 99, 111, 109, 0, 0,
-            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-            0, 0, 0, 0,
-        ]);
+        // カラム数、INTEGERのタグとzigzagのid、TEXTのタグと長さと中身
+        let mut expected = vec![3, 1, 2, 3, 6];
+        expected.extend_from_slice(username.as_bytes());
+        expected.extend_from_slice(&[3, 17]);
+        expected.extend_from_slice(email.as_bytes());
         assert_eq!(expected, buffer)
     }
 
-    #[test]
-    fn test_page_num() {
-        let table = Table::new("tmp/test.db").unwrap();
-        assert_eq!(table.page_num(12), 0);
-        assert_eq!(table.page_num(13), 0);
-        assert_eq!(table.page_num(14), 1);
-        assert_eq!(table.page_num(15), 1);
+    // rootのleafを、これ以上行が入らなくなるまで埋める。埋めた行数を返す
+    fn fill_root_leaf(table: &mut Table) -> u32 {
+        let root_page_num = table.root_page_num;
+        let mut count = 0;
+        if let Some(BTreeNode::Leaf(page)) = table.pager.get_page_mut(root_page_num) {
+            page.is_root = 1;
+            page.key_values.clear();
+            loop {
+                let mut buf = vec![];
+                let _ = cols_to_row(&mut buf, count, "", "");
+                if !page.has_room(buf.len()) {
+                    break;
+                }
+                page.key_values.push(KV { key: count, value: buf, overflow: None });
+                count += 1;
+            }
+            page.num_cells = count;
+        }
+        count
     }
 
-    fn test_bytes_offset() {
-        let table = Table::new("tmp/test.db").unwrap();
-        assert_eq!(table.bytes_offset(12), 291 * 12);
-        assert_eq!(table.bytes_offset(13), 291 * 13);
-        assert_eq!(table.bytes_offset(14), 0);
-        assert_eq!(table.bytes_offset(15), 291);
+    #[test]
+    fn test_execute_statement_insert_into_full_table() {
+        let mut table = Table::new("tmp/test.db").unwrap();
+        let count = fill_root_leaf(&mut table);
+        let mut stmt = Statement::new(StatementType::Insert);
+        let mut row = vec![];
+        let _ = cols_to_row(&mut row, count, "", "");
+        stmt.row_to_insert = Some(row);
+        let mut buf = vec![];
+        let result = execute_statement(&stmt, &mut table, &mut buf);
+        assert!(result.is_ok());
+        assert!(matches!(table.pager.get_page(table.root_page_num), Some(BTreeNode::Internal(_))));
     }
 
     #[test]
-    fn test_execute_statement_insert_into_full_table() {
-        let mut pager = Pager::new("tmp/test.db").unwrap();
-        if let Some(BTreeNode::Leaf(page)) = pager.get_page_mut(0) {
-            page.is_root = 1;
-            page.num_cells = BTreeLeafNode::NODE_MAX_CELLS as u32;
-            let mut buf0 = vec![];
-            let mut buf1 = vec![];
-            let mut buf2 = vec![];
-            let mut buf3 = vec![];
-            let mut buf4 = vec![];
-            let mut buf5 = vec![];
-            let mut buf6 = vec![];
-            let _ = default_row(&mut buf0);
-            let _ = default_row(&mut buf1);
-            let _ = default_row(&mut buf2);
-            let _ = default_row(&mut buf3);
-            let _ = default_row(&mut buf4);
-            let _ = default_row(&mut buf5);
-            let _ = default_row(&mut buf6);
-            page.key_values = vec![
-                KV { key: 0, value: buf0 },
-                KV { key: 1, value: buf1 },
-                KV { key: 2, value: buf2 },
-                KV { key: 3, value: buf3 },
-                KV { key: 4, value: buf4 },
-                KV { key: 5, value: buf5 },
-                KV { key: 6, value: buf6 },
-            ];
-        }
-        let mut table = Table {
-            pager,
-            root_page_num: 0,
-        };
+    fn test_execute_statement_insert_duplicate_key_into_full_table() {
+        let mut table = Table::new("tmp/test.db").unwrap();
+        fill_root_leaf(&mut table);
         let mut stmt = Statement::new(StatementType::Insert);
         let mut row = vec![];
         let _ = default_row(&mut row);
         stmt.row_to_insert = Some(row);
         let mut buf = vec![];
         let result = execute_statement(&stmt, &mut table, &mut buf);
-        assert!(result.is_ok());
+        assert_eq!(result.err(), Some(ExecuteResult::DuplicateKey));
+    }
+
+    // 左から順にleafのpage_numと使っているバイト数を集める
+    fn leaf_sizes(table: &mut Table) -> Vec<(usize, usize)> {
+        let mut page_nums = vec![];
+        let mut cursor = Cursor::table_start(table);
+        while !cursor.end_of_table {
+            if page_nums.last() != Some(&cursor.page_num) {
+                page_nums.push(cursor.page_num);
+            }
+            cursor.advance();
+        }
+        page_nums.into_iter().map(|page_num| match table.pager.get_page(page_num) {
+            Some(BTreeNode::Leaf(node)) => (page_num, node.used_size()),
+            _ => panic!("page {} is not a leaf", page_num),
+        }).collect()
+    }
+
+    // 全てのノードの親ポインタと、internalノードのkeyの数が正しいことを確かめ、木の深さを返す
+    fn check_tree(table: &mut Table, page_num: usize, parent: usize) -> usize {
+        let max_cells = table.pager.internal_max_cells();
+        let node = table.pager.get_page(page_num).unwrap().clone();
+        if node.is_root() == 0 {
+            assert_eq!(node.get_parent() as usize, parent);
+        }
+        match node {
+            BTreeNode::Leaf(_) => 1,
+            BTreeNode::Internal(node) => {
+                assert!(node.num_keys as usize <= max_cells);
+                if node.is_root == 0 {
+                    assert!(node.num_keys as usize >= max_cells / 2);
+                }
+                let depths: Vec<usize> = node.children().into_iter()
+                    .map(|child| check_tree(table, child as usize, page_num))
+                    .collect();
+                assert!(depths.iter().all(|d| *d == depths[0]));
+                depths[0] + 1
+            }
+            BTreeNode::Free(_) => panic!("free page {} is in the tree", page_num),
+            BTreeNode::Overflow(_) => panic!("overflow page {} is in the tree", page_num),
+        }
+    }
+
+    fn insert_rows(table: &mut Table, ids: impl Iterator<Item = u32>) {
+        for id in ids {
+            let mut row = vec![];
+            cols_to_row(&mut row, id, format!("user{}", id), format!("user{}@example.com", id)).unwrap();
+            let mut stmt = Statement::new(StatementType::Insert);
+            stmt.row_to_insert = Some(row);
+            let mut buf = vec![];
+            assert!(execute_statement(&stmt, table, &mut buf).is_ok());
+        }
+    }
+
+    // internalノードのkeyの上限を小さくしたtableを作ってidsの行を入れる。少ない行数で多段の木になる
+    fn deep_tree_table(filename: &str, ids: impl Iterator<Item = u32>) -> Table {
+        let _ = std::fs::remove_file(filename);
+        let mut table = Table::new(filename).unwrap();
+        table.pager.set_internal_max_cells(3);
+        insert_rows(&mut table, ids);
+        table
+    }
+
+    fn all_keys(table: &mut Table) -> Vec<u32> {
+        let mut keys = vec![];
+        table.collect_keys(table.root_page_num, u32::MIN, u32::MAX, &mut keys);
+        keys
     }
 
     #[test]
-    fn test_execute_statement_insert_without_row() {
-        let mut table = Table {
-            pager: Pager::new("tmp/test.db").unwrap(),
-            root_page_num: 0,
+    fn test_execute_insert_splits_internal_nodes() {
+        init();
+        let filename = "tmp/test_execute_insert_splits_internal_nodes.db";
+        let num_rows = 1000;
+        // 右端以外のleafも分割されるように順番を散らす
+        let mut table = deep_tree_table(filename, (0..num_rows).map(|i| (i * 37) % num_rows));
+        let root_page_num = table.root_page_num;
+        assert!(check_tree(&mut table, root_page_num, root_page_num) > 2);
+        for id in 0..num_rows {
+            let mut cursor = Cursor::find_insert_position(&mut table, root_page_num, id);
+            let cell_num = cursor.cell_num;
+            match cursor.get_page() {
+                Some(BTreeNode::Leaf(leaf)) => assert_eq!(leaf.key_values[cell_num].key, id),
+                _ => panic!("key {} not found", id),
+            }
+        }
+        table.close().unwrap();
+        let table = Table::new(filename).unwrap();
+        assert_eq!(table.root_page_num, root_page_num);
+    }
+
+    #[test]
+    fn test_prepare_statement_delete() {
+        init();
+        let stmt = prepare_users("delete where id = 3").unwrap();
+        assert_eq!(stmt.st_type, StatementType::Delete);
+        assert_eq!(stmt.key_range, Some(KeyRange { start: 3, end: 3 }));
+
+        let stmt = prepare_users("DELETE WHERE id BETWEEN 3 AND 10").unwrap();
+        assert_eq!(stmt.key_range, Some(KeyRange { start: 3, end: 10 }));
+
+        let stmt = prepare_users("delete").unwrap();
+        assert_eq!(stmt.key_range, Some(KeyRange::all()));
+        assert!(stmt.where_clause.is_none());
+
+        assert!(matches!(prepare_users("delete where name = 3"), Err(PrepareError::SyntaxError(e)) if e.position.column == 14));
+        assert_eq!(prepare_users("delete where id = -1").unwrap().key_range, Some(KeyRange::empty()));
+    }
+
+    #[test]
+    fn test_prepare_statement_update() {
+        init();
+        let input = InputBuffer {
+            buffer: r#"UPDATE SET username = "Foo Bar", email="foo, bar@example.com" WHERE id = 3"#.to_string(),
+        };
+        let stmt = prepare_statement(&input).unwrap();
+        assert_eq!(stmt.st_type, StatementType::Update);
+        let assignments: Vec<(&str, Option<&Value>)> = stmt.assignments.iter().map(|a| (a.column.as_str(), a.value.literal())).collect();
+        assert_eq!(assignments, vec![
+            ("username", Some(&Value::Text("Foo Bar".to_string()))),
+            ("email", Some(&Value::Text("foo, bar@example.com".to_string()))),
+        ]);
+        assert_eq!(stmt.key_range, Some(KeyRange::all()));
+        assert_eq!(prepare_users("update set username = 'a' where id = 3").unwrap().key_range, Some(KeyRange { start: 3, end: 3 }));
+
+        // カラムはschemaに合わせるときに調べる
+        let bind = |buffer: String| prepare_statement(&InputBuffer { buffer }).and_then(|mut stmt| stmt.bind(&Schema::users()));
+        assert_eq!(
+            bind(r#"update set id = "4" where id = 3"#.to_string()),
+            Err(PrepareError::SyntaxError(ParseError::new("primary key id cannot be updated", Position { line: 1, column: 12 })))
+        );
+        assert_eq!(
+            bind(r#"update set username = "a",  name = "foo" where id = 3"#.to_string()),
+            Err(PrepareError::SyntaxError(ParseError::new("no such column in table users: name", Position { line: 1, column: 29 })))
+        );
+        // 右辺には行のカラムを使った式を書ける
+        assert!(bind("update set username = upper(email) where id = 3".to_string()).is_ok());
+        assert_eq!(
+            bind("update set username = upper(nickname)".to_string()),
+            Err(PrepareError::SyntaxError(ParseError::new("no such column in table users: nickname", Position { line: 1, column: 29 })))
+        );
+        assert_eq!(
+            bind("update set username = count(*)".to_string()),
+            Err(PrepareError::SyntaxError(ParseError::new("aggregate functions are not allowed in update", Position { line: 1, column: 23 })))
+        );
+        assert!(bind(format!(r#"update set username = "{}" where id = 3"#, "a".repeat(33))).is_ok());
+        // 長さを指定したカラムには、それより長い値を入れられない
+        let short = Schema::parse("create table users (id integer primary key, username text(32), email text(255))").unwrap();
+        assert_eq!(
+            prepare_statement(&InputBuffer { buffer: format!(r#"update set username = "{}" where id = 3"#, "a".repeat(33)) })
+                .and_then(|mut stmt| stmt.bind(&short)),
+            Err(PrepareError::InvalidRecord)
+        );
+        assert_eq!(bind(r#"update set username = 3 where id = 3"#.to_string()), Err(PrepareError::InvalidRecord));
+    }
+
+    #[test]
+    fn test_prepare_statement_transaction() {
+        init();
+        for (buffer, st_type) in [
+            ("BEGIN", StatementType::Begin),
+            ("begin transaction", StatementType::Begin),
+            ("commit", StatementType::Commit),
+            ("end", StatementType::Commit),
+            ("rollback transaction", StatementType::Rollback),
+        ] {
+            let input = InputBuffer { buffer: buffer.to_string() };
+            assert_eq!(prepare_statement(&input).unwrap().st_type, st_type);
+        }
+        for (buffer, st_type) in [
+            ("SAVEPOINT step1", StatementType::Savepoint),
+            ("release step1", StatementType::Release),
+            ("release savepoint step1", StatementType::Release),
+            ("rollback to step1", StatementType::Rollback),
+            ("rollback transaction to savepoint step1", StatementType::Rollback),
+        ] {
+            let input = InputBuffer { buffer: buffer.to_string() };
+            let stmt = prepare_statement(&input).unwrap();
+            assert_eq!(stmt.st_type, st_type);
+            assert_eq!(stmt.savepoint.as_deref(), Some("step1"));
+        }
+        let input = InputBuffer { buffer: "savepoint".to_string() };
+        assert!(matches!(prepare_statement(&input), Err(PrepareError::SyntaxError(_))));
+        let input = InputBuffer { buffer: "begin immediately".to_string() };
+        assert!(matches!(prepare_statement(&input), Err(PrepareError::SyntaxError(_))));
+    }
+
+    fn select_keys(table: &mut Table, key_range: Option<KeyRange>) -> Vec<u32> {
+        let mut stmt = Statement::new(StatementType::Select);
+        stmt.key_range = key_range;
+        select_keys_by(table, &stmt)
+    }
+
+    fn select_keys_by(table: &mut Table, stmt: &Statement) -> Vec<u32> {
+        let result = execute_statement(stmt, table, &mut vec![]).unwrap();
+        result.rows.iter().map(|row| match row[0] {
+            Value::Integer(id) => id as u32,
+            ref v => panic!("key must be integer: {:?}", v),
+        }).collect()
+    }
+
+    #[test]
+    fn test_execute_select_by_key() {
+        init();
+        let filename = "tmp/test_execute_select_by_key.db";
+        let _ = std::fs::remove_file(filename);
+        let mut table = Table::new(filename).unwrap();
+        // 2の倍数だけ入れて、存在しないkeyから始まる範囲も試す
+        insert_rows(&mut table, (0..300).map(|i| i * 2));
+        assert!(matches!(table.pager.get_page(table.root_page_num), Some(BTreeNode::Internal(_))));
+
+        assert_eq!(select_keys(&mut table, Some(KeyRange { start: 10, end: 10 })), vec![10]);
+        assert_eq!(select_keys(&mut table, Some(KeyRange { start: 11, end: 11 })), Vec::<u32>::new());
+        assert_eq!(select_keys(&mut table, Some(KeyRange { start: 9, end: 311 })), (5..156).map(|i| i * 2).collect::<Vec<u32>>());
+        assert_eq!(select_keys(&mut table, Some(KeyRange { start: 500, end: 10000 })), (250..300).map(|i| i * 2).collect::<Vec<u32>>());
+        assert_eq!(select_keys(&mut table, Some(KeyRange { start: 599, end: 10000 })), Vec::<u32>::new());
+        assert_eq!(select_keys(&mut table, None), (0..300).map(|i| i * 2).collect::<Vec<u32>>());
+    }
+
+    #[test]
+    fn test_scan_deep_tree_through_next_leaf() {
+        init();
+        let filename = "tmp/test_scan_deep_tree_through_next_leaf.db";
+        let num_rows = 1000;
+        let mut table = deep_tree_table(filename, (0..num_rows).map(|i| (i * 37) % num_rows));
+        let root_page_num = table.root_page_num;
+        assert!(check_tree(&mut table, root_page_num, root_page_num) > 2);
+        assert_eq!(select_keys(&mut table, None), (0..num_rows).collect::<Vec<u32>>());
+        assert_eq!(select_keys(&mut table, Some(KeyRange { start: 42, end: 850 })), (42..851).collect::<Vec<u32>>());
+
+        // マージ後もleafの繋がりが保たれていること
+        let mut stmt = Statement::new(StatementType::Delete);
+        stmt.key_range = Some(KeyRange { start: 100, end: 699 });
+        let mut buf = vec![];
+        assert!(execute_statement(&stmt, &mut table, &mut buf).is_ok());
+        let expected: Vec<u32> = (0..100).chain(700..num_rows).collect();
+        assert_eq!(select_keys(&mut table, None), expected);
+
+        table.close().unwrap();
+        let mut table = Table::new(filename).unwrap();
+        assert_eq!(select_keys(&mut table, None), expected);
+        assert_eq!(select_keys(&mut table, Some(KeyRange { start: 90, end: 710 })), (90..100).chain(700..711).collect::<Vec<u32>>());
+    }
+
+    #[test]
+    fn test_execute_select_projection() {
+        init();
+        let filename = "tmp/test_execute_select_projection.db";
+        let _ = std::fs::remove_file(filename);
+        let mut table = Table::new(filename).unwrap();
+        insert_rows(&mut table, 0..300);
+
+        // 結果は表示用の文字列ではなく型の付いた値で返る
+        let stmt = prepare_users("select id * 2 as double, upper(username), * where id between 10 and 200 and id % 100 = 0").unwrap();
+        let result = execute_statement(&stmt, &mut table, &mut vec![]).unwrap();
+        assert_eq!(result.columns, vec!["double", "upper(username)", "id", "username", "email"]);
+        let text = |s: &str| Value::Text(s.to_string());
+        assert_eq!(result.rows, vec![
+            vec![Value::Integer(200), text("USER100"), Value::Integer(100), text("user100"), text("user100@example.com")],
+            vec![Value::Integer(400), text("USER200"), Value::Integer(200), text("user200"), text("user200@example.com")],
+        ]);
+        assert_eq!(result.display(&result.rows[0]), "Row<double:200, upper(username):USER100, id:100, username:user100, email:user100@example.com>");
+
+        let stmt = prepare_users("select length(email) - length(username), coalesce(null, id) order by id desc limit 2").unwrap();
+        let result = execute_statement(&stmt, &mut table, &mut vec![]).unwrap();
+        assert_eq!(result.columns, vec!["length(email) - length(username)", "coalesce(NULL, id)"]);
+        assert_eq!(result.rows, vec![
+            vec![Value::Integer(12), Value::Integer(299)],
+            vec![Value::Integer(12), Value::Integer(298)],
+        ]);
+
+        assert!(matches!(prepare_users("select nickname from users"), Err(PrepareError::SyntaxError(e)) if e.position.column == 8));
+        assert!(matches!(prepare_users("select lenght(email)"), Err(PrepareError::SyntaxError(e)) if e.message == "no such function: lenght"));
+        let stmt = prepare_users("select abs(username) where id = 1").unwrap();
+        assert!(matches!(execute_statement(&stmt, &mut table, &mut vec![]), Err(ExecuteResult::EvaluationError(_))));
+    }
+
+    #[test]
+    fn test_execute_select_aggregate() {
+        init();
+        let filename = "tmp/test_execute_select_aggregate.db";
+        let _ = std::fs::remove_file(filename);
+        let mut table = Table::new(filename).unwrap();
+        let select = |table: &mut Table, buffer: &str| {
+            let stmt = prepare_users(buffer).unwrap();
+            execute_statement(&stmt, table, &mut vec![]).unwrap()
+        };
+        // keyのminとmaxだけなら、行を辿らずに左端と右端のleafから求める
+        let extremes = |table: &mut Table, buffer: &str| {
+            let stmt = prepare_users(buffer).unwrap();
+            key_extremes(&stmt, table, &stmt.aggregate_exprs()).map(|group| group.results.into_iter().map(|(_, v)| v).collect::<Vec<Value>>())
+        };
+        assert_eq!(select(&mut table, "select count(*), sum(id), max(username)").rows, vec![vec![Value::Integer(0), Value::Null, Value::Null]]);
+        assert_eq!(extremes(&mut table, "select min(id), max(id)"), Some(vec![Value::Null, Value::Null]));
+
+        insert_rows(&mut table, 0..300);
+        let result = select(&mut table, "select count(*), min(id), max(id), sum(id), avg(id)");
+        assert_eq!(result.columns, vec!["count(*)", "min(id)", "max(id)", "sum(id)", "avg(id)"]);
+        assert_eq!(result.rows, vec![vec![Value::Integer(300), Value::Integer(0), Value::Integer(299), Value::Integer(44850), Value::Real(149.5)]]);
+
+        let result = select(&mut table, "select id % 3 as r, count(*) n, max(username) where id < 10 group by id % 3 having count(*) > 3");
+        assert_eq!(result.columns, vec!["r", "n", "max(username)"]);
+        assert_eq!(result.rows, vec![vec![Value::Integer(0), Value::Integer(4), Value::Text("user9".to_string())]]);
+        let result = select(&mut table, "select id / 100, count(*) group by id / 100 order by id desc limit 2");
+        assert_eq!(result.rows, vec![vec![Value::Integer(2), Value::Integer(100)], vec![Value::Integer(1), Value::Integer(100)]]);
+
+        assert_eq!(extremes(&mut table, "select max(id) - min(id) as span"), Some(vec![Value::Integer(299), Value::Integer(0)]));
+        assert_eq!(select(&mut table, "select max(id) - min(id) as span").rows, vec![vec![Value::Integer(299)]]);
+        assert_eq!(extremes(&mut table, "select min(username)"), None);
+        assert_eq!(extremes(&mut table, "select min(id), id"), None);
+        assert_eq!(extremes(&mut table, "select max(id) where id < 100"), None);
+        assert_eq!(select(&mut table, "select max(id) where id < 100").rows, vec![vec![Value::Integer(99)]]);
+
+        let error = |buffer: &str| match prepare_users(buffer) {
+            Err(PrepareError::SyntaxError(e)) => e,
+            other => panic!("unexpected result: {:?}", other),
+        };
+        assert_eq!(error("select id where count(*) > 1"), ParseError::new("aggregate functions are not allowed in where", Position { line: 1, column: 17 }));
+        assert_eq!(error("select count(*) group by max(id)").message, "aggregate functions are not allowed in group by");
+        assert_eq!(error("select count(*) having sum(nickname) > 1").message, "no such column in table users: nickname");
+    }
+
+    #[test]
+    fn test_execute_select_order_by_desc() {
+        init();
+        let mut table = deep_tree_table("tmp/test_execute_select_order_by_desc.db", std::iter::empty());
+        let mut stmt = Statement::new(StatementType::Select);
+        stmt.descending = true;
+        assert_eq!(select_keys_by(&mut table, &stmt), Vec::<u32>::new());
+
+        let num_rows = 1000;
+        insert_rows(&mut table, (0..num_rows).map(|i| ((i * 37) % num_rows) * 2));
+        let root_page_num = table.root_page_num;
+        assert!(check_tree(&mut table, root_page_num, root_page_num) > 2);
+        assert_eq!(select_keys_by(&mut table, &stmt), (0..num_rows).rev().map(|i| i * 2).collect::<Vec<u32>>());
+
+        stmt.key_range = Some(KeyRange { start: 101, end: 301 });
+        assert_eq!(select_keys_by(&mut table, &stmt), (51..151).rev().map(|i| i * 2).collect::<Vec<u32>>());
+        stmt.key_range = Some(KeyRange { start: 100, end: 300 });
+        stmt.limit = Some(3);
+        assert_eq!(select_keys_by(&mut table, &stmt), vec![300, 298, 296]);
+        stmt.key_range = None;
+        assert_eq!(select_keys_by(&mut table, &stmt), vec![1998, 1996, 1994]);
+
+        stmt.descending = false;
+        assert_eq!(select_keys_by(&mut table, &stmt), vec![0, 2, 4]);
+        stmt.limit = Some(0);
+        assert_eq!(select_keys_by(&mut table, &stmt), Vec::<u32>::new());
+    }
+
+    #[test]
+    fn test_small_cache_evicts_and_writes_back_pages() {
+        init();
+        let filename = "tmp/test_small_cache_evicts_and_writes_back_pages.db";
+        let _ = std::fs::remove_file(filename);
+        let mut table = Table::new(filename).unwrap();
+        table.pager.set_cache_size(CacheSize::Pages(8)).unwrap();
+        let num_rows = 8000;
+        insert_rows(&mut table, (0..num_rows).map(|i| (i * 37) % num_rows));
+        assert!(table.pager.cache_len() <= 8);
+        // 以前の上限だった100ページを超えていること
+        assert!(table.pager.num_pages() > 100);
+        assert_eq!(select_keys(&mut table, None), (0..num_rows).collect::<Vec<u32>>());
+        table.close().unwrap();
+
+        let mut table = Table::new(filename).unwrap();
+        table.pager.set_cache_size(CacheSize::Bytes(PAGE_SIZE * 4)).unwrap();
+        assert_eq!(select_keys(&mut table, Some(KeyRange { start: 700, end: 1200 })), (700..1201).collect::<Vec<u32>>());
+        assert!(table.pager.cache_len() <= 4);
+    }
+
+    #[test]
+    fn test_update_grows_rows_beyond_leaf() {
+        init();
+        let filename = "tmp/test_update_grows_rows_beyond_leaf.db";
+        let _ = std::fs::remove_file(filename);
+        let mut table = Table::new(filename).unwrap();
+        let schema = Schema::parse("create table notes (id integer, body text(900))").unwrap();
+        let root_page_num = table.create_table(schema.clone()).unwrap();
+        let row = |id: u32, body: &str| {
+            let mut row = vec![];
+            schema.encode(vec![Value::Integer(id as i64), Value::Text(body.to_string())], &mut row).unwrap();
+            row
+        };
+        for id in 0..30 {
+            assert!(table.insert_row(root_page_num, id, row(id, "short")));
+        }
+        assert!(matches!(table.pager.get_page(root_page_num), Some(BTreeNode::Leaf(_))));
+
+        // 長くなった行はleafに収まらないので、分割しながら入れ直される
+        let long = "x".repeat(900);
+        for id in 0..30 {
+            assert!(table.replace_row(root_page_num, id, row(id, &long)));
+        }
+        assert!(!table.replace_row(root_page_num, 30, row(30, &long)));
+        assert!(check_tree(&mut table, root_page_num, root_page_num) > 1);
+        let mut bodies = vec![];
+        table.for_each_row(root_page_num, |key, row| {
+            bodies.push((key, schema.decode(row)[1].clone()));
+            Ok(())
+        }).unwrap();
+        assert_eq!(bodies, (0..30).map(|id| (id, Value::Text(long.clone()))).collect::<Vec<_>>());
+
+        // 短くする分にはその場で書き換える
+        let num_pages = table.pager.num_pages();
+        assert!(table.replace_row(root_page_num, 3, row(3, "short")));
+        assert_eq!(table.pager.num_pages(), num_pages);
+        table.close().unwrap();
+    }
+
+    #[test]
+    fn test_flush_writes_only_dirty_pages() {
+        init();
+        let filename = "tmp/test_flush_writes_only_dirty_pages.db";
+        let _ = std::fs::remove_file(filename);
+        let mut table = Table::new(filename).unwrap();
+        insert_rows(&mut table, 0..300);
+        table.close().unwrap();
+        assert_eq!(table.pager.dirty_count(), 0);
+        let before = std::fs::read(filename).unwrap();
+
+        let mut table = Table::new(filename).unwrap();
+        assert_eq!(table.pager.dirty_count(), 0);
+        let mut stmt = Statement::new(StatementType::Update);
+        let position = Position { line: 1, column: 1 };
+        stmt.assignments = vec![ast::Assignment {
+            column: "username".to_string(),
+            value: ast::Expr::Literal { value: Value::Text("updated".to_string()), position },
+            position,
+        }];
+        stmt.key_range = Some(KeyRange { start: 150, end: 150 });
+        let mut buf = vec![];
+        assert!(execute_statement(&stmt, &mut table, &mut buf).is_ok());
+        assert_eq!(select_keys(&mut table, None), (0..300).collect::<Vec<u32>>());
+        assert_eq!(table.pager.dirty_count(), 1);
+        table.close().unwrap();
+
+        let after = std::fs::read(filename).unwrap();
+        assert_eq!(before.len(), after.len());
+        let changed_pages: Vec<usize> = (0..before.len() / PAGE_SIZE)
+            .filter(|i| before[i * PAGE_SIZE..(i + 1) * PAGE_SIZE] != after[i * PAGE_SIZE..(i + 1) * PAGE_SIZE])
+            .collect();
+        assert_eq!(changed_pages.len(), 1);
+
+        // 読み込んでいないページがあってもflushできること
+        let mut pager = Pager::new(filename).unwrap();
+        let page_num = changed_pages[0];
+        assert!(pager.get_page_mut(page_num).is_some());
+        assert!(pager.is_dirty(page_num));
+        assert!(pager.flush().is_ok());
+        assert_eq!(std::fs::read(filename).unwrap(), after);
+    }
+
+    #[test]
+    fn test_commit_writes_changes_to_disk() {
+        init();
+        for level in [Synchronous::Off, Synchronous::Normal, Synchronous::Full] {
+            let filename = format!("tmp/test_commit_writes_changes_to_disk_{}.db", level);
+            let _ = std::fs::remove_file(&filename);
+            let mut table = Table::new(&filename).unwrap();
+            table.pager.set_synchronous(level);
+            insert_rows(&mut table, 0..100);
+            table.commit().unwrap();
+            assert_eq!(table.pager.dirty_count(), 0);
+
+            // closeしていなくてもcommitした内容は別の接続から見える
+            let mut other = Table::new(&filename).unwrap();
+            assert_eq!(select_keys(&mut other, None), (0..100).collect::<Vec<u32>>());
+        }
+    }
+
+    #[test]
+    fn test_rollback_uncommitted_changes_after_crash() {
+        init();
+        let filename = "tmp/test_rollback_uncommitted_changes_after_crash.db";
+        let journal_path = crate::journal::Journal::path_for(std::path::Path::new(filename));
+        let _ = std::fs::remove_file(filename);
+        let mut table = Table::new(filename).unwrap();
+        insert_rows(&mut table, 0..100);
+        table.commit().unwrap();
+        assert!(!journal_path.exists());
+        let committed = std::fs::read(filename).unwrap();
+
+        // 分割や併合で複数のページを書き換えたところで、commitせずに落ちる
+        let mut stmt = Statement::new(StatementType::Delete);
+        stmt.key_range = Some(KeyRange { start: 20, end: 79 });
+        let mut buf = vec![];
+        assert!(execute_statement(&stmt, &mut table, &mut buf).is_ok());
+        insert_rows(&mut table, 100..200);
+        table.pager.flush().unwrap();
+        assert!(journal_path.exists());
+        assert_ne!(std::fs::read(filename).unwrap(), committed);
+        drop(table);
+
+        let mut table = Table::new(filename).unwrap();
+        assert!(!journal_path.exists());
+        assert_eq!(std::fs::read(filename).unwrap(), committed);
+        assert_eq!(select_keys(&mut table, None), (0..100).collect::<Vec<u32>>());
+        let root_page_num = table.root_page_num;
+        check_tree(&mut table, root_page_num, root_page_num);
+    }
+
+    #[test]
+    fn test_wal_mode_appends_frames_and_checkpoints() {
+        init();
+        let filename = "tmp/test_wal_mode_appends_frames_and_checkpoints.db";
+        let wal_path = crate::wal::Wal::path_for(std::path::Path::new(filename));
+        let _ = std::fs::remove_file(filename);
+        let _ = std::fs::remove_file(&wal_path);
+        let mut table = Table::new(filename).unwrap();
+        insert_rows(&mut table, 0..50);
+        table.pager.set_journal_mode(JournalMode::Wal).unwrap();
+        assert_eq!(table.pager.journal_mode(), JournalMode::Wal);
+        let before = std::fs::read(filename).unwrap();
+
+        // データファイルは書き換えず、commitした内容はWALから別の接続に見える
+        insert_rows(&mut table, 50..150);
+        table.commit().unwrap();
+        assert_eq!(std::fs::read(filename).unwrap(), before);
+        let mut other = Table::new(filename).unwrap();
+        assert_eq!(other.pager.journal_mode(), JournalMode::Wal);
+        assert_eq!(select_keys(&mut other, None), (0..150).collect::<Vec<u32>>());
+        drop(other);
+
+        // commitしていない変更はWALに書いてあっても復旧時に捨てられる
+        let mut stmt = Statement::new(StatementType::Delete);
+        stmt.key_range = Some(KeyRange { start: 0, end: 99 });
+        let mut buf = vec![];
+        assert!(execute_statement(&stmt, &mut table, &mut buf).is_ok());
+        table.pager.flush().unwrap();
+        drop(table);
+        let mut table = Table::new(filename).unwrap();
+        assert_eq!(select_keys(&mut table, None), (0..150).collect::<Vec<u32>>());
+
+        assert!(table.pager.checkpoint().unwrap() > 0);
+        assert_eq!(std::fs::metadata(&wal_path).unwrap().len(), 12);
+        assert_ne!(std::fs::read(filename).unwrap(), before);
+        table.pager.set_journal_mode(JournalMode::Delete).unwrap();
+        assert!(!wal_path.exists());
+        let mut table = Table::new(filename).unwrap();
+        assert_eq!(table.pager.journal_mode(), JournalMode::Delete);
+        assert_eq!(select_keys(&mut table, None), (0..150).collect::<Vec<u32>>());
+        let root_page_num = table.root_page_num;
+        check_tree(&mut table, root_page_num, root_page_num);
+    }
+
+    #[test]
+    fn test_rollback_restores_pages_touched_since_begin() {
+        init();
+        for mode in [JournalMode::Delete, JournalMode::Wal] {
+            let filename = format!("tmp/test_rollback_restores_pages_touched_since_begin_{}.db", mode);
+            let _ = std::fs::remove_file(&filename);
+            let _ = std::fs::remove_file(crate::wal::Wal::path_for(std::path::Path::new(&filename)));
+            let mut table = Table::new(&filename).unwrap();
+            table.pager.set_journal_mode(mode).unwrap();
+            insert_rows(&mut table, 0..100);
+            table.commit().unwrap();
+            let root_page_num = table.root_page_num;
+            let num_pages = table.pager.num_pages();
+            // 途中で追い出されたページも元に戻ること
+            table.pager.set_cache_size(CacheSize::Pages(10)).unwrap();
+
+            table.begin().unwrap();
+            assert!(table.begin().is_err());
+            let mut stmt = Statement::new(StatementType::Delete);
+            stmt.key_range = Some(KeyRange { start: 20, end: 79 });
+            let mut buf = vec![];
+            assert!(execute_statement(&stmt, &mut table, &mut buf).is_ok());
+            insert_rows(&mut table, 100..400);
+            assert_ne!(table.pager.num_pages(), num_pages);
+            table.rollback().unwrap();
+
+            assert!(!table.in_transaction());
+            assert_eq!(table.root_page_num, root_page_num);
+            assert_eq!(table.pager.num_pages(), num_pages);
+            assert_eq!(select_keys(&mut table, None), (0..100).collect::<Vec<u32>>());
+            check_tree(&mut table, root_page_num, root_page_num);
+            assert!(table.rollback().is_err());
+
+            // COMMITした変更は残る
+            table.begin().unwrap();
+            insert_rows(&mut table, 100..200);
+            table.end_transaction().unwrap();
+            table.close().unwrap();
+            let mut table = Table::new(&filename).unwrap();
+            assert_eq!(select_keys(&mut table, None), (0..200).collect::<Vec<u32>>());
+        }
+    }
+
+    #[test]
+    fn test_rollback_to_savepoint_restores_pages_since_savepoint() {
+        init();
+        let filename = "tmp/test_rollback_to_savepoint_restores_pages_since_savepoint.db";
+        let _ = std::fs::remove_file(filename);
+        let mut table = Table::new(filename).unwrap();
+        table.begin().unwrap();
+        insert_rows(&mut table, 0..50);
+        table.savepoint("a").unwrap();
+        let root_page_num = table.root_page_num;
+        let num_pages = table.pager.num_pages();
+        insert_rows(&mut table, 50..150);
+        table.savepoint("b").unwrap();
+        let mut stmt = Statement::new(StatementType::Delete);
+        stmt.key_range = Some(KeyRange { start: 0, end: 19 });
+        let mut buf = vec![];
+        assert!(execute_statement(&stmt, &mut table, &mut buf).is_ok());
+        assert_ne!(table.pager.num_pages(), num_pages);
+
+        // bより前に作ったaまで戻すと、bも無くなる
+        table.rollback_to("a").unwrap();
+        assert!(table.in_transaction());
+        assert!(table.has_savepoint("a"));
+        assert!(!table.has_savepoint("b"));
+        assert_eq!(table.root_page_num, root_page_num);
+        assert_eq!(table.pager.num_pages(), num_pages);
+        assert_eq!(select_keys(&mut table, None), (0..50).collect::<Vec<u32>>());
+        check_tree(&mut table, root_page_num, root_page_num);
+
+        // 同じsavepointに何度でも戻れる
+        insert_rows(&mut table, 200..260);
+        table.rollback_to("a").unwrap();
+        assert_eq!(select_keys(&mut table, None), (0..50).collect::<Vec<u32>>());
+
+        // releaseしても外側のトランザクションのROLLBACKで戻せる
+        insert_rows(&mut table, 200..260);
+        assert_eq!(table.release("a"), Ok(false));
+        assert!(!table.has_savepoint("a"));
+        table.end_transaction().unwrap();
+        table.commit().unwrap();
+
+        table.savepoint("outer").unwrap();
+        insert_rows(&mut table, 300..400);
+        table.savepoint("inner").unwrap();
+        insert_rows(&mut table, 400..500);
+        assert_eq!(table.release("inner"), Ok(false));
+        table.rollback().unwrap();
+        assert!(!table.in_transaction());
+        let expected: Vec<u32> = (0..50).chain(200..260).collect();
+        assert_eq!(select_keys(&mut table, None), expected);
+
+        // トランザクションの外でsavepointを作ると、releaseでトランザクションが終わる
+        table.savepoint("outer").unwrap();
+        insert_rows(&mut table, 300..310);
+        assert_eq!(table.release("OUTER"), Ok(true));
+        assert!(!table.in_transaction());
+        table.close().unwrap();
+        let mut table = Table::new(filename).unwrap();
+        let expected: Vec<u32> = (0..50).chain(200..260).chain(300..310).collect();
+        assert_eq!(select_keys(&mut table, None), expected);
+    }
+
+    #[test]
+    fn test_header_tracks_page_count_and_roots_stay_fixed() {
+        init();
+        let filename = "tmp/test_header_tracks_page_count_and_roots_stay_fixed.db";
+        let mut table = deep_tree_table(filename, 0..1000);
+        table.commit().unwrap();
+        let num_pages = table.pager.num_pages();
+        // page 1はcatalog、page 2は既定のusers table。rootが分割されてもページは動かない
+        assert_eq!(table.root_page_num, 2);
+        assert!(matches!(table.pager.get_page(2), Some(BTreeNode::Internal(_))));
+        let depth = check_tree(&mut table, 2, 0);
+        assert!(depth >= 3);
+
+        let bytes = std::fs::read(filename).unwrap();
+        let header = crate::header::Header::deserialize(&bytes[..PAGE_SIZE]).unwrap();
+        assert_eq!(header.num_pages as usize, num_pages);
+
+        // 消して木が低くなっても、rootのページは動かない
+        let statement = Statement { key_range: Some(KeyRange { start: 0, end: 994 }), ..Statement::new(StatementType::Delete) };
+        execute_statement(&statement, &mut table, &mut vec![]).unwrap();
+        assert_eq!(table.root_page_num, 2);
+        assert_eq!(check_tree(&mut table, 2, 0), 1);
+        assert_eq!(select_keys(&mut table, None), (995..1000).collect::<Vec<u32>>());
+        table.close().unwrap();
+
+        let mut table = Table::new(filename).unwrap();
+        assert_eq!(table.root_page_num, 2);
+        assert_eq!(select_keys(&mut table, None), (995..1000).collect::<Vec<u32>>());
+        insert_rows(&mut table, 0..995);
+        table.close().unwrap();
+
+        let mut table = Table::new(filename).unwrap();
+        assert_eq!(select_keys(&mut table, None), (0..1000).collect::<Vec<u32>>());
+
+        std::fs::write(filename, vec![0u8; PAGE_SIZE]).unwrap();
+        assert!(Table::new(filename).is_err());
+    }
+
+    #[test]
+    fn test_deleted_pages_are_reused() {
+        init();
+        let filename = "tmp/test_deleted_pages_are_reused.db";
+        let _ = std::fs::remove_file(filename);
+        let mut table = Table::new(filename).unwrap();
+        let mut delete_all = Statement::new(StatementType::Delete);
+        delete_all.key_range = Some(KeyRange::all());
+        let mut buf = vec![];
+
+        insert_rows(&mut table, 0..300);
+        let num_pages = table.pager.num_pages();
+        assert!(execute_statement(&delete_all, &mut table, &mut buf).is_ok());
+        let free_pages = table.pager.free_pages();
+        // headerとcatalogとrootの他は全て空きページになる
+        assert_eq!(free_pages.len(), num_pages - 3);
+        table.close().unwrap();
+
+        // 空きページのリストは閉じても残り、次の挿入で使われる
+        let mut table = Table::new(filename).unwrap();
+        assert_eq!(table.pager.free_pages(), free_pages);
+        for _ in 0..3 {
+            insert_rows(&mut table, 0..300);
+            table.commit().unwrap();
+            assert_eq!(table.pager.num_pages(), num_pages);
+            let root_page_num = table.root_page_num;
+            check_tree(&mut table, root_page_num, root_page_num);
+            assert_eq!(select_keys(&mut table, None), (0..300).collect::<Vec<u32>>());
+
+            // ROLLBACKすると空きページのリストも元に戻る
+            table.begin().unwrap();
+            assert!(execute_statement(&delete_all, &mut table, &mut buf).is_ok());
+            table.rollback().unwrap();
+            assert!(table.pager.free_pages().is_empty());
+
+            assert!(execute_statement(&delete_all, &mut table, &mut buf).is_ok());
+            table.commit().unwrap();
+            assert_eq!(table.pager.free_pages().len(), num_pages - 3);
+        }
+        assert_eq!(std::fs::metadata(filename).unwrap().len() as usize, num_pages * PAGE_SIZE);
+    }
+
+    #[test]
+    fn test_large_rows_use_overflow_pages() {
+        init();
+        let filename = "tmp/test_large_rows_use_overflow_pages.db";
+        let _ = std::fs::remove_file(filename);
+        let mut table = Table::new(filename).unwrap();
+        let schema = Schema::parse("create table docs (id integer, body blob(100000))").unwrap();
+        let root_page_num = table.create_table(schema.clone()).unwrap();
+        let row = |id: u32, len: usize| {
+            let mut row = vec![];
+            let body = (0..len).map(|i| (i % 251) as u8).collect();
+            schema.encode(vec![Value::Integer(id as i64), Value::Blob(body)], &mut row).unwrap();
+            row
+        };
+        let lens = [10, 5000, 30000, BTreeLeafNode::MAX_RECORD_SIZE, 100000];
+        for (id, len) in lens.iter().enumerate() {
+            assert!(table.insert_row(root_page_num, id as u32, row(id as u32, *len)));
+        }
+        let read = |table: &mut Table| {
+            let root_page_num = table.catalog.iter().find(|entry| entry.name == "docs").unwrap().root_page_num;
+            let mut rows = vec![];
+            table.for_each_row(root_page_num, |key, row| {
+                rows.push((key, row.to_vec()));
+                Ok(())
+            }).unwrap();
+            rows
+        };
+        let expected: Vec<(u32, Vec<u8>)> = lens.iter().enumerate().map(|(id, len)| (id as u32, row(id as u32, *len))).collect();
+        assert_eq!(read(&mut table), expected);
+        table.close().unwrap();
+
+        // 開き直しても繋げて読める
+        let mut table = Table::new(filename).unwrap();
+        assert_eq!(read(&mut table), expected);
+
+        // 消すとoverflowページは空きページになり、次の長い行で使われる
+        let num_pages = table.pager.num_pages();
+        let mut cursor = Cursor::find_insert_position(&mut table, root_page_num, 4);
+        cursor.delete();
+        let freed = table.pager.free_pages().len();
+        assert!(freed * BTreeOverflowNode::SPACE_FOR_DATA >= 100000 - BTreeLeafNode::OVERFLOW_LOCAL_SIZE);
+        assert!(table.replace_row(root_page_num, 2, row(2, 90000)));
+        assert!(table.replace_row(root_page_num, 1, row(1, 10)));
+        assert_eq!(table.pager.num_pages(), num_pages);
+        let expected: Vec<(u32, Vec<u8>)> = [(0, 10), (1, 10), (2, 90000), (3, BTreeLeafNode::MAX_RECORD_SIZE)].iter()
+            .map(|(id, len)| (*id, row(*id, *len)))
+            .collect();
+        assert_eq!(read(&mut table), expected);
+
+        // vacuumで書き直しても読める
+        table.commit().unwrap();
+        table.vacuum().unwrap();
+        assert!(table.pager.free_pages().is_empty());
+        assert_eq!(read(&mut table), expected);
+        table.close().unwrap();
+    }
+
+    #[test]
+    fn test_vacuum_rebuilds_tree_with_full_leaves() {
+        init();
+        for mode in [JournalMode::Delete, JournalMode::Wal] {
+            let filename = format!("tmp/test_vacuum_{}.db", mode);
+            let _ = std::fs::remove_file(&filename);
+            let _ = std::fs::remove_file(format!("{}-wal", filename));
+            let mut table = Table::new(&filename).unwrap();
+            table.pager.set_journal_mode(mode).unwrap();
+            insert_rows(&mut table, (0..600).rev());
+            // 3つのうち2つを消して、leafをスカスカにする
+            let mut keys = vec![];
+            for id in 0..600 {
+                if id % 3 == 0 {
+                    keys.push(id);
+                    continue;
+                }
+                let mut stmt = Statement::new(StatementType::Delete);
+                stmt.key_range = Some(KeyRange { start: id, end: id });
+                assert!(execute_statement(&stmt, &mut table, &mut vec![]).is_ok());
+            }
+            table.commit().unwrap();
+            table.pager.checkpoint().unwrap();
+            let old_size = std::fs::metadata(&filename).unwrap().len();
+
+            let reclaimed = table.vacuum().unwrap();
+            let new_size = std::fs::metadata(&filename).unwrap().len();
+            assert_eq!(reclaimed, old_size - new_size);
+            assert!(reclaimed > 0);
+            assert_eq!(table.pager.journal_mode(), mode);
+            assert!(table.pager.free_pages().is_empty());
+            let root_page_num = table.root_page_num;
+            check_tree(&mut table, root_page_num, root_page_num);
+            assert_eq!(all_keys(&mut table), keys);
+
+            // leafはcatalogとrootの後にkeyの順にページが並び、隣同士をまとめられないほど詰まっている
+            let leaves = leaf_sizes(&mut table);
+            assert_eq!(leaves.iter().map(|(page_num, _)| *page_num).collect::<Vec<_>>(), (3..3 + leaves.len()).collect::<Vec<_>>());
+            assert!(leaves.windows(2).all(|pair| pair[0].1 + pair[1].1 > BTreeLeafNode::NODE_SPACE_FOR_CELLS));
+
+            // 開き直しても同じ内容が読める
+            table.close().unwrap();
+            let mut table = Table::new(&filename).unwrap();
+            assert_eq!(select_keys(&mut table, None), keys);
+            insert_rows(&mut table, 1..3);
+            table.close().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_bulk_load_packs_leaves_to_fill_factor() {
+        init();
+        let rows = |ids: &mut dyn Iterator<Item = u32>| -> Vec<(u32, Vec<u8>)> {
+            ids.map(|id| {
+                let mut row = vec![];
+                cols_to_row(&mut row, id, format!("user{}", id), format!("user{}@example.com", id)).unwrap();
+                (id, row)
+            }).collect()
         };
+        for fill_factor in [100, 50] {
+            let filename = format!("tmp/test_bulk_load_{}.db", fill_factor);
+            let _ = std::fs::remove_file(&filename);
+            let mut table = Table::new(&filename).unwrap();
+            // 並んでいなくても読み込める
+            assert_eq!(table.bulk_load(rows(&mut (0..1000).rev()), fill_factor), Ok(1000));
+            let root_page_num = table.root_page_num;
+            check_tree(&mut table, root_page_num, root_page_num);
+            assert_eq!(all_keys(&mut table), (0..1000).collect::<Vec<u32>>());
+            let leaves = leaf_sizes(&mut table);
+            assert!(leaves.iter().all(|(_, used)| *used >= BTreeLeafNode::NODE_MIN_SIZE));
+            if fill_factor == 100 {
+                assert!(leaves.windows(2).all(|pair| pair[0].1 + pair[1].1 > BTreeLeafNode::NODE_SPACE_FOR_CELLS));
+            } else {
+                // 最後の2つは下限に合わせて分け直すことがあるので、それより前だけを見る
+                let cap = BTreeLeafNode::NODE_SPACE_FOR_CELLS.div_ceil(2);
+                assert!(leaves[..leaves.len() - 2].iter().all(|(_, used)| *used <= cap));
+            }
+            table.commit().unwrap();
+
+            // 空いたところに挿入を続けられる
+            assert!(table.bulk_load(rows(&mut (2000..2001)), fill_factor).is_err());
+            insert_rows(&mut table, 1000..1100);
+            table.close().unwrap();
+            let mut table = Table::new(&filename).unwrap();
+            assert_eq!(select_keys(&mut table, None), (0..1100).collect::<Vec<u32>>());
+        }
+
+        let filename = "tmp/test_bulk_load_invalid.db";
+        let _ = std::fs::remove_file(filename);
+        let mut table = Table::new(filename).unwrap();
+        assert!(table.bulk_load(rows(&mut vec![3, 1, 3].into_iter()), 100).is_err());
+        assert!(table.bulk_load(rows(&mut (0..10)), 0).is_err());
+        assert!(all_keys(&mut table).is_empty());
+        assert_eq!(table.bulk_load(vec![], 100), Ok(0));
+        assert!(all_keys(&mut table).is_empty());
+    }
+
+    #[test]
+    fn test_parse_synchronous() {
+        assert_eq!("OFF".parse::<Synchronous>(), Ok(Synchronous::Off));
+        assert_eq!("1".parse::<Synchronous>(), Ok(Synchronous::Normal));
+        assert_eq!("full".parse::<Synchronous>(), Ok(Synchronous::Full));
+        assert!("extra".parse::<Synchronous>().is_err());
+    }
+
+    #[test]
+    fn test_parse_journal_mode() {
+        assert_eq!("WAL".parse::<JournalMode>(), Ok(JournalMode::Wal));
+        assert_eq!("delete".parse::<JournalMode>(), Ok(JournalMode::Delete));
+        assert!("memory".parse::<JournalMode>().is_err());
+    }
+
+    #[test]
+    fn test_execute_delete_rebalances_tree() {
+        init();
+        let filename = "tmp/test_execute_delete_rebalances_tree.db";
+        let num_rows = 1000;
+        let mut table = deep_tree_table(filename, (0..num_rows).map(|i| (i * 37) % num_rows));
+        let root_page_num = table.root_page_num;
+        let height = check_tree(&mut table, root_page_num, root_page_num);
+
+        let mut stmt = Statement::new(StatementType::Delete);
+        stmt.key_range = Some(KeyRange { start: 250, end: 749 });
+        let mut buf = vec![];
+        assert!(execute_statement(&stmt, &mut table, &mut buf).is_ok());
+        for id in (0..250).map(|i| (i * 7) % 250) {
+            stmt.key_range = Some(KeyRange { start: id, end: id });
+            assert!(execute_statement(&stmt, &mut table, &mut buf).is_ok());
+        }
+        let root_page_num = table.root_page_num;
+        assert!(check_tree(&mut table, root_page_num, root_page_num) < height);
+        assert_eq!(all_keys(&mut table), (750..num_rows).collect::<Vec<u32>>());
+
+        stmt.key_range = Some(KeyRange::all());
+        assert!(execute_statement(&stmt, &mut table, &mut buf).is_ok());
+        let root_page_num = table.root_page_num;
+        match table.pager.get_page(root_page_num) {
+            Some(BTreeNode::Leaf(leaf)) => assert_eq!(leaf.num_cells, 0),
+            _ => panic!("root must be leaf after deleting all rows"),
+        }
+    }
+
+    #[test]
+    fn test_execute_delete_rebalances_tree_at_page_fanout() {
+        init();
+        let filename = "tmp/test_execute_delete_rebalances_tree_at_page_fanout.db";
+        let _ = std::fs::remove_file(filename);
+        let mut table = Table::new(filename).unwrap();
+        assert_eq!(table.pager.internal_max_cells(), BTreeInternalNode::INTERNAL_PAGE_MAX_CELLS);
+        // 1つのleafに十数行しか入らない行で、rootのinternalノードが上限を超えて分割されるまで入れる
+        let num_rows = 12000;
+        let padding = "x".repeat(200);
+        for id in (0..num_rows).map(|i| (i * 37) % num_rows) {
+            let mut row = vec![];
+            cols_to_row(&mut row, id, format!("user{}{}", id, padding), format!("user{}@example.com", id)).unwrap();
+            let mut stmt = Statement::new(StatementType::Insert);
+            stmt.row_to_insert = Some(row);
+            let mut buf = vec![];
+            assert!(execute_statement(&stmt, &mut table, &mut buf).is_ok());
+        }
+        let root_page_num = table.root_page_num;
+        assert_eq!(check_tree(&mut table, root_page_num, root_page_num), 3);
+        assert_eq!(all_keys(&mut table), (0..num_rows).collect::<Vec<u32>>());
+
+        // 子のinternalノードが下限を下回って借りたりマージしたりし、rootの下にleafが並ぶだけの木に戻る
+        let mut stmt = Statement::new(StatementType::Delete);
+        let mut buf = vec![];
+        for id in (0..num_rows - 1000).map(|i| (i * 7) % (num_rows - 1000)) {
+            stmt.key_range = Some(KeyRange { start: id, end: id });
+            assert!(execute_statement(&stmt, &mut table, &mut buf).is_ok());
+        }
+        let root_page_num = table.root_page_num;
+        assert_eq!(check_tree(&mut table, root_page_num, root_page_num), 2);
+        let min_cells = table.pager.internal_max_cells() / 2;
+        match table.pager.get_page(root_page_num) {
+            Some(BTreeNode::Internal(node)) => assert!((node.num_keys as usize) < min_cells),
+            _ => panic!("root must be internal node"),
+        }
+        assert_eq!(all_keys(&mut table), (num_rows - 1000..num_rows).collect::<Vec<u32>>());
+    }
+
+    #[test]
+    fn test_execute_statement_insert_without_row() {
+        let mut table = Table::new("tmp/test.db").unwrap();
         let stmt = Statement::new(StatementType::Insert);
         let mut buf = vec![];
         let result = execute_statement(&stmt, &mut table, &mut buf);
@@ -879,32 +2667,26 @@ mod test {
     #[test]
     fn test_execute_statement_insert() {
         init();
-        let mut table = Table {
-            pager: Pager::new("tmp/test.db").unwrap(),
-            root_page_num: 0,
-        };
+        let mut table = Table::new("tmp/test.db").unwrap();
         let id = 1;
         let username = "totem3";
         let email = "totem3@totem3.com";
         let mut row = vec![];
         cols_to_row(&mut row, id, username, email).unwrap();
-        let stmt = Statement {
-            st_type: StatementType::Insert,
-            row_to_insert: Some(row.clone()),
-        };
+        let mut stmt = Statement::new(StatementType::Insert);
+        stmt.row_to_insert = Some(row.clone());
         let mut buf = vec![];
         let result = execute_statement(&stmt, &mut table, &mut buf);
         assert!(result.is_ok());
         let expected = row;
         let mut buf: Vec<u8> = vec![];
-        match table.pager.get_page(0) {
+        match table.pager.get_page(table.root_page_num) {
             Some(BTreeNode::Leaf(leaf)) => {
-                leaf.key_values.first().and_then(|kv| {
+                if let Some(kv) = leaf.key_values.first() {
                     buf = kv.value.clone();
-                    Some(())
-                });
+                }
             }
-            Some(BTreeNode::Internal(_)) => { unimplemented!() }
+            Some(BTreeNode::Internal(_)) | Some(BTreeNode::Free(_)) | Some(BTreeNode::Overflow(_)) => { unreachable!("root page must be leaf node") }
             None => {}
         };
         assert_eq!(buf, expected);
@@ -928,10 +2710,8 @@ mod test {
         let email = "totem3@totem3.com";
         let mut row = vec![];
         let _ =cols_to_row(&mut row, id, username, email);
-        let stmt = Statement {
-            st_type: StatementType::Insert,
-            row_to_insert: Some(row),
-        };
+        let mut stmt = Statement::new(StatementType::Insert);
+        stmt.row_to_insert = Some(row);
         let mut buf = vec![];
         let result = execute_statement(&stmt, &mut table, &mut buf);
         assert!(result.is_ok());
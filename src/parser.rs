@@ -0,0 +1,736 @@
+use log::trace;
+use crate::ast::{AggregateFunc, Assignment, BinaryOp, ColumnDefinition, Expr, OrderBy, SelectItem, Statement, UnaryOp};
+use crate::lexer::{tokenize, ParseError, Position, Token, TokenKind};
+use crate::schema::{ColumnType, Value};
+
+/// 名前には使えない語。文の区切りを見分けるのに使う
+const RESERVED: [&str; 26] = [
+    "select", "insert", "update", "delete", "create", "table", "from", "into", "where", "set",
+    "group", "having", "order", "by", "limit", "and", "or", "not", "between", "is", "in", "like", "null", "true", "false", "as",
+];
+
+/// 比較の演算子
+const COMPARISONS: [(&str, BinaryOp); 8] = [
+    ("=", BinaryOp::Eq),
+    ("==", BinaryOp::Eq),
+    ("!=", BinaryOp::Ne),
+    ("<>", BinaryOp::Ne),
+    ("<", BinaryOp::Lt),
+    ("<=", BinaryOp::Le),
+    (">", BinaryOp::Gt),
+    (">=", BinaryOp::Ge),
+];
+
+/// 文を1つ読む。末尾の`;`は省略できる。
+/// 文のキーワードで始まらない入力はNoneを返し、呼び出し側で未知の文として扱う
+pub(crate) fn parse(sql: &str) -> Result<Option<Statement>, ParseError> {
+    let mut parser = Parser { tokens: tokenize(sql)?, pos: 0 };
+    let keyword = match &parser.peek().kind {
+        TokenKind::Identifier(v) => v.clone(),
+        _ => return Ok(None),
+    };
+    let statement = match keyword.as_str() {
+        "insert" => parser.insert()?,
+        "select" => parser.select()?,
+        "update" => parser.update()?,
+        "delete" => parser.delete()?,
+        "create" => parser.create_table()?,
+        "begin" | "commit" | "end" | "rollback" | "savepoint" | "release" => parser.transaction()?,
+        "vacuum" => {
+            parser.next();
+            Statement::Vacuum
+        }
+        _ => return Ok(None),
+    };
+    parser.eat_operator(";");
+    if parser.peek().kind != TokenKind::Eof {
+        return Err(parser.unexpected("end of statement"));
+    }
+    trace!("parse: {:?}", statement);
+    Ok(Some(statement))
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> &Token {
+        &self.tokens[self.pos]
+    }
+
+    fn peek_nth(&self, n: usize) -> &Token {
+        let last = self.tokens.len() - 1;
+        &self.tokens[std::cmp::min(self.pos + n, last)]
+    }
+
+    /// 次のtokenを読む。Eofのところでは進まない
+    fn next(&mut self) -> Token {
+        let token = self.tokens[self.pos].clone();
+        if token.kind != TokenKind::Eof {
+            self.pos += 1;
+        }
+        token
+    }
+
+    fn unexpected(&self, expected: &str) -> ParseError {
+        let token = self.peek();
+        ParseError::new(format!("expected {}, found {}", expected, token.kind), token.position)
+    }
+
+    fn is_keyword(&self, keyword: &str) -> bool {
+        matches!(&self.peek().kind, TokenKind::Identifier(v) if v == keyword)
+    }
+
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        let found = self.is_keyword(keyword);
+        if found {
+            self.next();
+        }
+        found
+    }
+
+    fn expect_keyword(&mut self, keyword: &str) -> Result<Position, ParseError> {
+        if !self.is_keyword(keyword) {
+            return Err(self.unexpected(&format!("'{}'", keyword)));
+        }
+        Ok(self.next().position)
+    }
+
+    fn is_operator(&self, op: &str) -> bool {
+        matches!(self.peek().kind, TokenKind::Operator(v) if v == op)
+    }
+
+    fn eat_operator(&mut self, op: &str) -> bool {
+        let found = self.is_operator(op);
+        if found {
+            self.next();
+        }
+        found
+    }
+
+    fn expect_operator(&mut self, op: &str) -> Result<Position, ParseError> {
+        if !self.is_operator(op) {
+            return Err(self.unexpected(&format!("'{}'", op)));
+        }
+        Ok(self.next().position)
+    }
+
+    /// tableやカラムの名前を読む。予約語は名前にできない
+    fn identifier(&mut self, what: &str) -> Result<(String, Position), ParseError> {
+        match &self.peek().kind {
+            TokenKind::Identifier(v) if !RESERVED.contains(&v.as_str()) => {
+                let token = self.next();
+                match token.kind {
+                    TokenKind::Identifier(v) => Ok((v, token.position)),
+                    _ => unreachable!(),
+                }
+            }
+            _ => Err(self.unexpected(what)),
+        }
+    }
+
+    /// 0以上の整数を読む
+    fn unsigned_integer(&mut self, what: &str) -> Result<usize, ParseError> {
+        match self.peek().kind {
+            TokenKind::Integer(v) if v >= 0 => {
+                self.next();
+                Ok(v as usize)
+            }
+            _ => Err(self.unexpected(what)),
+        }
+    }
+
+    /// `keyword name`の形で省略できるtableの名前を読む
+    fn table_name(&mut self, keyword: &str) -> Result<Option<String>, ParseError> {
+        if !self.eat_keyword(keyword) {
+            return Ok(None);
+        }
+        Ok(Some(self.identifier("table name")?.0))
+    }
+
+    fn where_clause(&mut self) -> Result<Option<Expr>, ParseError> {
+        if !self.eat_keyword("where") {
+            return Ok(None);
+        }
+        Ok(Some(self.expr()?))
+    }
+
+    fn insert(&mut self) -> Result<Statement, ParseError> {
+        self.expect_keyword("insert")?;
+        let table = self.table_name("into")?;
+        let mut values = vec![];
+        while !matches!(self.peek().kind, TokenKind::Eof | TokenKind::Operator(";")) {
+            values.push(self.literal()?);
+            // 値は空白かカンマで区切る
+            self.eat_operator(",");
+        }
+        Ok(Statement::Insert { table, values })
+    }
+
+    fn select(&mut self) -> Result<Statement, ParseError> {
+        self.expect_keyword("select")?;
+        // `select from ...`や`select where ...`のように省略すると全てのカラム
+        let mut columns = vec![];
+        let omitted = ["from", "where", "group", "having", "order", "limit"].iter().any(|k| self.is_keyword(k))
+            || matches!(self.peek().kind, TokenKind::Eof | TokenKind::Operator(";"));
+        if !omitted {
+            loop {
+                columns.push(self.select_item()?);
+                if !self.eat_operator(",") {
+                    break;
+                }
+            }
+        }
+        let table = self.table_name("from")?;
+        let where_clause = self.where_clause()?;
+        let mut group_by = vec![];
+        if self.eat_keyword("group") {
+            self.expect_keyword("by")?;
+            loop {
+                group_by.push(self.expr()?);
+                if !self.eat_operator(",") {
+                    break;
+                }
+            }
+        }
+        let having = if self.eat_keyword("having") { Some(self.expr()?) } else { None };
+        let mut order_by = None;
+        if self.eat_keyword("order") {
+            self.expect_keyword("by")?;
+            let (column, position) = self.identifier("column name")?;
+            let descending = if self.eat_keyword("desc") {
+                true
+            } else {
+                self.eat_keyword("asc");
+                false
+            };
+            order_by = Some(OrderBy { column, descending, position });
+        }
+        let mut limit = None;
+        if self.eat_keyword("limit") {
+            limit = Some(self.unsigned_integer("number of rows")?);
+        }
+        Ok(Statement::Select { columns, table, where_clause, group_by, having, order_by, limit })
+    }
+
+    /// `*` か `expr [[as] alias]`
+    fn select_item(&mut self) -> Result<SelectItem, ParseError> {
+        if self.eat_operator("*") {
+            return Ok(SelectItem::Wildcard);
+        }
+        let expr = self.expr()?;
+        let alias = if self.eat_keyword("as") || matches!(&self.peek().kind, TokenKind::Identifier(v) if !RESERVED.contains(&v.as_str())) {
+            Some(self.identifier("alias")?.0)
+        } else {
+            None
+        };
+        Ok(SelectItem::Expr { expr, alias })
+    }
+
+    fn update(&mut self) -> Result<Statement, ParseError> {
+        self.expect_keyword("update")?;
+        let table = if self.is_keyword("set") { None } else { Some(self.identifier("table name or 'set'")?.0) };
+        self.expect_keyword("set")?;
+        let mut assignments = vec![];
+        loop {
+            let (column, position) = self.identifier("column name")?;
+            self.expect_operator("=")?;
+            let value = self.expr()?;
+            assignments.push(Assignment { column, value, position });
+            if !self.eat_operator(",") {
+                break;
+            }
+        }
+        let where_clause = self.where_clause()?;
+        Ok(Statement::Update { table, assignments, where_clause })
+    }
+
+    fn delete(&mut self) -> Result<Statement, ParseError> {
+        self.expect_keyword("delete")?;
+        let table = self.table_name("from")?;
+        let where_clause = self.where_clause()?;
+        Ok(Statement::Delete { table, where_clause })
+    }
+
+    fn create_table(&mut self) -> Result<Statement, ParseError> {
+        self.expect_keyword("create")?;
+        self.expect_keyword("table")?;
+        let (name, position) = self.identifier("table name")?;
+        self.expect_operator("(")?;
+        let mut columns = vec![];
+        loop {
+            columns.push(self.column_definition()?);
+            if !self.eat_operator(",") {
+                break;
+            }
+        }
+        self.expect_operator(")")?;
+        Ok(Statement::CreateTable { name, columns, position })
+    }
+
+    /// `name TYPE[(n)] [primary key]`
+    fn column_definition(&mut self) -> Result<ColumnDefinition, ParseError> {
+        let (name, position) = self.identifier("column name")?;
+        let (type_name, type_position) = self.identifier("column type")?;
+        let col_type = type_name.parse::<ColumnType>().map_err(|e| ParseError::new(e, type_position))?;
+        let mut length = None;
+        if self.is_operator("(") {
+            if !col_type.has_length() {
+                return Err(ParseError::new(format!("column type {} does not take a length", col_type), self.peek().position));
+            }
+            self.next();
+            length = Some(self.unsigned_integer("length of column")?);
+            self.expect_operator(")")?;
+        }
+        let primary_key = self.eat_keyword("primary");
+        if primary_key {
+            self.expect_keyword("key")?;
+        }
+        Ok(ColumnDefinition { name, col_type, length, primary_key, position })
+    }
+
+    /// `begin [transaction]`, `commit [transaction]`(`end`), `rollback [transaction] [to [savepoint] name]`,
+    /// `savepoint name`, `release [savepoint] name`
+    fn transaction(&mut self) -> Result<Statement, ParseError> {
+        let keyword = match self.next().kind {
+            TokenKind::Identifier(v) => v,
+            _ => unreachable!(),
+        };
+        let statement = match keyword.as_str() {
+            "begin" => {
+                self.eat_keyword("transaction");
+                Statement::Begin
+            }
+            "commit" | "end" => {
+                self.eat_keyword("transaction");
+                Statement::Commit
+            }
+            "rollback" => {
+                self.eat_keyword("transaction");
+                let mut savepoint = None;
+                if self.eat_keyword("to") {
+                    self.eat_keyword("savepoint");
+                    savepoint = Some(self.identifier("savepoint name")?.0);
+                }
+                Statement::Rollback { savepoint }
+            }
+            "savepoint" => Statement::Savepoint { name: self.identifier("savepoint name")?.0 },
+            "release" => {
+                self.eat_keyword("savepoint");
+                Statement::Release { name: self.identifier("savepoint name")?.0 }
+            }
+            _ => unreachable!("transaction: not a transaction statement: {}", keyword),
+        };
+        Ok(statement)
+    }
+
+    /// 値を1つ読む。数値には符号を付けられる
+    fn literal(&mut self) -> Result<Expr, ParseError> {
+        let position = self.peek().position;
+        let sign = if self.eat_operator("-") {
+            Some(-1)
+        } else if self.eat_operator("+") {
+            Some(1)
+        } else {
+            None
+        };
+        let value = match (&self.peek().kind, sign) {
+            (TokenKind::Integer(v), _) => Value::Integer(v * sign.unwrap_or(1)),
+            (TokenKind::Real(v), _) => Value::Real(v * sign.unwrap_or(1) as f64),
+            (TokenKind::String(v), None) => Value::Text(v.clone()),
+            (TokenKind::Blob(v), None) => Value::Blob(v.clone()),
+            (TokenKind::Identifier(v), None) if v == "true" || v == "false" => Value::Boolean(v == "true"),
+            (TokenKind::Identifier(v), None) if v == "null" => Value::Null,
+            (_, Some(_)) => return Err(self.unexpected("a number")),
+            (_, None) => return Err(self.unexpected("a literal value")),
+        };
+        self.next();
+        Ok(Expr::Literal { value, position })
+    }
+
+    /// 式を読む。結合の弱い順に
+    /// `or`, `and`, `not`, 比較と`between`, `is`, `in`, `like`, `+ - ||`, `* / %`, 単項の`-`
+    fn expr(&mut self) -> Result<Expr, ParseError> {
+        self.or()
+    }
+
+    fn or(&mut self) -> Result<Expr, ParseError> {
+        let mut left = self.and()?;
+        while self.eat_keyword("or") {
+            let right = self.and()?;
+            left = binary(BinaryOp::Or, left, right);
+        }
+        Ok(left)
+    }
+
+    fn and(&mut self) -> Result<Expr, ParseError> {
+        let mut left = self.not()?;
+        while self.eat_keyword("and") {
+            let right = self.not()?;
+            left = binary(BinaryOp::And, left, right);
+        }
+        Ok(left)
+    }
+
+    fn not(&mut self) -> Result<Expr, ParseError> {
+        if !self.is_keyword("not") {
+            return self.comparison();
+        }
+        let position = self.next().position;
+        let operand = self.not()?;
+        Ok(Expr::Unary { op: UnaryOp::Not, operand: Box::new(operand), position })
+    }
+
+    fn comparison(&mut self) -> Result<Expr, ParseError> {
+        let left = self.additive()?;
+        let position = left.position();
+        if self.eat_keyword("is") {
+            let negated = self.eat_keyword("not");
+            self.expect_keyword("null")?;
+            return Ok(Expr::IsNull { operand: Box::new(left), negated, position });
+        }
+        let negated = self.is_keyword("not")
+            && matches!(&self.peek_nth(1).kind, TokenKind::Identifier(v) if ["between", "in", "like"].contains(&v.as_str()));
+        if negated {
+            self.next();
+        }
+        if self.eat_keyword("between") {
+            let low = self.additive()?;
+            self.expect_keyword("and")?;
+            let high = self.additive()?;
+            return Ok(Expr::Between { operand: Box::new(left), low: Box::new(low), high: Box::new(high), negated, position });
+        }
+        if self.eat_keyword("in") {
+            self.expect_operator("(")?;
+            let mut list = vec![self.expr()?];
+            while self.eat_operator(",") {
+                list.push(self.expr()?);
+            }
+            self.expect_operator(")")?;
+            return Ok(Expr::InList { operand: Box::new(left), list, negated, position });
+        }
+        if self.eat_keyword("like") {
+            let pattern = self.additive()?;
+            return Ok(Expr::Like { operand: Box::new(left), pattern: Box::new(pattern), negated, position });
+        }
+        let op = match COMPARISONS.iter().find(|(op, _)| self.is_operator(op)) {
+            Some((_, op)) => *op,
+            None => return Ok(left),
+        };
+        self.next();
+        let right = self.additive()?;
+        Ok(binary(op, left, right))
+    }
+
+    fn additive(&mut self) -> Result<Expr, ParseError> {
+        let mut left = self.multiplicative()?;
+        loop {
+            let op = if self.eat_operator("+") {
+                BinaryOp::Add
+            } else if self.eat_operator("-") {
+                BinaryOp::Sub
+            } else if self.eat_operator("||") {
+                BinaryOp::Concat
+            } else {
+                return Ok(left);
+            };
+            let right = self.multiplicative()?;
+            left = binary(op, left, right);
+        }
+    }
+
+    fn multiplicative(&mut self) -> Result<Expr, ParseError> {
+        let mut left = self.unary()?;
+        loop {
+            let op = if self.eat_operator("*") {
+                BinaryOp::Mul
+            } else if self.eat_operator("/") {
+                BinaryOp::Div
+            } else if self.eat_operator("%") {
+                BinaryOp::Rem
+            } else {
+                return Ok(left);
+            };
+            let right = self.unary()?;
+            left = binary(op, left, right);
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr, ParseError> {
+        let signed_number = matches!(self.peek_nth(1).kind, TokenKind::Integer(_) | TokenKind::Real(_));
+        if (self.is_operator("-") || self.is_operator("+")) && signed_number {
+            // `-1`は演算ではなく負の数のリテラルにする
+            return self.literal();
+        }
+        if self.is_operator("-") {
+            let position = self.next().position;
+            let operand = self.unary()?;
+            return Ok(Expr::Unary { op: UnaryOp::Neg, operand: Box::new(operand), position });
+        }
+        if self.eat_operator("+") {
+            return self.unary();
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Expr, ParseError> {
+        let token = self.peek().clone();
+        match token.kind {
+            TokenKind::Operator("(") => {
+                self.next();
+                let expr = self.expr()?;
+                self.expect_operator(")")?;
+                Ok(expr)
+            }
+            TokenKind::Identifier(v) if !RESERVED.contains(&v.as_str()) && self.peek_nth(1).kind == TokenKind::Operator("(") => {
+                self.next();
+                self.next();
+                if let Some(func) = AggregateFunc::from_name(&v) {
+                    return self.aggregate(func, token.position);
+                }
+                let args = self.arguments()?;
+                Ok(Expr::Function { name: v, args, position: token.position })
+            }
+            TokenKind::Identifier(v) if !RESERVED.contains(&v.as_str()) => {
+                self.next();
+                Ok(Expr::Column { name: v, position: token.position })
+            }
+            TokenKind::Integer(_) | TokenKind::Real(_) | TokenKind::String(_) | TokenKind::Blob(_) | TokenKind::Identifier(_) => {
+                self.literal().map_err(|_| self.unexpected("an expression"))
+            }
+            _ => Err(self.unexpected("an expression")),
+        }
+    }
+
+    /// 関数の`(`の後ろの引数を`)`まで読む
+    fn arguments(&mut self) -> Result<Vec<Expr>, ParseError> {
+        let mut args = vec![];
+        if self.eat_operator(")") {
+            return Ok(args);
+        }
+        loop {
+            args.push(self.expr()?);
+            if !self.eat_operator(",") {
+                break;
+            }
+        }
+        self.expect_operator(")")?;
+        Ok(args)
+    }
+
+    /// 集約関数の`(`の後ろを読む。引数は1つで、countだけは`*`も取れる
+    fn aggregate(&mut self, func: AggregateFunc, position: Position) -> Result<Expr, ParseError> {
+        if func == AggregateFunc::Count && self.eat_operator("*") {
+            self.expect_operator(")")?;
+            return Ok(Expr::Aggregate { func, arg: None, position });
+        }
+        let mut args = self.arguments()?;
+        if args.len() != 1 {
+            return Err(ParseError::new(format!("wrong number of arguments to function {}: {}", func, args.len()), position));
+        }
+        Ok(Expr::Aggregate { func, arg: args.pop().map(Box::new), position })
+    }
+}
+
+fn binary(op: BinaryOp, left: Expr, right: Expr) -> Expr {
+    let position = left.position();
+    Expr::Binary { op, left: Box::new(left), right: Box::new(right), position }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn at(line: usize, column: usize) -> Position {
+        Position { line, column }
+    }
+
+    fn parse_ok(sql: &str) -> Statement {
+        parse(sql).unwrap().unwrap()
+    }
+
+    fn parse_err(sql: &str) -> ParseError {
+        parse(sql).unwrap_err()
+    }
+
+    fn column(name: &str, position: Position) -> Box<Expr> {
+        Box::new(Expr::Column { name: name.to_string(), position })
+    }
+
+    fn integer(v: i64, position: Position) -> Box<Expr> {
+        Box::new(Expr::Literal { value: Value::Integer(v), position })
+    }
+
+    #[test]
+    fn test_parse_statements() {
+        assert_eq!(parse_ok("insert into items 1, 'a' -2 x'ff' true;"), Statement::Insert {
+            table: Some("items".to_string()),
+            values: vec![
+                *integer(1, at(1, 19)),
+                Expr::Literal { value: Value::Text("a".to_string()), position: at(1, 22) },
+                *integer(-2, at(1, 26)),
+                Expr::Literal { value: Value::Blob(vec![255]), position: at(1, 29) },
+                Expr::Literal { value: Value::Boolean(true), position: at(1, 35) },
+            ],
+        });
+        assert_eq!(parse_ok("select from t where id = 3 order by id desc limit 10"), Statement::Select {
+            columns: vec![],
+            table: Some("t".to_string()),
+            where_clause: Some(Expr::Binary { op: BinaryOp::Eq, left: column("id", at(1, 21)), right: integer(3, at(1, 26)), position: at(1, 21) }),
+            group_by: vec![],
+            having: None,
+            order_by: Some(OrderBy { column: "id".to_string(), descending: true, position: at(1, 37) }),
+            limit: Some(10),
+        });
+        assert_eq!(parse_ok("update set n = 1\n where id between 1 and 2"), Statement::Update {
+            table: None,
+            assignments: vec![Assignment { column: "n".to_string(), value: *integer(1, at(1, 16)), position: at(1, 12) }],
+            where_clause: Some(Expr::Between {
+                operand: column("id", at(2, 8)),
+                low: integer(1, at(2, 19)),
+                high: integer(2, at(2, 25)),
+                negated: false,
+                position: at(2, 8),
+            }),
+        });
+        assert_eq!(parse_ok("DELETE FROM t"), Statement::Delete { table: Some("t".to_string()), where_clause: None });
+        assert_eq!(parse_ok("create table t (id integer primary key, s text(8))"), Statement::CreateTable {
+            name: "t".to_string(),
+            columns: vec![
+                ColumnDefinition { name: "id".to_string(), col_type: ColumnType::Integer, length: None, primary_key: true, position: at(1, 17) },
+                ColumnDefinition { name: "s".to_string(), col_type: ColumnType::Text, length: Some(8), primary_key: false, position: at(1, 41) },
+            ],
+            position: at(1, 14),
+        });
+        assert_eq!(parse_ok("rollback transaction to savepoint a"), Statement::Rollback { savepoint: Some("a".to_string()) });
+        assert_eq!(parse_ok("end"), Statement::Commit);
+        assert_eq!(parse_ok("vacuum;"), Statement::Vacuum);
+        assert_eq!(parse("hoge"), Ok(None));
+        assert_eq!(parse(""), Ok(None));
+    }
+
+    #[test]
+    fn test_parse_expr() {
+        // andはorより強く、比較は算術より弱い
+        let where_clause = |sql: &str| match parse_ok(&format!("select where {}", sql)) {
+            Statement::Select { where_clause, .. } => where_clause.unwrap(),
+            _ => unreachable!(),
+        };
+        let expr = where_clause("a = 1 or not b + 2 * 3 > 4 and c not between -1 and 1");
+        let and = match expr {
+            Expr::Binary { op: BinaryOp::Or, right, .. } => *right,
+            e => panic!("unexpected expr: {:?}", e),
+        };
+        let (not, between) = match and {
+            Expr::Binary { op: BinaryOp::And, left, right, .. } => (*left, *right),
+            e => panic!("unexpected expr: {:?}", e),
+        };
+        match not {
+            Expr::Unary { op: UnaryOp::Not, operand, position } => {
+                assert_eq!(position, at(1, 23));
+                assert!(matches!(*operand, Expr::Binary { op: BinaryOp::Gt, left, .. } if matches!(*left, Expr::Binary { op: BinaryOp::Add, .. })));
+            }
+            e => panic!("unexpected expr: {:?}", e),
+        }
+        assert!(matches!(between, Expr::Between { negated: true, low, .. } if low.literal() == Some(&Value::Integer(-1))));
+        assert!(matches!(where_clause("a is not null"), Expr::IsNull { negated: true, .. }));
+        assert!(matches!(where_clause("a is null or b = null"), Expr::Binary { op: BinaryOp::Or, left, right, .. }
+            if matches!(*left, Expr::IsNull { negated: false, .. }) && matches!(*right, Expr::Binary { op: BinaryOp::Eq, .. })));
+        match where_clause("a not in (1, b + 1, 'x')") {
+            Expr::InList { list, negated: true, position, .. } => {
+                assert_eq!(list.len(), 3);
+                assert_eq!(position, at(1, 14));
+            }
+            e => panic!("unexpected expr: {:?}", e),
+        }
+        assert!(matches!(where_clause("name like 'a%' || '_'"), Expr::Like { negated: false, pattern, .. }
+            if matches!(*pattern, Expr::Binary { op: BinaryOp::Concat, .. })));
+        assert!(matches!(where_clause("-(a) || 'x'"), Expr::Binary { op: BinaryOp::Concat, left, .. } if matches!(*left, Expr::Unary { op: UnaryOp::Neg, .. })));
+    }
+
+    #[test]
+    fn test_parse_select_list() {
+        let columns = match parse_ok("select *, name, price * 2 as total, upper(name) n, now() from t") {
+            Statement::Select { columns, table, .. } => {
+                assert_eq!(table.as_deref(), Some("t"));
+                columns
+            }
+            s => panic!("unexpected statement: {:?}", s),
+        };
+        assert_eq!(columns.len(), 5);
+        assert_eq!(columns[0], SelectItem::Wildcard);
+        assert_eq!(columns[1], SelectItem::Expr { expr: *column("name", at(1, 11)), alias: None });
+        let labels: Vec<(String, Option<String>)> = columns[2..].iter().map(|item| match item {
+            SelectItem::Expr { expr, alias } => (expr.to_string(), alias.clone()),
+            SelectItem::Wildcard => unreachable!(),
+        }).collect();
+        assert_eq!(labels, vec![
+            ("price * 2".to_string(), Some("total".to_string())),
+            ("upper(name)".to_string(), Some("n".to_string())),
+            ("now()".to_string(), None),
+        ]);
+        assert!(matches!(parse_ok("select id where id = 1"), Statement::Select { columns, where_clause: Some(_), .. } if columns.len() == 1));
+    }
+
+    #[test]
+    fn test_parse_group_by() {
+        match parse_ok("select name, count(*) from t where id > 1 group by name, price % 2 having sum(price) > 10 order by id limit 3") {
+            Statement::Select { columns, group_by, having, order_by, limit, .. } => {
+                assert_eq!(columns[1], SelectItem::Expr { expr: Expr::Aggregate { func: AggregateFunc::Count, arg: None, position: at(1, 14) }, alias: None });
+                assert_eq!(group_by.len(), 2);
+                assert_eq!(group_by[0], *column("name", at(1, 52)));
+                assert!(matches!(having, Some(Expr::Binary { op: BinaryOp::Gt, left, .. })
+                    if matches!(*left, Expr::Aggregate { func: AggregateFunc::Sum, arg: Some(_), .. })));
+                assert!(order_by.is_some());
+                assert_eq!(limit, Some(3));
+            }
+            s => panic!("unexpected statement: {:?}", s),
+        }
+        assert!(matches!(parse_ok("select having count(*) > 1"), Statement::Select { columns, having: Some(_), .. } if columns.is_empty()));
+    }
+
+    #[test]
+    fn test_display_expr() {
+        let display = |sql: &str| match parse_ok(&format!("select {}", sql)) {
+            Statement::Select { mut columns, .. } => match columns.remove(0) {
+                SelectItem::Expr { expr, .. } => expr.to_string(),
+                SelectItem::Wildcard => unreachable!(),
+            },
+            _ => unreachable!(),
+        };
+        assert_eq!(display("a+b*2"), "a + (b * 2)");
+        assert_eq!(display("(a + b) * -c"), "(a + b) * -c");
+        assert_eq!(display("NOT x IS NULL"), "not (x is null)");
+        assert_eq!(display("s || 'it''s' NOT LIKE 'a%'"), "(s || 'it''s') not like 'a%'");
+        assert_eq!(display("n not in (1, x'ff', null) and m between 1 and 2.5"), "(n not in (1, x'ff', NULL)) and (m between 1 and 2.5)");
+        assert_eq!(display("coalesce(a, substr(b, 1, 2))"), "coalesce(a, substr(b, 1, 2))");
+        assert_eq!(display("COUNT(*) + Max(a * 2)"), "count(*) + max(a * 2)");
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(parse_err("insert 1 foo"), ParseError::new("expected a literal value, found 'foo'", at(1, 10)));
+        assert_eq!(parse_err("insert 1 - 'a'"), ParseError::new("expected a number, found string 'a'", at(1, 12)));
+        assert_eq!(parse_err("select limit 2 order by id"), ParseError::new("expected end of statement, found 'order'", at(1, 16)));
+        assert_eq!(parse_err("select\nwhere id ="), ParseError::new("expected an expression, found end of input", at(2, 11)));
+        assert_eq!(parse_err("update users set where id = 1").position, at(1, 18));
+        assert_eq!(parse_err("create table t (id integer(4))").position, at(1, 27));
+        assert_eq!(parse_err("create table t (id float)"), ParseError::new("unknown column type: float", at(1, 20)));
+        assert_eq!(parse_err("create table from (id integer)").position, at(1, 14));
+        assert_eq!(parse_err("savepoint").position, at(1, 10));
+        assert_eq!(parse_err("begin immediately").position, at(1, 7));
+        assert_eq!(parse_err("select where name = 'abc").position, at(1, 21));
+        assert_eq!(parse_err("select where a is 1"), ParseError::new("expected 'null', found 1", at(1, 19)));
+        assert_eq!(parse_err("select where a in (1, 2").position, at(1, 24));
+        assert_eq!(parse_err("select where a not = 1").position, at(1, 16));
+        assert_eq!(parse_err("select a, from t").position, at(1, 11));
+        assert_eq!(parse_err("select a as from t").position, at(1, 13));
+        assert_eq!(parse_err("select f(a, from t").position, at(1, 13));
+        assert_eq!(parse_err("select sum(*) from t").position, at(1, 12));
+        assert_eq!(parse_err("select max(a, b) from t"), ParseError::new("wrong number of arguments to function max: 2", at(1, 8)));
+        assert_eq!(parse_err("select count() from t"), ParseError::new("wrong number of arguments to function count: 0", at(1, 8)));
+        assert_eq!(parse_err("select a group name").position, at(1, 16));
+    }
+}
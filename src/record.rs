@@ -0,0 +1,187 @@
+use std::convert::TryInto;
+use crate::schema::Value;
+
+/// 行を可変長にシリアライズする。
+///
+/// 形式: カラム数(varint) + 値ごとに型のタグ(u8)と中身
+/// - INTEGER: zigzagにしたvarint
+/// - REAL: f64(8バイト)
+/// - TEXT, BLOB: 長さ(varint) + バイト列
+/// - BOOLEAN, NULL: タグだけ
+///
+/// タグで型が分かるので、schemaがなくても読める
+const TAG_INTEGER: u8 = 1;
+const TAG_REAL: u8 = 2;
+const TAG_TEXT: u8 = 3;
+const TAG_BLOB: u8 = 4;
+const TAG_FALSE: u8 = 5;
+const TAG_TRUE: u8 = 6;
+const TAG_NULL: u8 = 7;
+
+/// 7bitずつ下位から書き、続きがあれば最上位bitを立てる
+pub(crate) fn write_varint(buf: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        buf.push((v as u8 & 0x7f) | 0x80);
+        v >>= 7;
+    }
+    buf.push(v as u8);
+}
+
+/// varintを読み、値と読んだバイト数を返す
+pub(crate) fn read_varint(buf: &[u8]) -> Result<(u64, usize), String> {
+    let mut v = 0u64;
+    for (i, b) in buf.iter().enumerate().take(10) {
+        v |= ((b & 0x7f) as u64) << (7 * i);
+        if b & 0x80 == 0 {
+            return Ok((v, i + 1));
+        }
+    }
+    Err("invalid varint".to_string())
+}
+
+pub(crate) fn varint_len(v: u64) -> usize {
+    let bits = 64 - v.leading_zeros() as usize;
+    std::cmp::max(bits.div_ceil(7), 1)
+}
+
+/// 負の数も小さい値になるように、符号をbit 0に移す
+fn zigzag(v: i64) -> u64 {
+    ((v << 1) ^ (v >> 63)) as u64
+}
+
+fn unzigzag(v: u64) -> i64 {
+    ((v >> 1) as i64) ^ -((v & 1) as i64)
+}
+
+pub(crate) fn encode(values: &[Value], buf: &mut Vec<u8>) {
+    buf.clear();
+    write_varint(buf, values.len() as u64);
+    for value in values {
+        match value {
+            Value::Integer(v) => {
+                buf.push(TAG_INTEGER);
+                write_varint(buf, zigzag(*v));
+            }
+            Value::Real(v) => {
+                buf.push(TAG_REAL);
+                buf.extend_from_slice(&v.to_le_bytes());
+            }
+            Value::Text(v) => {
+                buf.push(TAG_TEXT);
+                write_varint(buf, v.len() as u64);
+                buf.extend_from_slice(v.as_bytes());
+            }
+            Value::Blob(v) => {
+                buf.push(TAG_BLOB);
+                write_varint(buf, v.len() as u64);
+                buf.extend_from_slice(v);
+            }
+            Value::Boolean(v) => buf.push(if *v { TAG_TRUE } else { TAG_FALSE }),
+            Value::Null => buf.push(TAG_NULL),
+        }
+    }
+}
+
+/// recordを先頭から1つずつ読む
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn varint(&mut self) -> Result<u64, String> {
+        let (v, n) = read_varint(self.buf)?;
+        self.buf = &self.buf[n..];
+        Ok(v)
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if self.buf.len() < len {
+            return Err(format!("record is truncated: {} bytes left, {} expected", self.buf.len(), len));
+        }
+        let (bytes, rest) = self.buf.split_at(len);
+        self.buf = rest;
+        Ok(bytes)
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        let tag = self.bytes(1)?[0];
+        let value = match tag {
+            TAG_INTEGER => Value::Integer(unzigzag(self.varint()?)),
+            TAG_REAL => Value::Real(f64::from_le_bytes(self.bytes(8)?.try_into().unwrap())),
+            TAG_TEXT => {
+                let len = self.varint()? as usize;
+                let bytes = self.bytes(len)?;
+                Value::Text(String::from_utf8(bytes.to_vec()).map_err(|e| e.to_string())?)
+            }
+            TAG_BLOB => {
+                let len = self.varint()? as usize;
+                Value::Blob(self.bytes(len)?.to_vec())
+            }
+            TAG_FALSE => Value::Boolean(false),
+            TAG_TRUE => Value::Boolean(true),
+            TAG_NULL => Value::Null,
+            _ => return Err(format!("unknown type tag: {}", tag)),
+        };
+        Ok(value)
+    }
+}
+
+pub(crate) fn decode(buf: &[u8]) -> Result<Vec<Value>, String> {
+    let mut reader = Reader { buf };
+    let count = reader.varint()? as usize;
+    let mut values = Vec::with_capacity(count);
+    for _ in 0..count {
+        values.push(reader.value()?);
+    }
+    Ok(values)
+}
+
+/// 先頭の値だけを読む
+pub(crate) fn decode_first(buf: &[u8]) -> Result<Value, String> {
+    let mut reader = Reader { buf };
+    match reader.varint()? {
+        0 => Err("record has no values".to_string()),
+        _ => reader.value(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_varint() {
+        for v in [0, 1, 127, 128, 300, 16383, 16384, u32::MAX as u64, u64::MAX] {
+            let mut buf = vec![];
+            write_varint(&mut buf, v);
+            assert_eq!(buf.len(), varint_len(v));
+            assert_eq!(read_varint(&buf), Ok((v, buf.len())));
+        }
+        assert!(read_varint(&[0x80]).is_err());
+        for v in [0, -1, 1, i64::MIN, i64::MAX] {
+            assert_eq!(unzigzag(zigzag(v)), v);
+        }
+        assert_eq!(zigzag(-1), 1);
+    }
+
+    #[test]
+    fn test_encode_and_decode() {
+        let values = vec![
+            Value::Integer(1),
+            Value::Integer(-300),
+            Value::Real(1.5),
+            Value::Text("abc".to_string()),
+            Value::Blob(vec![0, 1]),
+            Value::Boolean(true),
+            Value::Boolean(false),
+            Value::Null,
+        ];
+        let mut buf = vec![];
+        encode(&values, &mut buf);
+        assert_eq!(buf.len(), 1 + 2 + 3 + 9 + 5 + 4 + 1 + 1 + 1);
+        assert_eq!(decode(&buf), Ok(values));
+        assert_eq!(decode_first(&buf), Ok(Value::Integer(1)));
+        assert!(decode(&buf[..buf.len() - 3]).is_err());
+        assert!(decode(&[1, 9]).is_err());
+    }
+}
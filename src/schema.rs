@@ -0,0 +1,437 @@
+use std::error::Error;
+use std::fmt;
+use std::fmt::Formatter;
+use std::io;
+use crate::ast::{self, ColumnDefinition};
+use crate::lexer::{ParseError, Position};
+use crate::parser;
+use crate::record;
+
+/// カラムの型
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ColumnType {
+    Integer,
+    Real,
+    Text,
+    Blob,
+    Boolean,
+}
+
+impl ColumnType {
+    /// TEXTとBLOBに指定できる最大バイト数。長さを指定しなければこれになる。
+    /// leafに収まらない分はoverflowページに置く
+    pub(crate) const MAX_LENGTH: usize = u32::MAX as usize;
+
+    /// 長さを指定できる型か
+    pub(crate) fn has_length(&self) -> bool {
+        matches!(self, ColumnType::Text | ColumnType::Blob)
+    }
+}
+
+impl std::str::FromStr for ColumnType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "integer" => Ok(ColumnType::Integer),
+            "real" => Ok(ColumnType::Real),
+            "text" => Ok(ColumnType::Text),
+            "blob" => Ok(ColumnType::Blob),
+            "boolean" => Ok(ColumnType::Boolean),
+            _ => Err(format!("unknown column type: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for ColumnType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ColumnType::Integer => write!(f, "INTEGER"),
+            ColumnType::Real => write!(f, "REAL"),
+            ColumnType::Text => write!(f, "TEXT"),
+            ColumnType::Blob => write!(f, "BLOB"),
+            ColumnType::Boolean => write!(f, "BOOLEAN"),
+        }
+    }
+}
+
+/// カラムの値
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Value {
+    Integer(i64),
+    Real(f64),
+    Text(String),
+    Blob(Vec<u8>),
+    Boolean(bool),
+    /// 値がない。keyのカラムには入れられない
+    Null,
+}
+
+impl Value {
+    /// `.import`のファイルのように型の付いていない文字列を、カラムの型の値として読む
+    pub(crate) fn from_text(col_type: ColumnType, s: &str) -> Result<Value, String> {
+        match col_type {
+            ColumnType::Integer => s.trim().parse::<i64>().map(Value::Integer).map_err(|e| e.to_string()),
+            ColumnType::Real => s.trim().parse::<f64>().map(Value::Real).map_err(|e| e.to_string()),
+            ColumnType::Text => Ok(Value::Text(s.to_string())),
+            ColumnType::Blob => Ok(Value::Blob(s.as_bytes().to_vec())),
+            ColumnType::Boolean => match s.trim().to_lowercase().as_str() {
+                "true" | "1" => Ok(Value::Boolean(true)),
+                "false" | "0" => Ok(Value::Boolean(false)),
+                _ => Err(format!("invalid boolean: {}", s)),
+            },
+        }
+    }
+}
+
+impl Value {
+    /// エラーの表示に使う型の名前
+    pub(crate) fn type_name(&self) -> &'static str {
+        match self {
+            Value::Integer(_) => "INTEGER",
+            Value::Real(_) => "REAL",
+            Value::Text(_) => "TEXT",
+            Value::Blob(_) => "BLOB",
+            Value::Boolean(_) => "BOOLEAN",
+            Value::Null => "NULL",
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Integer(v) => write!(f, "{}", v),
+            Value::Real(v) => write!(f, "{}", v),
+            Value::Text(v) => write!(f, "{}", v),
+            Value::Blob(v) => {
+                write!(f, "x'")?;
+                for b in v {
+                    write!(f, "{:02x}", b)?;
+                }
+                write!(f, "'")
+            }
+            Value::Boolean(v) => write!(f, "{}", v),
+            Value::Null => write!(f, "NULL"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum RowConversionError {
+    TooLargeLength { col_name: String },
+    TypeMismatch { col_name: String },
+    OutOfRange { col_name: String },
+    ColumnCount { expected: usize, actual: usize },
+    IoError(io::Error),
+}
+
+impl fmt::Display for RowConversionError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            RowConversionError::TooLargeLength { col_name } => {
+                write!(f, "failed to convert columns to row. Column({}) is too long", col_name)
+            }
+            RowConversionError::TypeMismatch { col_name } => {
+                write!(f, "failed to convert columns to row. Column({}) has a value of wrong type", col_name)
+            }
+            RowConversionError::OutOfRange { col_name } => {
+                write!(f, "failed to convert columns to row. Column({}) is out of range", col_name)
+            }
+            RowConversionError::ColumnCount { expected, actual } => {
+                write!(f, "failed to convert columns to row. expected {} columns, but got {}", expected, actual)
+            }
+            RowConversionError::IoError(e) => {
+                write!(f, "failed to convert columns to row. io error: {}", e)
+            }
+        }
+    }
+}
+
+impl From<io::Error> for RowConversionError {
+    fn from(e: io::Error) -> Self {
+        RowConversionError::IoError(e)
+    }
+}
+
+impl Error for RowConversionError {}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct ColumnDef {
+    pub(crate) name: String,
+    pub(crate) col_type: ColumnType,
+    /// TEXTとBLOBの最大バイト数
+    pub(crate) length: usize,
+}
+
+impl ColumnDef {
+    pub(crate) fn new(name: &str, col_type: ColumnType) -> Self {
+        let length = if col_type.has_length() { ColumnType::MAX_LENGTH } else { 0 };
+        ColumnDef { name: name.to_string(), col_type, length }
+    }
+
+    pub(crate) fn with_length(name: &str, col_type: ColumnType, length: usize) -> Self {
+        ColumnDef { name: name.to_string(), col_type, length }
+    }
+}
+
+/// tableの名前とカラム。先頭のカラムはINTEGERのprimary keyで、B-treeのkeyになる。
+///
+/// 行は値を型のタグ付きで詰めたrecordで、長さは値によって変わる(形式はrecordを参照)。
+/// TEXT(n)とBLOB(n)のnは最大バイト数で、leafのcellに収まらない行はoverflowページに続く
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Schema {
+    pub(crate) name: String,
+    pub(crate) columns: Vec<ColumnDef>,
+}
+
+impl Schema {
+    /// 新しいデータベースに最初からあるtable
+    pub(crate) fn users() -> Self {
+        Schema {
+            name: "users".to_string(),
+            columns: vec![
+                ColumnDef::new("id", ColumnType::Integer),
+                ColumnDef::new("username", ColumnType::Text),
+                ColumnDef::new("email", ColumnType::Text),
+            ],
+        }
+    }
+
+    /// カラムを検査してSchemaを作る
+    pub(crate) fn new(name: &str, columns: Vec<ColumnDef>) -> Result<Self, String> {
+        match columns.first() {
+            None => return Err(format!("table {} has no columns", name)),
+            Some(column) if column.col_type != ColumnType::Integer => {
+                return Err(format!("the first column {} must be INTEGER primary key", column.name));
+            }
+            Some(_) => {}
+        }
+        for (i, column) in columns.iter().enumerate() {
+            if columns[..i].iter().any(|c| c.name == column.name) {
+                return Err(format!("duplicate column name: {}", column.name));
+            }
+            if column.length > ColumnType::MAX_LENGTH {
+                return Err(format!("length of column {} is too large: {} (max {})", column.name, column.length, ColumnType::MAX_LENGTH));
+            }
+        }
+        Ok(Schema { name: name.to_string(), columns })
+    }
+
+    /// `create table name (col TYPE[(n)] [primary key], ...)` を読む
+    pub(crate) fn parse(sql: &str) -> Result<Self, String> {
+        match parser::parse(sql) {
+            Ok(Some(ast::Statement::CreateTable { name, columns, position })) => {
+                Schema::from_definition(&name, &columns, position).map_err(|e| e.to_string())
+            }
+            Ok(_) => Err(format!("not a create table statement: {}", sql)),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+
+    /// CREATE TABLE文のカラムの定義からSchemaを作る。positionはtableの名前の位置
+    pub(crate) fn from_definition(name: &str, definitions: &[ColumnDefinition], position: Position) -> Result<Self, ParseError> {
+        let mut columns = vec![];
+        for (i, def) in definitions.iter().enumerate() {
+            if def.primary_key && i != 0 {
+                return Err(ParseError::new(format!("only the first column can be primary key: {}", def.name), def.position));
+            }
+            let length = match def.length {
+                _ if !def.col_type.has_length() => 0,
+                Some(length) => length,
+                None => ColumnType::MAX_LENGTH,
+            };
+            columns.push(ColumnDef::with_length(&def.name, def.col_type, length));
+        }
+        Schema::new(name, columns).map_err(|e| ParseError::new(e, position))
+    }
+
+    /// ファイルに保存するCREATE TABLE文
+    pub(crate) fn to_sql(&self) -> String {
+        let columns: Vec<String> = self.columns.iter().enumerate().map(|(i, column)| {
+            let mut def = format!("{} {}", column.name, column.col_type);
+            // 上限いっぱいの長さは、長さを省略したのと同じ
+            if column.col_type.has_length() && column.length != ColumnType::MAX_LENGTH {
+                def.push_str(&format!("({})", column.length));
+            }
+            if i == 0 {
+                def.push_str(" PRIMARY KEY");
+            }
+            def
+        }).collect();
+        format!("CREATE TABLE {} ({})", self.name, columns.join(", "))
+    }
+
+    pub(crate) fn column_index(&self, name: &str) -> Option<usize> {
+        self.columns.iter().position(|c| c.name == name)
+    }
+
+    /// カラムの値をrecordにしたときの最大バイト数
+    fn max_value_size(&self, index: usize) -> usize {
+        let column = &self.columns[index];
+        let len = match column.col_type {
+            ColumnType::Integer if index == 0 => record::varint_len(u32::MAX as u64 * 2),
+            ColumnType::Integer => record::varint_len(u64::MAX),
+            ColumnType::Real => 8,
+            ColumnType::Boolean => 0,
+            ColumnType::Text | ColumnType::Blob => record::varint_len(column.length as u64) + column.length,
+        };
+        // 型のタグの1バイト
+        1 + len
+    }
+
+    /// 行をrecordにしたときの最大バイト数
+    pub(crate) fn max_record_size(&self) -> usize {
+        record::varint_len(self.columns.len() as u64) + (0..self.columns.len()).map(|i| self.max_value_size(i)).sum::<usize>()
+    }
+
+    /// 値をカラムの型に合わせる。INTEGERはREALとBOOLEANに、TEXTはBLOBにしてよい。
+    /// NULLはkey以外のどのカラムにも入れられる
+    pub(crate) fn coerce(&self, index: usize, value: Value) -> Result<Value, RowConversionError> {
+        let column = &self.columns[index];
+        let value = match (column.col_type, value) {
+            (_, Value::Null) if index > 0 => Value::Null,
+            (ColumnType::Integer, Value::Integer(v)) => Value::Integer(v),
+            (ColumnType::Real, Value::Real(v)) => Value::Real(v),
+            (ColumnType::Real, Value::Integer(v)) => Value::Real(v as f64),
+            (ColumnType::Text, Value::Text(v)) => Value::Text(v),
+            (ColumnType::Blob, Value::Blob(v)) => Value::Blob(v),
+            (ColumnType::Blob, Value::Text(v)) => Value::Blob(v.into_bytes()),
+            (ColumnType::Boolean, Value::Boolean(v)) => Value::Boolean(v),
+            (ColumnType::Boolean, Value::Integer(v @ (0 | 1))) => Value::Boolean(v == 1),
+            _ => return Err(RowConversionError::TypeMismatch { col_name: column.name.clone() }),
+        };
+        let too_long = match &value {
+            Value::Text(v) => v.len() > column.length,
+            Value::Blob(v) => v.len() > column.length,
+            _ => false,
+        };
+        if too_long {
+            return Err(RowConversionError::TooLargeLength { col_name: column.name.clone() });
+        }
+        if index == 0 && !matches!(value, Value::Integer(v) if (0..=u32::MAX as i64).contains(&v)) {
+            return Err(RowConversionError::OutOfRange { col_name: column.name.clone() });
+        }
+        Ok(value)
+    }
+
+    /// 値をカラムの型に合わせてrecordにする
+    pub(crate) fn encode(&self, values: Vec<Value>, buf: &mut Vec<u8>) -> Result<(), RowConversionError> {
+        if values.len() != self.columns.len() {
+            return Err(RowConversionError::ColumnCount { expected: self.columns.len(), actual: values.len() });
+        }
+        let values = values.into_iter().enumerate()
+            .map(|(i, value)| self.coerce(i, value))
+            .collect::<Result<Vec<Value>, RowConversionError>>()?;
+        record::encode(&values, buf);
+        Ok(())
+    }
+
+    /// recordを値に戻す
+    pub(crate) fn decode(&self, row: &[u8]) -> Vec<Value> {
+        match record::decode(row) {
+            Ok(v) => v,
+            Err(e) => panic!("corrupt record in table {}: {}", self.name, e),
+        }
+    }
+
+    /// 行の主キー
+    pub(crate) fn key(&self, row: &[u8]) -> u32 {
+        match record::decode_first(row) {
+            Ok(Value::Integer(v)) => v as u32,
+            other => panic!("corrupt record in table {}: primary key is {:?}", self.name, other),
+        }
+    }
+
+    /// `Row<id:1, username:foo, email:bar>` の形式で表示する
+    pub(crate) fn display(&self, row: &[u8]) -> String {
+        let cols: Vec<String> = self.columns.iter().zip(self.decode(row))
+            .map(|(column, value)| format!("{}:{}", column.name, value))
+            .collect();
+        format!("Row<{}>", cols.join(", "))
+    }
+}
+
+impl Default for Schema {
+    fn default() -> Self {
+        Schema::users()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_to_sql() {
+        let schema = Schema::parse("CREATE TABLE items (id INTEGER PRIMARY KEY, name TEXT(20), price REAL, data BLOB, sold boolean)").unwrap();
+        assert_eq!(schema.name, "items");
+        assert_eq!(schema.columns, vec![
+            ColumnDef::new("id", ColumnType::Integer),
+            ColumnDef::with_length("name", ColumnType::Text, 20),
+            ColumnDef::new("price", ColumnType::Real),
+            ColumnDef::new("data", ColumnType::Blob),
+            ColumnDef::new("sold", ColumnType::Boolean),
+        ]);
+        assert_eq!(Schema::parse(&schema.to_sql()), Ok(schema));
+        assert_eq!(Schema::parse(&Schema::users().to_sql()), Ok(Schema::users()));
+
+        assert!(Schema::parse("create table t (name text)").is_err());
+        assert!(Schema::parse("create table t (id integer, id text)").is_err());
+        assert!(Schema::parse("create table t (id integer, n integer primary key)").is_err());
+        assert!(Schema::parse("create table t (id integer, body text(100000))").is_ok());
+        assert!(Schema::parse("create table t (id integer, body text(4294967296))").is_err());
+        assert!(Schema::parse("create table t (id integer, n float)").is_err());
+        assert!(Schema::parse("create table t id integer").is_err());
+    }
+
+    #[test]
+    fn test_encode_and_decode() {
+        let schema = Schema::parse("create table t (id integer, n integer, x real, s text(8), b blob(4), f boolean)").unwrap();
+        let values = vec![
+            Value::Integer(7),
+            Value::Integer(-3),
+            Value::Real(1.5),
+            Value::Text("abc".to_string()),
+            Value::Blob(vec![0, 1, 0]),
+            Value::Boolean(true),
+        ];
+        let mut row = vec![];
+        schema.encode(values.clone(), &mut row).unwrap();
+        assert!(row.len() <= schema.max_record_size());
+        assert_eq!(schema.key(&row), 7);
+        assert_eq!(schema.decode(&row), values);
+        assert_eq!(schema.display(&row), "Row<id:7, n:-3, x:1.5, s:abc, b:x'000100', f:true>");
+
+        // INTEGERはREALとBOOLEANに、TEXTはBLOBに変換する
+        let mut values = values;
+        values[2] = Value::Integer(2);
+        values[4] = Value::Text("ab".to_string());
+        values[5] = Value::Integer(0);
+        schema.encode(values, &mut row).unwrap();
+        assert_eq!(schema.display(&row), "Row<id:7, n:-3, x:2, s:abc, b:x'6162', f:false>");
+        let nulls = vec![Value::Integer(8), Value::Null, Value::Null, Value::Null, Value::Null, Value::Null];
+        schema.encode(nulls, &mut row).unwrap();
+        assert_eq!(schema.display(&row), "Row<id:8, n:NULL, x:NULL, s:NULL, b:NULL, f:NULL>");
+
+        let encode = |values: Vec<Value>| schema.encode(values, &mut vec![]);
+        let text = |s: &str| Value::Text(s.to_string());
+        assert!(matches!(encode(vec![Value::Integer(1)]), Err(RowConversionError::ColumnCount { .. })));
+        assert!(matches!(
+            encode(vec![Value::Null, Value::Integer(0), Value::Real(0.0), text(""), text(""), Value::Boolean(false)]),
+            Err(RowConversionError::TypeMismatch { .. })
+        ));
+        assert!(matches!(
+            encode(vec![Value::Integer(-1), Value::Integer(0), Value::Real(0.0), text(""), text(""), Value::Boolean(false)]),
+            Err(RowConversionError::OutOfRange { .. })
+        ));
+        assert!(matches!(
+            encode(vec![Value::Integer(1), Value::Real(0.5), Value::Real(0.0), text(""), text(""), Value::Boolean(false)]),
+            Err(RowConversionError::TypeMismatch { .. })
+        ));
+        assert!(matches!(
+            encode(vec![Value::Integer(1), Value::Integer(0), Value::Real(0.0), text("123456789"), text(""), Value::Boolean(false)]),
+            Err(RowConversionError::TooLargeLength { .. })
+        ));
+    }
+}
@@ -1,64 +1,507 @@
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use log::{trace};
-use crate::{ROWS_PER_PAGE, ROW_SIZE, TABLE_MAX_PAGES, PAGE_SIZE};
-use crate::tree::{BTreeNode, BTreeLeafNode, BTreeInternalNode};
+use crate::PAGE_SIZE;
+use crate::cache::{CacheSize, PageCache};
+use crate::header::{Header, HEADER_PAGE_NUM, CATALOG_PAGE_NUM};
+use crate::journal::Journal;
+use crate::wal::Wal;
+use crate::builder::{TreeBuilder, DEFAULT_FILL_FACTOR};
+use crate::catalog::{CatalogEntry, DEFAULT_TABLE};
+use crate::schema::Schema;
+use crate::tree::{BTreeNode, BTreeLeafNode, BTreeInternalNode, BTreeFreeNode, BTreeOverflowNode, KV, KC, Overflow};
 use std::fs::{File, OpenOptions};
 use std::fs;
+use std::collections::{HashMap, HashSet};
 use std::convert::TryInto;
 use std::io::{Seek, Write, SeekFrom, Read};
 
+/// 1つのデータベースファイル。catalogに載っているtableのうち、
+/// 今操作しているtableのrootとschemaを持つ
 pub(crate) struct Table {
     pub(crate) pager: Pager,
     pub(crate) root_page_num: usize,
+    pub(crate) schema: Schema,
+    // catalogの中身。開いたときとROLLBACKしたときに読み直す
+    pub(crate) catalog: Vec<CatalogEntry>,
 }
 
 impl Table {
+    /// ファイルを開く。新しいファイルなら既定のusers tableを作る
     pub(crate) fn new<P>(filename: P) -> Result<Self, String>
         where
             P: AsRef<Path>,
     {
-        let mut pager = Pager::new(&filename)?;
-        trace!("Table::new: initialize Table for {:?}", &filename.as_ref().display());
-        let mut root_page_num = 0;
-        if pager.num_pages == 1 {
-            trace!("Table::new: new_table, initialize it");
-            if let Some(BTreeNode::Leaf(node)) = pager.get_page_mut(0) {
-                node.is_root = 1
+        let mut table = Self::open(filename)?;
+        if table.catalog.is_empty() {
+            table.create_table(Schema::users())?;
+        }
+        table.select_table(DEFAULT_TABLE).or_else(|_| {
+            // usersがなければcatalogの最初のtableを使う
+            let name = table.catalog.first().map(|entry| entry.name.clone()).unwrap_or_default();
+            table.select_table(&name)
+        })?;
+        Ok(table)
+    }
+
+    /// ファイルを開いてcatalogを読む。tableは作らないし選ばない
+    fn open<P>(filename: P) -> Result<Self, String>
+        where
+            P: AsRef<Path>,
+    {
+        // 前回のcommitの途中で落ちていたら、ジャーナルから元に戻してから開く
+        if Journal::rollback(filename.as_ref(), true)? {
+            trace!("Table::open: rolled back incomplete transaction from hot journal");
+        }
+        let pager = Pager::new(&filename)?;
+        trace!("Table::open: initialize Table for {:?}", &filename.as_ref().display());
+        let mut table = Table { pager, root_page_num: CATALOG_PAGE_NUM, schema: Schema::users(), catalog: vec![] };
+        table.load_catalog()?;
+        Ok(table)
+    }
+
+    /// catalogのB-treeを読み直す
+    fn load_catalog(&mut self) -> Result<(), String> {
+        let mut catalog = vec![];
+        self.for_each_row(CATALOG_PAGE_NUM, |_, row| {
+            catalog.push(CatalogEntry::decode(row)?);
+            Ok(())
+        })?;
+        trace!("Table::load_catalog: {} entries", catalog.len());
+        self.catalog = catalog;
+        Ok(())
+    }
+
+    /// nameのtableのschema。なければNone
+    pub(crate) fn table_schema(&self, name: &str) -> Option<Schema> {
+        self.catalog.iter().find(|entry| entry.name == name).and_then(|entry| entry.table_schema())
+    }
+
+    /// 以降の操作の対象をnameのtableにする
+    pub(crate) fn select_table(&mut self, name: &str) -> Result<(), String> {
+        let entry = match self.catalog.iter().find(|entry| entry.name == name) {
+            Some(v) => v,
+            None => return Err(format!("no such table: {}", name)),
+        };
+        let schema = entry.table_schema().ok_or_else(|| format!("{} is not a table", name))?;
+        if self.root_page_num != entry.root_page_num {
+            trace!("Table::select_table: {}, root_page_num: {}", name, entry.root_page_num);
+        }
+        self.root_page_num = entry.root_page_num;
+        self.schema = schema;
+        Ok(())
+    }
+
+    /// CREATE TABLEする。空のrootのleafを確保してcatalogに載せ、rootのpage_numを返す
+    pub(crate) fn create_table(&mut self, schema: Schema) -> Result<usize, String> {
+        if self.catalog.iter().any(|entry| entry.name == schema.name) {
+            return Err(format!("table {} already exists", schema.name));
+        }
+        if !CatalogEntry::fits(&schema) {
+            return Err(format!("table definition is too long: {}", schema.to_sql()));
+        }
+        let root_page_num = self.pager.new_page_num();
+        let root = BTreeLeafNode { is_root: 1, ..BTreeLeafNode::default() };
+        self.pager.put_page(root_page_num, BTreeNode::Leaf(root));
+        let entry = CatalogEntry {
+            id: self.catalog.iter().map(|entry| entry.id).max().unwrap_or(0) + 1,
+            entry_type: CatalogEntry::TABLE.to_string(),
+            name: schema.name.clone(),
+            root_page_num,
+            sql: schema.to_sql(),
+        };
+        trace!("Table::create_table: {}, root_page_num: {}", entry.sql, root_page_num);
+        let mut row = vec![];
+        entry.encode(&mut row)?;
+        if !self.insert_row(CATALOG_PAGE_NUM, entry.id, row) {
+            return Err(format!("duplicate catalog id: {}", entry.id));
+        }
+        self.catalog.push(entry);
+        Ok(root_page_num)
+    }
+
+    /// root_page_numの木にkeyの行を挿入する。keyが既にあれば何もせずにfalseを返す
+    pub(crate) fn insert_row(&mut self, root_page_num: usize, key: u32, row: Vec<u8>) -> bool {
+        let mut cursor = Cursor::find_insert_position(self, root_page_num, key);
+        let cell_num = cursor.cell_num;
+        let (exists, has_room) = match cursor.get_page() {
+            Some(BTreeNode::Leaf(node)) => (node.key_values.get(cell_num).is_some_and(|kv| kv.key == key), node.has_room(row.len())),
+            _ => unreachable!("Table::insert_row: find_insert_position must return leaf node"),
+        };
+        trace!("Table::insert_row: root_page_num: {}, key: {}, exists: {}", root_page_num, key, exists);
+        if exists {
+            return false;
+        }
+        // overflowページを確保するとcacheから追い出されることがあるので、leafはその後に取り直す
+        let page_num = cursor.page_num;
+        let kv = self.pager.new_cell(key, row);
+        if !has_room {
+            log::debug!("leaf is full");
+            self.pager.split_and_insert(page_num, cell_num, kv);
+        } else if let Some(BTreeNode::Leaf(node)) = self.pager.get_page_mut(page_num) {
+            node.insert_at(cell_num, kv);
+        }
+        true
+    }
+
+    /// root_page_numの木のkeyの行を置き換える。keyがなければfalseを返す。
+    /// 大きくなってleafに収まらなくなった場合は、消してから挿入し直す
+    pub(crate) fn replace_row(&mut self, root_page_num: usize, key: u32, row: Vec<u8>) -> bool {
+        let mut cursor = Cursor::find_insert_position(self, root_page_num, key);
+        let (page_num, cell_num) = (cursor.page_num, cursor.cell_num);
+        let (fits, old_overflow) = match cursor.get_page() {
+            Some(BTreeNode::Leaf(node)) => {
+                let old = match node.key_values.get(cell_num) {
+                    Some(kv) if kv.key == key => kv,
+                    _ => return false,
+                };
+                let fits = node.used_size() - BTreeLeafNode::cell_size(old.record_len()) + BTreeLeafNode::cell_size(row.len())
+                    <= BTreeLeafNode::NODE_SPACE_FOR_CELLS;
+                (fits, old.overflow.clone())
             }
-        } else {
-            for num_page in 0..pager.num_pages {
-                if let Some(page) = pager.get_page(num_page) {
-                    if page.is_root() > 0 {
-                        root_page_num = num_page;
+            _ => unreachable!("Table::replace_row: find_insert_position must return leaf node"),
+        };
+        if !fits {
+            trace!("Table::replace_row: key {} does not fit in its leaf. delete and insert again", key);
+            cursor.delete();
+            self.insert_row(root_page_num, key, row);
+            return true;
+        }
+        if let Some(overflow) = old_overflow {
+            self.pager.free_overflow(overflow.page_num);
+        }
+        let kv = self.pager.new_cell(key, row);
+        if let Some(BTreeNode::Leaf(node)) = self.pager.get_page_mut(page_num) {
+            node.replace_cell(cell_num, kv);
+        }
+        true
+    }
+
+    /// root_page_numの木の行をkeyの順に全て渡す
+    pub(crate) fn for_each_row<F>(&mut self, root_page_num: usize, mut f: F) -> Result<(), String>
+        where
+            F: FnMut(u32, &[u8]) -> Result<(), String>,
+    {
+        let mut page_num = root_page_num;
+        while let Some(BTreeNode::Internal(node)) = self.pager.get_page(page_num) {
+            page_num = node.key_children.first().map_or(node.right_child, |kc| kc.child) as usize;
+        }
+        while page_num != 0 {
+            let node = match self.pager.get_page(page_num) {
+                Some(BTreeNode::Leaf(node)) => node.clone(),
+                _ => return Err(format!("for_each_row: page {} is not a leaf", page_num)),
+            };
+            for kv in &node.key_values {
+                let row = self.pager.read_record(kv);
+                f(kv.key, &row)?;
+            }
+            page_num = node.next_leaf as usize;
+        }
+        Ok(())
+    }
+
+    /// start以上end以下のkeyを、範囲に掛かる子だけを辿って集める
+    pub(crate) fn collect_keys(&mut self, page_num: usize, start: u32, end: u32, keys: &mut Vec<u32>) {
+        let node = self.pager.get_page(page_num).expect("collect_keys: page not found!").clone();
+        match node {
+            BTreeNode::Leaf(node) => {
+                keys.extend(node.key_values.iter().map(|kv| kv.key).filter(|key| start <= *key && *key <= end));
+            }
+            BTreeNode::Internal(node) => {
+                // 各子のkeyは直前のkeyより大きく、自身のkey以下
+                let mut lower = None;
+                for kc in &node.key_children {
+                    if kc.key >= start && lower.is_none_or(|lower| lower < end) {
+                        self.collect_keys(kc.child as usize, start, end, keys);
                     }
+                    lower = Some(kc.key);
+                }
+                if lower.is_none_or(|lower| lower < end) {
+                    self.collect_keys(node.right_child as usize, start, end, keys);
                 }
             }
+            BTreeNode::Free(_) | BTreeNode::Overflow(_) => unreachable!("collect_keys: page {} is not in the tree", page_num),
         }
-        trace!("Table::new: root_page_num: {}", root_page_num);
-        Ok(Table { pager, root_page_num })
     }
 
-    pub(crate) fn page_num(&self, row_num: usize) -> usize {
-        row_num / ROWS_PER_PAGE
+    /// 変更をディスクに書き出す。synchronousの設定に従ってfsyncまで済んでから返る
+    pub(crate) fn commit(&mut self) -> Result<(), String> {
+        self.pager.commit()
+    }
+
+    pub(crate) fn in_transaction(&self) -> bool {
+        self.pager.in_transaction()
+    }
+
+    /// BEGIN。COMMITかROLLBACKするまで変更をcommitしない
+    pub(crate) fn begin(&mut self) -> Result<(), String> {
+        self.pager.begin()
+    }
+
+    /// COMMIT。変更を取り消せないようにする。ディスクへの書き出しはcommitで行う
+    pub(crate) fn end_transaction(&mut self) -> Result<(), String> {
+        self.pager.end_transaction()
+    }
+
+    /// ROLLBACK。BEGINの後に変更したページとcatalogを元に戻す
+    pub(crate) fn rollback(&mut self) -> Result<(), String> {
+        self.pager.rollback()?;
+        self.reload()
+    }
+
+    pub(crate) fn has_savepoint(&self, name: &str) -> bool {
+        self.pager.has_savepoint(name)
+    }
+
+    /// SAVEPOINT name
+    pub(crate) fn savepoint(&mut self, name: &str) -> Result<(), String> {
+        self.pager.savepoint(name)
+    }
+
+    /// RELEASE name。トランザクションが終わった場合はtrueを返す
+    pub(crate) fn release(&mut self, name: &str) -> Result<bool, String> {
+        self.pager.release(name)
     }
 
-    pub(crate) fn bytes_offset(&self, row_num: usize) -> usize {
-        let rows = row_num % ROWS_PER_PAGE;
-        rows * ROW_SIZE
+    /// ROLLBACK TO name。savepointを作った後に変更したページとcatalogを元に戻す
+    pub(crate) fn rollback_to(&mut self, name: &str) -> Result<(), String> {
+        self.pager.rollback_to(name)?;
+        self.reload()
     }
 
+    /// 戻したcatalogを読み直す。操作していたtableがなくなっていれば既定のtableに戻る
+    fn reload(&mut self) -> Result<(), String> {
+        self.load_catalog()?;
+        let name = self.schema.name.clone();
+        self.select_table(&name).or_else(|_| self.select_table(DEFAULT_TABLE))
+    }
+
+    /// commitしてから閉じる。終わっていないトランザクションはROLLBACKする。
+    /// WALモードならcheckpointしておき、空の-walファイルだけを残す
     pub(crate) fn close(&mut self) -> Result<(), String> {
-        self.pager.flush()
+        if self.in_transaction() {
+            self.rollback()?;
+        }
+        self.pager.commit()?;
+        self.pager.checkpoint().map(|_| ())
+    }
+
+    /// catalogと全てのtableを新しいファイルに詰め直して、元のファイルと置き換える。減ったバイト数を返す。
+    /// leafはkeyの順に隙間なく並び、空きページもなくなる
+    pub(crate) fn vacuum(&mut self) -> Result<u64, String> {
+        if self.in_transaction() {
+            return Err("cannot vacuum within a transaction".to_string());
+        }
+        self.pager.commit()?;
+        self.pager.checkpoint()?;
+        let path = self.pager.path.clone();
+        let old_size = file_size(&path)?;
+        trace!("Table::vacuum: {} tables, old size: {}", self.catalog.len(), old_size);
+
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push("-vacuum");
+        let tmp_path = PathBuf::from(tmp_path);
+        // 前回のvacuumの途中で落ちていたら、作りかけのファイルは捨てる
+        for stale in [tmp_path.clone(), Journal::path_for(&tmp_path), Wal::path_for(&tmp_path)] {
+            if stale.exists() {
+                fs::remove_file(&stale).map_err(|e| e.to_string())?;
+            }
+        }
+        let mut new_table = Table::open(&tmp_path)?;
+        new_table.pager.set_synchronous(self.pager.synchronous());
+        for entry in self.catalog.clone() {
+            let schema = entry.table_schema().ok_or_else(|| format!("{} is not a table", entry.name))?;
+            let root_page_num = new_table.create_table(schema)?;
+            // leafに何行ずつ詰めるかを決めるため、先に行の大きさを集める
+            let mut record_sizes = vec![];
+            self.for_each_row(entry.root_page_num, |_, row| {
+                record_sizes.push(row.len());
+                Ok(())
+            })?;
+            trace!("Table::vacuum: table {}: {} rows", entry.name, record_sizes.len());
+            let mut builder = TreeBuilder::new(&mut new_table.pager, root_page_num, &record_sizes, DEFAULT_FILL_FACTOR);
+            self.for_each_row(entry.root_page_num, |key, row| builder.push(key, row.to_vec()))?;
+            builder.finish()?;
+        }
+        new_table.close()?;
+        drop(new_table);
+        if Wal::exists(&tmp_path) {
+            fs::remove_file(Wal::path_for(&tmp_path)).map_err(|e| e.to_string())?;
+        }
+
+        // renameはアトミックなので、落ちても元のファイルか新しいファイルのどちらかが残る
+        let journal_mode = self.pager.journal_mode();
+        if let Some(wal) = self.pager.wal.take() {
+            wal.delete()?;
+        }
+        fs::rename(&tmp_path, &path).map_err(|e| e.to_string())?;
+        if self.pager.synchronous != Synchronous::Off {
+            sync_dir(&path)?;
+        }
+        let mut table = Table::new(&path)?;
+        table.pager.set_synchronous(self.pager.synchronous());
+        table.pager.set_cache_size(CacheSize::Pages(self.pager.cache_capacity()))?;
+        table.pager.set_journal_mode(journal_mode)?;
+        table.select_table(&self.schema.name)?;
+        *self = table;
+        let new_size = file_size(&path)?;
+        trace!("Table::vacuum: new size: {}", new_size);
+        Ok(old_size.saturating_sub(new_size))
+    }
+
+    /// 空のtableに行をまとめて読み込み、木を葉から一度で組み立てる。読み込んだ行数を返す。
+    /// leafはfill_factor(%)まで埋める。keyの順に並んでいなければ先に並べ替える
+    pub(crate) fn bulk_load(&mut self, rows: impl IntoIterator<Item = (u32, Vec<u8>)>, fill_factor: usize) -> Result<usize, String> {
+        if !(1..=100).contains(&fill_factor) {
+            return Err(format!("fill factor must be between 1 and 100: {}", fill_factor));
+        }
+        let root_page_num = self.root_page_num;
+        match self.pager.get_page(root_page_num) {
+            Some(BTreeNode::Leaf(node)) if node.num_cells == 0 => {}
+            _ => return Err("bulk load requires an empty table".to_string()),
+        }
+        let mut rows: Vec<(u32, Vec<u8>)> = rows.into_iter().collect();
+        if !rows.is_sorted_by_key(|(key, _)| *key) {
+            trace!("Table::bulk_load: rows are not sorted. sort them first");
+            rows.sort_by_key(|(key, _)| *key);
+        }
+        // 途中で失敗すると作りかけの木が残るので、組み立てる前に確かめる
+        if let Some(pair) = rows.windows(2).find(|pair| pair[0].0 == pair[1].0) {
+            return Err(format!("duplicate key: {}", pair[0].0));
+        }
+        trace!("Table::bulk_load: {} rows, fill_factor: {}", rows.len(), fill_factor);
+        let num_rows = rows.len();
+        let record_sizes: Vec<usize> = rows.iter().map(|(_, row)| row.len()).collect();
+        let mut builder = TreeBuilder::new(&mut self.pager, root_page_num, &record_sizes, fill_factor);
+        for (key, row) in rows {
+            builder.push(key, row)?;
+        }
+        builder.finish()?;
+        Ok(num_rows)
     }
 }
 
+fn file_size(path: &Path) -> Result<u64, String> {
+    fs::metadata(path).map(|m| m.len()).map_err(|e| e.to_string())
+}
+
 type Page = BTreeNode;
 
+/// commitをどこまでディスクに保証するか。SQLiteのPRAGMA synchronousに相当する。
+/// どのレベルでもページはwrite_allで最後まで書き出す
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Synchronous {
+    /// fsyncしない。OSが落ちるとcommit済みの変更が失われたり、ページが壊れたりすることがある
+    Off,
+    /// データファイルを上書きする前にジャーナルを、commitのたびにデータファイルをfsyncする
+    Normal,
+    /// Normalに加えてファイルのメタデータもfsyncし、ジャーナルの削除やファイルの作成もディレクトリごとfsyncする
+    Full,
+}
+
+impl std::str::FromStr for Synchronous {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "off" | "0" => Ok(Synchronous::Off),
+            "normal" | "1" => Ok(Synchronous::Normal),
+            "full" | "2" => Ok(Synchronous::Full),
+            _ => Err(format!("unknown synchronous level: {}", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for Synchronous {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Synchronous::Off => write!(f, "off"),
+            Synchronous::Normal => write!(f, "normal"),
+            Synchronous::Full => write!(f, "full"),
+        }
+    }
+}
+
+/// データファイルの変更をどうやって原子的にするか。SQLiteのPRAGMA journal_modeに相当する
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum JournalMode {
+    /// 上書きする前のページをロールバックジャーナルに残し、commitしたら消す
+    Delete,
+    /// 変更したページを-walファイルに追記し、checkpointでデータファイルに書き戻す
+    Wal,
+}
+
+impl std::str::FromStr for JournalMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "delete" => Ok(JournalMode::Delete),
+            "wal" => Ok(JournalMode::Wal),
+            _ => Err(format!("unknown journal mode: {}", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for JournalMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            JournalMode::Delete => write!(f, "delete"),
+            JournalMode::Wal => write!(f, "wal"),
+        }
+    }
+}
+
+pub(crate) const DEFAULT_CACHE_SIZE: CacheSize = CacheSize::Pages(1000);
+pub(crate) const DEFAULT_SYNCHRONOUS: Synchronous = Synchronous::Full;
+/// WALのframeがこれだけ溜まったら、commitのついでにcheckpointする
+pub(crate) const WAL_AUTOCHECKPOINT: usize = 1000;
+
+/// pathを含むディレクトリをfsyncして、ファイルの作成や削除を永続化する
+#[cfg(unix)]
+pub(crate) fn sync_dir(path: &Path) -> Result<(), String> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    trace!("sync_dir: {}", dir.display());
+    File::open(dir).and_then(|dir| dir.sync_all()).map_err(|e| e.to_string())
+}
+
+// Windowsではディレクトリを開けないので何もしない
+#[cfg(not(unix))]
+pub(crate) fn sync_dir(_path: &Path) -> Result<(), String> {
+    Ok(())
+}
+
+/// 変更を取り消すために、savepointを作った後で最初に変更する前のページを残しておく。
+/// BEGINは名前のないsavepointとして扱う
+struct Savepoint {
+    name: Option<String>,
+    // savepointを作った時点であったページだけを入れる。後から増えたページは捨てればよい
+    pages: HashMap<usize, Page>,
+    num_pages: usize,
+    free_list_head: u32,
+}
+
 pub(crate) struct Pager {
+    path: PathBuf,
     file: File,
     file_length: usize,
-    pages: Vec<Option<Page>>,
+    cache: PageCache<Page>,
+    // headerの内容。page 0はキャッシュに入れず、dirtyならここから書き出す
+    free_list_head: u32,
+    // 読み込んでから変更されたページ。flushや追い出しのときにこれだけ書き出す
+    dirty: HashSet<usize>,
     num_pages: usize,
+    synchronous: Synchronous,
+    // データファイルを書き換え始めてからcommitが終わるまでの間だけ存在する
+    journal: Option<Journal>,
+    // WALモードのときだけ存在する。-walファイルがあればWALモードで開く
+    wal: Option<Wal>,
+    // BEGINやSAVEPOINTで積み、COMMITやROLLBACKで空になる。空でなければトランザクション中
+    savepoints: Vec<Savepoint>,
+    // internalノードのkeyの上限。テストでは小さくして多段の木を少ない行数で作る
+    internal_max_cells: usize,
 }
 
 impl Pager {
@@ -67,6 +510,7 @@ impl Pager {
             .read(true)
             .write(true)
             .create(true)
+            .truncate(false)
             .open(&filename)
         {
             Ok(v) => v,
@@ -76,103 +520,549 @@ impl Pager {
             Ok(v) => v,
             Err(e) => return Err(format!("{}", e)),
         };
-        let file_length = metadata.len().try_into().unwrap();
-        let pages = vec![None; TABLE_MAX_PAGES];
+        let file_length: usize = metadata.len().try_into().unwrap();
+        let cache = PageCache::new(DEFAULT_CACHE_SIZE);
         trace!("file_length: {}", file_length);
         trace!("PAGE_SIZE: {}", PAGE_SIZE);
-        let num_pages = ::std::cmp::max(((file_length as f32) / (PAGE_SIZE as f32)).ceil() as usize, 1);
-        trace!("num_pages: {}", num_pages);
-        Ok(Pager {
+        // 前回WALモードだったなら、commit済みのframeを読み直してWALモードで開く
+        let wal = if Wal::exists(filename.as_ref()) {
+            Some(Wal::open(filename.as_ref())?)
+        } else {
+            None
+        };
+        let is_new = file_length == 0 && wal.as_ref().is_none_or(|wal| wal.num_pages() == 0);
+        let header = Header::new();
+        let mut pager = Pager {
+            path: filename.as_ref().to_path_buf(),
             file,
             file_length,
-            pages,
-            num_pages,
-        })
+            cache,
+            free_list_head: header.free_list_head,
+            dirty: HashSet::new(),
+            num_pages: header.num_pages as usize,
+            synchronous: DEFAULT_SYNCHRONOUS,
+            journal: None,
+            wal,
+            savepoints: vec![],
+            internal_max_cells: BTreeInternalNode::INTERNAL_PAGE_MAX_CELLS,
+        };
+        if is_new {
+            trace!("Pager::new: new database, initialize header and catalog");
+            pager.mark_dirty(HEADER_PAGE_NUM);
+            if let Some(BTreeNode::Leaf(node)) = pager.get_page_mut(CATALOG_PAGE_NUM) {
+                node.is_root = 1;
+            }
+        } else {
+            let mut buf = vec![0u8; PAGE_SIZE];
+            pager.read_page(HEADER_PAGE_NUM, &mut buf)?;
+            let header = Header::deserialize(&buf)?;
+            trace!("Pager::new: header: {:?}", header);
+            pager.free_list_head = header.free_list_head;
+            pager.num_pages = header.num_pages as usize;
+        }
+        trace!("num_pages: {}", pager.num_pages);
+        Ok(pager)
+    }
+
+    pub(crate) fn header(&self) -> Header {
+        Header {
+            num_pages: self.num_pages as u32,
+            free_list_head: self.free_list_head,
+            ..Header::new()
+        }
+    }
+
+    pub(crate) fn cache_capacity(&self) -> usize {
+        self.cache.capacity()
+    }
+
+    pub(crate) fn cache_len(&self) -> usize {
+        self.cache.len()
+    }
+
+    pub(crate) fn num_pages(&self) -> usize {
+        self.num_pages
+    }
+
+    pub(crate) fn synchronous(&self) -> Synchronous {
+        self.synchronous
+    }
+
+    pub(crate) fn set_synchronous(&mut self, synchronous: Synchronous) {
+        trace!("Pager::set_synchronous: {}", synchronous);
+        self.synchronous = synchronous;
+    }
+
+    pub(crate) fn journal_mode(&self) -> JournalMode {
+        if self.wal.is_some() {
+            JournalMode::Wal
+        } else {
+            JournalMode::Delete
+        }
+    }
+
+    /// journal modeを切り替える。変更をcommitしてから切り替え、
+    /// WALモードをやめるときはcheckpointしてから-walファイルを消す
+    pub(crate) fn set_journal_mode(&mut self, mode: JournalMode) -> Result<(), String> {
+        trace!("Pager::set_journal_mode: {}", mode);
+        if self.journal_mode() == mode {
+            return Ok(());
+        }
+        if self.in_transaction() {
+            return Err("cannot change journal mode within a transaction".to_string());
+        }
+        self.commit()?;
+        match mode {
+            JournalMode::Wal => {
+                self.wal = Some(Wal::open(&self.path)?);
+            }
+            JournalMode::Delete => {
+                self.checkpoint()?;
+                if let Some(wal) = self.wal.take() {
+                    wal.delete()?;
+                }
+            }
+        }
+        if self.synchronous == Synchronous::Full {
+            sync_dir(&self.path)?;
+        }
+        Ok(())
+    }
+
+    /// WALのcommit済みのframeをデータファイルに書き戻す。書き戻したページ数を返す
+    pub(crate) fn checkpoint(&mut self) -> Result<usize, String> {
+        let sync = self.synchronous != Synchronous::Off;
+        let wal = match self.wal.as_mut() {
+            Some(wal) => wal,
+            None => return Ok(0),
+        };
+        trace!("Pager::checkpoint");
+        let pages = wal.checkpoint(&mut self.file, sync)?;
+        self.file_length = self.file.metadata().map_err(|e| e.to_string())?.len().try_into().unwrap();
+        Ok(pages)
+    }
+
+    pub(crate) fn in_transaction(&self) -> bool {
+        !self.savepoints.is_empty()
+    }
+
+    pub(crate) fn has_savepoint(&self, name: &str) -> bool {
+        self.find_savepoint(name).is_some()
+    }
+
+    /// 一番新しいnameのsavepointの位置
+    fn find_savepoint(&self, name: &str) -> Option<usize> {
+        self.savepoints.iter().rposition(|sp| sp.name.as_deref().is_some_and(|n| n.eq_ignore_ascii_case(name)))
+    }
+
+    fn push_savepoint(&mut self, name: Option<String>) -> Result<(), String> {
+        trace!("Pager::push_savepoint: {:?}", name);
+        // トランザクションを始めるときは、それまでの変更を先にcommitしておく
+        if !self.in_transaction() {
+            self.commit()?;
+        }
+        self.savepoints.push(Savepoint {
+            name,
+            pages: HashMap::new(),
+            num_pages: self.num_pages,
+            free_list_head: self.free_list_head,
+        });
+        Ok(())
+    }
+
+    /// トランザクションを始める
+    pub(crate) fn begin(&mut self) -> Result<(), String> {
+        if self.in_transaction() {
+            return Err("cannot start a transaction within a transaction".to_string());
+        }
+        self.push_savepoint(None)
+    }
+
+    /// savepointを作る。トランザクションの外ならトランザクションも始める
+    pub(crate) fn savepoint(&mut self, name: &str) -> Result<(), String> {
+        self.push_savepoint(Some(name.to_string()))
+    }
+
+    /// 残しておいたページを捨ててトランザクションを終える
+    pub(crate) fn end_transaction(&mut self) -> Result<(), String> {
+        trace!("Pager::end_transaction");
+        if !self.in_transaction() {
+            return Err("no transaction is active".to_string());
+        }
+        self.savepoints.clear();
+        Ok(())
+    }
+
+    /// nameのsavepointとそれより後のsavepointを取り除く。
+    /// 残したページは1つ外側のsavepointに引き継ぐ。トランザクションが終わった場合はtrueを返す
+    pub(crate) fn release(&mut self, name: &str) -> Result<bool, String> {
+        let index = match self.find_savepoint(name) {
+            Some(v) => v,
+            None => return Err(format!("no such savepoint: {}", name)),
+        };
+        trace!("Pager::release: {} at {}", name, index);
+        let released = self.savepoints.split_off(index);
+        match self.savepoints.last_mut() {
+            Some(outer) => {
+                // 外側のsavepointの方が古い内容を持っているので、無いページだけを引き継ぐ
+                for savepoint in released {
+                    for (page_num, page) in savepoint.pages {
+                        if page_num < outer.num_pages {
+                            outer.pages.entry(page_num).or_insert(page);
+                        }
+                    }
+                }
+                Ok(false)
+            }
+            None => Ok(true),
+        }
+    }
+
+    /// index以降のsavepointで残したページを、indexのsavepointを作った時点の状態に戻す
+    fn restore_savepoint(&mut self, index: usize) -> Result<(), String> {
+        let mut restored = self.savepoints.split_off(index);
+        let num_pages = restored[0].num_pages;
+        let free_list_head = restored[0].free_list_head;
+        // 同じページは一番古いsavepointの内容に戻す
+        let mut pages = HashMap::new();
+        for savepoint in restored.iter_mut().rev() {
+            pages.extend(savepoint.pages.drain().filter(|(page_num, _)| *page_num < num_pages));
+        }
+        trace!("Pager::restore_savepoint: restore {} pages, num_pages: {} -> {}", pages.len(), self.num_pages, num_pages);
+        // savepointの後に増えたページは捨てる
+        for page_num in num_pages..self.num_pages {
+            self.cache.remove(page_num);
+            self.dirty.remove(&page_num);
+        }
+        match self.wal.as_mut() {
+            Some(wal) => wal.truncate(num_pages),
+            None => {
+                // 途中で追い出して書き出した、増えたページも消す
+                let length = std::cmp::min(self.file_length, num_pages * PAGE_SIZE);
+                if self.file_length > length {
+                    self.file.set_len(length as u64).map_err(|e| e.to_string())?;
+                    self.file_length = length;
+                }
+            }
+        }
+        self.num_pages = num_pages;
+        self.free_list_head = free_list_head;
+        self.mark_dirty(HEADER_PAGE_NUM);
+        for (page_num, page) in pages {
+            self.make_room(page_num)?;
+            self.cache.insert(page_num, page);
+            self.mark_dirty(page_num);
+        }
+        Ok(())
+    }
+
+    /// nameのsavepointを作った時点に戻す。savepointは残るのでトランザクションは続く
+    pub(crate) fn rollback_to(&mut self, name: &str) -> Result<(), String> {
+        let index = match self.find_savepoint(name) {
+            Some(v) => v,
+            None => return Err(format!("no such savepoint: {}", name)),
+        };
+        trace!("Pager::rollback_to: {} at {}", name, index);
+        let savepoint_name = self.savepoints[index].name.clone();
+        self.restore_savepoint(index)?;
+        self.savepoints.push(Savepoint {
+            name: savepoint_name,
+            pages: HashMap::new(),
+            num_pages: self.num_pages,
+            free_list_head: self.free_list_head,
+        });
+        Ok(())
+    }
+
+    /// トランザクションを始めた時点のページに戻してcommitする
+    pub(crate) fn rollback(&mut self) -> Result<(), String> {
+        if !self.in_transaction() {
+            return Err("no transaction is active".to_string());
+        }
+        trace!("Pager::rollback");
+        self.restore_savepoint(0)?;
+        // 途中で追い出して書き出したページも元に戻す
+        self.commit()
+    }
+
+    /// トランザクション中なら、ページを変更する前の内容を一番新しいsavepointに残しておく
+    fn save_original(&mut self, page_num: usize) {
+        let savepoint = match self.savepoints.last_mut() {
+            Some(v) => v,
+            None => return,
+        };
+        if page_num >= savepoint.num_pages || savepoint.pages.contains_key(&page_num) {
+            return;
+        }
+        if let Some(page) = self.cache.peek(page_num) {
+            trace!("Pager::save_original: page_num: {}", page_num);
+            savepoint.pages.insert(page_num, page.clone());
+        }
+    }
+
+    pub(crate) fn is_dirty(&self, page_num: usize) -> bool {
+        self.dirty.contains(&page_num)
+    }
+
+    pub(crate) fn dirty_count(&self) -> usize {
+        self.dirty.len()
+    }
+
+    /// 変更したページとして記録する
+    pub(crate) fn mark_dirty(&mut self, page_num: usize) {
+        self.dirty.insert(page_num);
+    }
+
+    pub(crate) fn internal_max_cells(&self) -> usize {
+        self.internal_max_cells
+    }
+
+    /// internalノードのkeyの上限を変える。ページに収まる上限より大きくはできない
+    pub(crate) fn set_internal_max_cells(&mut self, max_cells: usize) {
+        assert!((3..=BTreeInternalNode::INTERNAL_PAGE_MAX_CELLS).contains(&max_cells), "Pager::set_internal_max_cells: {} is out of range", max_cells);
+        self.internal_max_cells = max_cells;
+    }
+
+    /// キャッシュの容量を変える。溢れた分は書き戻してから追い出す
+    pub(crate) fn set_cache_size(&mut self, size: CacheSize) -> Result<(), String> {
+        trace!("Pager::set_cache_size: {:?}", size);
+        self.cache.set_capacity(size);
+        self.evict(self.cache.capacity())
     }
 
     pub(crate) fn new_internal_page(&mut self, new_page_num: usize) -> Option<&Page> {
         log::trace!("new_page");
         trace!("new_page: page_num: {}", new_page_num);
-        let page = BTreeNode::Internal(BTreeInternalNode::default());
-        self.pages[new_page_num] = Some(page);
-        self.pages[new_page_num].as_ref()
+        let _ = self.new_internal_page_mut(new_page_num);
+        self.cache.get(new_page_num)
     }
 
     pub(crate) fn new_internal_page_mut(&mut self, new_page_num: usize) -> Option<&mut Page> {
         log::trace!("new_page");
         trace!("new_page: page_num: {}", new_page_num);
         let page = BTreeNode::Internal(BTreeInternalNode::default());
-        self.pages[new_page_num] = Some(page);
-        self.pages[new_page_num].as_mut()
+        if let Err(e) = self.make_room(new_page_num) {
+            log::error!("failed to evict page! {}", e);
+            return None;
+        }
+        self.cache.insert(new_page_num, page);
+        self.mark_dirty(new_page_num);
+        self.cache.get_mut(new_page_num)
     }
 
     pub(crate) fn get_page(&mut self, page_num: usize) -> Option<&Page> {
         log::trace!("get_page");
-        if self.pages[page_num].is_some() {
+        if self.cache.contains(page_num) {
             log::trace!("get_page: page is already on memory. return");
-            return self.pages[page_num].as_ref();
+            return self.cache.get(page_num);
         };
         log::trace!("get_page: page is not on memory. try to read from file");
-        let mut num_pages = self.file_length / PAGE_SIZE;
-        trace!("get_page: num_pages: {}", num_pages);
-        if self.file_length % PAGE_SIZE != 0 {
-            num_pages += 1;
-        }
         trace!("get_page: page_num: {}", page_num);
-        if page_num <= num_pages {
-            trace!("page_num is equal to or smaller than num_pages");
-            match self
-                .file
-                .seek(SeekFrom::Start((page_num * PAGE_SIZE) as u64))
-            {
-                Ok(_) => {
-                    trace!("get_page: seek to {}", page_num * PAGE_SIZE);
-                }
-                Err(e) => {
-                    log::error!("seek failed! {}", e);
-                    panic!("seek failed! {}", e);
-                }
-            };
-        }
         let mut buf = vec![0u8; PAGE_SIZE];
-        // fileのサイズを超えていたら何も読み込まない（けど試行するだけむだなので FIXME )
-        match self.file.read(&mut buf) {
-            Ok(n) => {
-                trace!("read from file succeeded. read {} bytes", n);
+        if let Err(e) = self.read_page(page_num, &mut buf) {
+            log::error!("read failed! {}", e);
+            panic!("read failed! {}", e);
+        }
+        let page = BTreeNode::from(buf.as_ref());
+        if let Err(e) = self.make_room(page_num) {
+            log::error!("failed to evict page! {}", e);
+            return None;
+        }
+        self.cache.insert(page_num, page);
+        self.cache.get(page_num)
+    }
+
+    /// ページの内容をbufに読む。WALに新しいframeがあればそちらを読み、
+    /// fileのサイズを超えるページはまだ書き出されていない新しいページなので読み込まない
+    fn read_page(&mut self, page_num: usize, buf: &mut [u8]) -> Result<(), String> {
+        if let Some(wal) = self.wal.as_mut() {
+            if wal.read_page(page_num, buf)? {
+                return Ok(());
             }
-            Err(e) => {
-                log::error!("read failed! {}", e);
-                panic!("read failed! {}", e);
+        }
+        if page_num >= self.file_length.div_ceil(PAGE_SIZE) {
+            trace!("Pager::read_page: page {} is not written yet", page_num);
+            return Ok(());
+        }
+        trace!("Pager::read_page: seek to {}", page_num * PAGE_SIZE);
+        self.file.seek(SeekFrom::Start((page_num * PAGE_SIZE) as u64)).map_err(|e| e.to_string())?;
+        let mut read = 0;
+        while read < PAGE_SIZE {
+            match self.file.read(&mut buf[read..PAGE_SIZE]).map_err(|e| e.to_string())? {
+                0 => break,
+                n => read += n,
             }
-        };
-        let page = BTreeNode::from(buf.as_ref());
-        self.pages[page_num] = Some(page);
-        self.pages[page_num].as_ref()
+        }
+        trace!("read from file succeeded. read {} bytes", read);
+        Ok(())
     }
 
+    /// ページの内容を丸ごと置き換える。get_page_mutを通すので、savepointには元の内容が残る
+    pub(crate) fn put_page(&mut self, page_num: usize, page: Page) {
+        trace!("Pager::put_page: page_num: {}", page_num);
+        let slot = self.get_page_mut(page_num).expect("put_page: page not found!");
+        *slot = page;
+    }
+
+    /// 変更するためにページを取得する。取得したページはdirtyになる
     pub(crate) fn get_page_mut(&mut self, page_num: usize) -> Option<&mut Page> {
         let _ = self.get_page(page_num);
-        self.pages[page_num].as_mut()
+        self.save_original(page_num);
+        self.mark_dirty(page_num);
+        self.cache.get_mut(page_num)
+    }
+
+    /// page_numを新しくキャッシュに入れられるように、必要なら古いページを追い出す
+    fn make_room(&mut self, page_num: usize) -> Result<(), String> {
+        if self.cache.contains(page_num) || !self.cache.is_full() {
+            return Ok(());
+        }
+        self.evict(self.cache.capacity() - 1)
+    }
+
+    /// キャッシュのページ数がkeep以下になるまで、使われていない順に追い出す。dirtyなページは書き戻してから追い出す
+    fn evict(&mut self, keep: usize) -> Result<(), String> {
+        while self.cache.len() > keep {
+            let (page_num, page) = match self.cache.pop_lru() {
+                Some(v) => v,
+                None => break,
+            };
+            trace!("Pager::evict: page_num: {}", page_num);
+            if self.dirty.contains(&page_num) {
+                trace!("Pager::evict: page {} is dirty. write back", page_num);
+                let mut buf = vec![];
+                page.serialize(&mut buf);
+                if let Err(e) = self.write_page(page_num, &buf) {
+                    // 書き出せなかったページは失わないようにキャッシュに戻す
+                    self.cache.insert(page_num, page);
+                    return Err(e);
+                }
+                self.dirty.remove(&page_num);
+            }
+        }
+        Ok(())
     }
 
     fn flush_page(&mut self, page_num: usize) -> Result<usize, String> {
-        if let Some(page) = &self.pages[page_num] {
-            let mut buf = vec![];
+        let mut buf = vec![];
+        if page_num == HEADER_PAGE_NUM {
+            self.header().serialize(&mut buf);
+        } else if let Some(page) = self.cache.peek(page_num) {
             page.serialize(&mut buf);
-            self.file.write(&buf).map_err(|e| e.to_string())
         } else {
-            Err("Page not exists".to_string())
+            return Err("Page not exists".to_string());
+        }
+        self.write_page(page_num, &buf)
+    }
+
+    /// データファイルのpage_numを上書きする前に、元の内容をジャーナルに残す
+    fn journal_page(&mut self, page_num: usize) -> Result<(), String> {
+        if self.journal.is_none() {
+            self.journal = Some(Journal::create(&self.path, self.file_length as u64)?);
         }
+        let journal = self.journal.as_mut().expect("journal must be created");
+        if !journal.needs(page_num) {
+            return Ok(());
+        }
+        let mut image = vec![0u8; PAGE_SIZE];
+        self.file.seek(SeekFrom::Start((page_num * PAGE_SIZE) as u64)).map_err(|e| e.to_string())?;
+        let mut read = 0;
+        while read < PAGE_SIZE {
+            match self.file.read(&mut image[read..]).map_err(|e| e.to_string())? {
+                0 => break,
+                n => read += n,
+            }
+        }
+        journal.append(page_num, &image)
     }
 
-    fn flush(&mut self) -> Result<(), String> {
+    /// ページを自身のoffsetに書き出す。先に元の内容をジャーナルに残してfsyncしておく。
+    /// WALモードではデータファイルには書かず、WALに追記する
+    fn write_page(&mut self, page_num: usize, buf: &[u8]) -> Result<usize, String> {
+        // 大きすぎるページを書くと隣のページを壊してしまう
+        if buf.len() > PAGE_SIZE {
+            return Err(format!("page {} is too large to write: {} bytes", page_num, buf.len()));
+        }
+        if let Some(wal) = self.wal.as_mut() {
+            wal.append_page(page_num, buf)?;
+            return Ok(buf.len());
+        }
+        self.journal_page(page_num)?;
+        if let Some(journal) = self.journal.as_mut() {
+            if !journal.is_synced() && self.synchronous != Synchronous::Off {
+                journal.sync(self.synchronous == Synchronous::Full)?;
+            }
+        }
+        let offset = page_num * PAGE_SIZE;
+        self.file.seek(SeekFrom::Start(offset as u64)).map_err(|e| e.to_string())?;
+        self.file.write_all(buf).map_err(|e| e.to_string())?;
+        self.file_length = std::cmp::max(self.file_length, offset + buf.len());
+        Ok(buf.len())
+    }
+
+    /// dirtyなページを書き出し、synchronousの設定に従ってfsyncする。
+    /// 最後にジャーナルを消した時点でcommitが完了する
+    pub(crate) fn commit(&mut self) -> Result<(), String> {
+        trace!("Pager::commit: synchronous: {}", self.synchronous);
+        self.flush()?;
+        if let Some(wal) = self.wal.as_mut() {
+            // WALモードではcommit frameを追記した時点でcommitが完了する
+            if !wal.has_pending() {
+                return Ok(());
+            }
+            wal.commit(self.num_pages)?;
+            if self.synchronous != Synchronous::Off {
+                wal.sync(self.synchronous == Synchronous::Full)?;
+            }
+            if wal.frame_count() >= WAL_AUTOCHECKPOINT {
+                self.checkpoint()?;
+            }
+            return Ok(());
+        }
+        // ジャーナルがなければデータファイルには何も書いていない
+        if let Some(journal) = self.journal.take() {
+            self.sync()?;
+            journal.delete()?;
+            // ジャーナルの削除とデータファイルの作成をディレクトリに永続化する
+            if self.synchronous == Synchronous::Full {
+                sync_dir(&self.path)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<(), String> {
+        match self.synchronous {
+            Synchronous::Off => {}
+            Synchronous::Normal => {
+                self.file.sync_data().map_err(|e| e.to_string())?;
+            }
+            Synchronous::Full => {
+                self.file.sync_all().map_err(|e| e.to_string())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// dirtyなページだけをそれぞれのoffsetに書き出す
+    pub(crate) fn flush(&mut self) -> Result<(), String> {
         trace!("Pager::flush");
-        let _ = self.file.seek(SeekFrom::Start(0));
         trace!("Pager::flush: num_pages: {}", self.num_pages);
-        for i in 0..self.num_pages {
+        let mut dirty: Vec<usize> = self.dirty.iter().copied().collect();
+        dirty.sort_unstable();
+        trace!("Pager::flush: dirty pages: {:?}", dirty);
+        // 先に全てのページの元の内容をジャーナルに残し、fsyncを1回で済ませる
+        if self.wal.is_none() {
+            for i in &dirty {
+                self.journal_page(*i)?;
+            }
+        }
+        for i in dirty {
             match self.flush_page(i) {
-                Ok(n) =>
-                    { log::trace!("write {} bytes to file", n) }
+                Ok(n) => {
+                    log::trace!("write {} bytes to file", n);
+                    self.dirty.remove(&i);
+                }
                 Err(e) => {
                     log::error!("failed to write file: {}", e);
                     return Err(e);
@@ -182,105 +1072,473 @@ impl Pager {
         Ok(())
     }
 
-    fn get_num_pages(&self) -> usize {
-        self.file_length / ROW_SIZE
-    }
-
-    const LEAF_NODE_RIGHT_SPLIT_COUNT: usize = (BTreeLeafNode::NODE_MAX_CELLS + 1) / 2;
-    const LEAF_NODE_LEFT_SPLIT_COUNT: usize = (BTreeLeafNode::NODE_MAX_CELLS + 1) - Self::LEAF_NODE_RIGHT_SPLIT_COUNT;
-
-    pub(crate) fn split_and_insert(&mut self, page_num: usize, cell_num: usize, key: u32, value: Vec<u8>) -> Option<usize> {
+    /// leafにkeyを挿入して分割する
+    pub(crate) fn split_and_insert(&mut self, page_num: usize, cell_num: usize, kv: KV) {
         trace!("Pager::split_and_insert!");
+        let right_page_num = self.new_page_num();
         let old_node = self.get_page_mut(page_num).expect("split_and_insert: current page not found!");
-        let mut parent_page_num = if old_node.is_root() > 0 {
-            // rootの場合は後で新しい親を作る
-            0
-        } else {
-            // 親がいる場合はまずその親を使う
-            old_node.get_parent() as usize
-        };
-        let right_values;
         trace!("target page_num: {}", page_num);
         trace!("target cell_num: {}", cell_num);
 
         // ここでコピーしないとmutな借用をし続けてしまうのでコピーしておく
-        let original_is_root = old_node.is_root();
         let original_parent = old_node.get_parent();
         let left_max_key;
+        let right_values;
+        let original_next_leaf;
 
         if let BTreeNode::Leaf(node) = old_node {
             trace!("Pager::split_and_insert: just insert to old node");
-            node.insert_at(cell_num, key, value);
+            node.insert_at(cell_num, kv);
             trace!("Pager::split_and_insert: split current key_values");
-            let cur_kv = node.key_values.clone();
-            let (left, right) = cur_kv.split_at(Self::LEAF_NODE_LEFT_SPLIT_COUNT);
-            node.key_values = left.to_vec();
-            node.num_cells = Self::LEAF_NODE_LEFT_SPLIT_COUNT as u32;
-            node.is_root = 0;
+            // 行の大きさが違うので、個数ではなくバイト数が半分ずつになるように分ける
+            let sizes: Vec<usize> = node.key_values.iter().map(|kv| BTreeLeafNode::cell_size(kv.record_len())).collect();
+            let right = node.key_values.split_off(BTreeLeafNode::split_point(&sizes));
+            node.num_cells = node.key_values.len() as u32;
             trace!("Pager::split_and_insert: left num_cells: {}", node.num_cells);
             trace!("Pager::split_and_insert: left parent: {}", node.parent);
             left_max_key = node.max_key();
-            right_values = right.to_vec();
+            right_values = right;
+            // 右側のleafは元のleafと元の次のleafの間に入る
+            original_next_leaf = node.next_leaf;
+            node.next_leaf = right_page_num as u32;
         } else {
-            unimplemented!("need to implement split internal node!");
+            unreachable!("Pager::split_and_insert: target page must be leaf node");
         }
 
-        let right_page_num = self.new_page_num();
-        if original_is_root > 0 {
-            parent_page_num = self.new_page_num();
-            let new_parent = self.new_internal_page_mut(parent_page_num).expect("split_and_insert: failed to allocate new parent!");
-            if let BTreeNode::Internal(node) = new_parent {
-                node.is_root = original_is_root;
-                node.parent = original_parent;
-                node.right_child = right_page_num as u32;
-                node.insert(left_max_key, page_num as u32)
-            }
-            let old_node = self.get_page_mut(page_num).expect("split_and_insert: current page not found!");
-            if let BTreeNode::Leaf(node) = old_node {
-                node.parent = parent_page_num as u32;
-            } else {
-                unimplemented!("need to implement split internal node!");
+        let new_node = self.get_page_mut(right_page_num).expect("split_and_insert: failed to allocate new page!");
+        if let BTreeNode::Leaf(node) = new_node {
+            node.num_cells = right_values.len() as u32;
+            node.key_values = right_values;
+            node.parent = original_parent;
+            node.next_leaf = original_next_leaf;
+        } else {
+            unreachable!("new node must be leaf");
+        }
+
+        self.insert_into_parent(page_num, left_max_key, right_page_num);
+        trace!("Pager::split_and_insert: done");
+    }
+
+    /// page_numのノードが左(page_num)と右(right_page_num)に分割されたことを親に反映する。
+    /// 親が溢れた場合は親も分割し、rootまで再帰的に辿る
+    fn insert_into_parent(&mut self, page_num: usize, left_max_key: u32, right_page_num: usize) {
+        trace!("Pager::insert_into_parent: left: {}, right: {}, key: {}", page_num, right_page_num, left_max_key);
+        let left_node = self.get_page(page_num).expect("insert_into_parent: left page not found!");
+        let is_root = left_node.is_root();
+        let parent_page_num = left_node.get_parent() as usize;
+
+        if is_root > 0 {
+            // catalogがrootのpage_numを覚えているので、rootは動かさずに左側を新しいページに移す
+            let left_page_num = self.new_page_num();
+            trace!("Pager::insert_into_parent: split node was root. move left to {}", left_page_num);
+            let mut left_node = self.get_page(page_num).expect("insert_into_parent: left page not found!").clone();
+            left_node.set_root(0);
+            left_node.set_parent(page_num as u32);
+            let children = match &left_node {
+                BTreeNode::Internal(node) => node.children(),
+                _ => vec![],
+            };
+            self.put_page(left_page_num, left_node);
+            for child in children {
+                let child_node = self.get_page_mut(child as usize).expect("insert_into_parent: child page not found!");
+                child_node.set_parent(left_page_num as u32);
+            }
+            let mut root = BTreeInternalNode::new(is_root, 0);
+            root.right_child = right_page_num as u32;
+            root.insert(left_max_key, left_page_num as u32);
+            self.put_page(page_num, BTreeNode::Internal(root));
+            let right_node = self.get_page_mut(right_page_num).expect("insert_into_parent: right page not found!");
+            right_node.set_parent(page_num as u32);
+            return;
+        }
+
+        let max_cells = self.internal_max_cells;
+        let overflow = match self.get_page_mut(parent_page_num) {
+            Some(BTreeNode::Internal(node)) => {
+                // 元々page_numを指していたポインタは右側が引き継ぎ、左側は新しいkeyとして挿入する
+                node.replace_child(page_num as u32, right_page_num as u32);
+                node.insert(left_max_key, page_num as u32);
+                node.is_overflow(max_cells)
+            }
+            Some(_) => {
+                unreachable!("Pager::insert_into_parent: parent is leaf node");
+            }
+            None => {
+                unreachable!("Pager::insert_into_parent: parent does not exist");
+            }
+        };
+        if overflow {
+            // 分割が終わるまで、溢れた親を書き出さないようにキャッシュに留めておく
+            self.cache.pin(parent_page_num);
+        }
+        let right_node = self.get_page_mut(right_page_num).expect("insert_into_parent: right page not found!");
+        right_node.set_parent(parent_page_num as u32);
+
+        if overflow {
+            self.split_internal(parent_page_num);
+        }
+    }
+
+    /// 溢れたinternal nodeを分割し、真ん中のkeyを親に押し上げる
+    fn split_internal(&mut self, page_num: usize) {
+        trace!("Pager::split_internal: page_num: {}", page_num);
+        // 上限 + 1個のkeyのうち、真ん中の1つを親に押し上げる
+        let left_split_count = self.internal_max_cells.div_ceil(2);
+        let (separator, right_key_children, right_child, parent) = match self.get_page_mut(page_num) {
+            Some(BTreeNode::Internal(node)) => {
+                let mut right_key_children = node.key_children.split_off(left_split_count);
+                let separator = right_key_children.remove(0);
+                let right_child = node.right_child;
+                // 押し上げるkeyの子は左側のright_childになる
+                node.right_child = separator.child;
+                node.num_keys = node.key_children.len() as u32;
+                trace!("Pager::split_internal: left num_keys: {}", node.num_keys);
+                (separator, right_key_children, right_child, node.parent)
             }
+            _ => unreachable!("Pager::split_internal: target page must be internal node"),
+        };
+        self.cache.unpin(page_num);
+
+        let right_page_num = self.new_page_num();
+        let new_node = self.new_internal_page_mut(right_page_num).expect("split_internal: failed to allocate new page!");
+        let children = if let BTreeNode::Internal(node) = new_node {
+            node.parent = parent;
+            node.num_keys = right_key_children.len() as u32;
+            node.key_children = right_key_children;
+            node.right_child = right_child;
+            trace!("Pager::split_internal: right num_keys: {}", node.num_keys);
+            node.children()
         } else {
-            match self.get_page_mut(original_parent as usize) {
-                Some(BTreeNode::Internal(node)) => {
-                    node.insert(left_max_key, page_num as u32);
-                    if node.right_child == page_num as u32 {
-                        node.right_child = right_page_num as u32;
+            unreachable!("new node must be internal");
+        };
+        for child in children {
+            let child_node = self.get_page_mut(child as usize).expect("split_internal: child page not found!");
+            child_node.set_parent(right_page_num as u32);
+        }
+
+        self.insert_into_parent(page_num, separator.key, right_page_num)
+    }
+
+    /// leafからcellを削除し、下限を下回ったら兄弟から借りるかマージする
+    pub(crate) fn delete(&mut self, page_num: usize, cell_num: usize) {
+        trace!("Pager::delete: page_num: {}, cell_num: {}", page_num, cell_num);
+        match self.get_page_mut(page_num) {
+            Some(BTreeNode::Leaf(node)) => {
+                let kv = node.remove_at(cell_num);
+                trace!("Pager::delete: removed key: {}", kv.key);
+                if let Some(overflow) = kv.overflow {
+                    self.free_overflow(overflow.page_num);
+                }
+            }
+            Some(_) => unreachable!("Pager::delete: target page must be leaf node"),
+            None => unreachable!("Pager::delete: target page not found"),
+        }
+        self.rebalance(page_num)
+    }
+
+    /// page_numのノードが下限を下回っていれば兄弟から借りるかマージし、親に向かって再帰的に辿る
+    fn rebalance(&mut self, page_num: usize) {
+        let max_cells = self.internal_max_cells;
+        let node = self.get_page(page_num).expect("rebalance: page not found!");
+        if node.is_root() > 0 {
+            // rootのinternal nodeの子が1つだけになったら、その子の中身をrootに移して木を低くする
+            if let BTreeNode::Internal(root) = node {
+                if root.num_keys == 0 {
+                    let child = root.right_child as usize;
+                    trace!("Pager::rebalance: root has only one child. move {} into root", child);
+                    let mut child_node = self.get_page(child).expect("rebalance: child page not found!").clone();
+                    child_node.set_root(1);
+                    child_node.set_parent(0);
+                    let grandchildren = match &child_node {
+                        BTreeNode::Internal(node) => node.children(),
+                        _ => vec![],
+                    };
+                    self.put_page(page_num, child_node);
+                    for grandchild in grandchildren {
+                        let grandchild_node = self.get_page_mut(grandchild as usize).expect("rebalance: grandchild page not found!");
+                        grandchild_node.set_parent(page_num as u32);
                     }
+                    self.free_page(child);
                 }
-                Some(_) => {
-                    unreachable!("Pager::split_and_insert: original parent is leaf node");
+            }
+            return;
+        }
+        if !node.is_underflow(max_cells) {
+            return;
+        }
+
+        let parent_page_num = node.get_parent() as usize;
+        let (index, children) = match self.get_page(parent_page_num) {
+            Some(BTreeNode::Internal(parent)) => (parent.child_index(page_num as u32), parent.children()),
+            _ => unreachable!("Pager::rebalance: parent must be internal node"),
+        };
+        // 左の兄弟がいればそちらを優先する。separatorは常に左側のノードを指すkey_childrenのindex
+        let (left, right, separator) = if index > 0 {
+            (children[index - 1] as usize, page_num, index - 1)
+        } else {
+            (page_num, children[index + 1] as usize, index)
+        };
+        let sibling = if left == page_num { right } else { left };
+        trace!("Pager::rebalance: page_num: {}, sibling: {}", page_num, sibling);
+
+        let sibling_can_lend = self.get_page(sibling).expect("rebalance: sibling page not found!").can_lend(max_cells);
+        if sibling_can_lend {
+            // leafは小さい行を1つ借りただけでは下限に届かないことがあるので、届くか兄弟が貸せなくなるまで借りる
+            loop {
+                if sibling == left {
+                    self.borrow_from_left(parent_page_num, separator, left, right);
+                } else {
+                    self.borrow_from_right(parent_page_num, separator, left, right);
                 }
-                None => {
-                    unreachable!("Pager::split_and_insert: original parent does not exist");
+                let underflow = self.get_page(page_num).expect("rebalance: page not found!").is_underflow(max_cells);
+                if !underflow || !self.get_page(sibling).expect("rebalance: sibling page not found!").can_lend(max_cells) {
+                    break;
                 }
             }
-        }
-        let new_node = self.get_page_mut(right_page_num).expect("split_and_insert: failed to allocate new page!");
-        if let BTreeNode::Leaf(node) = new_node {
-            node.key_values = right_values;
-            node.num_cells = Self::LEAF_NODE_RIGHT_SPLIT_COUNT as u32;
-            node.parent = parent_page_num as u32;
         } else {
-            unreachable!("new node must be leaf");
+            self.merge(parent_page_num, separator, left, right);
+            self.rebalance(parent_page_num);
+        }
+    }
+
+    /// 左の兄弟の末尾を右のノードの先頭に移す
+    fn borrow_from_left(&mut self, parent_page_num: usize, separator: usize, left: usize, right: usize) {
+        trace!("Pager::borrow_from_left: left: {}, right: {}", left, right);
+        let old_separator_key = self.separator_key(parent_page_num, separator);
+        let (new_separator_key, moved) = match self.get_page_mut(left) {
+            Some(BTreeNode::Leaf(node)) => {
+                let kv = node.remove_at(node.key_values.len() - 1);
+                (node.max_key(), Moved::KV(kv))
+            }
+            Some(BTreeNode::Internal(node)) => {
+                let last = node.remove_at(node.key_children.len() - 1);
+                let moved_child = node.right_child;
+                node.right_child = last.child;
+                (last.key, Moved::KC(KC { key: old_separator_key, child: moved_child }))
+            }
+            _ => unreachable!("Pager::borrow_from_left: left page not found"),
+        };
+        match (self.get_page_mut(right), moved) {
+            (Some(BTreeNode::Leaf(node)), Moved::KV(kv)) => {
+                node.insert_at(0, kv);
+            }
+            (Some(BTreeNode::Internal(node)), Moved::KC(kc)) => {
+                let moved_child = kc.child;
+                node.key_children.insert(0, kc);
+                node.num_keys += 1;
+                self.get_page_mut(moved_child as usize).expect("borrow_from_left: child page not found!").set_parent(right as u32);
+            }
+            _ => unreachable!("Pager::borrow_from_left: siblings must be same node type"),
         }
+        self.set_separator_key(parent_page_num, separator, new_separator_key);
+    }
 
-        trace!("Pager::split_and_insert: done");
-        if original_is_root > 0 {
-            trace!("Pager::split_and_insert: original was root");
-            Some(parent_page_num)
-        } else {
-            trace!("Pager::split_and_insert: original was not root");
-            None
+    /// 右の兄弟の先頭を左のノードの末尾に移す
+    fn borrow_from_right(&mut self, parent_page_num: usize, separator: usize, left: usize, right: usize) {
+        trace!("Pager::borrow_from_right: left: {}, right: {}", left, right);
+        let old_separator_key = self.separator_key(parent_page_num, separator);
+        let moved = match self.get_page_mut(right) {
+            Some(BTreeNode::Leaf(node)) => Moved::KV(node.remove_at(0)),
+            Some(BTreeNode::Internal(node)) => Moved::KC(node.remove_at(0)),
+            _ => unreachable!("Pager::borrow_from_right: right page not found"),
+        };
+        let new_separator_key = match (self.get_page_mut(left), moved) {
+            (Some(BTreeNode::Leaf(node)), Moved::KV(kv)) => {
+                let index = node.key_values.len();
+                node.insert_at(index, kv);
+                node.max_key()
+            }
+            (Some(BTreeNode::Internal(node)), Moved::KC(first)) => {
+                let kc = KC { key: old_separator_key, child: node.right_child };
+                node.key_children.push(kc);
+                node.num_keys += 1;
+                node.right_child = first.child;
+                self.get_page_mut(first.child as usize).expect("borrow_from_right: child page not found!").set_parent(left as u32);
+                first.key
+            }
+            _ => unreachable!("Pager::borrow_from_right: siblings must be same node type"),
+        };
+        self.set_separator_key(parent_page_num, separator, new_separator_key);
+    }
+
+    /// 右のノードを左のノードにまとめ、親から右のノードへのポインタを取り除く
+    fn merge(&mut self, parent_page_num: usize, separator: usize, left: usize, right: usize) {
+        trace!("Pager::merge: left: {}, right: {}", left, right);
+        let separator_key = self.separator_key(parent_page_num, separator);
+        let right_node = self.get_page(right).expect("merge: right page not found!").clone();
+        let moved_children = match (self.get_page_mut(left), right_node) {
+            (Some(BTreeNode::Leaf(node)), BTreeNode::Leaf(mut right_node)) => {
+                node.key_values.append(&mut right_node.key_values);
+                node.num_cells = node.key_values.len() as u32;
+                node.next_leaf = right_node.next_leaf;
+                vec![]
+            }
+            (Some(BTreeNode::Internal(node)), BTreeNode::Internal(mut right_node)) => {
+                // separatorは左のright_childの上限なので、左のkeyとして引き下ろす
+                let kc = KC { key: separator_key, child: node.right_child };
+                node.key_children.push(kc);
+                node.key_children.append(&mut right_node.key_children);
+                node.right_child = right_node.right_child;
+                node.num_keys = node.key_children.len() as u32;
+                node.children()
+            }
+            _ => unreachable!("Pager::merge: siblings must be same node type"),
+        };
+        for child in moved_children {
+            self.get_page_mut(child as usize).expect("merge: child page not found!").set_parent(left as u32);
+        }
+        match self.get_page_mut(parent_page_num) {
+            Some(BTreeNode::Internal(parent)) => {
+                parent.remove_at(separator);
+                parent.replace_child(right as u32, left as u32);
+            }
+            _ => unreachable!("Pager::merge: parent must be internal node"),
         }
+        self.free_page(right);
     }
 
-    // とりあえず今は末尾を返す
-    fn new_page_num(&mut self) -> usize {
-        let val = self.num_pages;
-        self.num_pages += 1;
-        val
+    fn separator_key(&mut self, parent_page_num: usize, separator: usize) -> u32 {
+        match self.get_page(parent_page_num) {
+            Some(BTreeNode::Internal(parent)) => parent.key_children[separator].key,
+            _ => unreachable!("Pager::separator_key: parent must be internal node"),
+        }
+    }
+
+    fn set_separator_key(&mut self, parent_page_num: usize, separator: usize, key: u32) {
+        match self.get_page_mut(parent_page_num) {
+            Some(BTreeNode::Internal(parent)) => parent.key_children[separator].key = key,
+            _ => unreachable!("Pager::set_separator_key: parent must be internal node"),
+        }
+    }
+
+    /// page_num以下の右端のleafを返す
+    pub(crate) fn rightmost_leaf(&mut self, page_num: usize) -> usize {
+        let mut page_num = page_num;
+        while let Some(BTreeNode::Internal(node)) = self.get_page(page_num) {
+            page_num = node.right_child as usize;
+        }
+        page_num
+    }
+
+    /// 左隣のleafを返す。親を辿って左の兄弟がいるところまで上り、その右端のleafまで下りる
+    pub(crate) fn prev_leaf(&mut self, page_num: usize) -> Option<usize> {
+        let mut child = page_num;
+        loop {
+            let node = self.get_page(child).expect("prev_leaf: page not found!");
+            if node.is_root() > 0 {
+                return None;
+            }
+            let parent = node.get_parent() as usize;
+            match self.get_page(parent) {
+                Some(BTreeNode::Internal(node)) => {
+                    let index = node.child_index(child as u32);
+                    if index > 0 {
+                        let sibling = node.key_children[index - 1].child as usize;
+                        return Some(self.rightmost_leaf(sibling));
+                    }
+                }
+                _ => unreachable!("Pager::prev_leaf: parent must be internal node"),
+            }
+            child = parent;
+        }
+    }
+
+    /// 新しいページを確保する。空きページがあればそれを空のleafにして使い、なければ末尾に増やす
+    pub(crate) fn new_page_num(&mut self) -> usize {
+        self.mark_dirty(HEADER_PAGE_NUM);
+        if self.free_list_head == 0 {
+            let val = self.num_pages;
+            self.num_pages += 1;
+            return val;
+        }
+        let page_num = self.free_list_head as usize;
+        let page = self.get_page_mut(page_num).expect("new_page_num: free page not found!");
+        let next_free = match page {
+            BTreeNode::Free(node) => node.next_free,
+            _ => unreachable!("Pager::new_page_num: page {} in free list is not free", page_num),
+        };
+        *page = BTreeNode::Leaf(BTreeLeafNode::default());
+        trace!("Pager::new_page_num: reuse free page {}. next free page is {}", page_num, next_free);
+        self.free_list_head = next_free;
+        page_num
+    }
+
+    /// 木から外れたページを空きページのリストの先頭に入れる
+    fn free_page(&mut self, page_num: usize) {
+        trace!("Pager::free_page: page_num: {}, next free page is {}", page_num, self.free_list_head);
+        let next_free = self.free_list_head;
+        let page = self.get_page_mut(page_num).expect("free_page: page not found!");
+        *page = BTreeNode::Free(BTreeFreeNode::new(next_free));
+        self.free_list_head = page_num as u32;
+        self.mark_dirty(HEADER_PAGE_NUM);
+    }
+
+    /// keyとrecordからleafのcellを作る。leafに収まらない部分はoverflowページに書く
+    pub(crate) fn new_cell(&mut self, key: u32, mut row: Vec<u8>) -> KV {
+        let len = row.len();
+        let local = BTreeLeafNode::local_size(len);
+        if local == len {
+            return KV { key, value: row, overflow: None };
+        }
+        let rest = row.split_off(local);
+        let chunks: Vec<&[u8]> = rest.chunks(BTreeOverflowNode::SPACE_FOR_DATA).collect();
+        let page_nums: Vec<usize> = chunks.iter().map(|_| self.new_page_num()).collect();
+        trace!("Pager::new_cell: key: {}, {} bytes overflow to pages {:?}", key, rest.len(), page_nums);
+        for (i, chunk) in chunks.iter().enumerate() {
+            let next = page_nums.get(i + 1).map_or(0, |page_num| *page_num as u32);
+            self.put_page(page_nums[i], BTreeNode::Overflow(BTreeOverflowNode::new(next, chunk.to_vec())));
+        }
+        KV { key, value: row, overflow: Some(Overflow { len, page_num: page_nums[0] as u32 }) }
+    }
+
+    /// cellのrecordを、overflowページに続く部分も繋げて読む
+    pub(crate) fn read_record(&mut self, kv: &KV) -> Vec<u8> {
+        let mut row = kv.value.clone();
+        let overflow = match &kv.overflow {
+            Some(overflow) => overflow,
+            None => return row,
+        };
+        row.reserve(overflow.len - row.len());
+        let mut page_num = overflow.page_num as usize;
+        while page_num != 0 {
+            page_num = match self.get_page(page_num) {
+                Some(BTreeNode::Overflow(node)) => {
+                    row.extend_from_slice(&node.data);
+                    node.next as usize
+                }
+                _ => panic!("Pager::read_record: page {} of key {} is not an overflow page", page_num, kv.key),
+            };
+        }
+        if row.len() != overflow.len {
+            panic!("Pager::read_record: record of key {} is {} bytes, expected {}", kv.key, row.len(), overflow.len);
+        }
+        row
+    }
+
+    /// page_numから続くoverflowページを全て空きページにする
+    pub(crate) fn free_overflow(&mut self, mut page_num: u32) {
+        while page_num != 0 {
+            let next = match self.get_page(page_num as usize) {
+                Some(BTreeNode::Overflow(node)) => node.next,
+                _ => unreachable!("Pager::free_overflow: page {} is not an overflow page", page_num),
+            };
+            self.free_page(page_num as usize);
+            page_num = next;
+        }
+    }
+
+    /// 空きページのリストを先頭から辿ったpage_num
+    pub(crate) fn free_pages(&mut self) -> Vec<usize> {
+        let mut pages = vec![];
+        let mut page_num = self.free_list_head as usize;
+        while page_num != 0 {
+            pages.push(page_num);
+            page_num = match self.get_page(page_num) {
+                Some(BTreeNode::Free(node)) => node.next_free as usize,
+                _ => unreachable!("Pager::free_pages: page {} in free list is not free", page_num),
+            };
+        }
+        pages
     }
 
     // pub(crate) fn find_key(&mut self, start_page_num: u32, key: u32) -> Option<u32> {
@@ -290,6 +1548,12 @@ impl Pager {
     // }
 }
 
+/// 兄弟間で移動するcell
+enum Moved {
+    KV(KV),
+    KC(KC),
+}
+
 pub(crate) struct Cursor<'a> {
     pub(crate) table: &'a mut Table,
     pub(crate) page_num: usize,
@@ -304,22 +1568,29 @@ struct CursorOpts {
 }
 
 impl<'a> Cursor<'a> {
+    /// 左端のleafの先頭を指すcursorを返す
     pub(crate) fn table_start(table: &'a mut Table) -> Self {
         trace!("table_start");
         let cell_num = 0;
         trace!("table_start: cell_num: {}", cell_num);
-        let page_num = table.root_page_num;
-        trace!("table_start: page_num: {}", page_num);
-        let end_of_table = table.pager.get_page(table.root_page_num).map_or(false, |page| {
-            match page {
-                BTreeNode::Leaf(page) => {
-                    page.num_cells == 0
+        let mut page_num = table.root_page_num;
+        let end_of_table = loop {
+            match table.pager.get_page(page_num) {
+                Some(BTreeNode::Leaf(page)) => {
+                    break page.num_cells == 0;
+                }
+                Some(BTreeNode::Internal(page)) => {
+                    page_num = match page.key_children.first() {
+                        Some(kc) => kc.child,
+                        None => page.right_child,
+                    } as usize;
                 }
-                BTreeNode::Internal(_) => {
-                    false
+                Some(BTreeNode::Free(_)) | Some(BTreeNode::Overflow(_)) | None => {
+                    break true;
                 }
             }
-        });
+        };
+        trace!("table_start: page_num: {}", page_num);
         trace!("table_start: end_of_table: {}", end_of_table);
         Cursor {
             table,
@@ -329,6 +1600,24 @@ impl<'a> Cursor<'a> {
         }
     }
 
+    /// 右端のleafの末尾を指すcursorを返す
+    pub(crate) fn table_end(table: &'a mut Table) -> Self {
+        trace!("table_end");
+        let root_page_num = table.root_page_num;
+        let page_num = table.pager.rightmost_leaf(root_page_num);
+        let num_cells = match table.pager.get_page(page_num) {
+            Some(BTreeNode::Leaf(page)) => page.num_cells as usize,
+            _ => 0,
+        };
+        trace!("table_end: page_num: {}, num_cells: {}", page_num, num_cells);
+        Cursor {
+            table,
+            page_num,
+            cell_num: num_cells.saturating_sub(1),
+            end_of_table: num_cells == 0,
+        }
+    }
+
     pub(crate) fn find_insert_position(table: &'a mut Table, page_num: usize, key: u32) -> Self {
         trace!("find_insert_position");
         match table.pager.get_page(page_num) {
@@ -372,77 +1661,101 @@ impl<'a> Cursor<'a> {
                 let next_page_num = page.find_key(key);
                 Self::find_insert_position(table, next_page_num as usize, key)
             }
+            Some(BTreeNode::Free(_)) | Some(BTreeNode::Overflow(_)) => panic!("page {} is not in the tree", page_num),
             None => panic!("page not found"),
         }
     }
 
+    /// key以上の最初のcellを指すcursorを返す。該当するcellがなければend_of_tableになる
+    pub(crate) fn table_seek(table: &'a mut Table, key: u32) -> Self {
+        trace!("table_seek: key: {}", key);
+        let root_page_num = table.root_page_num;
+        let mut cursor = Self::find_insert_position(table, root_page_num, key);
+        let num_cells = match cursor.get_page() {
+            Some(BTreeNode::Leaf(leaf)) => leaf.num_cells as usize,
+            _ => unreachable!("find_insert_position must return leaf node page num"),
+        };
+        if num_cells == 0 {
+            cursor.end_of_table = true;
+        } else if cursor.cell_num >= num_cells {
+            // このleafのkeyは全てkeyより小さいので次のleafの先頭に進める
+            cursor.end_of_table = false;
+            cursor.cell_num = num_cells - 1;
+            cursor.advance();
+        } else {
+            cursor.end_of_table = false;
+        }
+        trace!("table_seek: page_num: {}, cell_num: {}, end_of_table: {}", cursor.page_num, cursor.cell_num, cursor.end_of_table);
+        cursor
+    }
+
+    /// key以下の最後のcellを指すcursorを返す。該当するcellがなければend_of_tableになる
+    pub(crate) fn table_seek_back(table: &'a mut Table, key: u32) -> Self {
+        trace!("table_seek_back: key: {}", key);
+        let root_page_num = table.root_page_num;
+        let mut cursor = Self::find_insert_position(table, root_page_num, key);
+        cursor.end_of_table = false;
+        let cell_num = cursor.cell_num;
+        let found = match cursor.get_page() {
+            Some(BTreeNode::Leaf(leaf)) => cell_num < leaf.num_cells as usize && leaf.key_values[cell_num].key == key,
+            _ => unreachable!("find_insert_position must return leaf node page num"),
+        };
+        if !found {
+            // cell_numはkeyより大きい最初のcellなので1つ戻る
+            cursor.retreat();
+        }
+        trace!("table_seek_back: page_num: {}, cell_num: {}, end_of_table: {}", cursor.page_num, cursor.cell_num, cursor.end_of_table);
+        cursor
+    }
+
+    /// 1つ前のcellに戻る。leafの先頭に来たら親を辿って左隣のleafの末尾に移る
+    pub(crate) fn retreat(&mut self) {
+        trace!("retreat: before page_num: {}, cell_num: {}", self.page_num, self.cell_num);
+        while self.cell_num == 0 {
+            match self.table.pager.prev_leaf(self.page_num) {
+                Some(page_num) => {
+                    self.page_num = page_num;
+                    self.cell_num = match self.table.pager.get_page(page_num) {
+                        Some(BTreeNode::Leaf(leaf)) => leaf.num_cells as usize,
+                        _ => unreachable!("Cursor::retreat: prev_leaf must return leaf node page num"),
+                    };
+                }
+                None => {
+                    trace!("retreat: reached the leftmost leaf");
+                    self.end_of_table = true;
+                    return;
+                }
+            }
+        }
+        self.cell_num -= 1;
+        trace!("retreat: after page_num: {}, cell_num: {}", self.page_num, self.cell_num);
+    }
+
+    /// 次のcellに進む。leafの末尾に来たらnext_leafを辿る
     pub(crate) fn advance(&mut self) {
         trace!("advance");
-        let page_num = self.page_num;
-        let node = self.table.pager.get_page(page_num).expect("page not found!!");
         trace!("advance: before cell_num: {}", self.cell_num);
         self.cell_num += 1;
         trace!("advance: after cell_num: {}", self.cell_num);
-        match node {
-            BTreeNode::Leaf(leaf) => {
-                if self.cell_num >= leaf.num_cells as usize {
-                    if node.is_root() > 0 {
-                        self.end_of_table = true
-                    } else {
-                        self.page_num = leaf.parent as usize;
-                        match self.get_page() {
-                            Some(BTreeNode::Internal(parent)) => {
-                                trace!("advance: go up to parent");
-                                let mut is_next = false;
-                                let mut next_child = None;
-                                for kc in &parent.key_children {
-                                    if is_next {
-                                        next_child = Some(kc.child);
-                                    }
-                                    if kc.child == page_num as u32 {
-                                        is_next = true;
-                                    }
-                                }
-                                self.page_num = match next_child {
-                                    Some(v) => {
-                                        trace!("advance: choose next child. page_num is {}", v);
-                                        v
-                                    }
-                                    None => {
-                                        trace!("advance: choose right_child. page_num is {}", parent.right_child);
-                                        parent.right_child
-                                    }
-                                } as usize;
-                                if self.page_num == page_num {
-                                    self.end_of_table = true;
-                                } else {
-                                    self.cell_num = 0;
-                                }
-                            }
-                            Some(_) => {
-                                unreachable!("Cursor::advance: parent is not internal node")
-                            }
-                            None => {
-                                unreachable!("Cursor::advance: non root but parent not found")
-                            }
-                        }
+        loop {
+            let page_num = self.page_num;
+            let node = self.table.pager.get_page(page_num).expect("page not found!!");
+            match node {
+                BTreeNode::Leaf(leaf) => {
+                    if self.cell_num < leaf.num_cells as usize {
+                        return;
                     }
+                    if leaf.next_leaf == 0 {
+                        trace!("advance: reached the rightmost leaf");
+                        self.end_of_table = true;
+                        return;
+                    }
+                    trace!("advance: go to next leaf. page_num is {}", leaf.next_leaf);
+                    self.page_num = leaf.next_leaf as usize;
+                    self.cell_num = 0;
                 }
+                BTreeNode::Internal(_) | BTreeNode::Free(_) | BTreeNode::Overflow(_) => { unreachable!("Cursor::advance: cursor must point to leaf node") }
             }
-            BTreeNode::Internal(_) => { unimplemented!() }
-        }
-    }
-
-    fn get_row_mut(&mut self) -> Option<&mut Vec<u8>> {
-        trace!("TCursor::get_row_mut");
-        let page_num = self.page_num;
-        trace!("TCursor::get_row_mut: page_num: {}", page_num);
-        let cell_num = self.cell_num;
-        match self.table.pager.get_page_mut(page_num) {
-            Some(BTreeNode::Leaf(page)) => {
-                Some(page.get_row_mut(cell_num))
-            }
-            _ => None,
         }
     }
 
@@ -453,19 +1766,26 @@ impl<'a> Cursor<'a> {
         self.table.pager.get_page_mut(page_num)
     }
 
-    pub(crate) fn get_row(&mut self) -> Option<&Vec<u8>> {
+    /// cursorの位置の行。overflowページに続いていれば繋げて返す
+    pub(crate) fn get_row(&mut self) -> Option<Vec<u8>> {
         trace!("TCursor::get_row");
         let page_num = self.page_num;
         trace!("TCursor::get_row page_num: {}", page_num);
         let cell_num = self.cell_num;
-        self.table.pager.get_page_mut(page_num).map(|page| {
-            match page {
-                BTreeNode::Leaf(page) => {
-                    page.get_row(cell_num)
-                }
-                BTreeNode::Internal(_) => { unimplemented!() }
-            }
-        })
+        let kv = match self.table.pager.get_page(page_num)? {
+            BTreeNode::Leaf(page) => page.key_values.get(cell_num)?.clone(),
+            BTreeNode::Internal(_) | BTreeNode::Free(_) | BTreeNode::Overflow(_) => { unreachable!("Cursor::get_row: cursor must point to leaf node") }
+        };
+        Some(self.table.pager.read_record(&kv))
+    }
+
+    pub(crate) fn get_key(&mut self) -> Option<u32> {
+        trace!("TCursor::get_key");
+        let cell_num = self.cell_num;
+        match self.table.pager.get_page(self.page_num) {
+            Some(BTreeNode::Leaf(page)) => page.key_values.get(cell_num).map(|kv| kv.key),
+            _ => None,
+        }
     }
 
     pub(crate) fn get_page(&mut self) -> Option<&Page> {
@@ -475,9 +1795,9 @@ impl<'a> Cursor<'a> {
         self.table.pager.get_page(page_num)
     }
 
-    pub(crate) fn split_and_insert(&mut self, key: u32, value: Vec<u8>) -> Option<usize> {
-        trace!("TCursor::split_and_insert");
-        self.table.pager.split_and_insert(self.page_num, self.cell_num, key, value)
+    pub(crate) fn delete(&mut self) {
+        trace!("TCursor::delete");
+        self.table.pager.delete(self.page_num, self.cell_num)
     }
 }
 
@@ -1,14 +1,16 @@
-use std::io::{Read, Write};
-use crate::{ROW_SIZE, PAGE_SIZE};
+use std::io::Write;
+use crate::PAGE_SIZE;
+use crate::record;
 use byteorder::{ReadBytesExt, LittleEndian, WriteBytesExt};
 use std::convert::TryFrom;
-use std::borrow::{Borrow, BorrowMut};
 use log::trace;
 
 #[derive(Clone, Debug)]
 pub enum NodeType {
     Leaf = 0,
     Internal = 1,
+    Free = 2,
+    Overflow = 3,
 }
 
 impl TryFrom<u8> for NodeType {
@@ -19,6 +21,10 @@ impl TryFrom<u8> for NodeType {
             Ok(NodeType::Leaf)
         } else if value == NodeType::Internal as u8 {
             Ok(NodeType::Internal)
+        } else if value == NodeType::Free as u8 {
+            Ok(NodeType::Free)
+        } else if value == NodeType::Overflow as u8 {
+            Ok(NodeType::Overflow)
         } else {
             Err(format!("unknown node type: {}", value))
         }
@@ -29,52 +35,61 @@ impl TryFrom<u8> for NodeType {
 pub enum BTreeNode {
     Leaf(BTreeLeafNode),
     Internal(BTreeInternalNode),
+    /// 木から外れて空きページのリストに入っているページ
+    Free(BTreeFreeNode),
+    /// leafに収まらない行の続き
+    Overflow(BTreeOverflowNode),
 }
 
+/// keyの順に行を持つleaf。
+///
+/// ページの中ではslotted pageとして置く: headerの直後からcellへのポインタ(u16)をkeyの順に並べ、
+/// cell(key(u32) + recordの長さ(varint) + record)はページの末尾から詰める。
+/// MAX_RECORD_SIZEより長いrecordは先頭のOVERFLOW_LOCAL_SIZEバイトだけをcellに置き、
+/// 残りを入れたoverflowページの先頭のpage_num(u32)をその後に書く
 #[derive(Clone)]
 pub struct BTreeLeafNode {
     pub node_type: NodeType,
     pub is_root: u8,
     pub parent: u32,
     pub num_cells: u32,
+    /// 右隣のleafのpage_num。右端のleafは0(page 0はheaderなので次のleafにはならない)
+    pub next_leaf: u32,
     pub key_values: Vec<KV>,
 }
 
-impl BTreeLeafNode {
-    pub(crate) fn get_row(&self, cell_num: usize) -> &Vec<u8> {
-        self.key_values[cell_num].value.borrow()
-    }
-
-    pub(crate) fn get_row_mut(&mut self, cell_num: usize) -> &mut Vec<u8> {
-        trace!("BTreeLeafNode.get_row_mut");
-        trace!("BTreeLeafNode.get_row_mut: cell_num: {}", cell_num);
-        let diff = (cell_num + 1) - (self.num_cells as usize);
-        trace!("BTreeLeafNode.get_row_mut: diff: {}", diff);
-        for i in 0..diff {
-            trace!("BTreeLeafNode.get_row_mut: insert kv {}", i);
-            let new_row = vec![0; ROW_SIZE];
-            let kv = KV { key: 0, value: new_row };
-            self.key_values.push(kv);
+impl Default for BTreeLeafNode {
+    fn default() -> Self {
+        BTreeLeafNode {
+            node_type: NodeType::Leaf,
+            is_root: 0,
+            parent: 0,
+            num_cells: 0,
+            next_leaf: 0,
+            key_values: vec![],
         }
-        trace!("BTreeLeafNode.get_row_mut: key_values len: {}", self.key_values.len());
-        self.num_cells = self.key_values.len() as u32;
-        self.key_values[cell_num].value.borrow_mut()
     }
+}
 
-    pub(crate) fn insert(&mut self, key: u32, value: Vec<u8>) {
-        if self.num_cells >= Self::max_cells() {
-            panic!("max cells!");
+impl BTreeLeafNode {
+    /// cellを置き換える。収まるかどうかは呼ぶ側で確かめる
+    pub(crate) fn replace_cell(&mut self, cell_num: usize, kv: KV) {
+        self.key_values[cell_num] = kv;
+    }
+
+    pub(crate) fn insert(&mut self, kv: KV) {
+        if !self.has_room(kv.record_len()) {
+            panic!("no room for a record of {} bytes!", kv.record_len());
         }
-        let kv = KV { key, value };
         self.key_values.push(kv);
         self.num_cells += 1;
     }
 
-    pub(crate) fn insert_at(&mut self, index: usize, key: u32, value: Vec<u8>) {
-        if self.num_cells >= Self::max_cells() {
-            log::trace!("max cells!");
+    /// 収まらなくても挿入する。溢れた場合の分割はPagerにやらせる
+    pub(crate) fn insert_at(&mut self, index: usize, kv: KV) {
+        if !self.has_room(kv.record_len()) {
+            log::trace!("no room for a record of {} bytes!", kv.record_len());
         }
-        let kv = KV { key, value };
         log::trace!("BTreeLeafNode::insert_at: insert at {}. key_values length is {}", index, self.key_values.len());
         self.key_values.insert(index, kv);
         self.num_cells += 1;
@@ -82,18 +97,87 @@ impl BTreeLeafNode {
 
     pub const NODE_TYPE_SIZE: usize = 1;
     pub const IS_ROOT_SIZE: usize = 1;
-    pub const NUM_CELLS_SIZE: usize = 4;
-    pub const NODE_HEADER_SIZE: usize = Self::NODE_TYPE_SIZE + Self::IS_ROOT_SIZE + Self::NUM_CELLS_SIZE;
+    pub const PARENT_SIZE: usize = 4;
+    pub const NUM_CELLS_SIZE: usize = 2;
+    pub const CELL_CONTENT_START_SIZE: usize = 2;
+    pub const NEXT_LEAF_SIZE: usize = 4;
+    pub const NODE_HEADER_SIZE: usize = Self::NODE_TYPE_SIZE + Self::IS_ROOT_SIZE + Self::PARENT_SIZE + Self::NUM_CELLS_SIZE
+        + Self::CELL_CONTENT_START_SIZE + Self::NEXT_LEAF_SIZE;
+    pub const CELL_POINTER_SIZE: usize = 2;
     pub const NODE_KEY_SIZE: usize = 4;
-    pub const NODE_CELL_SIZE: usize = Self::NODE_KEY_SIZE + ROW_SIZE;
     pub const NODE_SPACE_FOR_CELLS: usize = PAGE_SIZE - Self::NODE_HEADER_SIZE;
-    pub const NODE_MAX_CELLS: usize = Self::NODE_SPACE_FOR_CELLS / Self::NODE_CELL_SIZE;
-    fn max_cells() -> u32 {
-        Self::NODE_MAX_CELLS as u32
+    /// 1つのleafに少なくとも4つの行が入るように、cellの大きさを制限する
+    pub const MAX_CELL_SIZE: usize = Self::NODE_SPACE_FOR_CELLS / 4;
+    /// cellにそのまま置けるrecordの最大バイト数。recordの長さのvarintは2バイトに収まる
+    pub const MAX_RECORD_SIZE: usize = Self::MAX_CELL_SIZE - Self::CELL_POINTER_SIZE - Self::NODE_KEY_SIZE - 2;
+    pub const OVERFLOW_POINTER_SIZE: usize = 4;
+    /// overflowページに続くrecordのうちcellに置くバイト数。
+    /// 大きくするとleafに入る行が減るので、cellの最大の1/4にしておく
+    pub const OVERFLOW_LOCAL_SIZE: usize = Self::MAX_CELL_SIZE / 4;
+    /// 使っているバイト数がこれを下回ったら兄弟から借りるかマージする。
+    /// 下回ったleafと貸せない兄弟(下限 + cell 1つ未満)を合わせても1ページに収まり、
+    /// 溢れたleafを半分に分けるとどちらもこれ以上になる
+    pub const NODE_MIN_SIZE: usize = (Self::NODE_SPACE_FOR_CELLS - Self::MAX_CELL_SIZE) / 2;
+
+    /// recordの長さがlenの行のcellに置くバイト数
+    pub(crate) fn local_size(len: usize) -> usize {
+        if len <= Self::MAX_RECORD_SIZE { len } else { Self::OVERFLOW_LOCAL_SIZE }
+    }
+
+    /// recordの長さがlenの行のcellが、ポインタを含めて使うバイト数
+    pub(crate) fn cell_size(len: usize) -> usize {
+        let overflow = if len <= Self::MAX_RECORD_SIZE { 0 } else { Self::OVERFLOW_POINTER_SIZE };
+        Self::CELL_POINTER_SIZE + Self::NODE_KEY_SIZE + record::varint_len(len as u64) + Self::local_size(len) + overflow
+    }
+
+    /// cellが使っているバイト数
+    pub(crate) fn used_size(&self) -> usize {
+        self.key_values.iter().map(|kv| Self::cell_size(kv.record_len())).sum()
+    }
+
+    /// recordの長さがlenの行を挿入できるか
+    pub(crate) fn has_room(&self, len: usize) -> bool {
+        self.used_size() + Self::cell_size(len) <= Self::NODE_SPACE_FOR_CELLS
+    }
+
+    pub(crate) fn is_overflow(&self) -> bool {
+        self.used_size() > Self::NODE_SPACE_FOR_CELLS
     }
 
-    pub(crate) fn is_max(&self) -> bool {
-        self.num_cells >= Self::max_cells()
+    pub(crate) fn is_underflow(&self) -> bool {
+        self.used_size() < Self::NODE_MIN_SIZE
+    }
+
+    /// 兄弟に先頭か末尾のcellを1つ渡しても下限を下回らないか
+    pub(crate) fn can_lend(&self) -> bool {
+        let (first, last) = match (self.key_values.first(), self.key_values.last()) {
+            (Some(first), Some(last)) if self.key_values.len() > 1 => (first, last),
+            _ => return false,
+        };
+        let largest = std::cmp::max(Self::cell_size(first.record_len()), Self::cell_size(last.record_len()));
+        self.used_size() - largest >= Self::NODE_MIN_SIZE
+    }
+
+    /// 大きさがsizesのcellを2つに分けるとき、左右のバイト数ができるだけ近くなる右側の先頭の位置
+    pub(crate) fn split_point(sizes: &[usize]) -> usize {
+        let total: usize = sizes.iter().sum();
+        let mut left = 0;
+        let mut best = (usize::MAX, 1);
+        for (i, size) in sizes.iter().enumerate().take(sizes.len().saturating_sub(1)) {
+            left += size;
+            let larger = std::cmp::max(left, total - left);
+            if larger < best.0 {
+                best = (larger, i + 1);
+            }
+        }
+        best.1
+    }
+
+    pub(crate) fn remove_at(&mut self, index: usize) -> KV {
+        log::trace!("BTreeLeafNode::remove_at: remove at {}. key_values length is {}", index, self.key_values.len());
+        let kv = self.key_values.remove(index);
+        self.num_cells -= 1;
+        kv
     }
 
     pub(crate) fn max_key(&self) -> u32 {
@@ -135,23 +219,76 @@ impl BTreeInternalNode {
 
     pub const INTERNAL_SPACE_FOR_CELLS: usize = PAGE_SIZE - 1 - 1 - 4 - 4 - 4;
     pub const INTERNAL_CELL_SIZE: usize = 8;
-    pub const INTERNAL_MAX_CELLS: usize = Self::INTERNAL_SPACE_FOR_CELLS / Self::INTERNAL_CELL_SIZE;
+    /// ページの大きさから決まるkeyの上限。分割する前の1つ多い状態でもページに収まるように1つ空けておく
+    pub const INTERNAL_PAGE_MAX_CELLS: usize = Self::INTERNAL_SPACE_FOR_CELLS / Self::INTERNAL_CELL_SIZE - 1;
+
+    /// 上限を超えても挿入する。超えた場合の分割はPagerにやらせる
     pub(crate) fn insert(&mut self, key: u32, child: u32) {
-        if self.num_keys as usize >= Self::INTERNAL_MAX_CELLS {
-            unimplemented!("need to implement split!");
-        }
         let kc = KC { child, key };
         let index = self.find_insert_position(key);
         self.key_children.insert(index, kc);
         self.num_keys += 1;
     }
 
+    /// keyの上限がmax_cellsのとき、溢れていて分割が要るか
+    pub(crate) fn is_overflow(&self, max_cells: usize) -> bool {
+        self.num_keys as usize > max_cells
+    }
+
+    /// keyの上限がmax_cellsのとき、下限(上限の半分)を下回っているか
+    pub(crate) fn is_underflow(&self, max_cells: usize) -> bool {
+        (self.num_keys as usize) < max_cells / 2
+    }
+
+    /// 兄弟に1つ渡しても下限を下回らないか
+    pub(crate) fn can_lend(&self, max_cells: usize) -> bool {
+        self.num_keys as usize > max_cells / 2
+    }
+
+    pub(crate) fn remove_at(&mut self, index: usize) -> KC {
+        let kc = self.key_children.remove(index);
+        self.num_keys -= 1;
+        kc
+    }
+
+    /// childを指すkey_childrenのindexを返す。right_childの場合はkey_childrenの長さを返す
+    pub(crate) fn child_index(&self, child: u32) -> usize {
+        match self.key_children.iter().position(|kc| kc.child == child) {
+            Some(index) => index,
+            None => {
+                assert_eq!(self.right_child, child, "BTreeInternalNode::child_index: child {} not found", child);
+                self.key_children.len()
+            }
+        }
+    }
+
+    /// old_childを指していたポインタ(key_childrenかright_child)をnew_childに付け替える
+    pub(crate) fn replace_child(&mut self, old_child: u32, new_child: u32) {
+        if self.right_child == old_child {
+            self.right_child = new_child;
+            return;
+        }
+        for kc in self.key_children.iter_mut() {
+            if kc.child == old_child {
+                kc.child = new_child;
+                return;
+            }
+        }
+        unreachable!("BTreeInternalNode::replace_child: child {} not found", old_child);
+    }
+
+    pub(crate) fn children(&self) -> Vec<u32> {
+        let mut children: Vec<u32> = self.key_children.iter().map(|kc| kc.child).collect();
+        children.push(self.right_child);
+        children
+    }
+
     /// 次のページを返す。Leafまで再帰的に辿るのはPagerにやらせる
     pub(crate) fn find_key(&self, key: u32) -> u32 {
         let index = self.find_insert_position(key);
 
-        if self.key_children.len() < index {
-            self.key_children[index].key
+        if index < self.key_children.len() {
+            self.key_children[index].child
         } else {
             self.right_child
         }
@@ -180,6 +317,40 @@ impl Default for BTreeInternalNode {
     }
 }
 
+/// overflowページ。recordのうちleafに置けなかった部分を先頭から順に持つ。
+///
+/// 形式: node_type(u8) + next(u32) + dataの長さ(u16) + data
+#[derive(Clone)]
+pub struct BTreeOverflowNode {
+    pub node_type: NodeType,
+    /// 続きのoverflowページ。最後のページは0
+    pub next: u32,
+    pub data: Vec<u8>,
+}
+
+impl BTreeOverflowNode {
+    pub const HEADER_SIZE: usize = 1 + 4 + 2;
+    pub const SPACE_FOR_DATA: usize = PAGE_SIZE - Self::HEADER_SIZE;
+
+    pub fn new(next: u32, data: Vec<u8>) -> Self {
+        BTreeOverflowNode { node_type: NodeType::Overflow, next, data }
+    }
+}
+
+/// 空きページ。次の空きページのpage_numだけを持つ
+#[derive(Clone)]
+pub struct BTreeFreeNode {
+    pub node_type: NodeType,
+    /// 次の空きページ。最後の空きページは0(page 0はheaderなので空きページにはならない)
+    pub next_free: u32,
+}
+
+impl BTreeFreeNode {
+    pub fn new(next_free: u32) -> Self {
+        BTreeFreeNode { node_type: NodeType::Free, next_free }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct KC {
     pub child: u32,
@@ -190,14 +361,33 @@ impl BTreeNode {
     pub(crate) fn serialize(&self, buf: &mut Vec<u8>) {
         match self {
             BTreeNode::Leaf(page) => {
+                // cellを末尾から詰めて、その位置をポインタとしてheaderの後に並べる
+                let mut cells = vec![0u8; BTreeLeafNode::NODE_SPACE_FOR_CELLS];
+                let mut pointers = vec![];
+                let mut content_start = PAGE_SIZE;
+                for key_value in &page.key_values {
+                    let mut cell = vec![];
+                    let _ = cell.write_u32::<LittleEndian>(key_value.key);
+                    record::write_varint(&mut cell, key_value.record_len() as u64);
+                    cell.extend_from_slice(&key_value.value);
+                    if let Some(overflow) = &key_value.overflow {
+                        let _ = cell.write_u32::<LittleEndian>(overflow.page_num);
+                    }
+                    content_start -= cell.len();
+                    let offset = content_start - BTreeLeafNode::NODE_HEADER_SIZE;
+                    cells[offset..offset + cell.len()].copy_from_slice(&cell);
+                    pointers.push(content_start as u16);
+                }
                 let _ = buf.write(&[NodeType::Leaf as u8]);
                 let _ = buf.write(&[page.is_root]);
                 let _ = buf.write_u32::<LittleEndian>(page.parent);
-                let _ = buf.write_u32::<LittleEndian>(page.num_cells);
-                for key_value in &page.key_values {
-                    let _ = buf.write_u32::<LittleEndian>(key_value.key);
-                    let _ = buf.write(&key_value.value);
+                let _ = buf.write_u16::<LittleEndian>(page.key_values.len() as u16);
+                let _ = buf.write_u16::<LittleEndian>(content_start as u16);
+                let _ = buf.write_u32::<LittleEndian>(page.next_leaf);
+                for (i, pointer) in pointers.iter().enumerate() {
+                    cells[i * 2..i * 2 + 2].copy_from_slice(&pointer.to_le_bytes());
                 }
+                let _ = buf.write(&cells);
             }
             BTreeNode::Internal(page) => {
                 let _ = buf.write(&[NodeType::Internal as u8]);
@@ -210,6 +400,16 @@ impl BTreeNode {
                     let _ = buf.write_u32::<LittleEndian>(key_child.key);
                 }
             }
+            BTreeNode::Free(page) => {
+                let _ = buf.write(&[NodeType::Free as u8]);
+                let _ = buf.write_u32::<LittleEndian>(page.next_free);
+            }
+            BTreeNode::Overflow(page) => {
+                let _ = buf.write(&[NodeType::Overflow as u8]);
+                let _ = buf.write_u32::<LittleEndian>(page.next);
+                let _ = buf.write_u16::<LittleEndian>(page.data.len() as u16);
+                let _ = buf.write(&page.data);
+            }
         };
         if PAGE_SIZE > buf.len() {
             let padding = vec![0; PAGE_SIZE - buf.len()];
@@ -221,6 +421,7 @@ impl BTreeNode {
         match self {
             BTreeNode::Leaf(node) => node.is_root,
             BTreeNode::Internal(node) => node.is_root,
+            BTreeNode::Free(_) | BTreeNode::Overflow(_) => 0,
         }
     }
 
@@ -228,6 +429,40 @@ impl BTreeNode {
         match self {
             BTreeNode::Leaf(node) => node.parent,
             BTreeNode::Internal(node) => node.parent,
+            BTreeNode::Free(_) | BTreeNode::Overflow(_) => 0,
+        }
+    }
+
+    pub(crate) fn set_root(&mut self, is_root: u8) {
+        match self {
+            BTreeNode::Leaf(node) => node.is_root = is_root,
+            BTreeNode::Internal(node) => node.is_root = is_root,
+            BTreeNode::Free(_) | BTreeNode::Overflow(_) => unreachable!("BTreeNode::set_root: page is not a tree node"),
+        }
+    }
+
+    /// internal_max_cellsはinternalノードのkeyの上限
+    pub(crate) fn is_underflow(&self, internal_max_cells: usize) -> bool {
+        match self {
+            BTreeNode::Leaf(node) => node.is_underflow(),
+            BTreeNode::Internal(node) => node.is_underflow(internal_max_cells),
+            BTreeNode::Free(_) | BTreeNode::Overflow(_) => false,
+        }
+    }
+
+    pub(crate) fn can_lend(&self, internal_max_cells: usize) -> bool {
+        match self {
+            BTreeNode::Leaf(node) => node.can_lend(),
+            BTreeNode::Internal(node) => node.can_lend(internal_max_cells),
+            BTreeNode::Free(_) | BTreeNode::Overflow(_) => false,
+        }
+    }
+
+    pub(crate) fn set_parent(&mut self, parent: u32) {
+        match self {
+            BTreeNode::Leaf(node) => node.parent = parent,
+            BTreeNode::Internal(node) => node.parent = parent,
+            BTreeNode::Free(_) | BTreeNode::Overflow(_) => unreachable!("BTreeNode::set_parent: page is not a tree node"),
         }
     }
 
@@ -235,34 +470,130 @@ impl BTreeNode {
         match self {
             BTreeNode::Leaf(node) => node.max_key(),
             BTreeNode::Internal(node) => node.max_key(),
+            BTreeNode::Free(_) | BTreeNode::Overflow(_) => 0,
         }
     }
 }
 
 #[test]
 fn test_serialize() {
-    let mut row = vec![0; ROW_SIZE];
-    row.write_u32::<LittleEndian>(1);
-    row.write(b"foo\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0");
-    row.write(b"bar\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0");
-    let key_value = KV { key: 1, value: row };
+    let mut key_values: Vec<KV> = [(1, "foo"), (2, ""), (5, "a longer row")].iter()
+        .map(|(key, value)| KV { key: *key, value: value.as_bytes().to_vec(), overflow: None })
+        .collect();
+    // leafに収まらない行は、先頭だけとoverflowページへのポインタを持つ
+    key_values.push(KV {
+        key: 9,
+        value: vec![1; BTreeLeafNode::OVERFLOW_LOCAL_SIZE],
+        overflow: Some(Overflow { len: 10000, page_num: 42 }),
+    });
     let node = BTreeNode::Leaf(BTreeLeafNode {
         node_type: NodeType::Leaf,
-        is_root: 0,
+        is_root: 1,
         parent: 0,
-        num_cells: 1,
-        key_values: vec![key_value],
+        num_cells: 4,
+        next_leaf: 7,
+        key_values: key_values.clone(),
     });
 
     let mut buf = vec![];
     node.serialize(&mut buf);
-    eprintln!("buf: {:?}", buf);
+    assert_eq!(buf.len(), PAGE_SIZE);
+    // 1つ目のcellはページの末尾にある
+    let first = u16::from_le_bytes([buf[BTreeLeafNode::NODE_HEADER_SIZE], buf[BTreeLeafNode::NODE_HEADER_SIZE + 1]]) as usize;
+    assert_eq!(first, PAGE_SIZE - (4 + 1 + 3));
+    assert_eq!(&buf[first + 5..PAGE_SIZE], b"foo");
+    match BTreeNode::from(buf.as_slice()) {
+        BTreeNode::Leaf(leaf) => {
+            assert_eq!((leaf.is_root, leaf.num_cells, leaf.next_leaf), (1, 4, 7));
+            assert_eq!(leaf.used_size(), key_values.iter().map(|kv| BTreeLeafNode::cell_size(kv.record_len())).sum::<usize>());
+            let read: Vec<(u32, Vec<u8>, Option<Overflow>)> = leaf.key_values.into_iter().map(|kv| (kv.key, kv.value, kv.overflow)).collect();
+            assert_eq!(read, key_values.into_iter().map(|kv| (kv.key, kv.value, kv.overflow)).collect::<Vec<_>>());
+        }
+        _ => panic!("leaf expected"),
+    }
+
+    let node = BTreeNode::Overflow(BTreeOverflowNode::new(3, b"rest of the row".to_vec()));
+    let mut buf = vec![];
+    node.serialize(&mut buf);
+    assert_eq!(buf.len(), PAGE_SIZE);
+    match BTreeNode::from(buf.as_slice()) {
+        BTreeNode::Overflow(node) => assert_eq!((node.next, node.data.as_slice()), (3, &b"rest of the row"[..])),
+        _ => panic!("overflow page expected"),
+    }
+}
+
+#[test]
+fn test_leaf_capacity() {
+    let leaf = |sizes: &[usize]| BTreeLeafNode {
+        num_cells: sizes.len() as u32,
+        key_values: sizes.iter().enumerate().map(|(i, size)| KV { key: i as u32, value: vec![0; *size], overflow: None }).collect(),
+        ..BTreeLeafNode::default()
+    };
+    assert!(BTreeLeafNode::cell_size(BTreeLeafNode::MAX_RECORD_SIZE) <= BTreeLeafNode::MAX_CELL_SIZE);
+    // 最大のrecordが4つ入る
+    let full = leaf(&[BTreeLeafNode::MAX_RECORD_SIZE; 4]);
+    assert!(!full.is_overflow());
+    assert!(!full.has_room(0));
+    assert!(full.can_lend());
+    // 小さい行ならたくさん入る
+    assert!(leaf(&[40; 80]).has_room(40));
+    assert!(leaf(&[40; 10]).is_underflow());
+    assert!(!leaf(&[40; 33]).can_lend());
+
+    assert_eq!(BTreeLeafNode::split_point(&[1, 1, 1, 1]), 2);
+    assert_eq!(BTreeLeafNode::split_point(&[10, 1, 1, 1]), 1);
+    assert_eq!(BTreeLeafNode::split_point(&[1, 1, 1, 10]), 3);
+    assert_eq!(BTreeLeafNode::split_point(&[5, 5]), 1);
+}
+
+#[test]
+fn test_serialize_full_internal_node() {
+    // 上限いっぱいのkeyを持つノードを作って大きさを確かめる
+    let internal = |num_keys: usize| {
+        let mut node = BTreeInternalNode::new(0, 1);
+        for i in 0..num_keys {
+            node.insert(i as u32 * 2, i as u32 + 10);
+        }
+        node.right_child = 3;
+        let mut buf = vec![];
+        BTreeNode::Internal(node).serialize(&mut buf);
+        buf
+    };
+    let full = internal(BTreeInternalNode::INTERNAL_PAGE_MAX_CELLS);
+    assert_eq!(full.len(), PAGE_SIZE);
+    // 分割を待っている1つ多いノードも、追い出して書き出せる
+    let over = internal(BTreeInternalNode::INTERNAL_PAGE_MAX_CELLS + 1);
+    assert_eq!(over.len(), PAGE_SIZE);
+    match BTreeNode::from(over.as_slice()) {
+        BTreeNode::Internal(node) => {
+            assert_eq!(node.num_keys as usize, BTreeInternalNode::INTERNAL_PAGE_MAX_CELLS + 1);
+            assert_eq!(node.key_children.last().map(|kc| kc.key), Some(BTreeInternalNode::INTERNAL_PAGE_MAX_CELLS as u32 * 2));
+            assert_eq!(node.right_child, 3);
+        }
+        _ => panic!("internal node expected"),
+    }
 }
 
+/// leafのcell。recordがleafに収まらなければ、valueは先頭の一部でoverflowに続きがある
 #[derive(Debug, Clone)]
 pub struct KV {
     pub(crate) key: u32,
     pub(crate) value: Vec<u8>,
+    pub(crate) overflow: Option<Overflow>,
+}
+
+/// cellから続くoverflowページ
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Overflow {
+    /// cellに置いた部分も含めたrecordの長さ
+    pub(crate) len: usize,
+    pub(crate) page_num: u32,
+}
+
+impl KV {
+    pub(crate) fn record_len(&self) -> usize {
+        self.overflow.as_ref().map_or(self.value.len(), |overflow| overflow.len)
+    }
 }
 
 impl From<&[u8]> for BTreeNode {
@@ -275,13 +606,24 @@ impl From<&[u8]> for BTreeNode {
         } else {
             buf
         };
+        // cellのポインタはページの先頭からの位置
+        let page = buf;
         // trace!("BTreeNode::from::<u8>: buf:\n{:?}", buf);
         let node_type = match NodeType::try_from(buf.read_u8().expect("node_type must be u8")) {
             Ok(v) => { v }
-            Err(e) => panic!(e),
+            Err(e) => panic!("{}", e),
         };
         trace!("BTreeNode::from::<u8>: node_type: {:?}", node_type);
 
+        if let NodeType::Free = node_type {
+            let next_free = buf.read_u32::<LittleEndian>().expect("next_free must be u32");
+            return BTreeNode::Free(BTreeFreeNode::new(next_free));
+        }
+        if let NodeType::Overflow = node_type {
+            let next = buf.read_u32::<LittleEndian>().expect("next must be u32");
+            let len = buf.read_u16::<LittleEndian>().expect("data length must be u16") as usize;
+            return BTreeNode::Overflow(BTreeOverflowNode::new(next, buf[..len].to_vec()));
+        }
         let is_root = buf.read_u8().expect("is_root must be u8");
         trace!("BTreeNode::from::<u8>: is_root: {}", is_root);
         let parent: u32 = buf.read_u32::<LittleEndian>().expect("parent must be u32");
@@ -309,15 +651,27 @@ impl From<&[u8]> for BTreeNode {
                 BTreeNode::Internal(node)
             }
             NodeType::Leaf => {
-                let num_cells: u32 = buf.read_u32::<LittleEndian>().expect("num_cells must be u32");
+                let num_cells = buf.read_u16::<LittleEndian>().expect("num_cells must be u16") as u32;
                 trace!("BTreeNode::from::<u8>: num_cells: {}", num_cells);
+                let _content_start = buf.read_u16::<LittleEndian>().expect("cell content start must be u16");
+                let next_leaf: u32 = buf.read_u32::<LittleEndian>().expect("next_leaf must be u32");
                 let mut key_values = vec![];
                 for _ in 0..num_cells {
-                    let key = buf.read_u32::<LittleEndian>().expect("key must be u32");
-                    let mut value = vec![0; ROW_SIZE];
-                    let n = buf.read(&mut value).expect("read row failed");
-                    trace!("BTreeNode::from:::<u8>: read row bytes: {}", n);
-                    let kv = KV { key, value };
+                    let offset = buf.read_u16::<LittleEndian>().expect("cell pointer must be u16") as usize;
+                    let mut cell = &page[offset..];
+                    let key = cell.read_u32::<LittleEndian>().expect("key must be u32");
+                    let (len, n) = record::read_varint(cell).expect("record length must be varint");
+                    let len = len as usize;
+                    let local = BTreeLeafNode::local_size(len);
+                    let value = cell[n..n + local].to_vec();
+                    let overflow = if local < len {
+                        let page_num = (&cell[n + local..]).read_u32::<LittleEndian>().expect("overflow page must be u32");
+                        Some(Overflow { len, page_num })
+                    } else {
+                        None
+                    };
+                    trace!("BTreeNode::from:::<u8>: read row bytes: {}", len);
+                    let kv = KV { key, value, overflow };
                     key_values.push(kv);
                 }
                 let node: BTreeLeafNode = BTreeLeafNode {
@@ -325,10 +679,12 @@ impl From<&[u8]> for BTreeNode {
                     is_root,
                     parent,
                     num_cells,
+                    next_leaf,
                     key_values,
                 };
                 BTreeNode::Leaf(node)
             }
+            NodeType::Free | NodeType::Overflow => unreachable!("BTreeNode::from::<u8>: page without parent is already returned"),
         }
     }
 }
\ No newline at end of file
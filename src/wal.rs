@@ -0,0 +1,330 @@
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use byteorder::{LittleEndian, WriteBytesExt};
+use log::trace;
+use crate::PAGE_SIZE;
+
+/// Write-Ahead Log。
+/// WALモードではデータファイルを直接書き換えず、変更したページをframeとして-walファイルの末尾に追記する。
+/// commitはcommit frameを追記するだけで、データファイルへの書き戻しはcheckpointでまとめて行う。
+///
+/// ファイルの形式:
+/// - header: MAGIC(8) + salt(u32)
+/// - page frame: FRAME_PAGE(u8) + page_num(u32) + ページ(PAGE_SIZE) + checksum(u32)
+/// - commit frame: FRAME_COMMIT(u8) + commit時のページ数(u32) + checksum(u32)
+///
+/// checksumは直前のframeのchecksum(最初のframeはsalt)から連鎖させるので、
+/// 書きかけのframeや、checkpoint前の古いframeは読み込まれない
+pub(crate) struct Wal {
+    path: PathBuf,
+    file: File,
+    salt: u32,
+    // commit済みのページ -> 最新のframeのページのoffset
+    index: HashMap<usize, u64>,
+    // まだcommitしていないframe。自分自身の読み込みではindexより優先する
+    pending: HashMap<usize, u64>,
+    // 最後に書いたframeのchecksum
+    last_checksum: u32,
+    // 次のframeを書く位置
+    end: u64,
+    // 最後のcommit時のページ数
+    num_pages: usize,
+    // checkpointしていないcommit済みのframe数
+    frame_count: usize,
+}
+
+impl Wal {
+    const MAGIC: &'static [u8; 8] = b"LBSDWAL\0";
+    const HEADER_SIZE: u64 = 8 + 4;
+    const FRAME_PAGE: u8 = 1;
+    const FRAME_COMMIT: u8 = 2;
+    const FRAME_HEADER_SIZE: usize = 1 + 4;
+    const PAGE_FRAME_SIZE: usize = Self::FRAME_HEADER_SIZE + PAGE_SIZE + 4;
+    const COMMIT_FRAME_SIZE: usize = Self::FRAME_HEADER_SIZE + 4;
+
+    pub(crate) fn path_for(db_path: &Path) -> PathBuf {
+        let mut path = db_path.as_os_str().to_owned();
+        path.push("-wal");
+        PathBuf::from(path)
+    }
+
+    pub(crate) fn exists(db_path: &Path) -> bool {
+        Self::path_for(db_path).exists()
+    }
+
+    /// -walファイルを開く。既にあればcommit済みのframeからindexを作り直し、
+    /// checksumが合わないframeやcommitされていないframeは切り捨てる
+    pub(crate) fn open(db_path: &Path) -> Result<Self, String> {
+        let path = Self::path_for(db_path);
+        trace!("Wal::open: {}", path.display());
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .map_err(|e| e.to_string())?;
+        let mut buf = vec![];
+        file.read_to_end(&mut buf).map_err(|e| e.to_string())?;
+        let mut wal = Wal {
+            path,
+            file,
+            salt: 0,
+            index: HashMap::new(),
+            pending: HashMap::new(),
+            last_checksum: 0,
+            end: Self::HEADER_SIZE,
+            num_pages: 0,
+            frame_count: 0,
+        };
+        if buf.len() < Self::HEADER_SIZE as usize || &buf[..8] != Self::MAGIC {
+            trace!("Wal::open: header is incomplete. initialize it");
+            wal.reset()?;
+            return Ok(wal);
+        }
+        wal.salt = u32::from_le_bytes(buf[8..12].try_into().unwrap());
+        wal.recover(&buf)?;
+        Ok(wal)
+    }
+
+    /// 先頭から順にframeを読み、commit frameまで揃っているframeだけをindexに入れる
+    fn recover(&mut self, buf: &[u8]) -> Result<(), String> {
+        self.last_checksum = self.salt;
+        let mut checksum = self.salt;
+        let mut pos = Self::HEADER_SIZE as usize;
+        let mut frames = HashMap::new();
+        let mut num_frames = 0;
+        while pos + Self::FRAME_HEADER_SIZE <= buf.len() {
+            let size = match buf[pos] {
+                Self::FRAME_PAGE => Self::PAGE_FRAME_SIZE,
+                Self::FRAME_COMMIT => Self::COMMIT_FRAME_SIZE,
+                _ => break,
+            };
+            if pos + size > buf.len() {
+                trace!("Wal::recover: ignore torn frame at {}", pos);
+                break;
+            }
+            let (body, sum) = buf[pos..pos + size].split_at(size - 4);
+            let expected = chain_checksum(checksum, body);
+            if expected != u32::from_le_bytes(sum.try_into().unwrap()) {
+                trace!("Wal::recover: checksum mismatch at {}. ignore the rest", pos);
+                break;
+            }
+            checksum = expected;
+            let value = u32::from_le_bytes(body[1..5].try_into().unwrap()) as usize;
+            if body[0] == Self::FRAME_PAGE {
+                frames.insert(value, (pos + Self::FRAME_HEADER_SIZE) as u64);
+                num_frames += 1;
+            } else {
+                trace!("Wal::recover: commit frame at {}. num_pages: {}", pos, value);
+                // commit時のページ数を超えるframeはROLLBACKで捨てたページ
+                frames.retain(|page_num, _| *page_num < value);
+                self.index.extend(frames.drain());
+                self.frame_count += num_frames;
+                num_frames = 0;
+                self.num_pages = value;
+                self.last_checksum = checksum;
+                self.end = (pos + size) as u64;
+            }
+            pos += size;
+        }
+        trace!("Wal::recover: {} pages in {} frames, end: {}", self.index.len(), self.frame_count, self.end);
+        // commitされていない残りは捨てる
+        self.file.set_len(self.end).map_err(|e| e.to_string())
+    }
+
+    /// 空のWALにする。saltを変えるので、消し損ねた古いframeも読み込まれない
+    fn reset(&mut self) -> Result<(), String> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.subsec_nanos()).unwrap_or(0);
+        self.salt = self.salt.wrapping_add(1) ^ now;
+        trace!("Wal::reset: salt: {}", self.salt);
+        let mut header = Vec::with_capacity(Self::HEADER_SIZE as usize);
+        header.extend_from_slice(Self::MAGIC);
+        let _ = header.write_u32::<LittleEndian>(self.salt);
+        self.file.set_len(0).map_err(|e| e.to_string())?;
+        self.file.seek(SeekFrom::Start(0)).map_err(|e| e.to_string())?;
+        self.file.write_all(&header).map_err(|e| e.to_string())?;
+        self.index.clear();
+        self.pending.clear();
+        self.last_checksum = self.salt;
+        self.end = Self::HEADER_SIZE;
+        self.frame_count = 0;
+        Ok(())
+    }
+
+    /// 最後のcommit時のページ数。commitしたことがなければ0
+    pub(crate) fn num_pages(&self) -> usize {
+        self.num_pages
+    }
+
+    pub(crate) fn frame_count(&self) -> usize {
+        self.frame_count
+    }
+
+    pub(crate) fn has_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// page_numの最新のframeを読む。WALになければfalseを返す
+    pub(crate) fn read_page(&mut self, page_num: usize, buf: &mut [u8]) -> Result<bool, String> {
+        let offset = match self.pending.get(&page_num).or_else(|| self.index.get(&page_num)) {
+            Some(offset) => *offset,
+            None => return Ok(false),
+        };
+        trace!("Wal::read_page: page_num: {}, offset: {}", page_num, offset);
+        self.file.seek(SeekFrom::Start(offset)).map_err(|e| e.to_string())?;
+        self.file.read_exact(&mut buf[..PAGE_SIZE]).map_err(|e| e.to_string())?;
+        Ok(true)
+    }
+
+    fn append_frame(&mut self, kind: u8, value: u32, data: &[u8]) -> Result<u64, String> {
+        let mut frame = Vec::with_capacity(Self::FRAME_HEADER_SIZE + data.len() + 4);
+        frame.push(kind);
+        let _ = frame.write_u32::<LittleEndian>(value);
+        frame.extend_from_slice(data);
+        self.last_checksum = chain_checksum(self.last_checksum, &frame);
+        let _ = frame.write_u32::<LittleEndian>(self.last_checksum);
+        let pos = self.end;
+        self.file.seek(SeekFrom::Start(pos)).map_err(|e| e.to_string())?;
+        self.file.write_all(&frame).map_err(|e| e.to_string())?;
+        self.end += frame.len() as u64;
+        Ok(pos)
+    }
+
+    /// ページをframeとして追記する。commitするまでは他の接続からは見えない
+    pub(crate) fn append_page(&mut self, page_num: usize, page: &[u8]) -> Result<(), String> {
+        trace!("Wal::append_page: page_num: {}", page_num);
+        let mut data = page.to_vec();
+        data.resize(PAGE_SIZE, 0);
+        let pos = self.append_frame(Self::FRAME_PAGE, page_num as u32, &data)?;
+        self.pending.insert(page_num, pos + Self::FRAME_HEADER_SIZE as u64);
+        Ok(())
+    }
+
+    /// num_pages以降のページのcommitしていないframeを捨てる
+    pub(crate) fn truncate(&mut self, num_pages: usize) {
+        trace!("Wal::truncate: num_pages: {}", num_pages);
+        self.pending.retain(|page_num, _| *page_num < num_pages);
+    }
+
+    /// commit frameを追記して、それまでのframeをcommit済みにする
+    pub(crate) fn commit(&mut self, num_pages: usize) -> Result<(), String> {
+        trace!("Wal::commit: {} frames, num_pages: {}", self.pending.len(), num_pages);
+        self.truncate(num_pages);
+        self.append_frame(Self::FRAME_COMMIT, num_pages as u32, &[])?;
+        self.frame_count += self.pending.len();
+        self.index.extend(self.pending.drain());
+        self.num_pages = num_pages;
+        Ok(())
+    }
+
+    pub(crate) fn sync(&mut self, full: bool) -> Result<(), String> {
+        trace!("Wal::sync: full: {}", full);
+        if full {
+            self.file.sync_all().map_err(|e| e.to_string())
+        } else {
+            self.file.sync_data().map_err(|e| e.to_string())
+        }
+    }
+
+    /// commit済みのframeをデータファイルに書き戻してからWALを空にする。書き戻したページ数を返す
+    pub(crate) fn checkpoint(&mut self, db: &mut File, sync: bool) -> Result<usize, String> {
+        if self.has_pending() {
+            return Err("cannot checkpoint while there are uncommitted frames".to_string());
+        }
+        let mut pages: Vec<(usize, u64)> = self.index.iter().map(|(k, v)| (*k, *v)).collect();
+        pages.sort_unstable();
+        trace!("Wal::checkpoint: {} pages", pages.len());
+        let mut buf = vec![0u8; PAGE_SIZE];
+        for (page_num, offset) in &pages {
+            self.file.seek(SeekFrom::Start(*offset)).map_err(|e| e.to_string())?;
+            self.file.read_exact(&mut buf).map_err(|e| e.to_string())?;
+            db.seek(SeekFrom::Start((page_num * PAGE_SIZE) as u64)).map_err(|e| e.to_string())?;
+            db.write_all(&buf).map_err(|e| e.to_string())?;
+        }
+        // データファイルが永続化される前にWALを消すと、落ちたときにcommitが失われる
+        if sync {
+            db.sync_all().map_err(|e| e.to_string())?;
+        }
+        self.reset()?;
+        if sync {
+            self.sync(true)?;
+        }
+        Ok(pages.len())
+    }
+
+    /// rollbackジャーナルに戻すので-walファイルを消す。先にcheckpointしておくこと
+    pub(crate) fn delete(self) -> Result<(), String> {
+        trace!("Wal::delete: {}", self.path.display());
+        drop(self.file);
+        fs::remove_file(&self.path).map_err(|e| e.to_string())
+    }
+}
+
+/// 直前のframeのchecksumとframeの内容からFNV-1aでchecksumを計算する
+fn chain_checksum(prev: u32, data: &[u8]) -> u32 {
+    let mut buf = Vec::with_capacity(4 + data.len());
+    buf.extend_from_slice(&prev.to_le_bytes());
+    buf.extend_from_slice(data);
+    crate::journal::checksum(&buf)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_recover_only_committed_frames() {
+        let db_path = Path::new("tmp/test_wal_recover.db");
+        let _ = fs::remove_file(Wal::path_for(db_path));
+        let mut wal = Wal::open(db_path).unwrap();
+        wal.append_page(0, &[1u8; PAGE_SIZE]).unwrap();
+        wal.append_page(1, &[1u8; PAGE_SIZE]).unwrap();
+        wal.commit(2).unwrap();
+        wal.append_page(0, &[2u8; PAGE_SIZE]).unwrap();
+        wal.commit(2).unwrap();
+        // commitしないまま落ちる
+        wal.append_page(1, &[3u8; PAGE_SIZE]).unwrap();
+        let mut buf = vec![0u8; PAGE_SIZE];
+        assert!(wal.read_page(1, &mut buf).unwrap());
+        assert_eq!(buf, vec![3u8; PAGE_SIZE]);
+        drop(wal);
+
+        let mut wal = Wal::open(db_path).unwrap();
+        assert_eq!(wal.num_pages(), 2);
+        assert_eq!(wal.frame_count(), 3);
+        assert!(wal.read_page(0, &mut buf).unwrap());
+        assert_eq!(buf, vec![2u8; PAGE_SIZE]);
+        assert!(wal.read_page(1, &mut buf).unwrap());
+        assert_eq!(buf, vec![1u8; PAGE_SIZE]);
+        assert!(!wal.read_page(2, &mut buf).unwrap());
+    }
+
+    #[test]
+    fn test_recover_stops_at_checksum_mismatch() {
+        let db_path = Path::new("tmp/test_wal_checksum.db");
+        let _ = fs::remove_file(Wal::path_for(db_path));
+        let mut wal = Wal::open(db_path).unwrap();
+        wal.append_page(0, &[1u8; PAGE_SIZE]).unwrap();
+        wal.commit(1).unwrap();
+        wal.append_page(0, &[2u8; PAGE_SIZE]).unwrap();
+        wal.commit(1).unwrap();
+        drop(wal);
+        // 2つ目のtransactionのページを壊す
+        let wal_path = Wal::path_for(db_path);
+        let mut bytes = fs::read(&wal_path).unwrap();
+        let pos = Wal::HEADER_SIZE as usize + Wal::PAGE_FRAME_SIZE + Wal::COMMIT_FRAME_SIZE + 10;
+        bytes[pos] ^= 0xff;
+        fs::write(&wal_path, &bytes).unwrap();
+
+        let mut wal = Wal::open(db_path).unwrap();
+        let mut buf = vec![0u8; PAGE_SIZE];
+        assert!(wal.read_page(0, &mut buf).unwrap());
+        assert_eq!(buf, vec![1u8; PAGE_SIZE]);
+        assert_eq!(fs::metadata(&wal_path).unwrap().len(), Wal::HEADER_SIZE + (Wal::PAGE_FRAME_SIZE + Wal::COMMIT_FRAME_SIZE) as u64);
+    }
+}